@@ -1,22 +1,102 @@
 // This is free and unencumbered software released into the public domain.
 
-use serde::Deserialize;
+use serde::{de, de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 use sha3::{Digest, Keccak256};
-use std::fmt;
+use std::{collections::HashMap, fmt};
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Default)]
-pub struct Functions(Vec<Function>);
+/// A contract ABI: its functions, along with its constructor, `fallback`
+/// and `receive` functions, events and custom errors.
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct Functions {
+    functions: Vec<Function>,
+    pub constructor: Option<Constructor>,
+    /// The state mutability of the `fallback` function, if any.
+    pub fallback: Option<StateMutability>,
+    /// The state mutability of the `receive` function, if any.
+    pub receive: Option<StateMutability>,
+    pub events: Vec<Event>,
+    pub errors: Vec<CustomError>,
+}
+
+impl Functions {
+    pub fn iter(&self) -> std::slice::Iter<'_, Function> {
+        self.functions.iter()
+    }
+}
+
+/// The `fallback` or `receive` entry of the JSON ABI.
+#[derive(Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+struct EntryPoint {
+    state_mutability: Option<StateMutability>,
+}
+
+impl<'de> Deserialize<'de> for Functions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        fn entry<T: DeserializeOwned, E: de::Error>(value: Value) -> Result<T, E> {
+            serde_json::from_value(value).map_err(E::custom)
+        }
+
+        let mut result = Functions::default();
+        for mut value in Vec::<Value>::deserialize(deserializer)? {
+            let object = match value.as_object_mut() {
+                Some(object) => object,
+                None => return Err(de::Error::custom("ABI entry is not an object")),
+            };
+            // ABIs predating `stateMutability`, of solc before 0.5 and of
+            // Vyper, give the mutability with `constant` and `payable`
+            if !object.contains_key("stateMutability") {
+                let flag = |key| object.get(key).map(|flag| flag == true);
+                let mutability = match (flag("constant"), flag("payable")) {
+                    (None, None) => None,
+                    (_, Some(true)) => Some("payable"),
+                    (Some(true), _) => Some("view"),
+                    _ => Some("nonpayable"),
+                };
+                if let Some(mutability) = mutability {
+                    object.insert("stateMutability".into(), mutability.into());
+                }
+            }
+            // the type defaults to `function`
+            let r#type = object.entry("type").or_insert_with(|| "function".into());
+            match r#type.as_str() {
+                Some("function") => result.functions.push(entry(value)?),
+                Some("constructor") => result.constructor = Some(entry(value)?),
+                Some("event") => result.events.push(entry(value)?),
+                Some("error") => result.errors.push(entry(value)?),
+                Some("fallback") => {
+                    let entry_point: EntryPoint = entry(value)?;
+                    let mutability = entry_point.state_mutability;
+                    result.fallback = mutability.or(Some(StateMutability::Nonpayable));
+                }
+                Some("receive") => {
+                    let entry_point: EntryPoint = entry(value)?;
+                    let mutability = entry_point.state_mutability;
+                    result.receive = mutability.or(Some(StateMutability::Payable));
+                }
+                _ => {
+                    return Err(de::Error::custom(format!(
+                        "unknown ABI entry type {}",
+                        r#type
+                    )))
+                }
+            }
+        }
+        Ok(result)
+    }
+}
 
 impl IntoIterator for Functions {
     type Item = Function;
     type IntoIter = std::vec::IntoIter<Self::Item>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.0.into_iter()
+        self.functions.into_iter()
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 #[serde(rename_all(deserialize = "camelCase"))]
 pub enum StateMutability {
     Nonpayable,
@@ -47,20 +127,66 @@ pub struct Parameter {
     /// for the distinction.
     pub r#type: String,
     pub internal_type: Option<String>,
+    /// The components of a `tuple` type, or of an array of tuples.
+    #[serde(default)]
+    pub components: Vec<Parameter>,
+}
+
+impl Parameter {
+    /// Returns the type as spelled in signatures, where tuples are spelled
+    /// out by their components: `tuple[]` as `(address,uint256)[]`.
+    pub fn canonical_type(&self) -> String {
+        match self.r#type.strip_prefix("tuple") {
+            Some(dimensions) => {
+                let components: Vec<String> = self
+                    .components
+                    .iter()
+                    .map(Parameter::canonical_type)
+                    .collect();
+                format!("({}){}", components.join(","), dimensions)
+            }
+            None => self.r#type.clone(),
+        }
+    }
 }
 
 impl fmt::Display for Parameter {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.r#type)
+        write!(f, "{}", self.canonical_type())
     }
 }
 
+/// Writes the signature `name(type,...)` that selectors and topics hash.
+fn write_signature<'a>(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    inputs: impl Iterator<Item = &'a Parameter>,
+) -> fmt::Result {
+    write!(f, "{}(", name)?;
+    for (i, input) in inputs.enumerate() {
+        if i > 0 {
+            write!(f, ",")?
+        }
+        write!(f, "{}", input)?;
+    }
+    write!(f, ")")
+}
+
+/// Returns the first four bytes of the hash of a signature.
+fn selector_bytes(signature: &str) -> [u8; 4] {
+    let bytes = Keccak256::digest(signature);
+    let mut result = [0u8; 4];
+    result.copy_from_slice(&bytes[0..4]);
+    result
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize, Debug, PartialEq, Eq)]
 #[serde(rename_all(deserialize = "camelCase"))]
 pub struct Function {
     pub name: String,
     pub inputs: Vec<Parameter>,
+    #[serde(default)]
     pub outputs: Vec<Parameter>,
     pub state_mutability: StateMutability,
     pub r#type: String,
@@ -68,14 +194,7 @@ pub struct Function {
 
 impl fmt::Display for Function {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}(", self.name)?;
-        for (i, input) in self.inputs.iter().enumerate() {
-            if i > 0 {
-                write!(f, ",")?
-            }
-            write!(f, "{}", input)?;
-        }
-        write!(f, ")")
+        write_signature(f, &self.name, self.inputs.iter())
     }
 }
 
@@ -86,22 +205,147 @@ impl Function {
     }
 
     pub fn selector_bytes(&self) -> [u8; 4] {
-        let input = format!("{}", self);
-        let bytes = Keccak256::digest(input);
-        let mut result = [0u8; 4];
-        result.copy_from_slice(&bytes[0..4]);
-        result
+        selector_bytes(&self.to_string())
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct Constructor {
+    pub inputs: Vec<Parameter>,
+    pub state_mutability: StateMutability,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+pub struct EventParameter {
+    #[serde(flatten)]
+    pub param: Parameter,
+    pub indexed: bool,
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub name: String,
+    pub inputs: Vec<EventParameter>,
+    #[serde(default)]
+    pub anonymous: bool,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_signature(f, &self.name, self.inputs.iter().map(|input| &input.param))
+    }
+}
+
+impl Event {
+    /// Returns the first topic of the logs of the event, the hash of its
+    /// signature, unless the event is anonymous.
+    #[allow(dead_code)]
+    pub fn topic(&self) -> Option<[u8; 32]> {
+        (!self.anonymous).then(|| Keccak256::digest(self.to_string()).into())
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+pub struct CustomError {
+    pub name: String,
+    pub inputs: Vec<Parameter>,
+}
+
+impl fmt::Display for CustomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_signature(f, &self.name, self.inputs.iter())
+    }
+}
+
+impl CustomError {
+    #[allow(dead_code)]
+    pub fn selector(&self) -> u32 {
+        u32::from_be_bytes(selector_bytes(&self.to_string()))
+    }
+}
+
+/// The export of an ABI function, as recorded in the output metadata.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MethodExport {
+    pub export: String,
+    pub signature: String,
+    pub selector: String,
+}
+
+/// Names the exports of the given functions. A function is exported under
+/// its own name unless it is overloaded: then the overload with the fewest
+/// inputs keeps the name, ties broken by signature, and the others get their
+/// input types appended, as in `safeTransferFrom_address_address_uint256_bytes`.
+///
+/// The `overrides` map signatures, as in `f(uint256)`, to export names, and
+/// take precedence over the mangling.
+pub fn method_exports(
+    funcs: &Functions,
+    overrides: &HashMap<String, String>,
+) -> Result<Vec<MethodExport>, String> {
+    let mut overloads: HashMap<&str, Vec<&Function>> = HashMap::new();
+    for func in funcs.iter() {
+        overloads.entry(&func.name).or_default().push(func);
+    }
+    for overloads in overloads.values_mut() {
+        overloads.sort_by_key(|func| (func.inputs.len(), func.to_string()));
     }
+
+    let signatures: Vec<String> = funcs.iter().map(Function::to_string).collect();
+    if let Some(signature) = overrides.keys().find(|sig| !signatures.contains(*sig)) {
+        return Err(format!(
+            "export name given for unknown function `{}`",
+            signature
+        ));
+    }
+
+    let exports = funcs.iter().zip(signatures).map(|(func, signature)| {
+        let export = match overrides.get(&signature) {
+            Some(export) => export.clone(),
+            None if std::ptr::eq(overloads[func.name.as_str()][0], func) => func.name.clone(),
+            None => mangle(func),
+        };
+        MethodExport {
+            export,
+            signature,
+            selector: format!("0x{}", hex::encode(func.selector_bytes())),
+        }
+    });
+    Ok(exports.collect())
+}
+
+/// Appends the input types to the function name, spelling arrays and tuples
+/// out so that the name remains an identifier: `uint256[2][]` as
+/// `uint256Array2Array`, `(address,bool)` as `Tuple_address_bool`.
+fn mangle(func: &Function) -> String {
+    let mut name = func.name.clone();
+    for input in func.inputs.iter() {
+        name.push('_');
+        for c in input.canonical_type().chars() {
+            match c {
+                '[' => name.push_str("Array"),
+                '(' => name.push_str("Tuple_"),
+                ',' => name.push('_'),
+                ']' | ')' => {}
+                c => name.push(c),
+            }
+        }
+    }
+    name
 }
 
 #[allow(dead_code)]
 pub fn parse_str(json: &str) -> Result<Functions, serde_json::Error> {
-    serde_json::from_str::<Vec<Function>>(json).map(Functions)
+    serde_json::from_str::<Functions>(json)
 }
 
 #[allow(dead_code)]
 pub fn parse_bytes(json: &[u8]) -> Result<Functions, serde_json::Error> {
-    serde_json::from_slice::<Vec<Function>>(json).map(Functions)
+    serde_json::from_slice::<Functions>(json)
 }
 
 #[cfg(test)]
@@ -132,27 +376,30 @@ mod tests {
                     name: "a".to_string(),
                     r#type: "int256".to_string(),
                     internal_type: Some("int256".to_string()),
+                    components: vec![],
                 },
                 Parameter {
                     name: "b".to_string(),
                     r#type: "int256".to_string(),
                     internal_type: Some("int256".to_string()),
+                    components: vec![],
                 },
             ],
             outputs: vec![Parameter {
                 name: "".to_string(),
                 r#type: "int256".to_string(),
                 internal_type: Some("int256".to_string()),
+                components: vec![],
             }],
             state_mutability: StateMutability::Pure,
             r#type: "function".to_string(),
         }];
-        assert_eq!(parse_str(MULTIPLY).unwrap().0, parsed);
+        assert_eq!(parse_str(MULTIPLY).unwrap().functions, parsed);
     }
 
     #[test]
     fn test_display() {
-        let funcs = parse_str(MULTIPLY).unwrap().0;
+        let funcs = parse_str(MULTIPLY).unwrap().functions;
         let func = funcs.first().unwrap();
         assert_eq!(format!("{}", func), "multiply(int256,int256)");
     }
@@ -174,8 +421,232 @@ mod tests {
                 "stateMutability":"pure"
             }
         ]"#;
-        let funcs = parse_str(baz_abi).unwrap().0;
+        let funcs = parse_str(baz_abi).unwrap().functions;
         let func = funcs.first().unwrap();
         assert_eq!(func.selector(), 0xcdcd77c0);
     }
+
+    #[test]
+    fn test_parse_entry_points() {
+        let weth_abi = r#"[
+            {"type":"fallback","stateMutability":"payable"},
+            {"type":"receive","stateMutability":"payable"},
+            {
+                "name":"Deposit",
+                "type":"event",
+                "inputs":[
+                    {"indexed":true,"name":"dst","type":"address"},
+                    {"indexed":false,"name":"wad","type":"uint256"}
+                ],
+                "anonymous":false
+            },
+            {
+                "name":"deposit",
+                "type":"function",
+                "inputs":[],
+                "outputs":[],
+                "stateMutability":"payable"
+            }
+        ]"#;
+        let funcs = parse_str(weth_abi).unwrap();
+        assert_eq!(funcs.fallback, Some(StateMutability::Payable));
+        assert_eq!(funcs.receive, Some(StateMutability::Payable));
+        assert_eq!(
+            funcs.iter().map(Function::to_string).collect::<Vec<_>>(),
+            ["deposit()"]
+        );
+
+        let funcs = parse_str(r#"[{"type":"fallback"}]"#).unwrap();
+        assert_eq!(funcs.fallback, Some(StateMutability::Nonpayable));
+        assert_eq!(funcs.receive, None);
+
+        let invalid = r#"[{"name":"f","type":"function","inputs":[]}]"#;
+        assert!(parse_str(invalid).is_err());
+    }
+
+    #[test]
+    fn test_parse_legacy_mutability() {
+        // as output by solc before 0.5 and by Vyper, without stateMutability
+        let legacy_abi = r#"[
+            {"type":"fallback","payable":true},
+            {"name":"a","inputs":[],"outputs":[],"constant":true,"payable":false},
+            {"name":"b","inputs":[],"outputs":[],"constant":false,"payable":true},
+            {"name":"c","inputs":[],"outputs":[],"constant":false,"payable":false},
+            {"name":"d","inputs":[],"outputs":[],"constant":true},
+            {"name":"e","inputs":[],"outputs":[],"payable":false,"stateMutability":"pure"}
+        ]"#;
+        let funcs = parse_str(legacy_abi).unwrap();
+        assert_eq!(funcs.fallback, Some(StateMutability::Payable));
+        assert_eq!(
+            funcs
+                .iter()
+                .map(|func| func.state_mutability)
+                .collect::<Vec<_>>(),
+            [
+                StateMutability::View,
+                StateMutability::Payable,
+                StateMutability::Nonpayable,
+                StateMutability::View,
+                StateMutability::Pure,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_full_abi() {
+        let abi = r#"[
+            {
+                "type":"constructor",
+                "inputs":[{"name":"owner","type":"address"}],
+                "stateMutability":"nonpayable"
+            },
+            {
+                "name":"Transfer",
+                "type":"event",
+                "inputs":[
+                    {"indexed":true,"name":"from","type":"address"},
+                    {"indexed":true,"name":"to","type":"address"},
+                    {"indexed":false,"name":"value","type":"uint256"}
+                ],
+                "anonymous":false
+            },
+            {
+                "name":"Panic",
+                "type":"error",
+                "inputs":[{"name":"code","type":"uint256"}]
+            },
+            {
+                "name":"exactInputSingle",
+                "type":"function",
+                "inputs":[{
+                    "name":"params",
+                    "type":"tuple",
+                    "components":[
+                        {"name":"tokenIn","type":"address"},
+                        {"name":"tokenOut","type":"address"},
+                        {"name":"fee","type":"uint24"},
+                        {"name":"recipient","type":"address"},
+                        {"name":"deadline","type":"uint256"},
+                        {"name":"amountIn","type":"uint256"},
+                        {"name":"amountOutMinimum","type":"uint256"},
+                        {"name":"sqrtPriceLimitX96","type":"uint160"}
+                    ]
+                }],
+                "outputs":[{"name":"amountOut","type":"uint256"}],
+                "stateMutability":"payable"
+            }
+        ]"#;
+        let abi = parse_str(abi).unwrap();
+        let constructor = abi.constructor.as_ref().unwrap();
+        assert_eq!(constructor.inputs[0].r#type, "address");
+
+        let event = &abi.events[0];
+        assert_eq!(event.to_string(), "Transfer(address,address,uint256)");
+        assert!(event.inputs[0].indexed && !event.inputs[2].indexed);
+        assert_eq!(
+            hex::encode(event.topic().unwrap()),
+            "ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"
+        );
+
+        assert_eq!(abi.errors[0].selector(), 0x4e487b71);
+
+        let func = abi.iter().next().unwrap();
+        assert_eq!(
+            func.to_string(),
+            "exactInputSingle((address,address,uint24,address,uint256,uint256,uint256,uint160))"
+        );
+        assert_eq!(func.selector(), 0x414bf389);
+    }
+
+    #[test]
+    fn test_canonical_type() {
+        let param: Parameter = serde_json::from_str(
+            r#"{
+                "name":"orders",
+                "type":"tuple[2][]",
+                "components":[
+                    {"name":"maker","type":"address"},
+                    {
+                        "name":"legs",
+                        "type":"tuple[]",
+                        "components":[{"name":"amount","type":"uint256"}]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(param.canonical_type(), "(address,(uint256)[])[2][]");
+        assert!(parse_str(r#"[{"type":"bogus"}]"#).is_err());
+    }
+
+    static OVERLOADS: &str = r#"[
+        {
+            "name":"safeTransferFrom",
+            "type":"function",
+            "inputs":[
+                {"name":"from","type":"address"},
+                {"name":"to","type":"address"},
+                {"name":"tokenId","type":"uint256"},
+                {"name":"data","type":"bytes"}
+            ],
+            "outputs":[],
+            "stateMutability":"nonpayable"
+        },
+        {
+            "name":"safeTransferFrom",
+            "type":"function",
+            "inputs":[
+                {"name":"from","type":"address"},
+                {"name":"to","type":"address"},
+                {"name":"tokenId","type":"uint256"}
+            ],
+            "outputs":[],
+            "stateMutability":"nonpayable"
+        },
+        {
+            "name":"balanceOf",
+            "type":"function",
+            "inputs":[{"name":"owners","type":"address[2][]"}],
+            "outputs":[],
+            "stateMutability":"view"
+        },
+        {
+            "name":"balanceOf",
+            "type":"function",
+            "inputs":[{"name":"owner","type":"address"}],
+            "outputs":[],
+            "stateMutability":"view"
+        }
+    ]"#;
+
+    #[test]
+    fn test_method_exports() {
+        let funcs = parse_str(OVERLOADS).unwrap();
+        let exports = method_exports(&funcs, &HashMap::new()).unwrap();
+        let names: Vec<&str> = exports.iter().map(|e| e.export.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "safeTransferFrom_address_address_uint256_bytes",
+                "safeTransferFrom",
+                "balanceOf_addressArray2Array",
+                "balanceOf",
+            ]
+        );
+        assert_eq!(
+            exports[1].signature,
+            "safeTransferFrom(address,address,uint256)"
+        );
+        assert_eq!(exports[1].selector, "0x42842e0e");
+
+        let overrides = HashMap::from([(
+            "safeTransferFrom(address,address,uint256,bytes)".to_string(),
+            "safeTransferFromWithData".to_string(),
+        )]);
+        let exports = method_exports(&funcs, &overrides).unwrap();
+        assert_eq!(exports[0].export, "safeTransferFromWithData");
+
+        let overrides = HashMap::from([("f()".to_string(), "g".to_string())]);
+        assert!(method_exports(&funcs, &overrides).is_err());
+    }
 }
@@ -1,16 +1,20 @@
 // This is free and unencumbered software released into the public domain.
 
+use ethnum::u256;
 use evm_rs::{Opcode, Program};
 use relooper::graph::cfg::{Cfg, CfgEdge};
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     fmt::{Debug, Display},
     ops::Range,
 };
 
+use crate::{fold::SymbolicStack, jumps::resolve_jumps};
+
 /// This struct represents offset of instruction in EVM bytecode.
 /// Also look at docs of Idx struct
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Offs(pub usize);
 
 /// This struct represents the serial number of instruction.
@@ -22,7 +26,7 @@ pub struct Offs(pub usize);
 ///
 /// Then,  first PUSH will have idx = 0 and offs = 0x00, second idx = 1 and offs = 0x03,
 ///  ADD will have idx = 2 and offs = 0x06
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Idx(pub usize);
 
 impl Debug for Offs {
@@ -51,10 +55,20 @@ pub struct NodeInfo {
 
 /// Represents either original node or artificial `Dynamic` node used for dynamic edges translation.
 /// During codegen phase, all dynamic node edges will be converted to table branch.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum CfgNode<T> {
     Orig(T),
     Dynamic,
+    /// Artificial entry of `_evm_exec` dispatching to the blocks it can be
+    /// entered at, see `dispatch::DirectEntry`.
+    Entry,
+    /// Artificial switch on the dispatcher local, which irreducible control
+    /// flow too costly to split is routed through, see
+    /// `compile::reduce_function`.
+    Dispatch,
+    /// Artificial node setting the dispatcher local to the given index into
+    /// the switch of `Dispatch`, then going there.
+    Route(usize),
 }
 
 impl<T: Display> Display for CfgNode<T> {
@@ -62,6 +76,9 @@ impl<T: Display> Display for CfgNode<T> {
         match self {
             Self::Orig(offs) => write!(f, "{}", offs),
             Self::Dynamic => write!(f, "dynamic"),
+            Self::Entry => write!(f, "entry"),
+            Self::Dispatch => write!(f, "dispatch"),
+            Self::Route(index) => write!(f, "route{}", index),
         }
     }
 }
@@ -70,9 +87,20 @@ impl<T: Display> Display for CfgNode<T> {
 pub struct BasicCfg {
     pub cfg: Cfg<CfgNode<Offs>>,
     pub code_ranges: HashMap<Offs, Range<Idx>>,
+    /// Number of words left on the stack by a block's terminator that no edge
+    /// consumes, e.g. the condition of a `JUMPI` folded into an unconditional edge.
+    pub exit_drops: HashMap<Offs, usize>,
 }
 
-pub fn basic_cfg(program: &Program) -> BasicCfg {
+/// Builds the basic-block CFG of `program`. With `fold_conditions`, `JUMPI`s
+/// whose condition is a constant become unconditional edges.
+///
+/// Jumps whose target is not pushed right before them are resolved with
+/// [`resolve_jumps`]: they get edges to the JUMPDESTs they can actually reach,
+/// and only the unresolved ones go through the `Dynamic` node. A jump is only
+/// resolved to a single edge if all of its targets are JUMPDESTs, others keep
+/// the trap of the table jump or of the `Dynamic` node for the invalid ones.
+pub fn basic_cfg(program: &Program, fold_conditions: bool) -> BasicCfg {
     struct BlockStart {
         start_offs: Offs,
         start_idx: Idx,
@@ -80,7 +108,9 @@ pub fn basic_cfg(program: &Program) -> BasicCfg {
 
     let mut cfg = Cfg::new(CfgNode::Orig(Offs(0)));
     let mut code_ranges: HashMap<Offs, Range<Idx>> = Default::default();
+    let mut exit_drops: HashMap<Offs, usize> = Default::default();
     let mut jumpdests: Vec<Offs> = Default::default();
+    let mut dynamic_jumps: HashMap<Offs, Option<Offs>> = Default::default();
 
     let mut curr_offs = Offs(0);
     let mut block_start: Option<BlockStart> = None;
@@ -106,13 +136,29 @@ pub fn basic_cfg(program: &Program) -> BasicCfg {
                     None => unreachable!(),
                 };
 
-                let jump_to = label.map(CfgNode::Orig).unwrap_or(CfgNode::Dynamic);
-                let edge = if op == &JUMP {
-                    CfgEdge::Uncond(jump_to)
-                } else {
-                    CfgEdge::Cond(jump_to, CfgNode::Orig(next_offs))
-                };
-                cfg.add_edge(CfgNode::Orig(start_offs), edge);
+                match label {
+                    Some(label) => {
+                        let condition = match op {
+                            JUMPI if fold_conditions => {
+                                // the jump target is pushed right before the JUMPI
+                                let code = &program.0[start_idx.0..curr_idx.0 - 1];
+                                SymbolicStack::eval(code).peek(0)
+                            }
+                            _ => None,
+                        };
+                        let fallthrough = (op == &JUMPI).then_some(next_offs);
+                        let edge = jump_edge(label, fallthrough, condition);
+                        if condition.is_some() {
+                            exit_drops.insert(start_offs, 1);
+                        }
+                        cfg.add_edge(CfgNode::Orig(start_offs), edge);
+                    }
+                    None => {
+                        // edges are added once the targets are resolved
+                        let fallthrough = (op == &JUMPI).then_some(next_offs);
+                        dynamic_jumps.insert(start_offs, fallthrough);
+                    }
+                }
 
                 code_ranges.insert(start_offs, start_idx..next_idx);
 
@@ -166,11 +212,152 @@ pub fn basic_cfg(program: &Program) -> BasicCfg {
         code_ranges.insert(start_offs, start_idx..next_idx);
     }
 
+    let resolved = resolve_jumps(program, &cfg, &code_ranges, &dynamic_jumps, &jumpdests);
+    for (start_offs, fallthrough) in dynamic_jumps {
+        let from = CfgNode::Orig(start_offs);
+        let resolved = resolved.get(&start_offs);
+        let targets = resolved
+            .and_then(|r| r.targets.as_ref())
+            .filter(|t| !t.is_empty());
+        let faults = resolved.map_or(false, |r| r.faults);
+        match (fallthrough, targets) {
+            (_, Some(targets)) if targets.len() == 1 && !faults => {
+                let label = *targets.first().unwrap();
+                let condition = resolved
+                    .and_then(|r| r.condition)
+                    .filter(|_| fold_conditions);
+                // the target, and the condition if it was folded
+                exit_drops.insert(start_offs, 1 + usize::from(condition.is_some()));
+                cfg.add_edge(from, jump_edge(label, fallthrough, condition));
+            }
+            (None, Some(targets)) => {
+                // the target is left on the stack for the table jump
+                let table = targets.iter().map(|t| (t.0, CfgNode::Orig(*t))).collect();
+                cfg.add_edge(from, CfgEdge::Switch(table));
+            }
+            (None, None) => cfg.add_edge(from, CfgEdge::Uncond(CfgNode::Dynamic)),
+            (Some(next_offs), _) => {
+                let edge = CfgEdge::Cond(CfgNode::Dynamic, CfgNode::Orig(next_offs));
+                cfg.add_edge(from, edge);
+            }
+        }
+    }
+
     let jump_table: Vec<_> = jumpdests
         .into_iter()
         .map(|j| (j.0, CfgNode::Orig(j)))
         .collect();
     cfg.add_edge(CfgNode::Dynamic, CfgEdge::Switch(jump_table));
 
-    BasicCfg { cfg, code_ranges }
+    BasicCfg {
+        cfg,
+        code_ranges,
+        exit_drops,
+    }
+}
+
+/// Returns the edge of a jump to `label`, where `fallthrough` is the next
+/// block of a `JUMPI` and `condition` its condition if constant.
+fn jump_edge(
+    label: Offs,
+    fallthrough: Option<Offs>,
+    condition: Option<u256>,
+) -> CfgEdge<CfgNode<Offs>> {
+    match (fallthrough, condition) {
+        (None, _) => CfgEdge::Uncond(CfgNode::Orig(label)),
+        (Some(next_offs), None) => CfgEdge::Cond(CfgNode::Orig(label), CfgNode::Orig(next_offs)),
+        (Some(_), Some(c)) if c != 0 => CfgEdge::Uncond(CfgNode::Orig(label)),
+        (Some(next_offs), Some(_)) => CfgEdge::Uncond(CfgNode::Orig(next_offs)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::decode_bytecode;
+
+    #[test]
+    fn test_constant_jumpi() {
+        // 0x00: PUSH1 0x00 ISZERO PUSH1 0x08 JUMPI
+        // 0x06: STOP INVALID
+        // 0x08: JUMPDEST STOP
+        let program = decode_bytecode("60001560085700fe5b00").unwrap();
+
+        let folded = basic_cfg(&program, true);
+        assert_eq!(
+            folded.cfg.edge(&CfgNode::Orig(Offs(0))),
+            &CfgEdge::Uncond(CfgNode::Orig(Offs(8)))
+        );
+        assert_eq!(folded.exit_drops.get(&Offs(0)), Some(&1));
+
+        let unfolded = basic_cfg(&program, false);
+        assert_eq!(
+            unfolded.cfg.edge(&CfgNode::Orig(Offs(0))),
+            &CfgEdge::Cond(CfgNode::Orig(Offs(8)), CfgNode::Orig(Offs(6)))
+        );
+        assert!(unfolded.exit_drops.is_empty());
+    }
+
+    #[test]
+    fn test_resolved_return() {
+        // 0x00: PUSH1 0x06 PUSH1 0x08 JUMP
+        // 0x05: INVALID
+        // 0x06: JUMPDEST STOP
+        // 0x08: JUMPDEST JUMP
+        let program = decode_bytecode("6006600856fe5b005b56").unwrap();
+        let basic_cfg = basic_cfg(&program, true);
+        assert_eq!(
+            basic_cfg.cfg.edge(&CfgNode::Orig(Offs(8))),
+            &CfgEdge::Uncond(CfgNode::Orig(Offs(6)))
+        );
+        assert_eq!(basic_cfg.exit_drops.get(&Offs(8)), Some(&1));
+    }
+
+    #[test]
+    fn test_resolved_switch() {
+        // 0x00: PUSH1 0x06 PUSH1 0x0e JUMP
+        // 0x05: INVALID
+        // 0x06: JUMPDEST PUSH1 0x0c PUSH1 0x0e JUMP
+        // 0x0c: JUMPDEST STOP
+        // 0x0e: JUMPDEST JUMP
+        let program = decode_bytecode("6006600e56fe5b600c600e565b005b56").unwrap();
+        let basic_cfg = basic_cfg(&program, true);
+        assert_eq!(
+            basic_cfg.cfg.edge(&CfgNode::Orig(Offs(0x0e))),
+            &CfgEdge::Switch(vec![
+                (0x06, CfgNode::Orig(Offs(0x06))),
+                (0x0c, CfgNode::Orig(Offs(0x0c))),
+            ])
+        );
+        assert_eq!(basic_cfg.exit_drops.get(&Offs(0x0e)), None);
+    }
+
+    #[test]
+    fn test_partly_invalid_jump() {
+        // 0x00: PUSH1 0x00 CALLDATALOAD PUSH1 0x0b JUMPI
+        // 0x06: PUSH1 0x05 PUSH1 0x13 JUMP
+        // 0x0b: JUMPDEST PUSH1 0x11 PUSH1 0x13 JUMP
+        // 0x11: JUMPDEST STOP
+        // 0x13: JUMPDEST JUMP
+        let program = decode_bytecode("600035600b5760056013565b60116013565b005b56").unwrap();
+        let basic_cfg = basic_cfg(&program, true);
+        // 0x05 is no JUMPDEST, so the jump must keep trapping on it
+        assert_eq!(
+            basic_cfg.cfg.edge(&CfgNode::Orig(Offs(0x13))),
+            &CfgEdge::Switch(vec![(0x11, CfgNode::Orig(Offs(0x11)))])
+        );
+        assert_eq!(basic_cfg.exit_drops.get(&Offs(0x13)), None);
+    }
+
+    #[test]
+    fn test_unresolved_jump() {
+        // 0x00: PUSH1 0x00 CALLDATALOAD JUMP
+        // 0x04: JUMPDEST STOP
+        let program = decode_bytecode("600035565b00").unwrap();
+        let basic_cfg = basic_cfg(&program, true);
+        assert_eq!(
+            basic_cfg.cfg.edge(&CfgNode::Orig(Offs(0))),
+            &CfgEdge::Uncond(CfgNode::Dynamic)
+        );
+    }
 }
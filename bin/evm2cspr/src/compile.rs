@@ -1,42 +1,91 @@
 // This is free and unencumbered software released into the public domain.
 
 use std::{
-    borrow::Cow,
     collections::{HashMap, HashSet},
     convert::TryInto,
     fmt::Display,
-    io::Write,
     path::PathBuf,
 };
 
+use abi_types::INTERFACE_VERSION;
 use evm_rs::{parse_opcode, Opcode, Program};
-use relooper::graph::{enrichments::EnrichedCfg, relooper::ReBlock, supergraph::reduce};
+use relooper::graph::{
+    cfg::{Cfg, CfgEdge},
+    enrichments::EnrichedCfg,
+    relooper::ReBlock,
+    supergraph::{reduce_within, ReduceError},
+};
 use relooper::graph::{relooper::ReSeq, supergraph::SLabel};
-use wasm_encoder::{BlockType, ExportKind, Function, Instruction, Module, ValType};
+use serde::{Deserialize, Serialize};
+use wasm_encoder::{BlockType, ExportKind, Function, GlobalType, Instruction, Module, ValType};
 
 use crate::{
-    abi::Functions,
+    abi::{method_exports, Function as AbiFunction, Functions, MethodExport, Parameter},
     analyze::{basic_cfg, BasicCfg, CfgNode, Idx, Offs},
     config::CompilerConfig,
+    dispatch::{direct_entry, DirectEntry, Step},
     encode::encode_push,
-    wasm_translate::{translator::DataMode, Export, ModuleBuilder, Signature},
+    functions::{split_functions, BlockExit},
+    inline::{inline_op, InlineRuntime, STACK_SLOTS},
+    jump_table::table_jump,
+    link::{link_shared, runtime_module, RuntimeExtent},
+    ops,
+    peephole::{peephole, FusedFunctions, Rewrite},
+    shake::shake,
+    stackify::{limbs, StackFunctions, VirtualStack, DISPATCH_LOCAL},
+    validate::{validate, CasperRules, Origin, Violation},
+    wasm_translate::{
+        translator::{Data, DataMode},
+        Export, Glob, ModuleBuilder, Signature,
+    },
 };
 
 const TABLE_OFFSET: i32 = 0x1000;
 
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+/// Serialized with all its fields, so that the JSON debug output can be read
+/// back, e.g. as regression fixtures.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct EvmBlock {
     pub label: Offs,
     pub code_start: Idx,
     pub code_end: Idx,
+    pub exit_drops: usize,
+    pub exit: BlockExit,
 }
 
 impl EvmBlock {
-    fn new(label: Offs, code_start: Idx, code_end: Idx) -> Self {
+    fn new(
+        label: Offs,
+        code_start: Idx,
+        code_end: Idx,
+        exit_drops: usize,
+        exit: BlockExit,
+    ) -> Self {
         Self {
             label,
             code_start,
             code_end,
+            exit_drops,
+            exit,
+        }
+    }
+}
+
+/// The kinds of wasm functions EVM code is compiled into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FunctionKind {
+    /// `_evm_exec`, which returns once execution halts.
+    Exec,
+    /// A recovered internal function entered at the given JUMPDEST, which
+    /// returns whether execution halted.
+    Internal(Offs),
+}
+
+impl Display for FunctionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exec => write!(f, "`_evm_exec`"),
+            Self::Internal(entry) => write!(f, "the internal function at {}", entry),
         }
     }
 }
@@ -60,57 +109,286 @@ fn evm_idx_to_offs(program: &Program) -> HashMap<Idx, Offs> {
     idx2offs
 }
 
+/// Compiles the program and validates the output module, see `validate`.
+/// Programs with unsupported operators are rejected, see `check_program`.
 pub fn compile<'a>(
     input_program: &'a Program,
     input_abi: Option<Functions>,
     runtime_library: ModuleBuilder<'a>,
     config: CompilerConfig,
-) -> Module {
+) -> Result<Module, Vec<Violation>> {
+    ops::check_program(input_program).map_err(|violation| vec![violation])?;
+    let selectors: Vec<u32> = input_abi
+        .iter()
+        .flat_map(Functions::iter)
+        .map(|func| func.selector())
+        .collect();
+    let exports = match &input_abi {
+        Some(funcs) => method_exports(funcs, &config.export_names).map_err(|message| {
+            vec![Violation {
+                location: None,
+                message,
+            }]
+        })?,
+        None => vec![],
+    };
+    // the op handlers would charge the static gas of the blocks again
+    if config.gas_accounting && find_runtime_function(&runtime_library, "_evm_block_gas").is_none()
+    {
+        return Err(vec![Violation {
+            location: None,
+            message: "the runtime library must be built with the `block-gas` feature".to_string(),
+        }]);
+    }
     let mut compiler = Compiler::new(runtime_library, config);
     compiler.emit_wasm_start();
     compiler.emit_evm_start();
-    compiler.compile_cfg(input_program);
-    compiler.emit_abi_execute();
-    let abi_data = compiler.emit_abi_methods(input_abi).unwrap();
-
-    let abi_buffer_ptr: usize = compiler.abi_buffer_off.try_into().unwrap();
-    for data in compiler.builder.data.iter_mut() {
-        let min_ptr: usize = match data.mode {
-            DataMode::Active {
-                memory_index: _,
-                offset_instr: Instruction::I32Const(off),
-            } => off.try_into().unwrap(),
-            _ => continue,
+    compiler.compile_cfg(input_program, &selectors);
+
+    // the ABI methods must not clash with each other nor the runtime exports,
+    // of which tree shaking and shared linking only keep the ones of memories
+    // and the like
+    let shared = compiler.config.shared_runtime;
+    let shaken = compiler.config.tree_shaking || shared;
+    let runtime_exports = compiler.runtime.exports;
+    let mut names: HashSet<&str> = compiler
+        .builder
+        .exports
+        .iter()
+        .enumerate()
+        .filter(|(i, e)| !shaken || *i >= runtime_exports || e.kind != ExportKind::Func)
+        .map(|(_, e)| e.name.as_str())
+        .collect();
+    names.insert("execute");
+    if let Some(abi) = &input_abi {
+        names.extend(abi.fallback.map(|_| "fallback"));
+        names.extend(abi.receive.map(|_| "receive"));
+    }
+    let clashes: Vec<Violation> = exports
+        .iter()
+        .filter(|export| !names.insert(&export.export))
+        .map(|export| Violation {
+            location: None,
+            message: format!(
+                "duplicate export `{}` for ABI function `{}`, rename it with --export-name",
+                export.export, export.signature
+            ),
+        })
+        .collect();
+    if !clashes.is_empty() {
+        return Err(clashes);
+    }
+    let abi_data = compiler
+        .emit_abi_methods(input_abi, &exports)
+        .map_err(|violation| vec![violation])?;
+    if let Err(message) = compiler.emit_abi_data(abi_data) {
+        return Err(vec![Violation {
+            location: None,
+            message,
+        }]);
+    }
+
+    let rules = compiler.config.casper_rules.then(|| CasperRules {
+        runtime_module: shared.then(runtime_module),
+        ..Default::default()
+    });
+    let mut origins = std::mem::take(&mut compiler.origins);
+    let mut module = compiler.builder.build();
+    if shaken {
+        // only the generated exports are kept
+        let wasm = module.finish();
+        let (builder, functions) = match shared {
+            true => link_shared(&wasm, compiler.runtime).map_err(|err| {
+                vec![Violation {
+                    location: None,
+                    message: format!("cannot link against a shared runtime: {}", err),
+                }]
+            })?,
+            false => shake(&wasm, |export| export >= runtime_exports).map_err(|err| {
+                vec![Violation {
+                    location: None,
+                    message: format!("cannot remove unreachable functions: {}", err),
+                }]
+            })?,
         };
-        let max_ptr: usize = min_ptr + data.data.len();
+        origins = origins
+            .into_iter()
+            .filter_map(|(f, origin)| Some((functions[usize::try_from(f).unwrap()]?, origin)))
+            .collect();
+        module = builder.build();
+    }
+    validate(module.as_slice(), &origins, rules.as_ref())?;
+    Ok(module)
+}
+
+/// Splits the program into the CFGs of the wasm functions it is compiled
+/// into, callees first. `exec_cfg` gets to extend the CFG of `_evm_exec`.
+pub fn function_cfgs(
+    program: &Program,
+    basic_cfg: &BasicCfg,
+    split: bool,
+    constant_folding: bool,
+    mut exec_cfg: impl FnMut(&Cfg<CfgNode<Offs>>) -> Cfg<CfgNode<Offs>>,
+) -> Vec<(FunctionKind, Cfg<CfgNode<EvmBlock>>)> {
+    let block = |l: &Offs, exit_drops: &HashMap<Offs, usize>, exit: BlockExit| {
+        let a = basic_cfg.code_ranges.get(l).unwrap();
+        let exit_drops = exit_drops.get(l).copied().unwrap_or_default();
+        EvmBlock::new(*l, a.start, a.end, exit_drops, exit)
+    };
 
-        if abi_buffer_ptr >= min_ptr && abi_buffer_ptr < max_ptr {
-            let min_off = abi_buffer_ptr - min_ptr;
-            let max_off = min_off + abi_data.len();
-            assert!(min_ptr + max_off <= max_ptr);
-            data.data[min_off..max_off].copy_from_slice(&abi_data);
-            break; // found it
+    let regions = if split {
+        split_functions(program, basic_cfg, constant_folding)
+    } else {
+        None
+    };
+    let regions = match regions {
+        Some(regions) => regions,
+        None => {
+            let evm_cfg = exec_cfg(&basic_cfg.cfg).map_label(|n| match n {
+                CfgNode::Orig(l) => CfgNode::Orig(block(l, &basic_cfg.exit_drops, BlockExit::Jump)),
+                CfgNode::Dynamic => CfgNode::Dynamic,
+                CfgNode::Entry => CfgNode::Entry,
+                CfgNode::Dispatch => CfgNode::Dispatch,
+                CfgNode::Route(index) => CfgNode::Route(*index),
+            });
+            return vec![(FunctionKind::Exec, evm_cfg)];
+        }
+    };
+
+    regions
+        .iter()
+        .map(|region| {
+            let region_cfg = match region.entry {
+                Offs(0) => exec_cfg(&region.cfg),
+                _ => region.cfg.clone(),
+            };
+            let evm_cfg = region_cfg.map_label(|n| match n {
+                CfgNode::Orig(l) => {
+                    let exit = region.exits.get(l).copied().unwrap_or_default();
+                    CfgNode::Orig(block(l, &region.exit_drops, exit))
+                }
+                CfgNode::Dynamic => CfgNode::Dynamic,
+                CfgNode::Entry => CfgNode::Entry,
+                CfgNode::Dispatch => CfgNode::Dispatch,
+                CfgNode::Route(index) => CfgNode::Route(*index),
+            });
+            let kind = match region.entry {
+                Offs(0) => FunctionKind::Exec,
+                entry => FunctionKind::Internal(entry),
+            };
+            (kind, evm_cfg)
+        })
+        .collect()
+}
+
+/// Makes the CFG of a function reducible for the relooper. Node splitting
+/// may grow it to `split_growth` times its size; past that, the edges into
+/// the entries of irreducible regions are routed through the `Dispatch`
+/// switch instead, which leaves them a single predecessor, and splitting is
+/// retried.
+pub fn reduce_function(
+    evm_cfg: &Cfg<CfgNode<EvmBlock>>,
+    split_growth: usize,
+) -> Cfg<SLabel<CfgNode<EvmBlock>>> {
+    let limit = split_growth.saturating_mul(evm_cfg.nodes().len());
+    let mut cfg = evm_cfg.clone();
+    loop {
+        match reduce_within(&cfg, limit) {
+            Ok(reduced) => return reduced,
+            Err(ReduceError::SplitBudget { entries, .. }) => {
+                if !route_through_dispatch(&mut cfg, &entries) {
+                    // the routed entries have a single predecessor left and the dispatcher
+                    // can't be the only one, so this splits nothing
+                    return reduce_within(&cfg, usize::MAX).unwrap();
+                }
+            }
         }
     }
-    compiler.builder.build()
 }
 
-type DataOffset = i32;
+/// Redirects every edge into `entries` to a `Route` node of its own, so that
+/// they are entered from `Dispatch` only. Returns whether any entry was not
+/// routed yet.
+fn route_through_dispatch(cfg: &mut Cfg<CfgNode<EvmBlock>>, entries: &[CfgNode<EvmBlock>]) -> bool {
+    let mut table = match cfg.edges().get(&CfgNode::Dispatch) {
+        Some(CfgEdge::Switch(table)) => table.clone(),
+        _ => Vec::new(),
+    };
+    let routed: HashSet<CfgNode<EvmBlock>> = entries
+        .iter()
+        .filter(|entry| matches!(entry, CfgNode::Orig(_) | CfgNode::Dynamic))
+        .filter(|entry| !table.iter().any(|(_, target)| target == *entry))
+        .copied()
+        .collect();
+    if routed.is_empty() {
+        return false;
+    }
+
+    let mut nodes: Vec<CfgNode<EvmBlock>> = cfg.nodes().into_iter().copied().collect();
+    nodes.sort();
+    let mut routes = Vec::new();
+    for node in nodes.iter().filter(|node| **node != CfgNode::Dispatch) {
+        let mut route = |to: &mut CfgNode<EvmBlock>| {
+            if routed.contains(to) {
+                let index = table.len();
+                table.push((index, *to));
+                routes.push(index);
+                *to = CfgNode::Route(index);
+            }
+        };
+        match cfg.edge_mut(node) {
+            CfgEdge::Uncond(to) => route(to),
+            CfgEdge::Cond(to, fallthrough) => {
+                route(to);
+                route(fallthrough);
+            }
+            CfgEdge::Switch(targets) => targets.iter_mut().for_each(|(_, to)| route(to)),
+            CfgEdge::Terminal => {}
+        }
+    }
+    for index in routes {
+        cfg.add_edge(CfgNode::Route(index), CfgEdge::Uncond(CfgNode::Dispatch));
+    }
+    *cfg.edge_mut(&CfgNode::Dispatch) = CfgEdge::Switch(table);
+    true
+}
+
 type FunctionIndex = u32;
 
+const WASM_PAGE_SIZE: u64 = 0x10000;
+
 struct Compiler<'a> {
     config: CompilerConfig,
-    abi_buffer_off: DataOffset,
-    abi_buffer_len: usize,
     op_table: HashMap<Opcode, FunctionIndex>,
     evm_start_function: FunctionIndex,     // _evm_start
+    evm_version_function: FunctionIndex,   // _evm_version
     evm_init_function: FunctionIndex,      // _evm_init
     evm_call_function: FunctionIndex,      // _evm_call
+    evm_receive_function: FunctionIndex,   // _evm_receive
     evm_exec_function: FunctionIndex,      // _evm_exec
     evm_post_exec_function: FunctionIndex, // _evm_post_exec
     evm_pop_function: FunctionIndex,       // _evm_pop_u32
+    evm_push_function: FunctionIndex,      // _evm_push_u32
     evm_burn_gas: FunctionIndex,           // _evm_burn_gas
+    evm_block_gas: Option<FunctionIndex>,  // _evm_block_gas
     evm_pc_function: FunctionIndex,        // _evm_set_pc
+    evm_stack_function: FunctionIndex,     // _evm_stack
+    evm_value_function: FunctionIndex,     // _evm_call_value
+    evm_slots_function: FunctionIndex,     // _evm_stack_slots
+    evm_fault_function: FunctionIndex,     // _evm_fault
+    stack_functions: StackFunctions,
+    fused_functions: FusedFunctions,
+    internal_functions: HashMap<Offs, FunctionIndex>,
+    /// The blocks `_evm_exec` can be entered at, by index on the stack.
+    /// Empty if it is only ever entered at `Offs(0)`.
+    entry_points: Vec<Offs>,
+    direct_entries: HashMap<u32, DirectEntry>,
+    /// The runtime globals and functions of inlined ops, if enabled.
+    inline_runtime: Option<InlineRuntime>,
+    /// How much of the module is the runtime library.
+    runtime: RuntimeExtent,
+    /// What the emitted functions were compiled from, for validation.
+    origins: HashMap<FunctionIndex, Origin>,
     builder: ModuleBuilder<'a>,
 }
 
@@ -119,18 +397,48 @@ impl<'a> Compiler<'a> {
     fn new(runtime_library: ModuleBuilder, config: CompilerConfig) -> Compiler {
         Compiler {
             config,
-            abi_buffer_off: find_abi_buffer(&runtime_library).unwrap(),
-            abi_buffer_len: 0xFFFF, // TODO: ensure this matches _abi_buffer.len() in evmlib
             op_table: make_op_table(&runtime_library),
             evm_start_function: 0, // filled in during emit_start()
+            evm_version_function: find_runtime_function(&runtime_library, "_evm_version").unwrap(),
             evm_init_function: find_runtime_function(&runtime_library, "_evm_init").unwrap(),
             evm_call_function: find_runtime_function(&runtime_library, "_evm_call").unwrap(),
+            evm_receive_function: find_runtime_function(&runtime_library, "_evm_receive").unwrap(),
             evm_post_exec_function: find_runtime_function(&runtime_library, "_evm_post_exec")
                 .unwrap(),
             evm_exec_function: 0, // filled in during compile_cfg()
             evm_pop_function: find_runtime_function(&runtime_library, "_evm_pop_u32").unwrap(),
+            evm_push_function: find_runtime_function(&runtime_library, "_evm_push_u32").unwrap(),
             evm_burn_gas: find_runtime_function(&runtime_library, "_evm_burn_gas").unwrap(),
+            evm_block_gas: find_runtime_function(&runtime_library, "_evm_block_gas"),
             evm_pc_function: find_runtime_function(&runtime_library, "_evm_set_pc").unwrap(),
+            evm_stack_function: find_runtime_function(&runtime_library, "_evm_stack").unwrap(),
+            evm_value_function: find_runtime_function(&runtime_library, "_evm_call_value").unwrap(),
+            evm_slots_function: find_runtime_function(&runtime_library, "_evm_stack_slots")
+                .unwrap(),
+            evm_fault_function: find_runtime_function(&runtime_library, "_evm_fault").unwrap(),
+            stack_functions: StackFunctions {
+                push: find_runtime_function(&runtime_library, "_evm_push_u256").unwrap(),
+                pop: find_runtime_function(&runtime_library, "_evm_pop_u32").unwrap(),
+                slot: find_runtime_function(&runtime_library, "_evm_stack_slot").unwrap(),
+                room: find_runtime_function(&runtime_library, "_evm_stack_room").unwrap(),
+                arith: find_runtime_function(&runtime_library, "_evm_arith").unwrap(),
+            },
+            fused_functions: FusedFunctions {
+                pop_n: find_runtime_function(&runtime_library, "_evm_pop_n").unwrap(),
+                swap_pop: find_runtime_function(&runtime_library, "_evm_swap_pop").unwrap(),
+                bool: find_runtime_function(&runtime_library, "_evm_bool").unwrap(),
+                mask: find_runtime_function(&runtime_library, "_evm_mask").unwrap(),
+            },
+            internal_functions: HashMap::new(), // filled in during compile_cfg()
+            entry_points: Vec::new(),           // filled in during compile_cfg()
+            direct_entries: HashMap::new(),     // filled in during compile_cfg()
+            inline_runtime: None,               // filled in during emit_evm_start()
+            runtime: RuntimeExtent {
+                functions: runtime_library.functions.len(),
+                exports: runtime_library.exports.len(),
+                data: runtime_library.data.len(),
+            },
+            origins: HashMap::new(),
             builder: runtime_library,
         }
     }
@@ -154,218 +462,532 @@ impl<'a> Compiler<'a> {
     fn emit_evm_start(&mut self) {
         assert_ne!(self.evm_init_function, 0);
 
-        self.evm_start_function = self.emit_function(
-            Some("_evm_start".to_string()),
-            vec![
-                Instruction::I32Const(TABLE_OFFSET),
-                Instruction::I64Const(self.config.chain_id.try_into().unwrap()), // --chain-id
-                Instruction::I64Const(0),                                        // TODO: --balance
-                Instruction::Call(self.evm_init_function),
-            ],
-        );
+        let mut code = vec![
+            // the generated code and data assume the interface of the runtime
+            Instruction::Call(self.evm_version_function),
+            Instruction::I32Const(INTERFACE_VERSION.try_into().unwrap()),
+            Instruction::I32Ne,
+            Instruction::If(BlockType::Empty),
+            Instruction::Unreachable,
+            Instruction::End,
+        ];
+        if self.config.gas_accounting {
+            // the runtime leaves static gas to the compiled code
+            code.push(Instruction::Call(self.evm_block_gas.unwrap()));
+        }
+        code.extend([
+            Instruction::I32Const(TABLE_OFFSET),
+            Instruction::I64Const(self.config.chain_id.try_into().unwrap()), // --chain-id
+            Instruction::I64Const(0),                                        // TODO: --balance
+            Instruction::Call(self.evm_init_function),
+        ]);
+        if self.config.inline_ops {
+            let runtime = InlineRuntime {
+                stack: self.add_address_global(),
+                call_value: self.add_address_global(),
+                set_pc: self.config.program_counter.then_some(self.evm_pc_function),
+                fault: self.evm_fault_function,
+            };
+            // the inlined ops assume the stack layout of the runtime
+            code.extend([
+                Instruction::Call(self.evm_slots_function),
+                Instruction::I32Const(STACK_SLOTS.try_into().unwrap()),
+                Instruction::I32Ne,
+                Instruction::If(BlockType::Empty),
+                Instruction::Unreachable,
+                Instruction::End,
+                Instruction::Call(self.evm_stack_function),
+                Instruction::GlobalSet(runtime.stack),
+                Instruction::Call(self.evm_value_function),
+                Instruction::GlobalSet(runtime.call_value),
+            ]);
+            self.inline_runtime = Some(runtime);
+        }
+        self.evm_start_function = self.emit_function(Some("_evm_start".to_string()), code);
     }
 
-    fn emit_abi_execute(&mut self) {
-        assert_ne!(self.evm_start_function, 0);
-        assert_ne!(self.evm_exec_function, 0); // filled in during compile_cfg()
-
-        _ = self.emit_function(
-            Some("execute".to_string()),
-            vec![
-                Instruction::Call(self.evm_start_function),
-                Instruction::Call(self.evm_exec_function),
-                Instruction::I32Const(0),
-                Instruction::I32Const(0), // output_types_len == 0 means no JSON encoding
-                Instruction::Call(self.evm_post_exec_function),
-            ],
-        );
+    /// Adds a global holding a runtime address, set by `_evm_start`.
+    fn add_address_global(&mut self) -> u32 {
+        self.builder.add_global(Glob {
+            global_type: GlobalType {
+                val_type: ValType::I32,
+                mutable: true,
+            },
+            init_instr: Instruction::I32Const(0),
+        })
     }
 
     /// Synthesizes public wrapper methods for each function in the Solidity
     /// contract's ABI, enabling users to directly call a contract method
-    /// without going through the low-level `execute` EVM dispatcher.
+    /// without going through the low-level `execute` EVM dispatcher, which
+    /// is synthesized here too.
     pub fn emit_abi_methods(
         &mut self,
         input_abi: Option<Functions>,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        exports: &[MethodExport],
+    ) -> Result<Vec<u8>, Violation> {
         assert_ne!(self.evm_start_function, 0);
         assert_ne!(self.evm_call_function, 0);
         assert_ne!(self.evm_exec_function, 0); // filled in during compile_cfg()
 
-        let mut data = Vec::with_capacity(self.abi_buffer_len);
-        for func in input_abi.unwrap_or_default() {
+        // pointers to the ABI data, which is checked to fit in emit_abi_data()
+        let base = self.abi_data_offset();
+        let ptr = |off: usize| Instruction::I32Const((base + off as u64) as u32 as i32);
+        let mut data = Vec::new();
+        let input_abi = input_abi.unwrap_or_default();
+
+        // the custom errors, for decoding revert data in `_evm_post_exec`
+        let errors_off = data.len();
+        for error in input_abi.errors.iter() {
+            let names: Vec<&str> = error
+                .inputs
+                .iter()
+                .map(|input| input.name.as_str())
+                .collect();
+            let line = format!("{:08x} {} {}\n", error.selector(), error, names.join(","));
+            data.extend(line.as_bytes());
+        }
+        let errors_len = data.len() - errors_off;
+        if errors_len > 0 {
+            data.push(0); // NUL
+        }
+        let errors = [
+            ptr(errors_off),                                       // errors_ptr
+            Instruction::I32Const(errors_len.try_into().unwrap()), // errors_len
+        ];
+
+        self.emit_abi_entry_point("execute", None, &errors);
+        if input_abi.fallback.is_some() {
+            self.emit_abi_entry_point("fallback", None, &errors);
+        }
+        if input_abi.receive.is_some() {
+            self.emit_abi_entry_point("receive", Some(self.evm_receive_function), &errors);
+        }
+
+        for (func, export) in input_abi.into_iter().zip(exports) {
             let names_off = data.len();
-            for (i, input) in func.inputs.iter().enumerate() {
-                if i > 0 {
-                    write!(data, ",")?;
-                }
-                write!(data, "{}", input.name)?;
-            }
+            let names: Vec<&str> = func
+                .inputs
+                .iter()
+                .map(|input| input.name.as_str())
+                .collect();
+            data.extend(names.join(",").as_bytes());
             let names_len = data.len() - names_off;
             data.push(0); // NUL
 
             let types_off = data.len();
-            for (i, input) in func.inputs.iter().enumerate() {
-                if i > 0 {
-                    write!(data, ",")?;
-                }
-                if abi_types::parse_param_type(&input.r#type).is_err() {
-                    panic!("Unknown ABI type: {}", input.r#type);
-                }
-                write!(data, "{}", input.r#type)?;
-            }
+            data.extend(param_types(&func, &func.inputs)?.as_bytes());
             let types_len = data.len() - types_off;
             data.push(0); // NUL
 
             let output_types_off = data.len();
-            for (i, output) in func.outputs.iter().enumerate() {
-                if i > 0 {
-                    write!(data, ",")?;
-                }
-                if abi_types::parse_param_type(&output.r#type).is_err() {
-                    panic!("Unknown ABI type: {}", output.r#type);
-                }
-                write!(data, "{}", output.r#type)?;
-            }
+            data.extend(param_types(&func, &func.outputs)?.as_bytes());
             let output_types_len = data.len() - output_types_off;
             data.push(0); // NUL
 
-            _ = self.emit_function(
-                Some(func.name.clone()),
-                vec![
-                    Instruction::Call(self.evm_start_function),
-                    Instruction::I32Const(func.selector() as i32),
-                    Instruction::I32Const(names_off.try_into().unwrap()), // params_names_ptr
-                    Instruction::I32Const(names_len.try_into().unwrap()), // params_names_len
-                    Instruction::I32Const(types_off.try_into().unwrap()), // params_types_ptr
-                    Instruction::I32Const(types_len.try_into().unwrap()), // params_types_len
-                    Instruction::Call(self.evm_call_function),
-                    Instruction::Call(self.evm_exec_function),
-                    Instruction::I32Const(output_types_off.try_into().unwrap()), // output_types_off
-                    Instruction::I32Const(output_types_len.try_into().unwrap()), // output_types_len
-                    Instruction::Call(self.evm_post_exec_function),
-                ],
-            );
+            let mut code = vec![
+                Instruction::Call(self.evm_start_function),
+                Instruction::I32Const(func.selector() as i32),
+                ptr(names_off), // params_names_ptr
+                Instruction::I32Const(names_len.try_into().unwrap()), // params_names_len
+                ptr(types_off), // params_types_ptr
+                Instruction::I32Const(types_len.try_into().unwrap()), // params_types_len
+                Instruction::Call(self.evm_call_function),
+            ];
+            match self.direct_entries.get(&func.selector()) {
+                Some(entry) => self.emit_direct_entry(entry, &mut code),
+                None => self.emit_exec(Offs(0), &mut code), // full dispatch
+            }
+            code.extend([
+                ptr(output_types_off),                                       // output_types_ptr
+                Instruction::I32Const(output_types_len.try_into().unwrap()), // output_types_len
+            ]);
+            code.extend(errors.iter().cloned());
+            code.push(Instruction::Call(self.evm_post_exec_function));
+            let func_idx = self.emit_function(Some(export.export.clone()), code);
+            self.origins
+                .insert(func_idx, Origin::Method(export.export.clone()));
         }
         Ok(data)
     }
 
-    //TODO self is only used for `evm_pop_function`
+    /// Synthesizes the export of `execute`, `fallback` or `receive`, which
+    /// run the full dispatcher on the raw call data or on the call data
+    /// prepared by `prepare`, if given. Their return data is passed through,
+    /// but reverts are decoded with the custom errors.
+    fn emit_abi_entry_point(
+        &mut self,
+        name: &str,
+        prepare: Option<FunctionIndex>,
+        errors: &[Instruction],
+    ) {
+        let mut code = vec![Instruction::Call(self.evm_start_function)];
+        code.extend(prepare.map(Instruction::Call));
+        self.emit_exec(Offs(0), &mut code);
+        code.extend([
+            Instruction::I32Const(0),
+            Instruction::I32Const(0), // output_types_len == 0 means no JSON but for reverts
+        ]);
+        code.extend(errors.iter().cloned());
+        code.push(Instruction::Call(self.evm_post_exec_function));
+        let func_idx = self.emit_function(Some(name.to_string()), code);
+        self.origins
+            .insert(func_idx, Origin::Method(name.to_string()));
+    }
+
+    /// Returns where the ABI data starts: right after the initial memory of
+    /// the runtime, so that it never overlaps memory the runtime allocates.
+    fn abi_data_offset(&self) -> u64 {
+        let memory = self.builder.memories.first();
+        memory.map_or(0, |memory| memory.minimum * WASM_PAGE_SIZE)
+    }
+
+    /// Emits the ABI data as an active data segment, growing the initial
+    /// memory by as many pages as it needs.
+    fn emit_abi_data(&mut self, data: Vec<u8>) -> Result<(), String> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let offset = self.abi_data_offset();
+        let memory = match self.builder.memories.first_mut() {
+            Some(memory) => memory,
+            None => return Err("the runtime library defines no memory for the ABI data".into()),
+        };
+        let len = u64::try_from(data.len()).unwrap();
+        let minimum = memory.minimum + (len + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+        let maximum = memory
+            .maximum
+            .unwrap_or(u64::from(u32::MAX) / WASM_PAGE_SIZE + 1);
+        if minimum > maximum {
+            return Err(format!(
+                "the ABI data of {} bytes does not fit in memory: {} pages needed, at most {} allowed",
+                len, minimum, maximum
+            ));
+        }
+        memory.minimum = minimum;
+        self.builder.data.push(Data {
+            mode: DataMode::Active {
+                memory_index: 0,
+                offset_instr: Instruction::I32Const(offset as u32 as i32),
+            },
+            data,
+        });
+        Ok(())
+    }
+
+    /// Runs `_evm_exec` from the given entry point.
+    fn emit_exec(&self, entry: Offs, res: &mut Vec<Instruction>) {
+        if let Some(index) = self.entry_points.iter().position(|offs| offs == &entry) {
+            res.push(Instruction::I32Const(index.try_into().unwrap()));
+            res.push(Instruction::Call(self.evm_push_function));
+        }
+        res.push(Instruction::Call(self.evm_exec_function));
+    }
+
+    /// Replays the selector dispatcher and runs `_evm_exec` from its target.
+    fn emit_direct_entry(&self, entry: &DirectEntry, res: &mut Vec<Instruction<'a>>) {
+        let entry_index = |offs: &Offs| {
+            let index = self.entry_points.iter().position(|o| o == offs).unwrap();
+            Instruction::I32Const(index.try_into().unwrap())
+        };
+        res.push(Instruction::Block(BlockType::Empty));
+        for step in &entry.steps {
+            match step {
+                Step::Block { start, gas } => {
+                    self.burn_static_gas(*gas, res);
+                    self.emit_block_pc(start.0, res);
+                }
+                Step::Push(v) => {
+                    res.extend(limbs(*v).map(Instruction::I64Const));
+                    res.push(Instruction::Call(self.stack_functions.push));
+                }
+                Step::Op(op, pc) => {
+                    let inlined = self.inline_runtime.and_then(|g| inline_op(op, pc.0, g));
+                    if let Some(code) = inlined {
+                        res.extend(code);
+                        continue;
+                    }
+                    self.emit_set_pc(Some(op), pc.0, res);
+                    res.push(self.compile_operator(op));
+                }
+                Step::Guard { expected, exit } => {
+                    res.push(Instruction::Call(self.evm_pop_function));
+                    if *expected {
+                        res.push(Instruction::I32Eqz);
+                    }
+                    // leave the dispatcher where it diverges
+                    res.push(Instruction::If(BlockType::Empty));
+                    res.push(entry_index(exit));
+                    res.push(Instruction::Call(self.evm_push_function));
+                    res.push(Instruction::Br(1));
+                    res.push(Instruction::End);
+                }
+            }
+        }
+        res.push(entry_index(&entry.target));
+        res.push(Instruction::Call(self.evm_push_function));
+        res.push(Instruction::End);
+        res.push(Instruction::Call(self.evm_exec_function));
+    }
+
+    /// Lowers a relooped tree into structured wasm control flow. Blocks, loops
+    /// and ifs have no results: the runtime stack is exact at every EVM block
+    /// exit, so no values are left on the wasm operand stack for them to carry.
     fn unfold_cfg(
         &self,
         program: &'a Program,
         cfg_part: &ReSeq<SLabel<CfgNode<EvmBlock>>>,
+        kind: FunctionKind,
+        stack: &mut VirtualStack,
         res: &mut Vec<Instruction<'a>>,
         wasm_idx2evm_idx: &mut HashMap<Idx, Idx>,
     ) {
-        for block in cfg_part.0.iter() {
+        for (i, block) in cfg_part.0.iter().enumerate() {
             match block {
                 ReBlock::Block(inner_seq) => {
                     res.push(Instruction::Block(BlockType::Empty));
-                    self.unfold_cfg(program, inner_seq, res, wasm_idx2evm_idx);
+                    self.unfold_cfg(program, inner_seq, kind, stack, res, wasm_idx2evm_idx);
                     res.push(Instruction::End);
                 }
                 ReBlock::Loop(inner_seq) => {
                     res.push(Instruction::Loop(BlockType::Empty));
-                    self.unfold_cfg(program, inner_seq, res, wasm_idx2evm_idx);
+                    self.unfold_cfg(program, inner_seq, kind, stack, res, wasm_idx2evm_idx);
                     res.push(Instruction::End);
                 }
                 ReBlock::If(true_branch, false_branch) => {
                     res.push(Instruction::Call(self.evm_pop_function));
+                    // only the non-empty arms are compiled
+                    let (arm, other) = match (true_branch.0.is_empty(), false_branch.0.is_empty()) {
+                        (true, true) => {
+                            res.push(Instruction::Drop);
+                            continue;
+                        }
+                        (true, false) => {
+                            res.push(Instruction::I32Eqz);
+                            (false_branch, None)
+                        }
+                        (false, true) => (true_branch, None),
+                        (false, false) => (true_branch, Some(false_branch)),
+                    };
                     res.push(Instruction::If(BlockType::Empty));
-                    self.unfold_cfg(program, true_branch, res, wasm_idx2evm_idx);
-                    res.push(Instruction::Else);
-                    self.unfold_cfg(program, false_branch, res, wasm_idx2evm_idx);
+                    self.unfold_cfg(program, arm, kind, stack, res, wasm_idx2evm_idx);
+                    if let Some(other) = other {
+                        res.push(Instruction::Else);
+                        self.unfold_cfg(program, other, kind, stack, res, wasm_idx2evm_idx);
+                    }
                     res.push(Instruction::End);
                 }
                 ReBlock::Br(levels) => {
                     res.push(Instruction::Br(*levels));
                 }
-                ReBlock::Return => {
-                    res.push(Instruction::Return);
+                ReBlock::BrIf(levels) => {
+                    res.push(Instruction::Call(self.evm_pop_function));
+                    res.push(Instruction::BrIf(*levels));
                 }
-                ReBlock::Actions(block) => {
-                    match block.origin {
-                        CfgNode::Orig(orig_label) => {
-                            let block_code =
-                                &program.0[orig_label.code_start.0..orig_label.code_end.0];
-                            let block_len = orig_label.code_end.0 - orig_label.code_start.0;
-                            let mut curr_idx = 0;
-                            let mut evm_offset: usize = 0;
-                            while curr_idx < block_len {
-                                match &block_code[curr_idx..] {
-                                    [p, j, ..] if p.is_push() && j.is_jump() => {
-                                        // this is static jump, already accounted during cfg analysis. we only need to burn gas there
-                                        let jump_gas = if j == &Opcode::JUMP { 8 } else { 10 };
-                                        res.extend(vec![
-                                            Instruction::I32Const(3),             // any push costs 3 gas
-                                            Instruction::Call(self.evm_burn_gas), // burn it
-                                            Instruction::I32Const(jump_gas),
-                                            Instruction::Call(self.evm_burn_gas),
-                                        ]);
-                                        curr_idx += 2;
-                                        evm_offset += p.size() + j.size();
-                                    }
-                                    [j, ..] if j.is_jump() => {
-                                        // this is dynamic jump
-                                        let jump_gas = if j == &Opcode::JUMP { 8 } else { 10 };
-                                        res.extend(vec![
-                                            Instruction::I32Const(jump_gas),
-                                            Instruction::Call(self.evm_burn_gas),
-                                        ]);
-                                        curr_idx += 1;
-                                        evm_offset += j.size();
-                                    }
-                                    [op, ..] => {
-                                        wasm_idx2evm_idx.insert(
-                                            Idx(res.len()),
-                                            Idx(curr_idx + orig_label.code_start.0),
-                                        );
-                                        if self.config.program_counter {
-                                            let pc = orig_label.label.0 + evm_offset;
-                                            res.extend(vec![
-                                                Instruction::I32Const(pc.try_into().unwrap()),
-                                                Instruction::Call(self.evm_pc_function),
-                                            ]);
-                                        }
-                                        if op.is_push() {
-                                            let operands = encode_push(op);
-                                            res.extend(operands);
-                                        }
-                                        let call = self.compile_operator(op);
-                                        res.push(call);
-                                        if op == &Opcode::RETURN {
-                                            res.push(Instruction::Return);
-                                        }
-                                        curr_idx += 1;
-                                        evm_offset += op.size();
-                                    }
-                                    [] => {
-                                        unreachable!()
-                                    }
-                                }
-                            }
-                        }
-                        CfgNode::Dynamic => {}
+                ReBlock::BrUnless(levels) => {
+                    res.push(Instruction::Call(self.evm_pop_function));
+                    res.push(Instruction::I32Eqz);
+                    res.push(Instruction::BrIf(*levels));
+                }
+                ReBlock::Return => {
+                    // the block may have returned already
+                    if !matches!(res.last(), Some(Instruction::Return)) {
+                        self.emit_halt(kind, res);
                     }
                 }
+                ReBlock::Actions(block) => match block.origin {
+                    CfgNode::Orig(orig_label) => {
+                        self.compile_block(
+                            program,
+                            &orig_label,
+                            kind,
+                            stack,
+                            res,
+                            wasm_idx2evm_idx,
+                        );
+                    }
+                    CfgNode::Route(index) => {
+                        res.push(Instruction::I32Const(index as i32));
+                        res.push(Instruction::LocalSet(DISPATCH_LOCAL));
+                    }
+                    CfgNode::Dynamic | CfgNode::Entry | CfgNode::Dispatch => {}
+                },
                 ReBlock::TableJump(table) => {
-                    let (table_len, _) = table.last_key_value().unwrap(); // should be safe as switch of zero variants is meaningless
-                    let mut licspr_table = vec![0; table_len + 1];
-                    for (&cond, &br_num) in table {
-                        licspr_table[cond] = br_num + 1; // increment due to additional block wrapping (for unreachable instruction)
+                    // switches follow the actions of their node, the dispatcher
+                    // switches on its local and the others on the stack top
+                    match cfg_part.0[..i].last() {
+                        Some(ReBlock::Actions(node)) if node.origin == CfgNode::Dispatch => {
+                            res.push(Instruction::LocalGet(DISPATCH_LOCAL));
+                        }
+                        _ => res.push(Instruction::Call(self.evm_pop_function)),
                     }
+                    table_jump(table, res);
+                }
+            }
+        }
+    }
 
-                    res.push(Instruction::Block(BlockType::Empty));
-                    res.push(Instruction::Call(self.evm_pop_function));
-                    let cow = Cow::Owned(licspr_table);
-                    let br_table = Instruction::BrTable(cow, 0);
-                    res.push(br_table);
-                    res.push(Instruction::End);
-                    res.push(Instruction::Unreachable);
+    /// Compiles the straight-line code of a single EVM block.
+    fn compile_block(
+        &self,
+        program: &'a Program,
+        block: &EvmBlock,
+        kind: FunctionKind,
+        stack: &mut VirtualStack,
+        res: &mut Vec<Instruction<'a>>,
+        wasm_idx2evm_idx: &mut HashMap<Idx, Idx>,
+    ) {
+        let block_code = &program.0[block.code_start.0..block.code_end.0];
+        let block_len = block.code_end.0 - block.code_start.0;
+        let mut curr_idx = 0;
+        let mut evm_offset: usize = 0;
+        let mut block_gas = ops::block_gas(block_code).into_iter();
+        self.burn_static_gas(block_gas.next().unwrap(), res);
+        self.emit_block_pc(block.label.0, res);
+        while curr_idx < block_len {
+            let rewrite = match self.config.peephole {
+                true => peephole(&block_code[curr_idx..]),
+                false => None,
+            };
+            match &block_code[curr_idx..] {
+                [p, j, ..] if p.is_push() && j.is_jump() => {
+                    // this is static jump, already accounted during cfg analysis
+                    curr_idx += 2;
+                    evm_offset += p.size() + j.size();
+                }
+                [j, ..] if j.is_jump() => {
+                    // this is dynamic jump
+                    curr_idx += 1;
+                    evm_offset += j.size();
+                }
+                _ if rewrite.is_some() => {
+                    let (rewrite, len) = rewrite.unwrap();
+                    wasm_idx2evm_idx.insert(Idx(res.len()), Idx(curr_idx + block.code_start.0));
+                    let pc = block.label.0 + evm_offset;
+                    let ops = &block_code[curr_idx..curr_idx + len];
+                    curr_idx += len;
+                    evm_offset += ops.iter().map(Opcode::size).sum::<usize>();
+                    // checked on the next spill, right below without stackify
+                    stack.reserve(ops::peak_growth(ops));
+                    let rewrite = match self.config.stackify {
+                        true => stack.apply_rewrite(rewrite),
+                        false => Some(rewrite),
+                    };
+                    if let Some(rewrite) = rewrite {
+                        stack.flush(res);
+                        self.emit_set_pc(None, pc, res);
+                        self.compile_rewrite(rewrite, res);
+                    }
+                }
+                [op, ..] => {
+                    wasm_idx2evm_idx.insert(Idx(res.len()), Idx(curr_idx + block.code_start.0));
+                    curr_idx += 1;
+                    evm_offset += op.size();
+                    let pc = block.label.0 + evm_offset - op.size();
+                    if self.config.stackify && stack.apply(op, pc, res) {
+                        continue;
+                    }
+                    stack.flush(res);
+                    let inlined = self.inline_runtime.and_then(|g| inline_op(op, pc, g));
+                    if let Some(code) = inlined {
+                        res.extend(code);
+                        continue;
+                    }
+                    self.emit_set_pc(Some(op), pc, res);
+                    if op.is_push() {
+                        let operands = encode_push(op);
+                        res.extend(operands);
+                    }
+                    let call = self.compile_operator(op);
+                    res.push(call);
+                    if op == &Opcode::RETURN {
+                        self.emit_halt(kind, res);
+                    }
+                    if ops::observes_gas(op) {
+                        self.burn_static_gas(block_gas.next().unwrap(), res);
+                    }
+                }
+                [] => {
+                    unreachable!()
                 }
             }
         }
+        // the runtime stack must be exact when leaving the block
+        stack.discard(block.exit_drops, res);
+        stack.flush(res);
+
+        match block.exit {
+            BlockExit::Jump => {}
+            BlockExit::Call(callee) => {
+                res.push(Instruction::Call(self.internal_functions[&callee]));
+                res.push(Instruction::If(BlockType::Empty));
+                self.emit_halt(kind, res);
+                res.push(Instruction::End);
+            }
+            BlockExit::Return => {
+                res.push(Instruction::I32Const(0));
+                res.push(Instruction::Return);
+            }
+        }
+    }
+
+    /// Compiles a peephole rewrite into a call to a fused runtime entry point
+    /// (operands must be already pushed).
+    fn compile_rewrite(&self, rewrite: Rewrite, res: &mut Vec<Instruction>) {
+        let funcs = &self.fused_functions;
+        let (func, arg) = match rewrite {
+            Rewrite::Nop => return,
+            Rewrite::Check(n) => return self.stack_functions.check_depth(n, res),
+            Rewrite::PopN(n) => (funcs.pop_n, Some(n)),
+            Rewrite::SwapPop(n) => (funcs.swap_pop, Some(n)),
+            Rewrite::Bool => (funcs.bool, None),
+            Rewrite::Mask(bits) => (funcs.mask, Some(bits)),
+        };
+        if let Some(arg) = arg {
+            res.push(Instruction::I32Const(arg.try_into().unwrap()));
+        }
+        res.push(Instruction::Call(func));
+    }
+
+    /// Sets the program counter on entering the block at `pc`, with a sparse
+    /// program counter, so that handlers failing before the next handler
+    /// observing it report the start of their block.
+    fn emit_block_pc(&self, pc: usize, res: &mut Vec<Instruction>) {
+        if self.config.program_counter && self.config.sparse_program_counter {
+            res.push(Instruction::I32Const(pc.try_into().unwrap()));
+            res.push(Instruction::Call(self.evm_pc_function));
+        }
+    }
+
+    /// Sets the program counter before calling the op handler of `op`, or a
+    /// fused entry point if `None`. With a sparse program counter, it is only
+    /// set before handlers observing it, see `emit_block_pc`.
+    fn emit_set_pc(&self, op: Option<&Opcode>, pc: usize, res: &mut Vec<Instruction>) {
+        if !self.config.program_counter {
+            return;
+        }
+        if self.config.sparse_program_counter && !op.map_or(false, ops::observes_pc) {
+            return;
+        }
+        res.push(Instruction::I32Const(pc.try_into().unwrap()));
+        res.push(Instruction::Call(self.evm_pc_function));
+    }
+
+    /// Returns from a function once execution has halted.
+    fn emit_halt(&self, kind: FunctionKind, res: &mut Vec<Instruction>) {
+        if let FunctionKind::Internal(_) = kind {
+            res.push(Instruction::I32Const(1));
+        }
+        res.push(Instruction::Return);
+    }
+
+    /// Charges static gas on behalf of the op handlers, which only charge
+    /// their dynamic part when the runtime is built with `block-gas`.
+    fn burn_static_gas(&self, gas: u32, res: &mut Vec<Instruction>) {
+        if self.config.gas_accounting && gas > 0 {
+            res.push(Instruction::I32Const(gas.try_into().unwrap()));
+            res.push(Instruction::Call(self.evm_burn_gas));
+        }
     }
 
     fn opcodes_debug(&self, program: &Program) {
@@ -478,58 +1100,201 @@ subgraph cluster_wasm {{ label = \"wasm\"
     }
 
     /// Compiles the program's control-flow graph.
-    fn compile_cfg(&mut self, program: &'a Program) {
+    fn compile_cfg(&mut self, program: &'a Program, selectors: &[u32]) {
         assert_ne!(self.evm_start_function, 0); // filled in during emit_start()
         assert_eq!(self.evm_exec_function, 0); // filled in below
 
         self.opcodes_debug(program);
 
-        let basic_cfg = basic_cfg(program);
+        let basic_cfg = basic_cfg(program, self.config.constant_folding);
         self.debug("basic_cfg.dot", || {
             format!("digraph {{{}}}", basic_cfg.cfg.cfg_to_dot("basic"))
         });
 
-        let mut evm_cfg = basic_cfg.cfg.map_label(|n| match n {
-            CfgNode::Orig(l) => {
-                let a = basic_cfg.code_ranges.get(l).unwrap();
-                CfgNode::Orig(EvmBlock::new(*l, a.start, a.end))
+        let functions = function_cfgs(
+            program,
+            &basic_cfg,
+            self.config.split_functions,
+            self.config.constant_folding,
+            |cfg| self.add_direct_entries(program, &basic_cfg, cfg, selectors),
+        );
+        // callees come first, so that their indices are known at call sites
+        for (kind, evm_cfg) in functions {
+            let func_id = self.compile_function(program, &basic_cfg, evm_cfg, kind);
+            match kind {
+                FunctionKind::Exec => self.evm_exec_function = func_id,
+                FunctionKind::Internal(entry) => {
+                    self.internal_functions.insert(entry, func_id);
+                }
             }
-            CfgNode::Dynamic => CfgNode::Dynamic,
-        });
+        }
+    }
+
+    /// Finds the direct entries of the ABI methods with the given selectors,
+    /// returning the CFG of `_evm_exec` with an `Entry` node dispatching to
+    /// their entry points if there are any.
+    fn add_direct_entries(
+        &mut self,
+        program: &Program,
+        basic_cfg: &BasicCfg,
+        cfg: &Cfg<CfgNode<Offs>>,
+        selectors: &[u32],
+    ) -> Cfg<CfgNode<Offs>> {
+        if !self.config.direct_entries {
+            return cfg.clone();
+        }
+        let nodes = cfg.nodes();
+        for &selector in selectors {
+            let entry = match direct_entry(program, &basic_cfg.code_ranges, selector) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            // blocks of internal functions can't be entered from outside
+            if entry
+                .entry_points()
+                .all(|offs| nodes.contains(&CfgNode::Orig(offs)))
+            {
+                self.direct_entries.insert(selector, entry);
+            }
+        }
+        if self.direct_entries.is_empty() {
+            return cfg.clone();
+        }
+
+        // in ABI order, to keep the output deterministic
+        self.entry_points = vec![Offs(0)];
+        for entry in selectors.iter().filter_map(|s| self.direct_entries.get(s)) {
+            for offs in entry.entry_points() {
+                if !self.entry_points.contains(&offs) {
+                    self.entry_points.push(offs);
+                }
+            }
+        }
+        let table = self
+            .entry_points
+            .iter()
+            .enumerate()
+            .map(|(index, offs)| (index, CfgNode::Orig(*offs)))
+            .collect();
+        let mut edges = cfg.edges().clone();
+        edges.insert(CfgNode::Entry, CfgEdge::Switch(table));
+        Cfg::from_edges(CfgNode::Entry, edges)
+    }
+
+    /// Reloops the CFG of `_evm_exec` or of an internal function and emits it.
+    fn compile_function(
+        &mut self,
+        program: &'a Program,
+        basic_cfg: &BasicCfg,
+        mut evm_cfg: Cfg<CfgNode<EvmBlock>>,
+        kind: FunctionKind,
+    ) -> FunctionIndex {
+        // debug files of internal functions are named after their entry
+        let prefix = match kind {
+            FunctionKind::Exec => String::new(),
+            FunctionKind::Internal(entry) => format!("func_{}.", entry),
+        };
 
         evm_cfg.strip_unreachable();
-        self.debug("stripped.dot", || {
+        self.debug(format!("{prefix}stripped.dot"), || {
             format!("digraph {{{}}}", evm_cfg.cfg_to_dot("stripped"))
         });
-        let reduced = reduce(&evm_cfg);
-        self.debug("reduced.dot", || {
+        // in the text format of the relooper's test data and benchmarks
+        self.debug(format!("{prefix}stripped.cfg"), || evm_cfg.to_string());
+        self.debug(format!("{prefix}stripped.json"), || {
+            serde_json::to_string(&evm_cfg).unwrap()
+        });
+        // before splitting, so that irreducible loops show with all their headers
+        self.debug(format!("{prefix}loops.json"), || {
+            serde_json::to_string(&evm_cfg.loop_forest()).unwrap()
+        });
+        let reduced = reduce_function(&evm_cfg, self.config.split_growth);
+        self.debug(format!("{prefix}reduced.dot"), || {
             format!("digraph {{{}}}", evm_cfg.cfg_to_dot("reduced"))
         });
         let enriched = EnrichedCfg::new(reduced);
-        self.debug("enriched.dot", || {
+        self.debug(format!("{prefix}enriched.dot"), || {
             format!(
                 "digraph {{{} {}}}",
                 enriched.cfg_to_dot("enriched"),
                 enriched.dom_to_dot()
             )
         });
+        self.debug(format!("{prefix}dominators.json"), || {
+            serde_json::to_string(&enriched.domination).unwrap()
+        });
         let relooped_cfg = enriched.reloop();
 
-        self.debug("relooped.dot", || {
+        self.debug(format!("{prefix}relooped.dot"), || {
             format!("digraph {{{}}}", relooped_cfg.to_dot())
         });
+        self.debug(format!("{prefix}relooped.json"), || {
+            serde_json::to_string(&relooped_cfg).unwrap()
+        });
 
         let mut wasm: Vec<Instruction> = Default::default();
         let mut wasm_idx2evm_idx = Default::default();
-        self.unfold_cfg(program, &relooped_cfg, &mut wasm, &mut wasm_idx2evm_idx);
-        wasm.push(Instruction::End);
+        let mut stack = VirtualStack::new(
+            self.stack_functions,
+            self.inline_runtime,
+            self.config.constant_folding,
+        );
+        self.unfold_cfg(
+            program,
+            &relooped_cfg,
+            kind,
+            &mut stack,
+            &mut wasm,
+            &mut wasm_idx2evm_idx,
+        );
 
-        if self.config.debug_path.is_some() {
-            self.evm_wasm_dot_debug(program, &basic_cfg, &wasm, &wasm_idx2evm_idx);
-        }
+        let mut locals = vec![ValType::I32, ValType::I32];
+        locals.extend((0..stack.word_locals()).map(|_| ValType::I64));
+        let origin = self.code_origin(program, basic_cfg, kind, &wasm_idx2evm_idx);
+        let func_idx = match kind {
+            FunctionKind::Exec => {
+                wasm.push(Instruction::End);
+                if self.config.debug_path.is_some() {
+                    self.evm_wasm_dot_debug(program, basic_cfg, &wasm, &wasm_idx2evm_idx);
+                }
+                let name = Some("_evm_exec".to_string());
+                self.emit_function_with_locals(name, vec![], locals, wasm)
+            }
+            FunctionKind::Internal(_) => {
+                // every path returns explicitly
+                wasm.push(Instruction::Unreachable);
+                wasm.push(Instruction::End);
+                self.emit_function_with_locals(None, vec![ValType::I32], locals, wasm)
+            }
+        };
+        self.origins.insert(func_idx, origin);
+        func_idx
+    }
 
-        let func_id = self.emit_function(Some("_evm_exec".to_string()), wasm);
-        self.evm_exec_function = func_id;
+    /// Describes the EVM operators a function was compiled from.
+    fn code_origin(
+        &self,
+        program: &Program,
+        basic_cfg: &BasicCfg,
+        kind: FunctionKind,
+        wasm_idx2evm_idx: &HashMap<Idx, Idx>,
+    ) -> Origin {
+        let evm_idx2offs = evm_idx_to_offs(program);
+        let mut evm_idx2block = HashMap::new();
+        for (label, range) in &basic_cfg.code_ranges {
+            for idx in range.start.0..range.end.0 {
+                evm_idx2block.insert(Idx(idx), *label);
+            }
+        }
+        let mut ops: Vec<_> = wasm_idx2evm_idx
+            .iter()
+            .map(|(wasm_idx, evm_idx)| (wasm_idx.0, evm_idx2offs[evm_idx], evm_idx2block[evm_idx]))
+            .collect();
+        ops.sort();
+        Origin::Code {
+            name: kind.to_string(),
+            ops,
+        }
     }
 
     /// Compiles the invocation of an EVM operator (operands must be already pushed).
@@ -539,7 +1304,17 @@ subgraph cluster_wasm {{ label = \"wasm\"
         Instruction::Call(*op_idx)
     }
 
-    fn emit_function(&mut self, name: Option<String>, mut code: Vec<Instruction>) -> FunctionIndex {
+    fn emit_function(&mut self, name: Option<String>, code: Vec<Instruction>) -> FunctionIndex {
+        self.emit_function_with_locals(name, vec![], vec![ValType::I32], code)
+    }
+
+    fn emit_function_with_locals(
+        &mut self,
+        name: Option<String>,
+        results: Vec<ValType>,
+        locals: Vec<ValType>,
+        mut code: Vec<Instruction>,
+    ) -> FunctionIndex {
         match code.last() {
             Some(Instruction::End) => {}
             Some(_) | None => code.push(Instruction::End),
@@ -547,10 +1322,10 @@ subgraph cluster_wasm {{ label = \"wasm\"
 
         let func_sig = Signature {
             params: vec![],
-            results: vec![],
+            results,
         };
 
-        let mut func_body = Function::new_with_locals_types(vec![ValType::I32]);
+        let mut func_body = Function::new_with_locals_types(locals);
         for instr in code {
             func_body.instruction(&instr);
         }
@@ -559,6 +1334,7 @@ subgraph cluster_wasm {{ label = \"wasm\"
         let func_idx = self.builder.add_function(func_sig, func_body) + imports_len;
 
         if let Some(name) = name {
+            self.origins.insert(func_idx, Origin::Export(name.clone()));
             let func_export = Export {
                 name,
                 kind: ExportKind::Func,
@@ -581,9 +1357,8 @@ fn make_op_table(module: &ModuleBuilder) -> HashMap<Opcode, FunctionIndex> {
         } = export
         {
             match name.as_str() {
-                "_abi_buffer" | "_evm_start" | "_evm_init" | "_evm_call" | "_evm_exec"
-                | "_evm_post_exec" | "_evm_pop_u32" | "_evm_push_u32" | "_evm_burn_gas"
-                | "_evm_set_pc" | "execute" => {}
+                "execute" => {}
+                runtime_sym if runtime_sym.starts_with('_') => {} // _evm_* runtime API
                 export_sym => match parse_opcode(&export_sym.to_ascii_uppercase()) {
                     None => unreachable!(), // TODO
                     Some(op) => _ = result.insert(op, *index),
@@ -610,22 +1385,328 @@ fn find_runtime_function(module: &ModuleBuilder, func_name: &str) -> Option<Func
     None // not found
 }
 
-fn find_abi_buffer(module: &ModuleBuilder) -> Option<DataOffset> {
-    for export in module.exports.iter() {
-        if let Export {
-            name,
-            kind: ExportKind::Global,
-            index,
-        } = export
-        {
-            if name == "_abi_buffer" {
-                let g = module.globals.get(*index as usize).unwrap();
-                match g.init_instr {
-                    wasm_encoder::Instruction::I32Const(off) => return Some(off),
-                    _ => return None,
+/// Lists the canonical types of the parameters for the runtime, which parses
+/// them to convert between JSON and the ABI encoding. Tuples are written with
+/// their components, as in the signature of the function.
+fn param_types(func: &AbiFunction, params: &[Parameter]) -> Result<String, Violation> {
+    let types: Vec<String> = params.iter().map(Parameter::canonical_type).collect();
+    if let Some(typ) = types
+        .iter()
+        .find(|typ| abi_types::parse_param_type(typ).is_err())
+    {
+        return Err(Violation {
+            location: None,
+            message: format!("unknown ABI type `{}` in function `{}`", typ, func),
+        });
+    }
+    Ok(types.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use wasm_encoder::{
+        CodeSection, ExportSection, FunctionSection, MemorySection, MemoryType, TypeSection,
+    };
+
+    use super::*;
+    use crate::{abi::parse_str, decode::decode_bytecode, wasm_translate::parse};
+
+    /// The runtime API the compiler calls, with the wasm signatures of the
+    /// functions in evmlib.
+    const RUNTIME_API: &[(&str, &[ValType], &[ValType])] = &[
+        ("_evm_version", &[], &[ValType::I32]),
+        (
+            "_evm_init",
+            &[ValType::I32, ValType::I64, ValType::I64],
+            &[],
+        ),
+        ("_evm_call", &[ValType::I32; 5], &[]),
+        ("_evm_receive", &[], &[]),
+        ("_evm_post_exec", &[ValType::I32; 4], &[]),
+        ("_evm_pop_u32", &[], &[ValType::I32]),
+        ("_evm_push_u32", &[ValType::I32], &[]),
+        ("_evm_push_u256", &[ValType::I64; 4], &[]),
+        ("_evm_stack_slot", &[ValType::I32], &[ValType::I32]),
+        ("_evm_stack_room", &[ValType::I32], &[]),
+        (
+            "_evm_arith",
+            &[
+                ValType::I32,
+                ValType::I64,
+                ValType::I64,
+                ValType::I64,
+                ValType::I64,
+                ValType::I64,
+                ValType::I64,
+                ValType::I64,
+                ValType::I64,
+                ValType::I64,
+                ValType::I64,
+                ValType::I64,
+                ValType::I64,
+            ],
+            &[ValType::I32],
+        ),
+        ("_evm_fault", &[], &[]),
+        ("_evm_stack", &[], &[ValType::I32]),
+        ("_evm_stack_slots", &[], &[ValType::I32]),
+        ("_evm_call_value", &[], &[ValType::I32]),
+        ("_evm_set_pc", &[ValType::I32], &[]),
+        ("_evm_burn_gas", &[ValType::I32], &[]),
+        ("_evm_block_gas", &[], &[]),
+        ("_evm_pop_n", &[ValType::I32], &[]),
+        ("_evm_swap_pop", &[ValType::I32], &[]),
+        ("_evm_bool", &[], &[]),
+        ("_evm_mask", &[ValType::I32], &[]),
+    ];
+
+    /// Returns a stand-in for evmlib: a memory and functions doing nothing,
+    /// exported under the names of the runtime API and of the op handlers
+    /// other than `PUSHn`, whose operands stackify keeps in wasm.
+    fn runtime() -> Vec<u8> {
+        runtime_without(&[])
+    }
+
+    /// Returns the stand-in for evmlib without the `missing` functions.
+    fn runtime_without(missing: &[&str]) -> Vec<u8> {
+        let source = include_str!("../../../lib/evmlib/src/ops.rs");
+        let handlers = source
+            .split("pub unsafe fn ")
+            .skip(1)
+            .map(|handler| handler[..handler.find('(').unwrap()].trim_start_matches("r#"))
+            .filter(|name| !name.starts_with("_evm") && !name.starts_with("push"))
+            .map(|name| (name, &[][..], &[][..]));
+
+        let mut types = TypeSection::new();
+        let mut functions = FunctionSection::new();
+        let mut exports = ExportSection::new();
+        let mut codes = CodeSection::new();
+        let api = RUNTIME_API
+            .iter()
+            .copied()
+            .filter(|(name, _, _)| !missing.contains(name));
+        for (index, (name, params, results)) in api.chain(handlers).enumerate() {
+            let index = index.try_into().unwrap();
+            types.function(params.iter().copied(), results.iter().copied());
+            functions.function(index);
+            exports.export(name, ExportKind::Func, index);
+            let mut body = Function::new([]);
+            for result in results {
+                body.instruction(&match result {
+                    ValType::I64 => Instruction::I64Const(0),
+                    _ => Instruction::I32Const(0),
+                });
+            }
+            body.instruction(&Instruction::End);
+            codes.function(&body);
+        }
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType {
+            minimum: 1,
+            maximum: None,
+            memory64: false,
+            shared: false,
+        });
+
+        let mut module = Module::new();
+        module
+            .section(&types)
+            .section(&functions)
+            .section(&memories)
+            .section(&exports)
+            .section(&codes);
+        module.finish()
+    }
+
+    fn config(inline_ops: bool) -> CompilerConfig {
+        CompilerConfig {
+            inline_ops,
+            chain_id: 1,
+            ..Default::default()
+        }
+    }
+
+    // 0x00: PUSH1 0x80 PUSH1 0x40 MSTORE CALLVALUE DUP1 ISZERO PUSH1 0x0f JUMPI
+    // 0x0b: PUSH1 0x00 DUP1 REVERT
+    // 0x0f: JUMPDEST POP PUSH1 0x04 CALLDATASIZE LT PUSH1 0x32 JUMPI
+    // 0x18: PUSH1 0x00 CALLDATALOAD PUSH1 0xe0 SHR
+    //       DUP1 PUSH4 0xaabbccdd EQ PUSH1 0x37 JUMPI
+    // 0x28: DUP1 PUSH4 0x11223344 EQ PUSH1 0x3c JUMPI
+    // 0x32: JUMPDEST PUSH1 0x00 DUP1 REVERT
+    // 0x37: JUMPDEST PUSH1 0x00 SLOAD STOP
+    // 0x3c: JUMPDEST CALLER STOP
+    const DISPATCHER: &str = concat!(
+        "608060405234801560",
+        "0f57600080fd5b5060043610603257600035",
+        "60e01c8063aabbccdd14603757806311223344",
+        "14603c575b600080fd5b600054005b3300"
+    );
+
+    #[test]
+    fn test_replayed_block_pc() {
+        let runtime = runtime();
+        let program = decode_bytecode(DISPATCHER).unwrap();
+        let mut compiler = Compiler::new(parse(&runtime).unwrap(), config(false));
+        compiler.emit_wasm_start();
+        compiler.emit_evm_start();
+        compiler.compile_cfg(&program, &[0xaabbccdd]);
+        let entry = compiler.direct_entries[&0xaabbccdd].clone();
+        let mut code = vec![];
+        compiler.emit_direct_entry(&entry, &mut code);
+
+        // the POP at 0x10 underflows on an empty stack, after the MSTORE at
+        // 0x04 of the previous block set the pc
+        let pop = compiler.op_table[&Opcode::POP];
+        let mut pc = None;
+        for pair in code.windows(2) {
+            match pair {
+                [Instruction::I32Const(x), Instruction::Call(f)]
+                    if *f == compiler.evm_pc_function =>
+                {
+                    pc = Some(*x)
                 }
+                [_, Instruction::Call(f)] if *f == pop => break,
+                _ => {}
             }
         }
+        assert_eq!(pc, Some(0x0f));
+    }
+
+    #[test]
+    fn test_debug_json_round_trip() {
+        let program = decode_bytecode(DISPATCHER).unwrap();
+        let basic_cfg = basic_cfg(&program, true);
+        for (_, evm_cfg) in function_cfgs(&program, &basic_cfg, true, true, Cfg::clone) {
+            let json = serde_json::to_string(&evm_cfg).unwrap();
+            let parsed: Cfg<CfgNode<EvmBlock>> = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed.edges(), evm_cfg.edges());
+
+            let reduced = reduce_function(&evm_cfg, 4);
+            let relooped = EnrichedCfg::new(reduced).reloop();
+            let json = serde_json::to_string(&relooped).unwrap();
+            let parsed: ReSeq<SLabel<CfgNode<EvmBlock>>> = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed, relooped);
+        }
+    }
+
+    #[test]
+    fn test_irreducible_over_budget() {
+        let block = |offs| {
+            CfgNode::Orig(EvmBlock::new(
+                Offs(offs),
+                Idx(0),
+                Idx(0),
+                0,
+                BlockExit::Jump,
+            ))
+        };
+        // the loop 1 <-> 2 is entered at both
+        let edges = HashMap::from([
+            (block(0), CfgEdge::Cond(block(1), block(2))),
+            (block(1), CfgEdge::Uncond(block(2))),
+            (block(2), CfgEdge::Cond(block(1), block(3))),
+        ]);
+        let cfg = Cfg::from_edges(block(0), edges);
+
+        let split = reduce_function(&cfg, 4);
+        assert!(!split.nodes().iter().any(|n| n.origin == CfgNode::Dispatch));
+        assert_eq!(split.nodes().len(), 5);
+
+        // no node is copied, the edges into 1 and 2 go through the dispatcher
+        let routed = reduce_function(&cfg, 0);
+        let origins: HashSet<_> = routed.nodes().iter().map(|n| n.origin).collect();
+        assert_eq!(origins.len(), routed.nodes().len());
+        assert!(origins.contains(&CfgNode::Dispatch));
+        for node in [block(1), block(2)] {
+            let preds: Vec<_> = routed
+                .edges()
+                .iter()
+                .filter(|(_, edge)| edge.iter().any(|to| to.origin == node))
+                .map(|(from, _)| from.origin)
+                .collect();
+            assert_eq!(preds, vec![CfgNode::Dispatch]);
+        }
+        // one route per edge
+        let dispatch = routed
+            .edges()
+            .iter()
+            .find(|(n, _)| n.origin == CfgNode::Dispatch);
+        match dispatch {
+            Some((_, CfgEdge::Switch(table))) => assert_eq!(table.len(), 4),
+            _ => panic!("dispatcher without a switch"),
+        }
+        EnrichedCfg::new(routed).reloop();
+    }
+
+    /// Returns the ABI of `f(p) returns (r)`, with `p` and `r` of the given
+    /// types and components.
+    fn tuple_abi(input: (&str, &str), output: (&str, &str)) -> Functions {
+        parse_str(&format!(
+            r#"[{{
+                "type": "function",
+                "name": "f",
+                "inputs": [{{ "name": "p", "type": "{}", "components": {} }}],
+                "outputs": [{{ "name": "r", "type": "{}", "components": {} }}],
+                "stateMutability": "view"
+            }}]"#,
+            input.0, input.1, output.0, output.1
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_tuple_abi_types() {
+        let runtime = runtime();
+        let program = decode_bytecode(DISPATCHER).unwrap();
+        let components = r#"[
+            { "name": "a", "type": "uint256" },
+            { "name": "b", "type": "address" }
+        ]"#;
+        let abi = tuple_abi(("tuple", components), ("tuple[2]", components));
+        let exports = method_exports(&abi, &HashMap::new()).unwrap();
+        let selectors: Vec<u32> = abi.iter().map(|func| func.selector()).collect();
+        let mut compiler = Compiler::new(parse(&runtime).unwrap(), config(false));
+        compiler.emit_wasm_start();
+        compiler.emit_evm_start();
+        compiler.compile_cfg(&program, &selectors);
+        let data = compiler.emit_abi_methods(Some(abi), &exports).unwrap();
+
+        // the names, the input types and the output types
+        let data: Vec<&[u8]> = data.split(|byte| *byte == 0).collect();
+        assert_eq!(
+            data[..3],
+            [
+                &b"p"[..],
+                &b"(uint256,address)"[..],
+                &b"(uint256,address)[2]"[..]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_abi_type() {
+        let runtime = runtime();
+        let program = decode_bytecode(DISPATCHER).unwrap();
+        let components = r#"[{ "name": "a", "type": "uint7" }]"#;
+        let abi = tuple_abi(("tuple", components), ("bool", "[]"));
+        let result = compile(&program, Some(abi), parse(&runtime).unwrap(), config(false));
+        assert_eq!(
+            result.unwrap_err(),
+            [Violation {
+                location: None,
+                message: "unknown ABI type `(uint7)` in function `f((uint7))`".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_runtime_without_block_gas() {
+        let program = decode_bytecode(DISPATCHER).unwrap();
+        let runtime = runtime_without(&["_evm_block_gas"]);
+        let result = compile(&program, None, parse(&runtime).unwrap(), config(false));
+        assert_eq!(
+            result.unwrap_err()[0].message,
+            "the runtime library must be built with the `block-gas` feature"
+        );
     }
-    None // not found
 }
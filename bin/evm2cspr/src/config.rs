@@ -1,35 +1,104 @@
 // This is free and unencumbered software released into the public domain.
 
-use std::{fs, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
 
 pub struct CompilerConfig {
     pub debug_path: Option<PathBuf>,
     pub optimize_level: u8,
     pub gas_accounting: bool,
     pub program_counter: bool,
+    pub sparse_program_counter: bool,
+    pub stackify: bool,
+    pub constant_folding: bool,
+    pub peephole: bool,
+    pub inline_ops: bool,
+    pub split_functions: bool,
+    pub direct_entries: bool,
+    pub tree_shaking: bool,
+    pub shared_runtime: bool,
+    pub casper_rules: bool,
+    /// How many times its size node splitting may grow a function to, before
+    /// irreducible control flow is routed through a dispatcher instead.
+    pub split_growth: usize,
+    pub export_names: HashMap<String, String>,
     pub chain_id: u64,
 }
 
+impl Default for CompilerConfig {
+    /// Every optimization on, on mainnet.
+    fn default() -> Self {
+        CompilerConfig {
+            debug_path: None,
+            optimize_level: 0,
+            gas_accounting: true,
+            program_counter: true,
+            sparse_program_counter: true,
+            stackify: true,
+            constant_folding: true,
+            peephole: true,
+            inline_ops: true,
+            split_functions: true,
+            direct_entries: true,
+            tree_shaking: true,
+            shared_runtime: false,
+            casper_rules: true,
+            split_growth: 4,
+            export_names: HashMap::new(),
+            chain_id: 1313161554,
+        }
+    }
+}
+
 impl CompilerConfig {
-    pub fn new(
-        debug_path: Option<PathBuf>,
-        optimize_level: u8,
-        gas_accounting: bool,
-        program_counter: bool,
-        chain_id: u64,
-    ) -> Self {
-        if let Some(debug_dir) = &debug_path {
+    /// Empties the debug directory, if any, for the files of this compile.
+    pub fn create_debug_dir(&self) {
+        if let Some(debug_dir) = &self.debug_path {
             if fs::read_dir(debug_dir).is_ok() {
                 fs::remove_dir_all(debug_dir).expect("unable to remove previous debug directory!");
             }
             fs::create_dir_all(debug_dir).expect("unable to create debug directory!");
         }
-        CompilerConfig {
-            debug_path,
-            optimize_level,
-            gas_accounting,
-            program_counter,
-            chain_id,
-        }
+    }
+}
+
+/// Settings of a single contract, read from the JSON file given with
+/// `--config`.
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContractConfig {
+    /// Export names of ABI functions by signature, like `--export-name`.
+    pub export_names: HashMap<String, String>,
+}
+
+impl ContractConfig {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let json = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        serde_json::from_str(&json).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contract_config() {
+        let json = r#"{"export_names": {"transfer(address,uint256)": "transfer_to"}}"#;
+        let config: ContractConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            config.export_names["transfer(address,uint256)"],
+            "transfer_to"
+        );
+        assert_eq!(
+            serde_json::from_str::<ContractConfig>("{}").unwrap(),
+            ContractConfig::default()
+        );
+        assert!(serde_json::from_str::<ContractConfig>(r#"{"exports": {}}"#).is_err());
     }
 }
@@ -0,0 +1,319 @@
+// This is free and unencumbered software released into the public domain.
+
+use std::collections::{HashMap, HashSet};
+
+use evm_rs::{Opcode, Program};
+use relooper::graph::{
+    cfg::Cfg,
+    enrichments::EnrichedCfg,
+    relooper::{ReBlock, ReSeq},
+    supergraph::SLabel,
+};
+
+use crate::{
+    abi::Functions,
+    analyze::{basic_cfg, CfgNode, Offs},
+    compile::{function_cfgs, reduce_function, EvmBlock, FunctionKind},
+    config::CompilerConfig,
+    dispatch::direct_entry,
+    functions::BlockExit,
+    ops::{check_program, stack_effect},
+    validate::Violation,
+};
+
+/// Renders the program as structured pseudo-code, from the relooped trees
+/// the wasm functions are compiled from. Operators are annotated with how
+/// many words they pop and push, and the blocks the dispatcher enters ABI
+/// methods at with their signatures.
+///
+/// Conditions are the words popped by the `JUMPI`s before them. Like wasm
+/// ones, loops are left at their end unless continued.
+pub fn decompile(
+    program: &Program,
+    abi: Option<&Functions>,
+    config: &CompilerConfig,
+) -> Result<String, Violation> {
+    check_program(program)?;
+    let basic_cfg = basic_cfg(program, config.constant_folding);
+    let mut methods: HashMap<Offs, Vec<String>> = HashMap::new();
+    for func in abi.iter().flat_map(|abi| abi.iter()) {
+        if let Some(entry) = direct_entry(program, &basic_cfg.code_ranges, func.selector()) {
+            methods
+                .entry(entry.target)
+                .or_default()
+                .push(func.to_string());
+        }
+    }
+    let offsets: Vec<Offs> = program
+        .0
+        .iter()
+        .scan(Offs(0), |offs, op| {
+            let start = *offs;
+            offs.0 += op.size();
+            Some(start)
+        })
+        .collect();
+
+    let functions = function_cfgs(
+        program,
+        &basic_cfg,
+        config.split_functions,
+        config.constant_folding,
+        Cfg::clone,
+    );
+    let mut lines = Vec::new();
+    for (kind, mut evm_cfg) in functions {
+        evm_cfg.strip_unreachable();
+        let relooped = EnrichedCfg::new(reduce_function(&evm_cfg, config.split_growth)).reloop();
+        let mut printer = Printer {
+            program,
+            offsets: &offsets,
+            methods: &methods,
+            scopes: Vec::new(),
+            next_label: 0,
+            targeted: HashSet::new(),
+            lines: Vec::new(),
+        };
+        printer.seq(&relooped, 1);
+        let name = match kind {
+            FunctionKind::Exec => "_evm_exec".to_string(),
+            FunctionKind::Internal(entry) => format!("func_{}", entry),
+        };
+        lines.push(format!("function {}() {{", name));
+        lines.extend(printer.lines);
+        lines.push("}".to_string());
+        lines.push(String::new());
+    }
+    Ok(lines.join("\n"))
+}
+
+const INDENT: &str = "    ";
+
+/// Operators as in assembly listings, pushes with their operand in hex.
+fn op_text(op: &Opcode) -> String {
+    match op {
+        Opcode::PUSH1(value) => format!("PUSH1 0x{:02x}", value),
+        Opcode::PUSHn(n, _, bytes) => format!("PUSH{} 0x{}", n, hex::encode(bytes)),
+        op => op.to_string(),
+    }
+}
+
+struct Printer<'a> {
+    program: &'a Program,
+    offsets: &'a [Offs],
+    methods: &'a HashMap<Offs, Vec<String>>,
+    /// The label of each construct around the code printed, innermost
+    /// last, and whether it is a loop.
+    scopes: Vec<(usize, bool)>,
+    next_label: usize,
+    /// The labels branched to, which are the only ones printed.
+    targeted: HashSet<usize>,
+    lines: Vec<String>,
+}
+
+impl<'a> Printer<'a> {
+    fn line(&mut self, indent: usize, text: String) {
+        self.lines
+            .push(format!("{}{}", INDENT.repeat(indent), text));
+    }
+
+    fn seq(&mut self, seq: &ReSeq<SLabel<CfgNode<EvmBlock>>>, indent: usize) {
+        for block in &seq.0 {
+            match block {
+                ReBlock::Block(inner) => {
+                    let scope = self.open(false);
+                    self.seq(inner, indent + 1);
+                    self.close(scope, indent, "{");
+                }
+                ReBlock::Loop(inner) => {
+                    let scope = self.open(true);
+                    self.seq(inner, indent + 1);
+                    if inner.falls_through() {
+                        self.targeted.insert(scope.0);
+                        self.line(indent + 1, format!("break 'l{}", scope.0));
+                    }
+                    self.close(scope, indent, "loop {");
+                }
+                ReBlock::If(then, otherwise) => {
+                    let (arm, header) = match (then.0.is_empty(), otherwise.0.is_empty()) {
+                        (true, true) => {
+                            self.line(indent, "pop cond".to_string());
+                            continue;
+                        }
+                        (true, false) => (otherwise, "if !cond {"),
+                        _ => (then, "if cond {"),
+                    };
+                    let scope = self.open(false);
+                    self.seq(arm, indent + 1);
+                    if !then.0.is_empty() && !otherwise.0.is_empty() {
+                        self.line(indent, "} else {".to_string());
+                        self.seq(otherwise, indent + 1);
+                    }
+                    self.close(scope, indent, header);
+                }
+                ReBlock::Br(depth) => {
+                    let branch = self.branch(*depth);
+                    self.line(indent, branch);
+                }
+                ReBlock::BrIf(depth) => {
+                    let branch = self.branch(*depth);
+                    self.line(indent, format!("if cond {{ {} }}", branch));
+                }
+                ReBlock::BrUnless(depth) => {
+                    let branch = self.branch(*depth);
+                    self.line(indent, format!("if !cond {{ {} }}", branch));
+                }
+                ReBlock::TableJump(table) => {
+                    self.line(indent, "switch dest {".to_string());
+                    for (dest, depth) in table {
+                        let branch = self.branch(*depth);
+                        self.line(indent + 1, format!("0x{:x} => {},", dest, branch));
+                    }
+                    self.line(indent + 1, "_ => invalid,".to_string());
+                    self.line(indent, "}".to_string());
+                }
+                ReBlock::Return => {
+                    // the block may have returned already
+                    if self.lines.last().map(|l| l.trim()) != Some("return") {
+                        self.line(indent, "return".to_string());
+                    }
+                }
+                ReBlock::Actions(label) => self.actions(label.origin, indent),
+            }
+        }
+    }
+
+    /// Enters a construct, returning its label and the index of the line
+    /// of its header, which is known once its body is printed.
+    fn open(&mut self, is_loop: bool) -> (usize, usize) {
+        let label = self.next_label;
+        self.next_label += 1;
+        self.scopes.push((label, is_loop));
+        self.lines.push(String::new());
+        (label, self.lines.len() - 1)
+    }
+
+    fn close(&mut self, (label, header): (usize, usize), indent: usize, text: &str) {
+        self.scopes.pop();
+        let text = match self.targeted.contains(&label) {
+            true => format!("'l{}: {}", label, text),
+            false => text.to_string(),
+        };
+        self.lines[header] = format!("{}{}", INDENT.repeat(indent), text);
+        self.line(indent, "}".to_string());
+    }
+
+    /// Branches to the construct `depth` levels out, continuing loops.
+    fn branch(&mut self, depth: u32) -> String {
+        let depth = usize::try_from(depth).unwrap();
+        let (label, is_loop) = self.scopes[self.scopes.len() - 1 - depth];
+        self.targeted.insert(label);
+        match is_loop {
+            true => format!("continue 'l{}", label),
+            false => format!("break 'l{}", label),
+        }
+    }
+
+    fn actions(&mut self, node: CfgNode<EvmBlock>, indent: usize) {
+        let block = match node {
+            CfgNode::Orig(block) => block,
+            CfgNode::Dynamic => return self.line(indent, "// dynamic jump".to_string()),
+            CfgNode::Entry => return self.line(indent, "// direct entry".to_string()),
+            CfgNode::Dispatch => return self.line(indent, "// dispatcher".to_string()),
+            CfgNode::Route(index) => return self.line(indent, format!("dest = 0x{:x}", index)),
+        };
+        let methods = self.methods.get(&block.label).into_iter().flatten();
+        let mut lines: Vec<String> = methods.map(|method| format!("// {}", method)).collect();
+        for idx in block.code_start.0..block.code_end.0 {
+            let op = &self.program.0[idx];
+            let (pops, pushes) = stack_effect(op);
+            let code = format!("{}: {}", self.offsets[idx], op_text(op));
+            lines.push(format!("{:<28} // {} -> {}", code, pops, pushes));
+        }
+        match block.exit {
+            BlockExit::Jump => {}
+            BlockExit::Call(callee) => lines.push(format!("call func_{}", callee)),
+            BlockExit::Return => lines.push("return".to_string()),
+        }
+        for line in lines {
+            self.line(indent, line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{abi::parse_str, decode::decode_bytecode};
+
+    fn config(split_functions: bool) -> CompilerConfig {
+        CompilerConfig {
+            split_functions,
+            chain_id: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_dispatcher() {
+        // the dispatcher of the `dispatch` tests, for `get()` and `set(uint256)`
+        let program = decode_bytecode(concat!(
+            "608060405234801560",
+            "0f57600080fd5b5060043610603257600035",
+            "60e01c80636d4ce63c14603757806360fe47b1",
+            "14603c575b600080fd5b600054005b3300"
+        ))
+        .unwrap();
+        let abi = parse_str(
+            r#"[
+                {"name":"get","type":"function","inputs":[],"stateMutability":"view"},
+                {
+                    "name":"set",
+                    "type":"function",
+                    "inputs":[{"name":"x","type":"uint256"}],
+                    "stateMutability":"nonpayable"
+                }
+            ]"#,
+        )
+        .unwrap();
+        let code = decompile(&program, Some(&abi), &config(true)).unwrap();
+
+        assert!(code.starts_with("function _evm_exec() {\n"));
+        let lines: Vec<&str> = code.lines().map(str::trim).collect();
+        let get = lines.iter().position(|&l| l == "// get()").unwrap();
+        assert!(lines[get + 1].starts_with("0x37: JUMPDEST"));
+        let set = lines.iter().position(|&l| l == "// set(uint256)").unwrap();
+        assert!(lines[set + 1].starts_with("0x3c: JUMPDEST"));
+        let push = lines.iter().find(|l| l.starts_with("0x33: ")).unwrap();
+        assert!(push.starts_with("0x33: PUSH1 0x00 "));
+        assert!(push.ends_with(" // 0 -> 1"));
+        assert!(lines
+            .iter()
+            .any(|l| l.starts_with("0x1f: PUSH4 0x6d4ce63c ")));
+        assert!(lines
+            .iter()
+            .any(|l| l.starts_with("if cond {") || l.starts_with("if !cond {")));
+    }
+
+    #[test]
+    fn test_loop_and_call() {
+        // 0x00: JUMPDEST PUSH1 0x06 PUSH1 0x0e JUMP
+        // 0x06: JUMPDEST PUSH1 0x00 SLOAD PUSH1 0x00 JUMPI STOP
+        // 0x0e: JUMPDEST PUSH1 0x01 PUSH1 0x00 SSTORE JUMP
+        let program = decode_bytecode("5b6006600e565b600054600057005b600160005556").unwrap();
+        let code = decompile(&program, None, &config(true)).unwrap();
+
+        // the callee comes first, and returns once
+        assert!(code.starts_with("function func_0xe() {\n"));
+        assert_eq!(code.matches("return").count(), 2);
+        let lines: Vec<&str> = code.lines().map(str::trim).collect();
+        assert!(lines.contains(&"call func_0xe"));
+        let head = lines.iter().position(|l| l.ends_with("loop {")).unwrap();
+        let label = lines[head].strip_suffix(": loop {").unwrap();
+        assert!(lines.contains(&format!("if cond {{ continue {} }}", label).as_str()));
+
+        // inline without function splitting
+        let code = decompile(&program, None, &config(false)).unwrap();
+        assert!(!code.contains("func_"));
+    }
+}
@@ -0,0 +1,354 @@
+// This is free and unencumbered software released into the public domain.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+};
+
+use ethnum::u256;
+use evm_rs::{Opcode, Program};
+
+use crate::{
+    analyze::{Idx, Offs},
+    fold::fold,
+    functions::jumpdests,
+    ops::{block_gas, dup_depth, stack_effect, swap_depth},
+};
+
+/// A word on top of the runtime stack that the dispatcher can reason about
+/// at compile time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Word {
+    Const(u256),
+    /// `CALLDATALOAD(0)`, whose four most significant bytes are the selector.
+    Head,
+    /// `CALLDATASIZE`, which is at least 4 for a method call.
+    Size,
+}
+
+/// An action of a direct entry, replaying the dispatcher up to its target.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Enters the replayed block at `start`, charging its static gas.
+    Block { start: Offs, gas: u32 },
+    /// Pushes a word on the runtime stack.
+    Push(u256),
+    /// Executes the operator at the given offset through its op handler.
+    Op(Opcode, Offs),
+    /// Pops the condition of a `JUMPI` the selector does not decide. If it is
+    /// not `expected`, execution continues in `_evm_exec` at `exit` instead.
+    Guard { expected: bool, exit: Offs },
+}
+
+/// The code an ABI method runs in place of the selector dispatcher.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirectEntry {
+    pub steps: Vec<Step>,
+    /// The block `_evm_exec` is entered at once all steps are done.
+    pub target: Offs,
+}
+
+impl DirectEntry {
+    /// Returns all the blocks `_evm_exec` may be entered at.
+    pub fn entry_points(&self) -> impl Iterator<Item = Offs> + '_ {
+        let exits = self.steps.iter().filter_map(|step| match step {
+            Step::Guard { exit, .. } => Some(*exit),
+            _ => None,
+        });
+        exits.chain([self.target])
+    }
+}
+
+/// Executes the dispatcher prologue of `program` for a call with the given
+/// selector, returning the steps that leave the runtime in the same state as
+/// the dispatcher would at the first block it cannot replay.
+///
+/// Only blocks made of stack shuffles, pure operators, call data accesses and
+/// memory stores are replayed, and every `JUMPI` must either be decided by
+/// the selector and the call data size or guard a reverting block. Returns
+/// `None` if the selector is never loaded, i.e. no dispatcher is recognized.
+pub fn direct_entry(
+    program: &Program,
+    code_ranges: &HashMap<Offs, Range<Idx>>,
+    selector: u32,
+) -> Option<DirectEntry> {
+    let jumpdests = jumpdests(program);
+    let mut replay = Replay {
+        selector: u256::from(selector),
+        ..Default::default()
+    };
+    let mut block = Offs(0);
+    let mut visited = HashSet::new();
+    while visited.insert(block) {
+        let range = match code_ranges.get(&block) {
+            Some(range) => range,
+            None => break,
+        };
+        let code = &program.0[range.start.0..range.end.0];
+        let reverts = |offs: Offs| match code_ranges.get(&offs) {
+            Some(range) => matches!(program.0[range.end.0 - 1], Opcode::REVERT | Opcode::INVALID),
+            None => false,
+        };
+        // blocks are replayed completely or not at all
+        let mut attempt = replay.clone();
+        match attempt.block(block, code, &jumpdests, reverts) {
+            Some(next) => {
+                replay = attempt;
+                block = next;
+            }
+            None => break,
+        }
+    }
+    // the runtime stack must be exact when entering `_evm_exec`
+    replay.flush(block);
+    replay.selected.then_some(DirectEntry {
+        steps: replay.steps,
+        target: block,
+    })
+}
+
+#[derive(Clone, Debug, Default)]
+struct Replay {
+    selector: u256,
+    /// Whether the selector was loaded from the call data.
+    selected: bool,
+    /// Words above the runtime stack, top of stack last. They are only
+    /// pushed when needed, so they carry over from block to block.
+    slots: Vec<Word>,
+    steps: Vec<Step>,
+}
+
+impl Replay {
+    /// Replays the block starting at `start`, returning the next block.
+    fn block(
+        &mut self,
+        start: Offs,
+        code: &[Opcode],
+        jumpdests: &HashSet<Offs>,
+        reverts: impl Fn(Offs) -> bool,
+    ) -> Option<Offs> {
+        use Opcode::*;
+        self.steps.push(Step::Block {
+            start,
+            gas: block_gas(code)[0],
+        });
+        let mut offs = start;
+        for op in code {
+            let next = Offs(offs.0 + op.size());
+            match op {
+                JUMP => {
+                    return self.pop_target(jumpdests);
+                }
+                JUMPI => {
+                    let target = self.pop_target(jumpdests);
+                    match self.slots.last() {
+                        Some(Word::Const(condition)) if *condition == 0 => {
+                            self.slots.pop();
+                            return Some(next);
+                        }
+                        Some(Word::Const(_)) => {
+                            self.slots.pop();
+                            return target;
+                        }
+                        _ => {
+                            let target = target?;
+                            let expected = match (reverts(target), reverts(next)) {
+                                (false, true) => true,
+                                (true, false) => false,
+                                _ => return None,
+                            };
+                            self.flush(offs);
+                            let exit = if expected { next } else { target };
+                            self.steps.push(Step::Guard { expected, exit });
+                            return Some(if expected { target } else { next });
+                        }
+                    }
+                }
+                op if replayable(op) => self.step(op, offs),
+                _ => return None,
+            }
+            offs = next;
+        }
+        // falls through into a JUMPDEST
+        Some(offs)
+    }
+
+    fn step(&mut self, op: &Opcode, offs: Offs) {
+        use Opcode::*;
+        let len = self.slots.len();
+        match op {
+            PUSH1(b) => self.slots.push(Word::Const(u256::from(*b))),
+            PUSHn(_, v, _) => self.slots.push(Word::Const(*v)),
+            JUMPDEST => {}
+            POP if len > 0 => _ = self.slots.pop(),
+            CALLDATASIZE => self.slots.push(Word::Size),
+            CALLDATALOAD if self.slots.last() == Some(&Word::Const(u256::ZERO)) => {
+                self.slots[len - 1] = Word::Head;
+            }
+            op => match (dup_depth(op), swap_depth(op)) {
+                (Some(n), _) if n <= len => self.slots.push(self.slots[len - n]),
+                (_, Some(n)) if n < len => self.slots.swap(len - 1, len - 1 - n),
+                _ => match self.fold_top(op) {
+                    Some(word) => self.slots.push(word),
+                    None => {
+                        self.flush(offs);
+                        self.steps.push(Step::Op(op.clone(), offs));
+                    }
+                },
+            },
+        }
+    }
+
+    /// Pops the operands of `op` if its result is known at compile time,
+    /// returning the result.
+    fn fold_top(&mut self, op: &Opcode) -> Option<Word> {
+        use Opcode::*;
+        let (pops, pushes) = stack_effect(op);
+        if pushes != 1 || pops == 0 || pops > self.slots.len() {
+            return None;
+        }
+        let args: Vec<Word> = self.slots.iter().rev().take(pops).copied().collect();
+        let selector_shift = u256::ONE << 224;
+        let result = match (op, args.as_slice()) {
+            (SHR, [Word::Const(shift), Word::Head]) if *shift == 224 => {
+                self.selected = true;
+                Word::Const(self.selector)
+            }
+            (DIV, [Word::Head, Word::Const(divisor)]) if *divisor == selector_shift => {
+                self.selected = true;
+                Word::Const(self.selector)
+            }
+            (LT, [Word::Size, Word::Const(n)]) | (GT, [Word::Const(n), Word::Size]) if *n <= 4 => {
+                Word::Const(u256::ZERO)
+            }
+            _ => {
+                let args: Option<Vec<u256>> = args
+                    .iter()
+                    .map(|word| match word {
+                        Word::Const(v) => Some(*v),
+                        _ => None,
+                    })
+                    .collect();
+                Word::Const(fold(op, &args?)?)
+            }
+        };
+        self.slots.truncate(self.slots.len() - pops);
+        Some(result)
+    }
+
+    /// Pops a jump target, which must be a JUMPDEST known at compile time.
+    fn pop_target(&mut self, jumpdests: &HashSet<Offs>) -> Option<Offs> {
+        match self.slots.pop()? {
+            Word::Const(target) if target <= u256::from(u32::MAX) => {
+                Some(Offs(target.as_usize())).filter(|offs| jumpdests.contains(offs))
+            }
+            _ => None,
+        }
+    }
+
+    /// Pushes all words above the runtime stack to it.
+    fn flush(&mut self, offs: Offs) {
+        for word in self.slots.drain(..) {
+            match word {
+                Word::Const(v) => self.steps.push(Step::Push(v)),
+                Word::Head => {
+                    self.steps.push(Step::Push(u256::ZERO));
+                    self.steps.push(Step::Op(Opcode::CALLDATALOAD, offs));
+                }
+                Word::Size => self.steps.push(Step::Op(Opcode::CALLDATASIZE, offs)),
+            }
+        }
+    }
+}
+
+/// Returns whether `op` may be part of a dispatcher prologue.
+fn replayable(op: &Opcode) -> bool {
+    use Opcode::*;
+    op.is_push()
+        || dup_depth(op).is_some()
+        || swap_depth(op).is_some()
+        || matches!(
+            op,
+            JUMPDEST
+                | POP
+                | CALLVALUE
+                | CALLDATALOAD
+                | CALLDATASIZE
+                | MSTORE
+                | ADD
+                | SUB
+                | DIV
+                | LT
+                | GT
+                | EQ
+                | ISZERO
+                | AND
+                | OR
+                | SHR
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analyze::basic_cfg, decode::decode_bytecode};
+
+    // 0x00: PUSH1 0x80 PUSH1 0x40 MSTORE CALLVALUE DUP1 ISZERO PUSH1 0x0f JUMPI
+    // 0x0b: PUSH1 0x00 DUP1 REVERT
+    // 0x0f: JUMPDEST POP PUSH1 0x04 CALLDATASIZE LT PUSH1 0x32 JUMPI
+    // 0x18: PUSH1 0x00 CALLDATALOAD PUSH1 0xe0 SHR
+    //       DUP1 PUSH4 0xaabbccdd EQ PUSH1 0x37 JUMPI
+    // 0x28: DUP1 PUSH4 0x11223344 EQ PUSH1 0x3c JUMPI
+    // 0x32: JUMPDEST PUSH1 0x00 DUP1 REVERT
+    // 0x37: JUMPDEST PUSH1 0x00 SLOAD STOP
+    // 0x3c: JUMPDEST CALLER STOP
+    const DISPATCHER: &str = concat!(
+        "608060405234801560",
+        "0f57600080fd5b5060043610603257600035",
+        "60e01c8063aabbccdd14603757806311223344",
+        "14603c575b600080fd5b600054005b3300"
+    );
+
+    #[test]
+    fn test_direct_entry() {
+        let program = decode_bytecode(DISPATCHER).unwrap();
+        let code_ranges = basic_cfg(&program, true).code_ranges;
+
+        let entry = direct_entry(&program, &code_ranges, 0xaabbccdd).unwrap();
+        assert_eq!(entry.target, Offs(0x37));
+        let guards: Vec<_> = entry
+            .steps
+            .iter()
+            .filter(|step| matches!(step, Step::Guard { .. }))
+            .collect();
+        assert_eq!(
+            guards,
+            vec![&Step::Guard {
+                expected: true,
+                exit: Offs(0x0b)
+            }]
+        );
+        // the selector is left on the stack
+        assert_eq!(
+            entry.steps.last(),
+            Some(&Step::Push(u256::from(0xaabbccddu32)))
+        );
+        let entry_points: Vec<_> = entry.entry_points().collect();
+        assert_eq!(entry_points, vec![Offs(0x0b), Offs(0x37)]);
+
+        let entry = direct_entry(&program, &code_ranges, 0x11223344).unwrap();
+        assert_eq!(entry.target, Offs(0x3c));
+
+        // unknown selectors end up in the fallback
+        let entry = direct_entry(&program, &code_ranges, 0xdeadbeef).unwrap();
+        assert_eq!(entry.target, Offs(0x32));
+    }
+
+    #[test]
+    fn test_no_dispatcher() {
+        // CALLER PUSH1 0x00 SSTORE STOP
+        let program = decode_bytecode("3360005500").unwrap();
+        let code_ranges = basic_cfg(&program, true).code_ranges;
+        assert_eq!(direct_entry(&program, &code_ranges, 0xaabbccdd), None);
+    }
+}
@@ -0,0 +1,212 @@
+// This is free and unencumbered software released into the public domain.
+
+use ethnum::u256;
+use evm_rs::Opcode;
+
+use crate::ops::{dup_depth, stack_effect, swap_depth};
+
+/// Evaluates a pure operator over constant operands, topmost operand first.
+///
+/// Only operators whose evmlib handlers can be reproduced exactly are folded;
+/// `None` means the operator must be executed at run time.
+pub fn fold(op: &Opcode, args: &[u256]) -> Option<u256> {
+    use Opcode::*;
+    let bool_word = |b: bool| if b { u256::ONE } else { u256::ZERO };
+    Some(match (op, args) {
+        (ADD, &[a, b]) => a.wrapping_add(b),
+        (MUL, &[a, b]) => a.wrapping_mul(b),
+        (SUB, &[a, b]) => a.wrapping_sub(b),
+        (DIV, &[a, b]) => {
+            if b == u256::ZERO {
+                u256::ZERO
+            } else {
+                a / b
+            }
+        }
+        (MOD, &[a, b]) => {
+            if b == u256::ZERO {
+                u256::ZERO
+            } else {
+                a % b
+            }
+        }
+        (SIGNEXTEND, &[op1, op2]) => {
+            if op1 < u256::new(32) {
+                let bit_index = 8 * op1.as_u32() + 7;
+                let bit = op2 & (u256::ONE << bit_index) != u256::ZERO;
+                let mask = (u256::ONE << bit_index) - u256::ONE;
+                if bit {
+                    op2 | !mask
+                } else {
+                    op2 & mask
+                }
+            } else {
+                op2
+            }
+        }
+        (LT, &[a, b]) => bool_word(a < b),
+        (GT, &[a, b]) => bool_word(a > b),
+        (SLT, &[a, b]) => bool_word(a.as_i256() < b.as_i256()),
+        (SGT, &[a, b]) => bool_word(a.as_i256() > b.as_i256()),
+        (EQ, &[a, b]) => bool_word(a == b),
+        (ISZERO, &[x]) => bool_word(x == u256::ZERO),
+        (AND, &[a, b]) => a & b,
+        (OR, &[a, b]) => a | b,
+        (XOR, &[a, b]) => a ^ b,
+        (NOT, &[x]) => !x,
+        (BYTE, &[index, word]) => {
+            if index > 31 {
+                u256::ZERO
+            } else {
+                u256::from(word.to_be_bytes()[index.as_usize()])
+            }
+        }
+        (SHL, &[shift, value]) => {
+            if shift > 255 {
+                u256::ZERO
+            } else {
+                value << shift.as_u32()
+            }
+        }
+        (SHR, &[shift, value]) => {
+            if shift > 255 {
+                u256::ZERO
+            } else {
+                value >> shift.as_u32()
+            }
+        }
+        _ => return None,
+    })
+}
+
+/// Simplifies a pure operator with some constant operands, returning the
+/// position (topmost first) of the operand that is the result as is.
+pub fn identity_operand(op: &Opcode, args: &[Option<u256>]) -> Option<usize> {
+    use Opcode::*;
+    match (op, args) {
+        (AND, [Some(mask), _]) if *mask == u256::MAX => Some(1),
+        (AND, [_, Some(mask)]) if *mask == u256::MAX => Some(0),
+        (OR | XOR | ADD, [Some(zero), _]) if *zero == u256::ZERO => Some(1),
+        (OR | XOR | ADD, [_, Some(zero)]) if *zero == u256::ZERO => Some(0),
+        (SUB, [_, Some(zero)]) if *zero == u256::ZERO => Some(0),
+        (SHL | SHR, [Some(zero), _]) if *zero == u256::ZERO => Some(1),
+        _ => None,
+    }
+}
+
+/// A symbolic model of the EVM stack within a basic block, where every word
+/// is either a known constant or unknown.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymbolicStack {
+    /// Words above the block's entry height, top of stack last.
+    slots: Vec<Option<u256>>,
+}
+
+impl SymbolicStack {
+    /// Evaluates `ops` starting from an unknown stack.
+    pub fn eval(ops: &[Opcode]) -> Self {
+        let mut stack = Self::default();
+        for op in ops {
+            stack.step(op);
+        }
+        stack
+    }
+
+    /// Returns the `n`-th word from the top, if known.
+    pub fn peek(&self, n: usize) -> Option<u256> {
+        self.slots.iter().rev().nth(n).copied().flatten()
+    }
+
+    pub fn step(&mut self, op: &Opcode) {
+        use Opcode::*;
+        let (pops, pushes) = stack_effect(op);
+        self.reserve(pops);
+        let top = self.slots.len() - 1;
+        match op {
+            PUSH1(b) => self.slots.push(Some(u256::from(*b))),
+            PUSHn(_, v, _) => self.slots.push(Some(*v)),
+            op if dup_depth(op).is_some() => {
+                self.slots.push(self.slots[top + 1 - pops]);
+            }
+            op if swap_depth(op).is_some() => {
+                self.slots.swap(top, top + 1 - pops);
+            }
+            op => {
+                let args: Vec<_> = self.slots.drain(top + 1 - pops..).rev().collect();
+                let result = if args.iter().all(Option::is_some) {
+                    let args: Vec<_> = args.into_iter().flatten().collect();
+                    fold(op, &args)
+                } else {
+                    None
+                };
+                match (pushes, result) {
+                    (1, result) => self.slots.push(result),
+                    (n, _) => self.slots.extend((0..n).map(|_| None)),
+                }
+            }
+        }
+    }
+
+    /// Makes sure at least `n` words are modelled, padding the bottom with
+    /// unknown words that were on the stack on block entry.
+    fn reserve(&mut self, n: usize) {
+        if self.slots.len() < n.max(1) {
+            let missing = n.max(1) - self.slots.len();
+            self.slots.splice(0..0, (0..missing).map(|_| None));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::decode_bytecode;
+
+    #[test]
+    fn test_fold_arith() {
+        let w = |x: u64| u256::from(x);
+        assert_eq!(fold(&Opcode::ADD, &[w(0x20), w(0x40)]), Some(w(0x60)));
+        assert_eq!(fold(&Opcode::SUB, &[w(1), w(2)]), Some(u256::MAX));
+        assert_eq!(fold(&Opcode::DIV, &[w(1), w(0)]), Some(w(0)));
+        assert_eq!(fold(&Opcode::ISZERO, &[w(0)]), Some(w(1)));
+        assert_eq!(fold(&Opcode::SHL, &[w(224), w(1)]), Some(u256::ONE << 224));
+        assert_eq!(fold(&Opcode::SHR, &[w(256), w(1)]), Some(w(0)));
+        assert_eq!(fold(&Opcode::SIGNEXTEND, &[w(0), w(0xff)]), Some(u256::MAX));
+        assert_eq!(fold(&Opcode::SLT, &[u256::MAX, w(0)]), Some(w(1)));
+        assert_eq!(fold(&Opcode::SSTORE, &[w(0), w(0)]), None);
+    }
+
+    #[test]
+    fn test_identity_operand() {
+        let mask = Some(u256::MAX);
+        assert_eq!(identity_operand(&Opcode::AND, &[mask, None]), Some(1));
+        assert_eq!(identity_operand(&Opcode::AND, &[None, mask]), Some(0));
+        assert_eq!(
+            identity_operand(&Opcode::SUB, &[Some(u256::ZERO), None]),
+            None
+        );
+        assert_eq!(
+            identity_operand(&Opcode::SUB, &[None, Some(u256::ZERO)]),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_symbolic_eval() {
+        // PUSH1 0x20 PUSH1 0x40 ADD PUSH1 0x00 ISZERO CALLVALUE SWAP1
+        let program = decode_bytecode("60206040016000153490").unwrap();
+        let stack = SymbolicStack::eval(&program.0);
+        assert_eq!(stack.peek(0), Some(u256::ONE));
+        assert_eq!(stack.peek(1), None);
+        assert_eq!(stack.peek(2), Some(u256::from(0x60u8)));
+        assert_eq!(stack.peek(3), None); // below block entry
+    }
+
+    #[test]
+    fn test_symbolic_eval_below_entry() {
+        // SWAP1 POP DUP2
+        let program = decode_bytecode("905081").unwrap();
+        let stack = SymbolicStack::eval(&program.0);
+        assert_eq!(stack.peek(0), None);
+    }
+}
@@ -12,6 +12,9 @@ pub enum OutputFormat {
     Auto,
     Wasm,
     Wat,
+    /// Structured pseudo-code of the EVM program, see `decompile`
+    #[clap(alias = "decompile")]
+    Pseudo,
 }
 
 #[derive(clap::ValueEnum, Clone, Debug)]
@@ -20,6 +23,15 @@ pub enum OutputABI {
     Wasi,
 }
 
+/// How the runtime library is linked into the output module.
+#[derive(clap::ValueEnum, Clone, Debug)]
+pub enum Linking {
+    /// Include a copy of the runtime library
+    Static,
+    /// Import the runtime library from a shared runtime
+    Shared,
+}
+
 #[allow(dead_code)]
 pub fn parse_input_format(format: &str) -> Option<InputFormat> {
     use InputFormat::*;
@@ -49,6 +61,7 @@ pub fn parse_output_format(format: &str) -> Option<OutputFormat> {
         "auto" => Auto,
         "wasm" => Wasm,
         "wat" => Wat,
+        "pseudo" | "decompile" => Pseudo,
         _ => return None,
     };
     Some(result)
@@ -0,0 +1,535 @@
+// This is free and unencumbered software released into the public domain.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use ethnum::u256;
+use evm_rs::{Opcode, Program};
+use relooper::graph::cfg::{Cfg, CfgEdge};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    analyze::{BasicCfg, CfgNode, Offs},
+    fold::fold,
+    ops::{dup_depth, stack_effect, swap_depth},
+};
+
+/// How control leaves a basic block, besides following its CFG edge.
+#[derive(
+    Clone, Copy, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub enum BlockExit {
+    #[default]
+    Jump,
+    /// The block calls the function entered at the given JUMPDEST, which
+    /// returns to the block's successor.
+    Call(Offs),
+    /// The block returns from the function it is part of.
+    Return,
+}
+
+/// A part of the program compiled into a wasm function of its own.
+#[derive(Debug)]
+pub struct Region {
+    /// The JUMPDEST the function is entered at, `Offs(0)` for the main region.
+    pub entry: Offs,
+    pub cfg: Cfg<CfgNode<Offs>>,
+    /// Replaces `BasicCfg::exit_drops` for the blocks of this region.
+    pub exit_drops: HashMap<Offs, usize>,
+    pub exits: HashMap<Offs, BlockExit>,
+    callees: BTreeSet<Offs>,
+}
+
+/// The calling convention of a recovered function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Summary {
+    /// Depth of the return address below the stack top on entry.
+    return_depth: usize,
+    /// Number of entry words the function consumes, the return address included.
+    pops: usize,
+    /// Number of words the function leaves on top of the remaining ones.
+    pushes: usize,
+}
+
+#[derive(Debug)]
+enum Failure {
+    /// The region calls a function that has not been analyzed yet.
+    Pending,
+    /// The region does not follow the calling convention.
+    Invalid,
+}
+
+/// A stack word, relative to the entry of the function being analyzed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Sym {
+    /// The word that was `n` positions below the top on entry.
+    Entry(usize),
+    Const(u256),
+    Unknown,
+}
+
+/// The stack of a function activation, bottom first. The words below `slots`
+/// are the untouched entry words starting at `Entry(below)`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Frame {
+    slots: Vec<Sym>,
+    below: usize,
+    /// Paths with different stack heights meet, so no word is known.
+    lost: bool,
+}
+
+impl Frame {
+    fn peek(&mut self, n: usize) -> Sym {
+        if self.lost {
+            return Sym::Unknown;
+        }
+        self.reserve(n + 1);
+        self.slots[self.slots.len() - 1 - n]
+    }
+
+    fn step(&mut self, op: &Opcode) {
+        use Opcode::*;
+        if self.lost {
+            return;
+        }
+        let (pops, pushes) = stack_effect(op);
+        self.reserve(pops);
+        let len = self.slots.len();
+        match op {
+            PUSH1(b) => self.slots.push(Sym::Const(u256::from(*b))),
+            PUSHn(_, v, _) => self.slots.push(Sym::Const(*v)),
+            op if dup_depth(op).is_some() => self.slots.push(self.slots[len - pops]),
+            op if swap_depth(op).is_some() => self.slots.swap(len - 1, len - pops),
+            op => {
+                let args: Option<Vec<u256>> = self
+                    .slots
+                    .drain(len - pops..)
+                    .rev()
+                    .map(|sym| match sym {
+                        Sym::Const(v) => Some(v),
+                        _ => None,
+                    })
+                    .collect();
+                match (pushes, args.and_then(|args| fold(op, &args))) {
+                    (1, Some(v)) => self.slots.push(Sym::Const(v)),
+                    (n, _) => self.slots.extend((0..n).map(|_| Sym::Unknown)),
+                }
+            }
+        }
+    }
+
+    /// Applies the stack effect of a call to a function.
+    fn call(&mut self, summary: &Summary) {
+        if self.lost {
+            return;
+        }
+        self.reserve(summary.pops);
+        self.slots.truncate(self.slots.len() - summary.pops);
+        self.slots.extend((0..summary.pushes).map(|_| Sym::Unknown));
+    }
+
+    /// Merges the frame of another path into this one, returning whether
+    /// this frame changed.
+    fn join(&mut self, other: &Self) -> bool {
+        let before = self.clone();
+        let mut other = other.clone();
+        let below = self.below.max(other.below);
+        self.reserve(self.slots.len() + below - self.below);
+        other.reserve(other.slots.len() + below - other.below);
+        if self.lost || other.lost || self.slots.len() != other.slots.len() {
+            *self = Self {
+                lost: true,
+                ..Self::default()
+            };
+        }
+        for (ours, theirs) in self.slots.iter_mut().zip(other.slots) {
+            if *ours != theirs {
+                *ours = Sym::Unknown;
+            }
+        }
+        *self != before
+    }
+
+    /// Returns the calling convention of a function returning with this frame.
+    fn summary(&self, return_depth: usize) -> Summary {
+        let untouched = self
+            .slots
+            .iter()
+            .zip((0..self.below).rev())
+            .take_while(|(sym, n)| **sym == Sym::Entry(*n))
+            .count();
+        Summary {
+            return_depth,
+            pops: self.below - untouched,
+            pushes: self.slots.len() - untouched,
+        }
+    }
+
+    /// Makes sure at least `n` words are modelled.
+    fn reserve(&mut self, n: usize) {
+        if self.slots.len() < n {
+            let missing = n - self.slots.len();
+            let below = self.below;
+            let words = (0..missing).rev().map(|i| Sym::Entry(below + i));
+            self.slots.splice(0..0, words);
+            self.below += missing;
+        }
+    }
+}
+
+/// Splits `program` into its Solidity internal functions and the code that
+/// calls them, returning the regions to compile with callees first and the
+/// main region last.
+///
+/// A function is entered with a static jump and returns by jumping to the
+/// address its caller pushed before the arguments. Every region is checked
+/// to follow this convention on all paths, so that a wasm call can stand in
+/// for the jumps; code that does not is left inline in its callers. Returns
+/// `None` if the main region has jumps that cannot be resolved.
+pub fn split_functions(
+    program: &Program,
+    basic_cfg: &BasicCfg,
+    fold_conditions: bool,
+) -> Option<Vec<Region>> {
+    let mut analysis = Analysis {
+        program,
+        basic_cfg,
+        fold_conditions,
+        jumpdests: jumpdests(program),
+        candidates: BTreeSet::new(),
+        functions: BTreeMap::new(),
+    };
+    analysis.candidates = basic_cfg
+        .code_ranges
+        .values()
+        .map(|range| &program.0[range.start.0..range.end.0])
+        .filter(|code| code.last() == Some(&Opcode::JUMP))
+        .filter_map(static_jump)
+        .filter(|offs| offs.0 != 0 && analysis.jumpdests.contains(offs))
+        .collect();
+
+    loop {
+        let mut progress = false;
+        let mut pending = vec![];
+        for entry in analysis.candidates.clone() {
+            if analysis.functions.contains_key(&entry) {
+                continue;
+            }
+            match analysis.region(entry) {
+                Ok((region, Some(summary))) => {
+                    analysis.functions.insert(entry, (region, summary));
+                    progress = true;
+                }
+                Ok((_, None)) | Err(Failure::Invalid) => {
+                    analysis.reject(entry);
+                    progress = true;
+                }
+                Err(Failure::Pending) => pending.push(entry),
+            }
+        }
+        if !progress {
+            if pending.is_empty() {
+                break;
+            }
+            // functions calling each other recursively
+            pending.into_iter().for_each(|entry| analysis.reject(entry));
+        }
+    }
+
+    let (main, _) = analysis.region(Offs(0)).ok()?;
+    let mut regions = vec![];
+    let mut visited = HashSet::new();
+    analysis.collect(main, &mut visited, &mut regions);
+    Some(regions)
+}
+
+struct Analysis<'a> {
+    program: &'a Program,
+    basic_cfg: &'a BasicCfg,
+    fold_conditions: bool,
+    jumpdests: HashSet<Offs>,
+    /// Entries of blocks that may be functions.
+    candidates: BTreeSet<Offs>,
+    /// Regions of the candidates found to be functions.
+    functions: BTreeMap<Offs, (Region, Summary)>,
+}
+
+impl<'a> Analysis<'a> {
+    /// Stops treating `entry` as a function.
+    fn reject(&mut self, entry: Offs) {
+        self.candidates.remove(&entry);
+        self.invalidate(entry);
+    }
+
+    /// Forgets the analysis of `entry` and of the functions relying on it.
+    fn invalidate(&mut self, entry: Offs) {
+        self.functions.remove(&entry);
+        let callers: Vec<_> = self
+            .functions
+            .iter()
+            .filter(|(_, (region, _))| region.callees.contains(&entry))
+            .map(|(caller, _)| *caller)
+            .collect();
+        callers
+            .into_iter()
+            .for_each(|caller| self.invalidate(caller));
+    }
+
+    /// Appends the regions reachable from `region` through calls, callees first.
+    fn collect(&mut self, region: Region, visited: &mut HashSet<Offs>, out: &mut Vec<Region>) {
+        visited.insert(region.entry);
+        for callee in &region.callees {
+            if !visited.contains(callee) {
+                let (callee, _) = self.functions.remove(callee).unwrap();
+                self.collect(callee, visited, out);
+            }
+        }
+        out.push(region);
+    }
+
+    /// Analyzes the code reachable from `entry` up to the calls of known
+    /// functions, returning its summary if it is a function itself.
+    fn region(&self, entry: Offs) -> Result<(Region, Option<Summary>), Failure> {
+        let is_function = entry.0 != 0;
+        let mut frames = HashMap::from([(entry, Frame::default())]);
+        let mut worklist = BTreeSet::from([entry]);
+        let mut edges = HashMap::new();
+        let mut exit_drops = HashMap::new();
+        let mut exits = HashMap::new();
+        let mut returns = HashMap::new();
+
+        while let Some(block) = worklist.pop_first() {
+            let range = match self.basic_cfg.code_ranges.get(&block) {
+                Some(range) => range,
+                None => continue,
+            };
+            let code = &self.program.0[range.start.0..range.end.0];
+            let mut frame = frames[&block].clone();
+            exits.remove(&block);
+            returns.remove(&block);
+            let basic_edge = self
+                .basic_cfg
+                .cfg
+                .edges()
+                .get(&CfgNode::Orig(block))
+                .cloned()
+                .unwrap_or(CfgEdge::Terminal);
+            let mut drops = self.basic_cfg.exit_drops.get(&block).copied();
+
+            let edge = match code.split_last() {
+                Some((jump, body)) if jump.is_jump() && static_jump(code).is_none() => {
+                    body.iter().for_each(|op| frame.step(op));
+                    let target = frame.peek(0);
+                    let condition = frame.peek(1);
+                    frame.step(jump);
+                    match (jump, target) {
+                        (Opcode::JUMP, Sym::Entry(depth)) if is_function => {
+                            exits.insert(block, BlockExit::Return);
+                            returns.insert(block, frame.summary(depth));
+                            drops = Some(1);
+                            CfgEdge::Terminal
+                        }
+                        (_, Sym::Const(t)) if self.is_jumpdest(t) => {
+                            let label = CfgNode::Orig(Offs(t.as_usize()));
+                            drops = Some(1);
+                            match (basic_edge, condition) {
+                                (CfgEdge::Cond(_, next), Sym::Const(c)) if self.fold_conditions => {
+                                    drops = Some(2);
+                                    CfgEdge::Uncond(if c != 0 { label } else { next })
+                                }
+                                (CfgEdge::Cond(_, next), _) => CfgEdge::Cond(label, next),
+                                _ => CfgEdge::Uncond(label),
+                            }
+                        }
+                        _ if basic_edge.iter().any(|n| n == &CfgNode::Dynamic) => {
+                            return Err(Failure::Invalid);
+                        }
+                        _ => basic_edge,
+                    }
+                }
+                _ => match self.call(entry, code, &mut frame)? {
+                    Some((callee, continuation)) => {
+                        exits.insert(block, BlockExit::Call(callee));
+                        CfgEdge::Uncond(CfgNode::Orig(continuation))
+                    }
+                    None => {
+                        code.iter().for_each(|op| frame.step(op));
+                        basic_edge
+                    }
+                },
+            };
+
+            match drops {
+                Some(drops) => exit_drops.insert(block, drops),
+                None => exit_drops.remove(&block),
+            };
+            for successor in edge.iter() {
+                let successor = match successor {
+                    CfgNode::Orig(offs) => *offs,
+                    CfgNode::Dynamic | CfgNode::Entry | CfgNode::Dispatch | CfgNode::Route(_) => {
+                        unreachable!()
+                    }
+                };
+                let changed = match frames.get_mut(&successor) {
+                    Some(successor_frame) => successor_frame.join(&frame),
+                    None => {
+                        frames.insert(successor, frame.clone());
+                        true
+                    }
+                };
+                if changed {
+                    worklist.insert(successor);
+                }
+            }
+            edges.insert(CfgNode::Orig(block), edge);
+        }
+
+        let mut summaries = returns.values().copied();
+        let summary = summaries.next();
+        if summaries.any(|other| Some(other) != summary) || is_function != summary.is_some() {
+            return Err(Failure::Invalid);
+        }
+        let callees = exits
+            .values()
+            .filter_map(|exit| match exit {
+                BlockExit::Call(callee) => Some(*callee),
+                _ => None,
+            })
+            .collect();
+        let region = Region {
+            entry,
+            cfg: Cfg::from_edges(CfgNode::Orig(entry), edges),
+            exit_drops,
+            exits,
+            callees,
+        };
+        Ok((region, summary))
+    }
+
+    /// Checks whether a block ending in a static jump calls a function. If it
+    /// does, applies the call to `frame` and returns the callee and the
+    /// address it returns to.
+    fn call(
+        &self,
+        caller: Offs,
+        code: &[Opcode],
+        frame: &mut Frame,
+    ) -> Result<Option<(Offs, Offs)>, Failure> {
+        let callee = match (code.last(), static_jump(code)) {
+            (Some(Opcode::JUMP), Some(callee)) if self.candidates.contains(&callee) => callee,
+            _ => return Ok(None),
+        };
+        if callee == caller {
+            return Err(Failure::Invalid); // recursion
+        }
+        let summary = match self.functions.get(&callee) {
+            Some((_, summary)) => summary,
+            None => return Err(Failure::Pending),
+        };
+        let mut call_frame = frame.clone();
+        code[..code.len() - 2]
+            .iter()
+            .for_each(|op| call_frame.step(op));
+        match call_frame.peek(summary.return_depth) {
+            Sym::Const(r) if self.is_jumpdest(r) => {
+                call_frame.call(summary);
+                *frame = call_frame;
+                Ok(Some((callee, Offs(r.as_usize()))))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn is_jumpdest(&self, value: u256) -> bool {
+        value <= u256::from(u32::MAX) && self.jumpdests.contains(&Offs(value.as_usize()))
+    }
+}
+
+/// Returns the target of a block ending in a push and a jump.
+fn static_jump(code: &[Opcode]) -> Option<Offs> {
+    match code {
+        [.., Opcode::PUSH1(addr), jump] if jump.is_jump() => Some(Offs(usize::from(*addr))),
+        [.., Opcode::PUSHn(_, addr, _), jump]
+            if jump.is_jump() && *addr <= u256::from(u32::MAX) =>
+        {
+            Some(Offs(addr.as_usize()))
+        }
+        _ => None,
+    }
+}
+
+pub fn jumpdests(program: &Program) -> HashSet<Offs> {
+    let mut offs = 0;
+    let mut result = HashSet::new();
+    for op in &program.0 {
+        if op == &Opcode::JUMPDEST {
+            result.insert(Offs(offs));
+        }
+        offs += op.size();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analyze::basic_cfg, decode::decode_bytecode};
+
+    #[test]
+    fn test_frame_summary() {
+        // a function with two arguments returning one word
+        let mut frame = Frame::default();
+        for op in [Opcode::ADD, Opcode::SWAP1] {
+            frame.step(&op);
+        }
+        assert_eq!(frame.peek(0), Sym::Entry(2));
+        frame.step(&Opcode::JUMP);
+        assert_eq!(
+            frame.summary(2),
+            Summary {
+                return_depth: 2,
+                pops: 3,
+                pushes: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_split_functions() {
+        // 0x00: PUSH1 0x0c PUSH1 0x01 PUSH1 0x02 PUSH1 0x11 JUMP
+        // 0x09: INVALID INVALID INVALID
+        // 0x0c: JUMPDEST PUSH1 0x00 SSTORE STOP
+        // 0x11: JUMPDEST ADD SWAP1 JUMP
+        let program = decode_bytecode("600c60016002601156fefefe5b600055005b019056").unwrap();
+        let regions = split_functions(&program, &basic_cfg(&program, true), true).unwrap();
+        assert_eq!(regions.len(), 2);
+
+        let function = &regions[0];
+        assert_eq!(function.entry, Offs(0x11));
+        assert_eq!(function.exits.get(&Offs(0x11)), Some(&BlockExit::Return));
+        assert_eq!(function.exit_drops.get(&Offs(0x11)), Some(&1));
+
+        let main = &regions[1];
+        assert_eq!(main.exits.get(&Offs(0)), Some(&BlockExit::Call(Offs(0x11))));
+        assert_eq!(
+            main.cfg.edge(&CfgNode::Orig(Offs(0))),
+            &CfgEdge::Uncond(CfgNode::Orig(Offs(0x0c)))
+        );
+    }
+
+    #[test]
+    fn test_unbalanced_function() {
+        // 0x00: PUSH1 0x06 PUSH1 0x08 JUMP
+        // 0x05: INVALID
+        // 0x06: JUMPDEST STOP
+        // 0x08: JUMPDEST CALLVALUE PUSH1 0x10 JUMPI
+        // 0x0d: CALLER POP JUMP
+        // 0x10: JUMPDEST PUSH1 0x01 SWAP1 JUMP
+        let program = decode_bytecode("6006600856fe5b005b346010573350565b60019056").unwrap();
+        let regions = split_functions(&program, &basic_cfg(&program, true), true).unwrap();
+        assert_eq!(regions.len(), 1);
+        assert!(regions[0]
+            .exits
+            .values()
+            .all(|exit| exit == &BlockExit::Jump));
+    }
+}
@@ -0,0 +1,419 @@
+// This is free and unencumbered software released into the public domain.
+
+use evm_rs::Opcode;
+use wasm_encoder::{BlockType, Instruction, MemArg};
+
+use crate::{
+    ops::stack_effect,
+    stackify::{limbs, Value, SCRATCH_LOCAL},
+};
+
+/// The layout of the runtime stack, which is an ABI between the compiler and
+/// evmlib, see `Stack` there. Slots are words of four little-endian `i64`
+/// limbs, bottom of the stack first, followed by the depth as an `i32`.
+const SLOT_SIZE: u64 = 32;
+/// `MAX_STACK_DEPTH` in evmlib, which `_evm_start` checks against
+/// `_evm_stack_slots` when ops are inlined.
+pub const STACK_SLOTS: u32 = 32;
+const DEPTH_OFFSET: u64 = STACK_SLOTS as u64 * SLOT_SIZE;
+
+/// The runtime globals and functions inlined ops use.
+#[derive(Clone, Copy, Debug)]
+pub struct InlineRuntime {
+    pub stack: u32,          // global set to _evm_stack()
+    pub call_value: u32,     // global set to _evm_call_value()
+    pub set_pc: Option<u32>, // _evm_set_pc, unless the pc is disabled
+    pub fault: u32,          // _evm_fault
+}
+
+/// Compiles `op` at offset `pc` into wasm code working on the runtime stack
+/// directly, for the ops simple enough not to need their op handler.
+///
+/// The code checks for stack underflow and overflow like the op handlers,
+/// faulting through `_evm_fault` at `pc`, but charges no gas: the runtime
+/// must be built with `block-gas`, so that static gas is charged per block.
+pub fn inline_op(
+    op: &Opcode,
+    pc: usize,
+    runtime: InlineRuntime,
+) -> Option<Vec<Instruction<'static>>> {
+    use Instruction::*;
+    use Opcode::*;
+    let mut body = vec![];
+    match op {
+        JUMPDEST => return Some(vec![]),
+        POP => {}
+        PC => {
+            for limb in 0..4 {
+                let value = if limb == 0 { pc as i64 } else { 0 };
+                body.push(LocalGet(SCRATCH_LOCAL));
+                body.push(I64Const(value));
+                body.push(I64Store(limb_memarg(0, limb)));
+            }
+        }
+        op if word_op(op, &Words::Stack, runtime, &mut body) => {}
+        _ => return None,
+    }
+
+    let (pops, pushes) = stack_effect(op);
+    let (pops, pushes): (u32, u32) = (pops.try_into().unwrap(), pushes.try_into().unwrap());
+    let depth = || [GlobalGet(runtime.stack), I32Load(depth_memarg())];
+    let mut res = vec![];
+    let trap_if = |res: &mut Vec<Instruction>, check: Instruction<'static>, limit: u32| {
+        res.extend(depth());
+        res.push(I32Const(limit.try_into().unwrap()));
+        res.push(check);
+        res.push(If(BlockType::Empty));
+        if let Some(set_pc) = runtime.set_pc {
+            res.push(I32Const(pc.try_into().unwrap()));
+            res.push(Call(set_pc));
+        }
+        res.push(Call(runtime.fault));
+        res.push(Unreachable);
+        res.push(End);
+    };
+    if pops > 0 {
+        trap_if(&mut res, I32LtU, pops); // stack underflow
+    }
+    if pushes > pops {
+        trap_if(&mut res, I32GtU, STACK_SLOTS - (pushes - pops)); // stack overflow
+    }
+    if !body.is_empty() {
+        // the scratch local points at the deepest operand or result
+        res.push(GlobalGet(runtime.stack));
+        res.extend(depth());
+        res.push(I32Const(pops.try_into().unwrap()));
+        res.push(I32Sub);
+        res.push(I32Const(SLOT_SIZE.trailing_zeros().try_into().unwrap()));
+        res.push(I32Shl);
+        res.push(I32Add);
+        res.push(LocalSet(SCRATCH_LOCAL));
+    }
+    if pushes != pops {
+        res.push(GlobalGet(runtime.stack));
+        res.extend(depth());
+        res.push(I32Const(pushes as i32 - pops as i32));
+        res.push(I32Add);
+        res.push(I32Store(depth_memarg()));
+    }
+    res.extend(body);
+    Some(res)
+}
+
+/// Compiles `op` over words stackify tracks, deepest operand first, into the
+/// four `i64` locals from `result`, for the ops `inline_op` compiles over the
+/// runtime stack other than `PC`, whose result stackify knows.
+pub fn inline_word_op(
+    op: &Opcode,
+    args: &[Value],
+    result: u32,
+    runtime: InlineRuntime,
+) -> Option<Vec<Instruction<'static>>> {
+    let mut res = vec![];
+    word_op(op, &Words::Tracked(args, result), runtime, &mut res).then_some(res)
+}
+
+/// Where the operands and the result of an inlined op are.
+enum Words<'a> {
+    /// On the runtime stack, the deepest operand and the result at the
+    /// address in the scratch local.
+    Stack,
+    /// Tracked by stackify, deepest operand first, and the first of the
+    /// locals of the result.
+    Tracked(&'a [Value], u32),
+}
+
+impl Words<'_> {
+    /// Emits a limb of the operand `slot` words above the deepest one.
+    fn load(&self, slot: u64, limb: u64) -> Vec<Instruction<'static>> {
+        use Instruction::*;
+        match self {
+            Words::Stack => vec![LocalGet(SCRATCH_LOCAL), I64Load(limb_memarg(slot, limb))],
+            Words::Tracked(args, _) => match args[usize::try_from(slot).unwrap()] {
+                Value::Const(v) => vec![I64Const(limbs(v)[usize::try_from(limb).unwrap()])],
+                Value::Local(local) => vec![LocalGet(local + u32::try_from(limb).unwrap())],
+            },
+        }
+    }
+
+    /// Emits the store of a limb of the result, computed by `value`.
+    fn store(
+        &self,
+        res: &mut Vec<Instruction<'static>>,
+        limb: u64,
+        value: impl FnOnce(&mut Vec<Instruction<'static>>),
+    ) {
+        use Instruction::*;
+        match self {
+            Words::Stack => {
+                res.push(LocalGet(SCRATCH_LOCAL));
+                value(res);
+                res.push(I64Store(limb_memarg(0, limb)));
+            }
+            Words::Tracked(_, result) => {
+                value(res);
+                res.push(LocalSet(result + u32::try_from(limb).unwrap()));
+            }
+        }
+    }
+}
+
+/// Emits `op` over `words`, returning `false` if it is not one of the ops
+/// compiled to wasm code over either.
+fn word_op(
+    op: &Opcode,
+    words: &Words,
+    runtime: InlineRuntime,
+    res: &mut Vec<Instruction<'static>>,
+) -> bool {
+    use Instruction::*;
+    use Opcode::*;
+    match op {
+        ISZERO => store_bool(words, res, |res| {
+            res.extend(words.load(0, 0));
+            for limb in 1..4 {
+                res.extend(words.load(0, limb));
+                res.push(I64Or);
+            }
+            res.push(I64Eqz);
+        }),
+        EQ => store_bool(words, res, |res| {
+            for limb in 0..4 {
+                res.extend(words.load(1, limb));
+                res.extend(words.load(0, limb));
+                res.push(I64Xor);
+                if limb > 0 {
+                    res.push(I64Or);
+                }
+            }
+            res.push(I64Eqz);
+        }),
+        // the top of the stack is the left operand
+        LT => store_bool(words, res, |res| less_than(words, res, 1, 0)),
+        GT => store_bool(words, res, |res| less_than(words, res, 0, 1)),
+        AND | OR | XOR => {
+            let instr = match op {
+                AND => I64And,
+                OR => I64Or,
+                _ => I64Xor,
+            };
+            for limb in 0..4 {
+                words.store(res, limb, |res| {
+                    res.extend(words.load(1, limb));
+                    res.extend(words.load(0, limb));
+                    res.push(instr.clone());
+                });
+            }
+        }
+        NOT => {
+            for limb in 0..4 {
+                words.store(res, limb, |res| {
+                    res.extend(words.load(0, limb));
+                    res.push(I64Const(-1));
+                    res.push(I64Xor);
+                });
+            }
+        }
+        CALLVALUE => {
+            for limb in 0..4 {
+                words.store(res, limb, |res| {
+                    res.push(GlobalGet(runtime.call_value));
+                    res.push(I64Load(limb_memarg(0, limb)));
+                });
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Emits `lhs < rhs` over the operands at the given slots, comparing limbs
+/// from the least significant one up.
+fn less_than(words: &Words, res: &mut Vec<Instruction<'static>>, lhs: u64, rhs: u64) {
+    use Instruction::*;
+    res.extend(words.load(lhs, 0));
+    res.extend(words.load(rhs, 0));
+    res.push(I64LtU);
+    for limb in 1..4 {
+        // a more significant limb decides unless the limbs are equal
+        res.extend(words.load(lhs, limb));
+        res.extend(words.load(rhs, limb));
+        res.push(I64Eq);
+        res.push(I32And);
+        res.extend(words.load(lhs, limb));
+        res.extend(words.load(rhs, limb));
+        res.push(I64LtU);
+        res.push(I32Or);
+    }
+}
+
+/// Stores the `i32` condition emitted by `cond` as the result word.
+fn store_bool(
+    words: &Words,
+    res: &mut Vec<Instruction<'static>>,
+    cond: impl FnOnce(&mut Vec<Instruction<'static>>),
+) {
+    use Instruction::*;
+    words.store(res, 0, |res| {
+        cond(res);
+        res.push(I64ExtendI32U);
+    });
+    for limb in 1..4 {
+        words.store(res, limb, |res| res.push(I64Const(0)));
+    }
+}
+
+fn limb_memarg(slot: u64, limb: u64) -> MemArg {
+    MemArg {
+        offset: slot * SLOT_SIZE + 8 * limb,
+        align: 3,
+        memory_index: 0,
+    }
+}
+
+fn depth_memarg() -> MemArg {
+    MemArg {
+        offset: DEPTH_OFFSET,
+        align: 2,
+        memory_index: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethnum::u256;
+    use wasm_encoder::{
+        CodeSection, ConstExpr, Function, FunctionSection, GlobalSection, GlobalType,
+        MemorySection, MemoryType, Module, TypeSection, ValType,
+    };
+
+    const RUNTIME: InlineRuntime = InlineRuntime {
+        stack: 0,
+        call_value: 1,
+        set_pc: Some(0),
+        fault: 1,
+    };
+
+    /// Checks that `code` is valid in a function shaped like the compiled
+    /// ones, with room for two words in the `i64` locals after the scratch one.
+    fn validate(code: &[Instruction]) {
+        let mut types = TypeSection::new();
+        types.function([], []);
+        types.function([ValType::I32], []);
+        let mut functions = FunctionSection::new();
+        functions.function(1); // _evm_set_pc
+        functions.function(0); // _evm_fault
+        functions.function(0);
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType {
+            minimum: 1,
+            maximum: None,
+            memory64: false,
+            shared: false,
+        });
+        let mut globals = GlobalSection::new();
+        for _ in 0..2 {
+            let global_type = GlobalType {
+                val_type: ValType::I32,
+                mutable: true,
+            };
+            globals.global(global_type, &ConstExpr::i32_const(0));
+        }
+        let mut body = Function::new_with_locals_types([
+            ValType::I32,
+            ValType::I64,
+            ValType::I64,
+            ValType::I64,
+            ValType::I64,
+            ValType::I64,
+            ValType::I64,
+            ValType::I64,
+            ValType::I64,
+        ]);
+        for instr in code {
+            body.instruction(instr);
+        }
+        body.instruction(&Instruction::End);
+        let mut codes = CodeSection::new();
+        for _ in 0..2 {
+            let mut runtime = Function::new([]);
+            runtime.instruction(&Instruction::End);
+            codes.function(&runtime);
+        }
+        codes.function(&body);
+
+        let mut module = Module::new();
+        module
+            .section(&types)
+            .section(&functions)
+            .section(&memories)
+            .section(&globals)
+            .section(&codes);
+        wasmparser::validate(&module.finish()).unwrap();
+    }
+
+    #[test]
+    fn test_inlined_ops_validate() {
+        use Opcode::*;
+        for op in [POP, ISZERO, EQ, LT, GT, AND, OR, XOR, NOT, PC, CALLVALUE] {
+            validate(&inline_op(&op, 0x42, RUNTIME).unwrap());
+        }
+        assert!(inline_op(&JUMPDEST, 0, RUNTIME).unwrap().is_empty());
+        assert!(inline_op(&ADD, 0, RUNTIME).is_none());
+        assert!(inline_op(&SLOAD, 0, RUNTIME).is_none());
+    }
+
+    #[test]
+    fn test_word_ops_validate() {
+        use Opcode::*;
+        let args = [Value::Local(1), Value::Const(u256::from(2u8))];
+        for op in [ISZERO, EQ, LT, GT, AND, OR, XOR, NOT] {
+            let (pops, _) = stack_effect(&op);
+            let code = inline_word_op(&op, &args[2 - pops..], 5, RUNTIME).unwrap();
+            assert!(!code
+                .iter()
+                .any(|instr| matches!(instr, Instruction::Call(_))));
+            validate(&code);
+        }
+        validate(&inline_word_op(&CALLVALUE, &[], 1, RUNTIME).unwrap());
+        assert!(inline_word_op(&PC, &[], 1, RUNTIME).is_none());
+        assert!(inline_word_op(&ADD, &args, 5, RUNTIME).is_none());
+    }
+
+    #[test]
+    fn test_stack_checks() {
+        let count = |op: Opcode, pred: fn(&Instruction) -> bool| {
+            let code = inline_op(&op, 0, RUNTIME).unwrap();
+            code.iter().filter(|instr| pred(instr)).count()
+        };
+        let trap = |instr: &Instruction| matches!(instr, Instruction::Call(1));
+        let scratch = |instr: &Instruction| matches!(instr, Instruction::LocalSet(_));
+        let depth = |instr: &Instruction| matches!(instr, Instruction::I32Store(_));
+        // underflow checks for pops, overflow checks for pushes
+        assert_eq!(count(Opcode::LT, trap), 1);
+        assert_eq!(count(Opcode::PC, trap), 1);
+        assert_eq!(count(Opcode::ISZERO, depth), 0);
+        assert_eq!(count(Opcode::POP, depth), 1);
+        assert_eq!(count(Opcode::POP, scratch), 0);
+    }
+
+    #[test]
+    fn test_faults_set_pc() {
+        let code = inline_op(&Opcode::POP, 0x42, RUNTIME).unwrap();
+        let fault = code
+            .iter()
+            .position(|instr| matches!(instr, Instruction::Call(1)))
+            .unwrap();
+        assert!(matches!(code[fault - 2], Instruction::I32Const(0x42)));
+        assert!(matches!(code[fault - 1], Instruction::Call(0)));
+        let no_pc = InlineRuntime {
+            set_pc: None,
+            ..RUNTIME
+        };
+        let code = inline_op(&Opcode::POP, 0x42, no_pc).unwrap();
+        assert!(!code
+            .iter()
+            .any(|instr| matches!(instr, Instruction::Call(0))));
+    }
+}
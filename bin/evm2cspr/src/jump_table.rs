@@ -0,0 +1,286 @@
+// This is free and unencumbered software released into the public domain.
+
+use std::{borrow::Cow, collections::BTreeMap};
+
+use wasm_encoder::{BlockType, Instruction};
+
+use crate::stackify::SCRATCH_LOCAL;
+
+/// The most targets a dense `br_table` may have, the default
+/// `max_br_table_size` of the Casper rules.
+const MAX_TABLE_SPAN: usize = 256;
+
+/// A `br_table` is used for a run of targets when at least one in
+/// `MAX_TABLE_SPARSENESS` of its entries is a target.
+const MAX_TABLE_SPARSENESS: usize = 4;
+
+/// Runs of at most this many targets are compared one by one.
+const MAX_COMPARISONS: usize = 4;
+
+/// Compiles a jump through `table`, which maps JUMPDEST offsets to the
+/// relative depth of the block to branch to, on the offset on top of the
+/// wasm stack. Other offsets trap.
+///
+/// Offsets are searched for by a binary search over the sorted targets,
+/// down to runs of targets close enough for a `br_table` indexed by the
+/// offset from the first one, or few enough to compare one by one. The
+/// code is thus linear in the number of targets, not in the largest offset.
+/// The offset is kept in the scratch local.
+pub fn table_jump(table: &BTreeMap<usize, u32>, res: &mut Vec<Instruction>) {
+    let targets: Vec<_> = table.iter().map(|(&offs, &br)| (offs, br)).collect();
+    res.push(Instruction::LocalSet(SCRATCH_LOCAL));
+    // a search without `br_table`s falls through to the trap, those leave
+    // a block wrapping the search for it
+    let trap_block = has_table(&targets);
+    if trap_block {
+        res.push(Instruction::Block(BlockType::Empty));
+    }
+    search(&targets, trap_block.into(), res);
+    if trap_block {
+        res.push(Instruction::End);
+    }
+    res.push(Instruction::Unreachable);
+}
+
+enum Strategy {
+    Table,
+    Compare,
+    /// at the index of the first target of the higher half
+    Split(usize),
+}
+
+fn strategy(targets: &[(usize, u32)]) -> Strategy {
+    let (first, _) = targets[0];
+    let (last, _) = targets[targets.len() - 1];
+    let span = last - first + 1;
+    if span <= MAX_TABLE_SPAN && span <= MAX_TABLE_SPARSENESS * targets.len() {
+        Strategy::Table
+    } else if targets.len() <= MAX_COMPARISONS {
+        Strategy::Compare
+    } else {
+        Strategy::Split(targets.len() / 2)
+    }
+}
+
+fn has_table(targets: &[(usize, u32)]) -> bool {
+    match strategy(targets) {
+        Strategy::Table => true,
+        Strategy::Compare => false,
+        Strategy::Split(mid) => has_table(&targets[..mid]) || has_table(&targets[mid..]),
+    }
+}
+
+/// Branches to one of `targets`, sorted by offset, from `depth` blocks
+/// inside the ones branched to, or falls through to the trap. The trap
+/// block is the outermost of these if there is one.
+fn search(targets: &[(usize, u32)], depth: u32, res: &mut Vec<Instruction>) {
+    let offset = |offs: usize| Instruction::I32Const(offs.try_into().unwrap());
+    match strategy(targets) {
+        Strategy::Table => {
+            let (first, _) = targets[0];
+            let (last, _) = targets[targets.len() - 1];
+            let trap = depth - 1;
+            let mut entries = vec![trap; last - first + 1];
+            for &(offs, br) in targets {
+                entries[offs - first] = br + depth;
+            }
+            res.push(Instruction::LocalGet(SCRATCH_LOCAL));
+            if first != 0 {
+                res.push(offset(first));
+                res.push(Instruction::I32Sub); // offsets below wrap past the table
+            }
+            res.push(Instruction::BrTable(Cow::Owned(entries), trap));
+        }
+        Strategy::Compare => {
+            for &(offs, br) in targets {
+                res.push(Instruction::LocalGet(SCRATCH_LOCAL));
+                res.push(offset(offs));
+                res.push(Instruction::I32Eq);
+                res.push(Instruction::BrIf(br + depth));
+            }
+        }
+        Strategy::Split(mid) => {
+            let (low, high) = targets.split_at(mid);
+            res.push(Instruction::LocalGet(SCRATCH_LOCAL));
+            res.push(offset(high[0].0));
+            res.push(Instruction::I32LtU);
+            res.push(Instruction::If(BlockType::Empty));
+            search(low, depth + 1, res);
+            res.push(Instruction::Else);
+            search(high, depth + 1, res);
+            res.push(Instruction::End);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_encoder::{CodeSection, Function, FunctionSection, Module, TypeSection, ValType};
+
+    /// Runs the jump code on `offs`, returning the depth of the block
+    /// branched to outside it, or `None` if it traps.
+    fn run(code: &[Instruction], offs: i32) -> Option<u32> {
+        let mut stack = vec![offs];
+        let mut local = 0;
+        // the depth of the code in blocks, and of the block being skipped
+        // to its end, or to its else branch
+        let mut depth = 0u32;
+        let mut skipping: Option<(u32, bool)> = None;
+        for instr in code {
+            if let Some((target, to_else)) = skipping {
+                match instr {
+                    Instruction::Block(_) | Instruction::If(_) => depth += 1,
+                    Instruction::Else if to_else && depth == target => skipping = None,
+                    Instruction::End => {
+                        if depth == target {
+                            skipping = None;
+                        }
+                        depth -= 1;
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            let br = match instr {
+                Instruction::Block(_) => {
+                    depth += 1;
+                    None
+                }
+                Instruction::If(_) => {
+                    depth += 1;
+                    if stack.pop().unwrap() == 0 {
+                        skipping = Some((depth, true));
+                    }
+                    None
+                }
+                Instruction::Else => {
+                    skipping = Some((depth, false));
+                    None
+                }
+                Instruction::End => {
+                    depth -= 1;
+                    None
+                }
+                Instruction::LocalSet(_) => {
+                    local = stack.pop().unwrap();
+                    None
+                }
+                Instruction::LocalGet(_) => {
+                    stack.push(local);
+                    None
+                }
+                Instruction::I32Const(c) => {
+                    stack.push(*c);
+                    None
+                }
+                Instruction::I32Sub => {
+                    let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
+                    stack.push(a.wrapping_sub(b));
+                    None
+                }
+                Instruction::I32Eq | Instruction::I32LtU => {
+                    let (b, a) = (stack.pop().unwrap() as u32, stack.pop().unwrap() as u32);
+                    let holds = match instr {
+                        Instruction::I32Eq => a == b,
+                        _ => a < b,
+                    };
+                    stack.push(holds.into());
+                    None
+                }
+                Instruction::BrIf(br) => (stack.pop().unwrap() != 0).then_some(*br),
+                Instruction::BrTable(entries, default) => {
+                    let index = stack.pop().unwrap() as u32 as usize;
+                    Some(*entries.get(index).unwrap_or(default))
+                }
+                Instruction::Unreachable => return None,
+                _ => unreachable!(),
+            };
+            match br {
+                Some(br) if br >= depth => return Some(br - depth),
+                Some(br) => skipping = Some((depth - br, false)),
+                None => {}
+            }
+        }
+        unreachable!()
+    }
+
+    fn check(offsets: &[usize]) -> Vec<Instruction<'static>> {
+        let table: BTreeMap<_, _> = offsets.iter().zip(0..).map(|(&o, br)| (o, br)).collect();
+        let mut code = vec![Instruction::I32Const(0)];
+        table_jump(&table, &mut code);
+        let code = code.split_off(1);
+        for offs in 0..offsets.last().unwrap() + 8 {
+            let expected = table.get(&offs).copied();
+            assert_eq!(run(&code, offs as i32), expected, "offset {}", offs);
+        }
+        assert_eq!(run(&code, -1), None);
+        validate(offsets.len(), &code);
+        code
+    }
+
+    /// Validates the jump code inside blocks for the targets.
+    fn validate(targets: usize, code: &[Instruction]) {
+        let mut types = TypeSection::new();
+        types.function([ValType::I32], []);
+        let mut functions = FunctionSection::new();
+        functions.function(0);
+        let mut body = Function::new_with_locals_types([ValType::I32]);
+        for _ in 0..targets {
+            body.instruction(&Instruction::Block(BlockType::Empty));
+        }
+        body.instruction(&Instruction::LocalGet(0));
+        for instr in code {
+            body.instruction(instr);
+        }
+        for _ in 0..targets {
+            body.instruction(&Instruction::End);
+        }
+        body.instruction(&Instruction::End);
+        let mut codes = CodeSection::new();
+        codes.function(&body);
+
+        let mut module = Module::new();
+        module.section(&types).section(&functions).section(&codes);
+        wasmparser::validate(&module.finish()).unwrap();
+    }
+
+    fn table_sizes(code: &[Instruction]) -> Vec<usize> {
+        code.iter()
+            .filter_map(|instr| match instr {
+                Instruction::BrTable(entries, _) => Some(entries.len()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dense_targets() {
+        let code = check(&[0x10, 0x12, 0x13, 0x17]);
+        assert_eq!(table_sizes(&code), [8]);
+    }
+
+    #[test]
+    fn test_sparse_targets() {
+        let code = check(&[0x3, 0x400, 0x4000]);
+        assert!(table_sizes(&code).is_empty());
+        // falling through to the trap without a block
+        assert!(!code.iter().any(|i| matches!(i, Instruction::Block(_))));
+
+        let offsets: Vec<_> = (0..600).map(|i| 0x20 + i * 37 + i % 5).collect();
+        let code = check(&offsets);
+        assert!(table_sizes(&code).is_empty());
+        assert!(code.len() < 20 * offsets.len());
+    }
+
+    #[test]
+    fn test_clustered_targets() {
+        // a dispatcher at the start, functions spread over 20 KB
+        let mut offsets: Vec<_> = (0..40).map(|i| 0x40 + 3 * i).collect();
+        offsets.extend((1..50).map(|i| i * 400));
+        let code = check(&offsets);
+        let sizes = table_sizes(&code);
+        assert!(!sizes.is_empty());
+        assert!(sizes.iter().all(|&size| size <= MAX_TABLE_SPAN));
+    }
+}
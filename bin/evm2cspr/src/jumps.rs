@@ -0,0 +1,246 @@
+// This is free and unencumbered software released into the public domain.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    ops::Range,
+};
+
+use ethnum::u256;
+use evm_rs::{Opcode, Program};
+use relooper::graph::cfg::Cfg;
+
+use crate::{
+    analyze::{CfgNode, Idx, Offs},
+    fold::fold,
+    ops::{dup_depth, stack_effect, swap_depth},
+};
+
+/// Largest number of distinct constants tracked for a single stack word.
+const MAX_VALUES: usize = 64;
+
+/// The possible values of a stack word, `None` if unknown.
+type Values = Option<BTreeSet<u256>>;
+
+/// An abstract EVM stack, where every word is a small set of constants or
+/// unknown. Only the top of the stack is modelled; the words below are unknown.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AbstractStack {
+    /// Top of stack last.
+    slots: Vec<Values>,
+}
+
+impl AbstractStack {
+    /// Returns the possible values of the `n`-th word from the top, if known.
+    pub fn peek(&self, n: usize) -> Option<&BTreeSet<u256>> {
+        self.slots.iter().rev().nth(n).and_then(Option::as_ref)
+    }
+
+    pub fn step(&mut self, op: &Opcode) {
+        use Opcode::*;
+        let (pops, pushes) = stack_effect(op);
+        self.reserve(pops);
+        let top = self.slots.len() - 1;
+        match op {
+            PUSH1(b) => self.slots.push(Some(BTreeSet::from([u256::from(*b)]))),
+            PUSHn(_, v, _) => self.slots.push(Some(BTreeSet::from([*v]))),
+            op if dup_depth(op).is_some() => {
+                self.slots.push(self.slots[top + 1 - pops].clone());
+            }
+            op if swap_depth(op).is_some() => {
+                self.slots.swap(top, top + 1 - pops);
+            }
+            op => {
+                let args: Vec<_> = self.slots.drain(top + 1 - pops..).rev().collect();
+                let args: Option<Vec<u256>> = args
+                    .iter()
+                    .map(|values| match values {
+                        Some(values) if values.len() == 1 => values.first().copied(),
+                        _ => None,
+                    })
+                    .collect();
+                let result = args.and_then(|args| fold(op, &args));
+                match (pushes, result) {
+                    (1, Some(v)) => self.slots.push(Some(BTreeSet::from([v]))),
+                    (n, _) => self.slots.extend((0..n).map(|_| None)),
+                }
+            }
+        }
+    }
+
+    /// Merges the stack of another path into this one, returning whether
+    /// anything changed.
+    pub fn join(&mut self, other: &Self) -> bool {
+        let n = self.slots.len().min(other.slots.len());
+        let ours = &self.slots[self.slots.len() - n..];
+        let theirs = &other.slots[other.slots.len() - n..];
+        let mut slots: Vec<Values> = ours
+            .iter()
+            .zip(theirs)
+            .map(|pair| match pair {
+                (Some(a), Some(b)) => Some(a.union(b).copied().collect::<BTreeSet<_>>())
+                    .filter(|values| values.len() <= MAX_VALUES),
+                _ => None,
+            })
+            .collect();
+        trim_unknown(&mut slots);
+        let changed = slots != self.slots;
+        self.slots = slots;
+        changed
+    }
+
+    /// Makes sure at least `n` words are modelled, padding the bottom with
+    /// unknown words.
+    fn reserve(&mut self, n: usize) {
+        if self.slots.len() < n.max(1) {
+            let missing = n.max(1) - self.slots.len();
+            self.slots.splice(0..0, (0..missing).map(|_| None));
+        }
+    }
+}
+
+/// Drops unknown words at the bottom, which are implied.
+fn trim_unknown(slots: &mut Vec<Values>) {
+    let known = slots
+        .iter()
+        .position(Option::is_some)
+        .unwrap_or(slots.len());
+    slots.drain(..known);
+}
+
+/// The outcome of the analysis for a block ending in a dynamic jump.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvedJump {
+    /// The JUMPDESTs the jump can go to, `None` if it may go to any of them.
+    pub targets: Option<BTreeSet<Offs>>,
+    /// Whether the jump may also take a target that is not a JUMPDEST, and
+    /// fault.
+    pub faults: bool,
+    /// The condition of a `JUMPI`, if it is the same on every path.
+    pub condition: Option<u256>,
+}
+
+/// Tracks constant jump targets through the stack across blocks to find
+/// where the dynamic jumps of `program` can go.
+///
+/// `cfg` holds the edges of all other blocks, and `dynamic_jumps` maps each
+/// block ending in a dynamic jump to its fallthrough block, if it ends in a
+/// `JUMPI`. Jumps that may take a target that is not known at compile time
+/// are assumed to go to any JUMPDEST, so the result is sound for all paths.
+pub fn resolve_jumps(
+    program: &Program,
+    cfg: &Cfg<CfgNode<Offs>>,
+    code_ranges: &HashMap<Offs, Range<Idx>>,
+    dynamic_jumps: &HashMap<Offs, Option<Offs>>,
+    jumpdests: &[Offs],
+) -> HashMap<Offs, ResolvedJump> {
+    let mut entry_stacks = HashMap::from([(Offs(0), AbstractStack::default())]);
+    let mut jump_stacks: HashMap<Offs, AbstractStack> = HashMap::new();
+    let mut worklist = BTreeSet::from([Offs(0)]);
+
+    while let Some(block) = worklist.pop_first() {
+        let range = match code_ranges.get(&block) {
+            Some(range) => range,
+            None => continue,
+        };
+        let code = &program.0[range.start.0..range.end.0];
+        let mut stack = entry_stacks[&block].clone();
+        let successors: Vec<Offs> = match dynamic_jumps.get(&block) {
+            Some(fallthrough) => {
+                let (jump, body) = code.split_last().expect("block should end in a jump");
+                body.iter().for_each(|op| stack.step(op));
+                jump_stacks.insert(block, stack.clone());
+                let targets = jump_targets(&stack, jumpdests);
+                stack.step(jump);
+                let targets =
+                    targets.map_or_else(|| jumpdests.to_vec(), |(t, _)| Vec::from_iter(t));
+                targets.into_iter().chain(*fallthrough).collect()
+            }
+            None => {
+                code.iter().for_each(|op| stack.step(op));
+                cfg.children(&CfgNode::Orig(block))
+                    .into_iter()
+                    .filter_map(|node| match node {
+                        CfgNode::Orig(offs) => Some(*offs),
+                        CfgNode::Dynamic
+                        | CfgNode::Entry
+                        | CfgNode::Dispatch
+                        | CfgNode::Route(_) => None,
+                    })
+                    .collect()
+            }
+        };
+        for successor in successors {
+            let changed = match entry_stacks.get_mut(&successor) {
+                Some(entry_stack) => entry_stack.join(&stack),
+                None => {
+                    let mut entry_stack = stack.clone();
+                    trim_unknown(&mut entry_stack.slots);
+                    entry_stacks.insert(successor, entry_stack);
+                    true
+                }
+            };
+            if changed {
+                worklist.insert(successor);
+            }
+        }
+    }
+
+    jump_stacks
+        .into_iter()
+        .map(|(block, stack)| {
+            let condition = stack
+                .peek(1)
+                .filter(|values| values.len() == 1)
+                .and_then(|values| values.first().copied())
+                .filter(|_| dynamic_jumps[&block].is_some());
+            let (targets, faults) = match jump_targets(&stack, jumpdests) {
+                Some((targets, faults)) => (Some(targets), faults),
+                None => (None, false),
+            };
+            let resolved = ResolvedJump {
+                targets,
+                faults,
+                condition,
+            };
+            (block, resolved)
+        })
+        .collect()
+}
+
+/// Returns the valid JUMPDESTs among the possible values of the stack top,
+/// and whether there are other values, which make the jump fail at run time.
+fn jump_targets(stack: &AbstractStack, jumpdests: &[Offs]) -> Option<(BTreeSet<Offs>, bool)> {
+    let values = stack.peek(0)?;
+    let targets: BTreeSet<Offs> = jumpdests
+        .iter()
+        .filter(|offs| values.contains(&u256::from(offs.0 as u64)))
+        .copied()
+        .collect();
+    let faults = targets.len() < values.len();
+    Some((targets, faults))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join() {
+        let word = |values: &[u8]| Some(values.iter().map(|&v| u256::from(v)).collect());
+        let mut a = AbstractStack {
+            slots: vec![word(&[1]), word(&[2]), word(&[3])],
+        };
+        let b = AbstractStack {
+            slots: vec![word(&[4]), word(&[3])],
+        };
+        assert!(a.join(&b));
+        assert_eq!(a.slots, vec![word(&[2, 4]), word(&[3])]);
+        assert!(!a.join(&b));
+
+        let c = AbstractStack {
+            slots: vec![None, word(&[3])],
+        };
+        assert!(a.join(&c));
+        assert_eq!(a.slots, vec![word(&[3])]);
+    }
+}
@@ -0,0 +1,261 @@
+// This is free and unencumbered software released into the public domain.
+
+use std::collections::{BTreeSet, HashMap};
+
+use abi_types::INTERFACE_VERSION;
+use anyhow::{bail, Result};
+use wasm_encoder::{EntityType, ExportKind};
+use wasmparser::{Operator, Payload};
+
+use crate::wasm_translate::{parse, translator::code_with_calls, Import, ModuleBuilder};
+
+/// Returns the module a shared runtime is imported from. The version suffix
+/// is the `INTERFACE_VERSION` evmlib is built with, so that linking against a
+/// runtime with another interface fails at instantiation.
+pub fn runtime_module() -> String {
+    format!("evmlib.v{}", INTERFACE_VERSION)
+}
+
+/// How much of each section of a module comes from the runtime library,
+/// whose items precede the generated ones.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RuntimeExtent {
+    /// The number of defined functions, after the imported ones.
+    pub functions: usize,
+    pub exports: usize,
+    pub data: usize,
+}
+
+/// Replaces the runtime library in a module by imports from a shared runtime,
+/// leaving only the generated code and data. The import interface is:
+///
+/// - the memory, imported as `env.memory`. The host creates it with the
+///   minimum size of the import, which leaves room for the ABI data past the
+///   initial memory of the runtime;
+/// - the runtime functions the generated code calls, imported from
+///   [`runtime_module`] under their evmlib export names: the `_evm_*`
+///   functions and the op handlers, named after the lowercase opcodes.
+///
+/// The host instantiates the runtime against the same memory, once per
+/// contract, as the runtime keeps the EVM state in memory.
+///
+/// Returns the linked module along with the new index of each function, by
+/// its old index.
+pub fn link_shared(
+    wasm: &[u8],
+    runtime: RuntimeExtent,
+) -> Result<(ModuleBuilder, Vec<Option<u32>>)> {
+    let mut builder = parse(wasm)?;
+    let imported = builder
+        .imports
+        .iter()
+        .filter(|import| matches!(import.ty, EntityType::Function(_)))
+        .count();
+    let count = imported + builder.functions.len();
+    let generated = imported + runtime.functions;
+    let names: HashMap<u32, String> = builder.exports[..runtime.exports]
+        .iter()
+        .filter(|export| export.kind == ExportKind::Func)
+        .map(|export| (export.index, export.name.clone()))
+        .collect();
+
+    // the generated functions, and the other functions they call
+    let mut bodies = vec![];
+    let mut referenced = BTreeSet::new();
+    let code = wasmparser::Parser::new(0)
+        .parse_all(wasm)
+        .filter_map(|payload| match payload {
+            Ok(Payload::CodeSectionEntry(body)) => Some(Ok(body)),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        });
+    for body in code.skip(runtime.functions) {
+        let body = body?;
+        let mut reader = body.get_operators_reader()?;
+        reader.allow_memarg64(true);
+        for operator in reader {
+            match operator? {
+                Operator::Call { function_index } | Operator::RefFunc { function_index }
+                    if usize::try_from(function_index).unwrap() < generated =>
+                {
+                    referenced.insert(function_index);
+                }
+                _ => {}
+            }
+        }
+        bodies.push(body);
+    }
+
+    // host functions stay imported, runtime functions become imports
+    let (function_imports, mut imports): (Vec<Import>, Vec<Import>) =
+        std::mem::take(&mut builder.imports)
+            .into_iter()
+            .partition(|import| matches!(import.ty, EntityType::Function(_)));
+    let mut functions = vec![None; count];
+    let mut next = 0;
+    for f in referenced {
+        let index = usize::try_from(f).unwrap();
+        let import = match function_imports.get(index) {
+            Some(import) => Import {
+                module: import.module.clone(),
+                field: import.field.clone(),
+                ty: import.ty,
+            },
+            None => match names.get(&f) {
+                Some(name) => Import {
+                    module: runtime_module(),
+                    field: name.clone(),
+                    ty: EntityType::Function(builder.functions[index - imported]),
+                },
+                None => bail!("runtime function {} is called but not exported", f),
+            },
+        };
+        imports.push(import);
+        functions[index] = Some(next);
+        next += 1;
+    }
+    for new_index in functions[generated..].iter_mut() {
+        *new_index = Some(next);
+        next += 1;
+    }
+    let map = |f: u32| functions[usize::try_from(f).unwrap()].unwrap();
+
+    match builder.memories.len() {
+        0 => {} // already imported
+        1 => imports.push(Import {
+            module: "env".to_string(),
+            field: "memory".to_string(),
+            ty: EntityType::Memory(builder.memories.remove(0)),
+        }),
+        _ => bail!("the runtime library defines more than one memory"),
+    }
+    builder.imports = imports;
+    builder.functions.drain(..runtime.functions);
+    builder.code = bodies
+        .into_iter()
+        .map(|body| code_with_calls(body, map))
+        .collect::<Result<_>>()?;
+
+    // the runtime keeps its tables, elements and data to itself
+    let mut i = 0;
+    builder.exports.retain(|export| {
+        i += 1;
+        i > runtime.exports || matches!(export.kind, ExportKind::Memory | ExportKind::Global)
+    });
+    for export in builder.exports.iter_mut() {
+        if export.kind == ExportKind::Func {
+            export.index = map(export.index);
+        }
+    }
+    builder.start_sect = builder
+        .start_sect
+        .take()
+        .filter(|start| usize::try_from(start.function_index).unwrap() >= generated)
+        .map(|mut start| {
+            start.function_index = map(start.function_index);
+            start
+        });
+    builder.tables.clear();
+    builder.elements.clear();
+    builder.data.drain(..runtime.data);
+    Ok((builder, functions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_encoder::{
+        CodeSection, ConstExpr, DataSection, ExportSection, Function, FunctionSection,
+        ImportSection, Instruction, MemorySection, MemoryType, Module, TypeSection, ValType,
+    };
+
+    /// Builds a module with a runtime importing `env.log`, defining `add`
+    /// calling `helper`, `helper` and `mul`, and generated code calling
+    /// `add` and `_evm_start`.
+    fn module() -> Vec<u8> {
+        let mut types = TypeSection::new();
+        types.function([], []);
+        types.function([ValType::I32], []);
+        let mut imports = ImportSection::new();
+        imports.import("env", "log", EntityType::Function(1));
+        let mut functions = FunctionSection::new();
+        let mut codes = CodeSection::new();
+        for callees in [&[2][..], &[0], &[], &[1, 5], &[]] {
+            functions.function(0);
+            let mut body = Function::new([]);
+            for &f in callees {
+                if f == 0 {
+                    body.instruction(&Instruction::I32Const(0));
+                }
+                body.instruction(&Instruction::Call(f));
+            }
+            body.instruction(&Instruction::End);
+            codes.function(&body);
+        }
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType {
+            minimum: 17,
+            maximum: None,
+            memory64: false,
+            shared: false,
+        });
+        let mut exports = ExportSection::new();
+        exports.export("add", ExportKind::Func, 1);
+        exports.export("mul", ExportKind::Func, 3);
+        exports.export("memory", ExportKind::Memory, 0);
+        exports.export("execute", ExportKind::Func, 4);
+        exports.export("_evm_start", ExportKind::Func, 5);
+        let mut data = DataSection::new();
+        data.active(0, &ConstExpr::i32_const(1024), b"runtime".to_vec());
+        data.active(0, &ConstExpr::i32_const(0x100000), b"abi".to_vec());
+
+        let mut module = Module::new();
+        module
+            .section(&types)
+            .section(&imports)
+            .section(&functions)
+            .section(&memories)
+            .section(&exports)
+            .section(&codes)
+            .section(&data);
+        module.finish()
+    }
+
+    const RUNTIME: RuntimeExtent = RuntimeExtent {
+        functions: 3,
+        exports: 3,
+        data: 1,
+    };
+
+    #[test]
+    fn test_link_shared() {
+        let wasm = module();
+        let (builder, functions) = link_shared(&wasm, RUNTIME).unwrap();
+        assert_eq!(functions, [None, Some(0), None, None, Some(1), Some(2)]);
+        let imports: Vec<_> = builder
+            .imports
+            .iter()
+            .map(|import| (import.module.as_str(), import.field.as_str()))
+            .collect();
+        let module = runtime_module();
+        assert_eq!(imports, [(module.as_str(), "add"), ("env", "memory")]);
+        let exports: Vec<_> = builder
+            .exports
+            .iter()
+            .map(|export| (export.name.as_str(), export.index))
+            .collect();
+        assert_eq!(exports, [("memory", 0), ("execute", 1), ("_evm_start", 2)]);
+        assert_eq!(builder.data.len(), 1);
+        wasmparser::validate(builder.build().as_slice()).unwrap();
+    }
+
+    #[test]
+    fn test_unexported_runtime_function() {
+        let wasm = module();
+        let runtime = RuntimeExtent {
+            exports: 0,
+            ..RUNTIME
+        };
+        assert!(link_shared(&wasm, runtime).is_err());
+    }
+}
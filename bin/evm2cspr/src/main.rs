@@ -7,25 +7,40 @@ mod analyze;
 mod compile;
 mod config;
 mod decode;
+mod decompile;
+mod dispatch;
 mod encode;
 mod error;
+mod fold;
 mod format;
+mod functions;
+mod inline;
+mod jump_table;
+mod jumps;
+mod link;
+mod ops;
+mod peephole;
+mod shake;
 mod solidity;
+mod stackify;
+mod validate;
 mod wasm_translate;
 
 use clap::Parser;
 use std::{
+    collections::HashMap,
     ffi::OsStr,
-    fs::{File, OpenOptions},
+    fs::{self, File, OpenOptions},
     io::{stdin, stdout, Read, Write},
     path::PathBuf,
 };
 
 use crate::{
     compile::compile,
-    config::CompilerConfig,
+    config::{CompilerConfig, ContractConfig},
     decode::decode_bytecode,
-    format::{parse_input_extension, InputFormat, OutputABI, OutputFormat},
+    decompile::decompile,
+    format::{parse_input_extension, InputFormat, Linking, OutputABI, OutputFormat},
     solidity::SOLC,
 };
 
@@ -53,6 +68,68 @@ struct Options {
     #[clap(long = "fno-program-counter", value_parser)]
     no_program_counter: bool,
 
+    /// Disable setting the EVM program counter only where it is observable
+    #[clap(long = "fno-sparse-program-counter", value_parser)]
+    no_sparse_program_counter: bool,
+
+    /// Disable keeping EVM stack slots in WebAssembly locals
+    #[clap(long = "fno-stackify", value_parser)]
+    no_stackify: bool,
+
+    /// Disable compile-time constant folding. Constant JUMPI conditions are
+    /// folded regardless of --fno-stackify, other operators only with stackify
+    #[clap(long = "fno-constant-folding", value_parser)]
+    no_constant_folding: bool,
+
+    /// Disable rewriting stack-shuffling idioms into fused runtime calls
+    #[clap(long = "fno-peephole", value_parser)]
+    no_peephole: bool,
+
+    /// Disable compiling simple EVM operators into inline WebAssembly code
+    #[clap(long = "fno-inline-ops", value_parser)]
+    no_inline_ops: bool,
+
+    /// Disable compiling internal functions into separate WebAssembly functions
+    #[clap(long = "fno-function-splitting", value_parser)]
+    no_function_splitting: bool,
+
+    /// Disable entering ABI methods past the selector dispatcher
+    #[clap(long = "fno-direct-entries", value_parser)]
+    no_direct_entries: bool,
+
+    /// Disable removing unreachable runtime functions from the output module
+    #[clap(long = "fno-tree-shaking", value_parser)]
+    no_tree_shaking: bool,
+
+    /// Skip checking a module for the cspr ABI against the host functions and
+    /// limits of Casper nodes
+    #[clap(long = "fno-casper-rules", value_parser)]
+    no_casper_rules: bool,
+
+    /// Route irreducible control flow that node splitting would grow over N
+    /// times the size of its function through a dispatcher instead
+    #[clap(
+        value_name = "N",
+        long = "fsplit-growth",
+        value_parser,
+        default_value = "4"
+    )]
+    split_growth: usize,
+
+    /// Read per-contract settings from a JSON file, e.g.
+    /// `{"export_names": {"transfer(address,uint256)": "transfer_to"}}`
+    #[clap(value_name = "FILE", long, value_parser)]
+    config: Option<PathBuf>,
+
+    /// Export the ABI function with the given signature under the given name,
+    /// overriding the config file
+    #[clap(value_name = "SIGNATURE=NAME", long = "export-name", value_parser)]
+    export_names: Vec<String>,
+
+    /// Write the names of the exported ABI functions as JSON to a file
+    #[clap(value_name = "FILE", long, value_parser)]
+    metadata: Option<PathBuf>,
+
     /// The input file
     #[clap(value_name = "FILE", value_parser, default_value = "/dev/stdin")]
     input: PathBuf,
@@ -74,6 +151,10 @@ struct Options {
     #[clap(short = 'b', long, value_parser, default_value = "cspr")]
     abi: OutputABI,
 
+    /// How to link the runtime library
+    #[clap(long, value_parser, default_value = "static")]
+    link: Linking,
+
     /// Enable verbose output
     #[clap(short = 'v', long, value_parser)]
     verbose: bool,
@@ -154,6 +235,36 @@ fn main() -> impl std::process::Termination {
         },
     };
 
+    let mut export_names = match &options.config {
+        Some(config_path) => match ContractConfig::read(config_path) {
+            Ok(config) => config.export_names,
+            Err(err) => abort!(
+                "Could not read config file `{}': {}",
+                config_path.display(),
+                err
+            ),
+        },
+        None => HashMap::new(),
+    };
+    for export_name in &options.export_names {
+        match export_name.split_once('=') {
+            Some((signature, name)) => export_names.insert(signature.to_string(), name.to_string()),
+            None => abort!("Could not parse `{}': expected SIGNATURE=NAME", export_name),
+        };
+    }
+
+    // written once the module compiled, so that it describes its exports
+    let metadata = options.metadata.as_ref().map(|metadata_path| {
+        let exports = match &input_abi {
+            Some(abi) => match abi::method_exports(abi, &export_names) {
+                Ok(exports) => exports,
+                Err(err) => abort!("{}", err),
+            },
+            None => vec![],
+        };
+        (metadata_path, serde_json::json!({ "methods": exports }))
+    });
+
     let mut output = match options.output.to_str() {
         Some("/dev/stdout") | Some("-") => Box::new(stdout()) as Box<dyn Write>,
         _ => match OpenOptions::new()
@@ -171,6 +282,47 @@ fn main() -> impl std::process::Termination {
         },
     };
 
+    let config = CompilerConfig {
+        debug_path: options.debug_folder,
+        optimize_level: 0, // TODO: -O{0,1,2}
+        gas_accounting: !options.no_gas_accounting,
+        program_counter: !options.no_program_counter,
+        sparse_program_counter: !options.no_sparse_program_counter,
+        stackify: !options.no_stackify,
+        constant_folding: !options.no_constant_folding,
+        peephole: !options.no_peephole,
+        inline_ops: !options.no_inline_ops,
+        split_functions: !options.no_function_splitting,
+        direct_entries: !options.no_direct_entries,
+        tree_shaking: !options.no_tree_shaking,
+        shared_runtime: matches!(options.link, Linking::Shared),
+        // the WASI runtime imports WASI and not the Casper host functions
+        casper_rules: !options.no_casper_rules && matches!(options.abi, OutputABI::cspr),
+        split_growth: options.split_growth,
+        export_names,
+        chain_id: match options.chain_id.as_str() {
+            "mainnet" => 1313161554,
+            "testnet" => 1313161555,
+            "betanet" => 1313161556,
+            s => match s.parse::<u64>() {
+                Ok(n) => n,
+                Err(err) => abort!("Could not parse `{}': {}", s, err),
+            },
+        },
+    };
+    config.create_debug_dir();
+
+    if let OutputFormat::Pseudo = options.to {
+        let code = match decompile(&input_program, input_abi.as_ref(), &config) {
+            Ok(code) => code,
+            Err(violation) => abort!("{}", violation),
+        };
+        output
+            .write_all(code.as_bytes())
+            .expect("Failed to write pseudo-code");
+        return;
+    }
+
     let runtime_wasm = include_bytes!("../../../evmlib.wasm");
     let runtime_wasi = include_bytes!("../../../evmlib.wasi");
     let current_runtime = match options.abi {
@@ -180,26 +332,26 @@ fn main() -> impl std::process::Termination {
 
     let runtime_library = wasm_translate::parse(&current_runtime).unwrap();
 
-    let module = compile(
-        &input_program,
-        input_abi,
-        runtime_library,
-        CompilerConfig::new(
-            options.debug_folder,
-            0, // TODO: -O{0,1,2}
-            !options.no_gas_accounting,
-            !options.no_program_counter,
-            match options.chain_id.as_str() {
-                "mainnet" => 1313161554,
-                "testnet" => 1313161555,
-                "betanet" => 1313161556,
-                s => match s.parse::<u64>() {
-                    Ok(n) => n,
-                    Err(err) => abort!("Could not parse `{}': {}", s, err),
-                },
-            },
-        ),
-    );
+    let result = compile(&input_program, input_abi, runtime_library, config);
+    let module = match result {
+        Ok(module) => module,
+        Err(violations) => {
+            for violation in violations {
+                eprintln!("{}", violation);
+            }
+            abort!("Compiled module failed validation")
+        }
+    };
+
+    if let Some((metadata_path, metadata)) = metadata {
+        if let Err(err) = fs::write(metadata_path, metadata.to_string()) {
+            abort!(
+                "Could not write metadata file `{}': {}",
+                metadata_path.display(),
+                err
+            )
+        }
+    }
 
     let module_bytes = module.finish();
     output
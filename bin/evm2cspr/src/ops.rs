@@ -0,0 +1,419 @@
+// This is free and unencumbered software released into the public domain.
+
+use evm_rs::{Opcode, Program};
+
+use crate::{analyze::Offs, validate::Violation};
+
+/// Rejects programs with operators the compiler doesn't know the stack
+/// effect or gas of, so that the passes after it can rely on both.
+pub fn check_program(program: &Program) -> Result<(), Violation> {
+    let mut offs = Offs(0);
+    for op in &program.0 {
+        if try_stack_effect(op).is_none() || try_static_gas(op).is_none() {
+            return Err(Violation {
+                location: None,
+                message: format!("unsupported operator {} at {}", op, offs),
+            });
+        }
+        offs.0 += op.size();
+    }
+    Ok(())
+}
+
+/// Returns the number of words an operator pops and pushes. The operator
+/// must have passed `check_program`.
+pub fn stack_effect(op: &Opcode) -> (usize, usize) {
+    try_stack_effect(op).expect("unsupported operators are rejected by `check_program`")
+}
+
+fn try_stack_effect(op: &Opcode) -> Option<(usize, usize)> {
+    use Opcode::*;
+    if let Some(n) = dup_depth(op) {
+        return Some((n, n + 1));
+    }
+    if let Some(n) = swap_depth(op) {
+        return Some((n + 1, n + 1));
+    }
+    Some(match op {
+        STOP | JUMPDEST | INVALID => (0, 0),
+        ADD | MUL | SUB | DIV | SDIV | MOD | SMOD | EXP | SIGNEXTEND => (2, 1),
+        LT | GT | SLT | SGT | EQ | AND | OR | XOR | BYTE | SHL | SHR | SAR => (2, 1),
+        ADDMOD | MULMOD => (3, 1),
+        ISZERO | NOT => (1, 1),
+        SHA3 => (2, 1),
+        ADDRESS | ORIGIN | CALLER | CALLVALUE | CALLDATASIZE | CODESIZE | GASPRICE
+        | RETURNDATASIZE => (0, 1),
+        BALANCE | CALLDATALOAD | EXTCODESIZE | EXTCODEHASH | BLOCKHASH => (1, 1),
+        CALLDATACOPY | CODECOPY | RETURNDATACOPY => (3, 0),
+        EXTCODECOPY => (4, 0),
+        COINBASE | TIMESTAMP | NUMBER | DIFFICULTY | GASLIMIT | CHAINID | SELFBALANCE | BASEFEE => {
+            (0, 1)
+        }
+        POP | JUMP | SELFDESTRUCT => (1, 0),
+        MLOAD | SLOAD => (1, 1),
+        MSTORE | MSTORE8 | SSTORE | JUMPI | RETURN | REVERT => (2, 0),
+        PC | MSIZE | GAS => (0, 1),
+        PUSH1(_) | PUSHn(..) => (0, 1),
+        LOG0 => (2, 0),
+        LOG1 => (3, 0),
+        LOG2 => (4, 0),
+        LOG3 => (5, 0),
+        LOG4 => (6, 0),
+        CREATE => (3, 1),
+        CREATE2 => (4, 1),
+        CALL | CALLCODE => (7, 1),
+        DELEGATECALL | STATICCALL => (6, 1),
+        _ => return None,
+    })
+}
+
+/// Returns the gas an operator charges regardless of its operands, matching
+/// the `EVM.burn_static_gas` calls of the evmlib op handlers. The operator
+/// must have passed `check_program`.
+pub fn static_gas(op: &Opcode) -> u32 {
+    try_static_gas(op).expect("unsupported operators are rejected by `check_program`")
+}
+
+fn try_static_gas(op: &Opcode) -> Option<u32> {
+    use Opcode::*;
+    if dup_depth(op).is_some() || swap_depth(op).is_some() {
+        return Some(3);
+    }
+    Some(match op {
+        STOP | RETURN | REVERT => 0,
+        JUMPDEST => 1,
+        INVALID => 0, // consumes all remaining gas at run time
+        ADDRESS | ORIGIN | CALLER | CALLVALUE | CALLDATASIZE | CODESIZE | GASPRICE
+        | RETURNDATASIZE | COINBASE | TIMESTAMP | NUMBER | DIFFICULTY | GASLIMIT | CHAINID
+        | BASEFEE | POP | PC | MSIZE | GAS => 2,
+        ADD | SUB | LT | GT | SLT | SGT | EQ | ISZERO | AND | OR | XOR | NOT | BYTE | SHL | SHR
+        | SAR | CALLDATALOAD | CALLDATACOPY | CODECOPY | RETURNDATACOPY | MLOAD | MSTORE
+        | MSTORE8 => 3,
+        PUSH1(_) | PUSHn(..) => 3,
+        MUL | DIV | SDIV | MOD | SMOD | SIGNEXTEND | SELFBALANCE => 5,
+        ADDMOD | MULMOD | JUMP => 8,
+        EXP | JUMPI => 10,
+        BLOCKHASH => 20,
+        SHA3 => 30,
+        BALANCE | EXTCODESIZE | EXTCODECOPY | EXTCODEHASH | SLOAD | SSTORE | CALL | CALLCODE
+        | DELEGATECALL | STATICCALL => 100,
+        LOG0 => 375,
+        LOG1 => 750,
+        LOG2 => 1125,
+        LOG3 => 1500,
+        LOG4 => 1875,
+        SELFDESTRUCT => 5000,
+        CREATE | CREATE2 => 32000,
+        _ => return None,
+    })
+}
+
+/// Splits the static gas of a basic block into the amounts charged on block
+/// entry and right after each op observing the remaining gas, so that these
+/// observe exactly the gas an interpreter would have charged up to and
+/// including them.
+pub fn block_gas(ops: &[Opcode]) -> Vec<u32> {
+    let mut segments = vec![0];
+    for op in ops {
+        *segments.last_mut().unwrap() += static_gas(op);
+        if observes_gas(op) {
+            segments.push(0);
+        }
+    }
+    segments
+}
+
+/// Returns by how many words at most the stack grows while executing `ops`,
+/// over its depth before them.
+pub fn peak_growth(ops: &[Opcode]) -> usize {
+    let mut depth = 0isize;
+    let mut peak = 0;
+    for op in ops {
+        let (pops, pushes) = stack_effect(op);
+        depth += pushes as isize - pops as isize;
+        peak = peak.max(depth);
+    }
+    peak as usize
+}
+
+/// Returns whether the op handler of `op` depends on the remaining gas:
+/// `GAS` reads it, and calls and contract creations forward all but 1/64
+/// of it.
+pub fn observes_gas(op: &Opcode) -> bool {
+    use Opcode::*;
+    matches!(
+        op,
+        GAS | CALL | CALLCODE | DELEGATECALL | STATICCALL | CREATE | CREATE2
+    )
+}
+
+/// Returns whether the op handler of `op` can observe the program counter,
+/// i.e. whether it reads it, halts or can fail other than by overflowing or
+/// underflowing the stack. The others are pure operators and context reads.
+pub fn observes_pc(op: &Opcode) -> bool {
+    use Opcode::*;
+    if op.is_push() || dup_depth(op).is_some() || swap_depth(op).is_some() {
+        return false;
+    }
+    !matches!(
+        op,
+        JUMPDEST
+            | POP
+            | ADD
+            | MUL
+            | SUB
+            | DIV
+            | SDIV
+            | MOD
+            | SMOD
+            | ADDMOD
+            | MULMOD
+            | SIGNEXTEND
+            | LT
+            | GT
+            | SLT
+            | SGT
+            | EQ
+            | ISZERO
+            | AND
+            | OR
+            | XOR
+            | NOT
+            | BYTE
+            | SHL
+            | SHR
+            | SAR
+            | ADDRESS
+            | ORIGIN
+            | CALLER
+            | CALLVALUE
+            | CALLDATALOAD
+            | CALLDATASIZE
+            | CODESIZE
+            | GASPRICE
+            | RETURNDATASIZE
+            | COINBASE
+            | TIMESTAMP
+            | NUMBER
+            | DIFFICULTY
+            | GASLIMIT
+            | CHAINID
+            | SELFBALANCE
+            | BASEFEE
+            | MSIZE
+            | GAS
+    )
+}
+
+/// Returns the EVM opcode of the pure operators `_evm_arith` evaluates, for
+/// stackify to pass their operands and results in wasm locals. `EXP` is left
+/// out, as its gas depends on the exponent.
+pub fn arith_opcode(op: &Opcode) -> Option<u32> {
+    use Opcode::*;
+    Some(match op {
+        ADD => 0x01,
+        MUL => 0x02,
+        SUB => 0x03,
+        DIV => 0x04,
+        SDIV => 0x05,
+        MOD => 0x06,
+        SMOD => 0x07,
+        ADDMOD => 0x08,
+        MULMOD => 0x09,
+        SIGNEXTEND => 0x0b,
+        LT => 0x10,
+        GT => 0x11,
+        SLT => 0x12,
+        SGT => 0x13,
+        EQ => 0x14,
+        ISZERO => 0x15,
+        AND => 0x16,
+        OR => 0x17,
+        XOR => 0x18,
+        NOT => 0x19,
+        BYTE => 0x1a,
+        SHL => 0x1b,
+        SHR => 0x1c,
+        SAR => 0x1d,
+        _ => return None,
+    })
+}
+
+pub fn dup_depth(op: &Opcode) -> Option<usize> {
+    use Opcode::*;
+    Some(match op {
+        DUP1 => 1,
+        DUP2 => 2,
+        DUP3 => 3,
+        DUP4 => 4,
+        DUP5 => 5,
+        DUP6 => 6,
+        DUP7 => 7,
+        DUP8 => 8,
+        DUP9 => 9,
+        DUP10 => 10,
+        DUP11 => 11,
+        DUP12 => 12,
+        DUP13 => 13,
+        DUP14 => 14,
+        DUP15 => 15,
+        DUP16 => 16,
+        _ => return None,
+    })
+}
+
+pub fn swap_depth(op: &Opcode) -> Option<usize> {
+    use Opcode::*;
+    Some(match op {
+        SWAP1 => 1,
+        SWAP2 => 2,
+        SWAP3 => 3,
+        SWAP4 => 4,
+        SWAP5 => 5,
+        SWAP6 => 6,
+        SWAP7 => 7,
+        SWAP8 => 8,
+        SWAP9 => 9,
+        SWAP10 => 10,
+        SWAP11 => 11,
+        SWAP12 => 12,
+        SWAP13 => 13,
+        SWAP14 => 14,
+        SWAP15 => 15,
+        SWAP16 => 16,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use evm_rs::parse_opcode;
+
+    use super::*;
+    use crate::decode::decode_bytecode;
+
+    #[test]
+    fn test_stack_effect() {
+        assert_eq!(stack_effect(&Opcode::ADD), (2, 1));
+        assert_eq!(stack_effect(&Opcode::DUP3), (3, 4));
+        assert_eq!(stack_effect(&Opcode::SWAP16), (17, 17));
+        assert_eq!(stack_effect(&Opcode::LOG2), (4, 0));
+        assert_eq!(stack_effect(&Opcode::CALL), (7, 1));
+    }
+
+    #[test]
+    fn test_check_program() {
+        // 0x00: PUSH1 0x01 DUP1 ADD STOP
+        let program = decode_bytecode("6001800100").unwrap();
+        assert_eq!(check_program(&program), Ok(()));
+    }
+
+    #[test]
+    fn test_static_gas() {
+        assert_eq!(static_gas(&Opcode::PUSH1(0)), 3);
+        assert_eq!(static_gas(&Opcode::SWAP2), 3);
+        assert_eq!(static_gas(&Opcode::POP), 2);
+        assert_eq!(static_gas(&Opcode::MUL), 5);
+        assert_eq!(static_gas(&Opcode::SSTORE), 100);
+        assert_eq!(static_gas(&Opcode::JUMPDEST), 1);
+    }
+
+    #[test]
+    fn test_observes_pc() {
+        use Opcode::*;
+        for op in [
+            PC,
+            SSTORE,
+            CALL,
+            MLOAD,
+            CALLDATACOPY,
+            EXP,
+            SHA3,
+            RETURN,
+            REVERT,
+        ] {
+            assert!(observes_pc(&op), "{}", op);
+        }
+        for op in [PUSH1(0), DUP1, SWAP3, POP, ADD, DIV, LT, CALLVALUE, GAS] {
+            assert!(!observes_pc(&op), "{}", op);
+        }
+    }
+
+    #[test]
+    fn test_block_gas() {
+        use Opcode::*;
+        assert_eq!(block_gas(&[JUMPDEST, PUSH1(0), JUMP]), vec![12]);
+        assert_eq!(block_gas(&[JUMPDEST, GAS, POP, GAS]), vec![3, 4, 0]);
+        assert_eq!(block_gas(&[CALL, POP, STATICCALL]), vec![100, 102, 0]);
+        assert_eq!(
+            block_gas(&[PUSH1(0), DUP1, DUP1, CREATE2, POP]),
+            vec![32009, 2]
+        );
+        assert_eq!(block_gas(&[]), vec![0]);
+    }
+
+    #[test]
+    fn test_peak_growth() {
+        use Opcode::*;
+        assert_eq!(peak_growth(&[PUSH1(0), POP]), 1);
+        assert_eq!(peak_growth(&[DUP2, DUP2, ADD, POP]), 2);
+        assert_eq!(peak_growth(&[SWAP1, SWAP1]), 0);
+        assert_eq!(peak_growth(&[POP, PUSH1(0)]), 0);
+        assert_eq!(peak_growth(&[]), 0);
+    }
+
+    /// The static gas charged by each op handler, by opcode.
+    fn handler_gas() -> HashMap<Opcode, u32> {
+        let source = include_str!("../../../lib/evmlib/src/ops.rs");
+        let mut result = HashMap::new();
+        for handler in source.split("pub unsafe fn ").skip(1) {
+            let name = handler[..handler.find('(').unwrap()].trim_start_matches("r#");
+            let op = match parse_opcode(&name.to_ascii_uppercase()) {
+                Some(op) => op,
+                None => continue, // a fused entry point
+            };
+            let body = &handler[..handler.find("\n}").unwrap()];
+            let gas = match body.split_once("EVM.burn_static_gas(") {
+                Some((_, rest)) => rest[..rest.find(')').unwrap()].parse().unwrap(),
+                None => 0, // `INVALID` only burns all the gas
+            };
+            result.insert(op.zeroed(), gas);
+        }
+        result
+    }
+
+    #[test]
+    fn test_arith_opcodes_match_runtime() {
+        // the ops `_evm_arith` evaluates, as `0x01 => (3, add(a, b)),`
+        let source = include_str!("../../../lib/evmlib/src/arith.rs");
+        let mut count = 0;
+        for line in source
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("0x"))
+        {
+            let (opcode, rest) = line.split_once(" => (").unwrap();
+            let (gas, call) = rest.split_once(", ").unwrap();
+            let name = call[..call.find('(').unwrap()].trim_start_matches("r#");
+            let op = parse_opcode(&name.to_ascii_uppercase()).unwrap();
+            let opcode = u32::from_str_radix(opcode, 16).unwrap();
+            assert_eq!(arith_opcode(&op), Some(opcode), "{}", op);
+            assert_eq!(static_gas(&op), gas.parse().unwrap(), "{}", op);
+            count += 1;
+        }
+        assert_eq!(count, 24);
+    }
+
+    #[test]
+    fn test_block_gas_matches_handlers() {
+        use Opcode::*;
+        let handlers = handler_gas();
+        let block = [JUMPDEST, SLOAD, GAS, CALL, MSTORE, LOG0, CREATE, STOP];
+        let expected: u32 = block.iter().map(|op| handlers[&op.zeroed()]).sum();
+        assert_eq!(block_gas(&block).iter().sum::<u32>(), expected);
+        for (op, gas) in &handlers {
+            assert_eq!(static_gas(op), *gas, "{}", op);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+// This is free and unencumbered software released into the public domain.
+
+use ethnum::u256;
+use evm_rs::Opcode;
+
+use crate::ops::{dup_depth, swap_depth};
+
+/// Fused runtime entry points, each standing for a short operator sequence.
+/// They charge the static gas of the whole sequence, like the op handlers.
+#[derive(Clone, Copy, Debug)]
+pub struct FusedFunctions {
+    pub pop_n: u32,    // _evm_pop_n
+    pub swap_pop: u32, // _evm_swap_pop
+    pub bool: u32,     // _evm_bool
+    pub mask: u32,     // _evm_mask
+}
+
+/// A sequence of operators with the same stack effect as a cheaper one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rewrite {
+    /// The operators cancel out, e.g. `PUSH1 0x00 POP`.
+    Nop,
+    /// The operators cancel out but fault unless the stack holds the given
+    /// number of words, e.g. 2 for `SWAP1 SWAP1` or `DUP2 POP`.
+    Check(u32),
+    /// `POP` repeated the given number of times.
+    PopN(u32),
+    /// `SWAPn POP`, which removes the word `n` deep and keeps the top.
+    SwapPop(u32),
+    /// `ISZERO ISZERO`, which turns any word into 0 or 1.
+    Bool,
+    /// `PUSH 2^bits-1 AND`, which keeps the given number of low bits.
+    Mask(u32),
+}
+
+impl Rewrite {
+    /// Returns the result of the rewrite applied to a constant top of stack,
+    /// for the rewrites that only replace the top.
+    pub fn fold(&self, top: u256) -> Option<u256> {
+        match self {
+            Rewrite::Bool => Some(if top == 0 { u256::ZERO } else { u256::ONE }),
+            Rewrite::Mask(bits) => Some(top & mask(*bits)),
+            _ => None,
+        }
+    }
+}
+
+/// Matches the operators at the start of `code` against the known idioms,
+/// returning the rewrite and the number of operators it replaces.
+///
+/// Only sequences whose result does not depend on the stack contents are
+/// rewritten, so the stack is exact after every rewrite, and the rewrites
+/// fault on stack underflow where the operators would. Rewrites of sequences
+/// pushing before they pop, like `DUP2 POP`, do not check for stack overflow
+/// themselves: the caller checks for room for the `peak_growth` of the
+/// operators. Static gas is charged per block over the original operators
+/// and is unaffected.
+pub fn peephole(code: &[Opcode]) -> Option<(Rewrite, usize)> {
+    use Opcode::*;
+    match code {
+        [a, b, ..] if swap_depth(a).is_some() && a == b => {
+            let n: u32 = swap_depth(a).unwrap().try_into().unwrap();
+            Some((Rewrite::Check(n + 1), 2))
+        }
+        [a, POP, ..] if a.is_push() => Some((Rewrite::Nop, 2)),
+        [a, POP, ..] if dup_depth(a).is_some() => {
+            let n = dup_depth(a).unwrap().try_into().unwrap();
+            Some((Rewrite::Check(n), 2))
+        }
+        [a, POP, ..] if swap_depth(a).is_some() => {
+            let n = swap_depth(a).unwrap().try_into().unwrap();
+            Some((Rewrite::SwapPop(n), 2))
+        }
+        [POP, POP, ..] => {
+            let n = code.iter().take_while(|op| op == &&POP).count();
+            Some((Rewrite::PopN(n.try_into().unwrap()), n))
+        }
+        [ISZERO, ISZERO, ..] => Some((Rewrite::Bool, 2)),
+        [push, AND, ..] => {
+            let value = match push {
+                PUSH1(b) => u256::from(*b),
+                PUSHn(_, v, _) => *v,
+                _ => return None,
+            };
+            // the mask must be a run of low bits
+            if value == 0 || value & value.wrapping_add(u256::ONE) != 0 {
+                return None;
+            }
+            match 256 - value.leading_zeros() {
+                256 => Some((Rewrite::Check(1), 2)),
+                bits => Some((Rewrite::Mask(bits), 2)),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Returns the word with the given number of low bits set.
+fn mask(bits: u32) -> u256 {
+    if bits >= 256 {
+        u256::MAX
+    } else {
+        (u256::ONE << bits) - u256::ONE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::decode_bytecode;
+
+    fn rewrite(bytecode: &str) -> Option<(Rewrite, usize)> {
+        peephole(&decode_bytecode(bytecode).unwrap().0)
+    }
+
+    #[test]
+    fn test_cancelling_shuffles() {
+        assert_eq!(rewrite("9191"), Some((Rewrite::Check(3), 2))); // SWAP2 SWAP2
+        assert_eq!(rewrite("9190"), None); // SWAP2 SWAP1
+        assert_eq!(rewrite("8150"), Some((Rewrite::Check(2), 2))); // DUP2 POP
+        assert_eq!(rewrite("600050"), Some((Rewrite::Nop, 2))); // PUSH1 0x00 POP
+    }
+
+    #[test]
+    fn test_pops() {
+        assert_eq!(rewrite("925050"), Some((Rewrite::SwapPop(3), 2))); // SWAP3 POP POP
+        assert_eq!(rewrite("50505001"), Some((Rewrite::PopN(3), 3))); // POP POP POP ADD
+        assert_eq!(rewrite("5001"), None); // POP ADD
+    }
+
+    #[test]
+    fn test_masks() {
+        assert_eq!(rewrite("60ff16"), Some((Rewrite::Mask(8), 2))); // PUSH1 0xff AND
+        let address_mask = format!("73{}16", "ff".repeat(20));
+        assert_eq!(rewrite(&address_mask), Some((Rewrite::Mask(160), 2)));
+        let full_mask = format!("7f{}16", "ff".repeat(32));
+        assert_eq!(rewrite(&full_mask), Some((Rewrite::Check(1), 2)));
+        assert_eq!(rewrite("60f016"), None); // PUSH1 0xf0 AND
+        assert_eq!(rewrite("600016"), None); // PUSH1 0x00 AND
+    }
+
+    #[test]
+    fn test_fold() {
+        let w = |x: u64| u256::from(x);
+        assert_eq!(Rewrite::Bool.fold(w(42)), Some(w(1)));
+        assert_eq!(Rewrite::Bool.fold(w(0)), Some(w(0)));
+        assert_eq!(Rewrite::Mask(8).fold(w(0x1234)), Some(w(0x34)));
+        assert_eq!(Rewrite::Mask(256).fold(u256::MAX), Some(u256::MAX));
+        assert_eq!(Rewrite::PopN(2).fold(w(1)), None);
+    }
+}
@@ -0,0 +1,233 @@
+// This is free and unencumbered software released into the public domain.
+
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use wasm_encoder::{EntityType, ExportKind, Instruction};
+use wasmparser::{Operator, Payload};
+
+use crate::wasm_translate::{
+    parse,
+    translator::{code_with_calls, Elements},
+    ModuleBuilder,
+};
+
+/// Removes the functions of a module that are unreachable from its start
+/// function, its element segments, and the function exports `keep` selects
+/// by their position. The other function exports are dropped, while the
+/// exports of memories, tables and globals are kept.
+///
+/// Returns the shaken module along with the new index of each function, by
+/// its old index. The module is returned as is if functions are referenced
+/// from element expressions, which are not remapped.
+pub fn shake(
+    wasm: &[u8],
+    keep: impl Fn(usize) -> bool,
+) -> Result<(ModuleBuilder, Vec<Option<u32>>)> {
+    let mut builder = parse(wasm)?;
+    let imported = builder
+        .imports
+        .iter()
+        .filter(|import| matches!(import.ty, EntityType::Function(_)))
+        .count();
+    let count = imported + builder.functions.len();
+    if builder
+        .elements
+        .iter()
+        .any(|element| matches!(element.elements, Elements::Expressions(_)))
+    {
+        let identity = (0..count).map(|f| Some(f.try_into().unwrap())).collect();
+        return Ok((builder, identity));
+    }
+
+    // the functions each defined function calls or references
+    let mut bodies = vec![];
+    let mut callees = vec![];
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let Payload::CodeSectionEntry(body) = payload? {
+            let mut reader = body.get_operators_reader()?;
+            reader.allow_memarg64(true);
+            let mut refs = vec![];
+            for operator in reader {
+                match operator? {
+                    Operator::Call { function_index } | Operator::RefFunc { function_index } => {
+                        refs.push(function_index)
+                    }
+                    _ => {}
+                }
+            }
+            callees.push(refs);
+            bodies.push(body);
+        }
+    }
+
+    let mut roots: Vec<u32> = builder
+        .exports
+        .iter()
+        .enumerate()
+        .filter(|(i, export)| export.kind == ExportKind::Func && keep(*i))
+        .map(|(_, export)| export.index)
+        .collect();
+    roots.extend(
+        builder
+            .start_sect
+            .as_ref()
+            .map(|start| start.function_index),
+    );
+    for element in builder.elements.iter() {
+        if let Elements::Functions(functions) = &element.elements {
+            roots.extend(functions);
+        }
+    }
+    for global in builder.globals.iter() {
+        if let Instruction::RefFunc(f) = global.init_instr {
+            roots.push(f);
+        }
+    }
+
+    let mut reachable = vec![false; count];
+    let mut queue = VecDeque::from(roots);
+    while let Some(f) = queue.pop_front() {
+        let f = usize::try_from(f).unwrap();
+        if !std::mem::replace(&mut reachable[f], true) && f >= imported {
+            queue.extend(&callees[f - imported]);
+        }
+    }
+
+    let mut functions = vec![None; count];
+    let mut next = 0;
+    for (f, new_index) in functions.iter_mut().enumerate() {
+        if reachable[f] {
+            *new_index = Some(next);
+            next += 1;
+        }
+    }
+    let map = |f: u32| functions[usize::try_from(f).unwrap()].unwrap();
+
+    let mut f = 0;
+    builder.imports.retain(|import| match import.ty {
+        EntityType::Function(_) => {
+            f += 1;
+            reachable[f - 1]
+        }
+        _ => true,
+    });
+    let mut code = vec![];
+    let mut types = vec![];
+    for (i, (body, type_index)) in bodies.into_iter().zip(&builder.functions).enumerate() {
+        if reachable[imported + i] {
+            code.push(code_with_calls(body, map)?);
+            types.push(*type_index);
+        }
+    }
+    builder.code = code;
+    builder.functions = types;
+
+    let mut i = 0;
+    builder.exports.retain(|export| {
+        i += 1;
+        export.kind != ExportKind::Func || keep(i - 1)
+    });
+    for export in builder.exports.iter_mut() {
+        if export.kind == ExportKind::Func {
+            export.index = map(export.index);
+        }
+    }
+    if let Some(start) = builder.start_sect.as_mut() {
+        start.function_index = map(start.function_index);
+    }
+    for element in builder.elements.iter_mut() {
+        if let Elements::Functions(functions) = &mut element.elements {
+            functions.iter_mut().for_each(|f| *f = map(*f));
+        }
+    }
+    for global in builder.globals.iter_mut() {
+        if let Instruction::RefFunc(f) = &mut global.init_instr {
+            *f = map(*f);
+        }
+    }
+    Ok((builder, functions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_encoder::{
+        CodeSection, ConstExpr, ElementSection, Elements as ElementItems, ExportSection, Function,
+        FunctionSection, ImportSection, Module, RefType, TableSection, TableType, TypeSection,
+    };
+
+    /// Imports `used` and `unused`, then defines `a` calling `b` and
+    /// `used`, `b`, `c` calling `a`, and `d` in the table.
+    fn module() -> Vec<u8> {
+        let mut types = TypeSection::new();
+        types.function([], []);
+        let mut imports = ImportSection::new();
+        imports.import("env", "used", EntityType::Function(0));
+        imports.import("env", "unused", EntityType::Function(0));
+        let mut functions = FunctionSection::new();
+        let mut codes = CodeSection::new();
+        for callees in [&[3, 0][..], &[], &[2], &[]] {
+            functions.function(0);
+            let mut body = Function::new([]);
+            for &f in callees {
+                body.instruction(&Instruction::Call(f));
+            }
+            body.instruction(&Instruction::End);
+            codes.function(&body);
+        }
+        let mut tables = TableSection::new();
+        tables.table(TableType {
+            element_type: RefType::FUNCREF,
+            minimum: 1,
+            maximum: None,
+        });
+        let mut exports = ExportSection::new();
+        exports.export("c", ExportKind::Func, 4);
+        exports.export("a", ExportKind::Func, 2);
+        exports.export("table", ExportKind::Table, 0);
+        let mut elements = ElementSection::new();
+        let offset = ConstExpr::i32_const(0);
+        elements.active(
+            None,
+            &offset,
+            RefType::FUNCREF,
+            ElementItems::Functions(&[5]),
+        );
+
+        let mut module = Module::new();
+        module
+            .section(&types)
+            .section(&imports)
+            .section(&functions)
+            .section(&tables)
+            .section(&exports)
+            .section(&elements)
+            .section(&codes);
+        module.finish()
+    }
+
+    #[test]
+    fn test_shake() {
+        let wasm = module();
+        let (builder, functions) = shake(&wasm, |export| export == 1).unwrap();
+        assert_eq!(functions, [Some(0), None, Some(1), Some(2), None, Some(3)]);
+        assert_eq!(builder.imports.len(), 1);
+        assert_eq!(builder.functions.len(), 3);
+        let exports: Vec<_> = builder
+            .exports
+            .iter()
+            .map(|e| (e.name.as_str(), e.index))
+            .collect();
+        assert_eq!(exports, [("a", 1), ("table", 0)]);
+        wasmparser::validate(builder.build().as_slice()).unwrap();
+
+        // everything is reachable when all exports are kept
+        let (builder, functions) = shake(&wasm, |_| true).unwrap();
+        assert_eq!(
+            functions,
+            [Some(0), None, Some(1), Some(2), Some(3), Some(4)]
+        );
+        assert_eq!(builder.exports.len(), 3);
+    }
+}
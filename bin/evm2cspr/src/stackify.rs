@@ -0,0 +1,577 @@
+// This is free and unencumbered software released into the public domain.
+
+use ethnum::u256;
+use evm_rs::Opcode;
+use wasm_encoder::{Instruction, MemArg};
+
+use crate::{
+    fold::{fold, identity_operand},
+    inline::{inline_word_op, InlineRuntime, STACK_SLOTS},
+    ops::{arith_opcode, dup_depth, stack_effect, swap_depth},
+    peephole::Rewrite,
+};
+
+/// Every compiled function declares a scratch `i32` local first.
+pub const SCRATCH_LOCAL: u32 = 0;
+
+/// Functions compiled from EVM code declare the `i32` local of their
+/// dispatcher next, see `CfgNode::Dispatch`.
+pub const DISPATCH_LOCAL: u32 = 1;
+
+/// Stack words kept in locals start right after the `i32` ones.
+const FIRST_WORD_LOCAL: u32 = 2;
+
+/// Runtime entry points used to move words between wasm locals and the
+/// runtime `EVM.stack`, and to evaluate pure ops over words in locals.
+#[derive(Clone, Copy, Debug)]
+pub struct StackFunctions {
+    pub push: u32,  // _evm_push_u256
+    pub pop: u32,   // _evm_pop_u32
+    pub slot: u32,  // _evm_stack_slot
+    pub room: u32,  // _evm_stack_room
+    pub arith: u32, // _evm_arith
+}
+
+impl StackFunctions {
+    /// Faults unless the runtime stack holds at least `n` words, by taking
+    /// the address of the deepest of them.
+    pub fn check_depth(&self, n: u32, res: &mut Vec<Instruction>) {
+        res.push(Instruction::I32Const((n - 1).try_into().unwrap()));
+        res.push(Instruction::Call(self.slot));
+        res.push(Instruction::Drop);
+    }
+
+    /// Faults unless the runtime stack has room for `n` more words.
+    pub fn check_room(&self, n: u32, res: &mut Vec<Instruction>) {
+        res.push(Instruction::I32Const(n.try_into().unwrap()));
+        res.push(Instruction::Call(self.room));
+    }
+}
+
+/// A stack word whose location is tracked by the compiler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value {
+    /// A compile-time constant.
+    Const(u256),
+    /// A word held in four consecutive `i64` locals, least significant limb first.
+    Local(u32),
+}
+
+/// Models the words pushed on top of the runtime stack within a basic block.
+///
+/// The stack shuffles `PUSH`, `DUP`, `SWAP` and `POP` are executed at
+/// compile time on these words. Pure ops over tracked words compute their
+/// result into fresh locals, as wasm code for the ops `inline_op` compiles
+/// and through `_evm_arith` for the others, and so does `CALLVALUE` when
+/// ops are inlined. Every other op spills the words to the runtime stack
+/// first and its handler still pops its operands from there, so the runtime
+/// stack is always exact at block boundaries and around op handler calls.
+/// The gas of the shuffles is part of the static gas charged on block
+/// entry. With constant folding enabled, pure operators over tracked
+/// constants are evaluated at compile time instead.
+///
+/// Locals are reused once no tracked word refers to them, and there are as
+/// many as the runtime stack has slots; an op finding none left is compiled
+/// against the runtime stack instead.
+///
+/// Words that never reach the runtime stack cannot overflow it, so the most
+/// words tracked at once are checked for room before spilling them. The
+/// check comes later than the overflowing op within the block, which makes
+/// no difference: either way the block faults before its exit.
+pub struct VirtualStack {
+    funcs: StackFunctions,
+    inline: Option<InlineRuntime>,
+    fold: bool,
+    slots: Vec<Value>,
+    /// The most words tracked at once since the last spill, or reserved.
+    peak: usize,
+    max_local: u32,
+}
+
+impl VirtualStack {
+    pub fn new(funcs: StackFunctions, inline: Option<InlineRuntime>, fold: bool) -> Self {
+        Self {
+            funcs,
+            inline,
+            fold,
+            slots: Vec::new(),
+            peak: 0,
+            max_local: FIRST_WORD_LOCAL,
+        }
+    }
+
+    /// Number of `i64` locals the function needs beyond the `i32` ones.
+    pub fn word_locals(&self) -> u32 {
+        self.max_local - FIRST_WORD_LOCAL
+    }
+
+    #[allow(dead_code)]
+    pub fn values(&self) -> &[Value] {
+        &self.slots
+    }
+
+    /// Executes `op` at offset `pc` at compile time if possible, emitting at
+    /// most loads from the runtime stack and code over locals. Returns
+    /// `false` if `op` needs the runtime stack.
+    pub fn apply(&mut self, op: &Opcode, pc: usize, res: &mut Vec<Instruction>) -> bool {
+        use Opcode::*;
+        match op {
+            PUSH1(b) => self.slots.push(Value::Const(u256::from(*b))),
+            PUSHn(_, v, _) => self.slots.push(Value::Const(*v)),
+            POP if !self.slots.is_empty() => _ = self.slots.pop(),
+            op => match (dup_depth(op), swap_depth(op)) {
+                (Some(n), _) if n <= self.slots.len() => {
+                    let value = self.slots[self.slots.len() - n];
+                    self.slots.push(value);
+                }
+                (Some(n), _) => match self.load(n - 1 - self.slots.len(), res) {
+                    Some(value) => self.slots.push(value),
+                    None => return false,
+                },
+                (_, Some(n)) if n < self.slots.len() => {
+                    let top = self.slots.len() - 1;
+                    self.slots.swap(top, top - n);
+                }
+                (None, None) => {
+                    let value = match self.fold {
+                        true => self.fold_top(op),
+                        false => None,
+                    };
+                    match value.or_else(|| self.compute(op, pc, res)) {
+                        Some(value) => self.slots.push(value),
+                        None => return false,
+                    }
+                }
+                _ => return false,
+            },
+        }
+        self.peak = self.peak.max(self.slots.len());
+        true
+    }
+
+    /// Reserves room for `n` words above the tracked ones, which the ops
+    /// replaced by a rewrite push before popping them again.
+    pub fn reserve(&mut self, n: usize) {
+        self.peak = self.peak.max(self.slots.len() + n);
+    }
+
+    /// Executes a peephole rewrite at compile time as far as the tracked
+    /// words allow, returning what is left for the runtime.
+    pub fn apply_rewrite(&mut self, rewrite: Rewrite) -> Option<Rewrite> {
+        let len = self.slots.len();
+        match rewrite {
+            Rewrite::Nop => None,
+            Rewrite::Check(n) => {
+                let n = n - u32::try_from(len).unwrap().min(n);
+                (n > 0).then_some(Rewrite::Check(n))
+            }
+            Rewrite::PopN(n) => {
+                let tracked = len.min(n.try_into().unwrap());
+                self.slots.truncate(len - tracked);
+                let n = n - u32::try_from(tracked).unwrap();
+                (n > 0).then_some(Rewrite::PopN(n))
+            }
+            Rewrite::SwapPop(n) if usize::try_from(n).unwrap() < len => {
+                self.slots
+                    .swap(len - 1, len - 1 - usize::try_from(n).unwrap());
+                self.slots.pop();
+                None
+            }
+            rewrite if self.fold => match self.slots.last() {
+                Some(Value::Const(top)) => match rewrite.fold(*top) {
+                    Some(result) => {
+                        self.slots[len - 1] = Value::Const(result);
+                        None
+                    }
+                    None => Some(rewrite),
+                },
+                _ => Some(rewrite),
+            },
+            rewrite => Some(rewrite),
+        }
+    }
+
+    /// Pops the operands of `op` if its result can be determined from the
+    /// tracked words, returning the result.
+    fn fold_top(&mut self, op: &Opcode) -> Option<Value> {
+        let (pops, pushes) = stack_effect(op);
+        if pushes != 1 || pops == 0 || pops > self.slots.len() {
+            return None;
+        }
+        let args: Vec<Value> = self.slots.iter().rev().take(pops).copied().collect();
+        let consts: Vec<Option<u256>> = args
+            .iter()
+            .map(|value| match value {
+                Value::Const(v) => Some(*v),
+                Value::Local(_) => None,
+            })
+            .collect();
+        let result = match consts.iter().copied().collect::<Option<Vec<_>>>() {
+            Some(consts) => Value::Const(fold(op, &consts)?),
+            None => args[identity_operand(op, &consts)?],
+        };
+        self.slots.truncate(self.slots.len() - pops);
+        Some(result)
+    }
+
+    /// Pops the operands of `op` if it can be computed from the tracked
+    /// words, emitting the code computing its result into fresh locals.
+    fn compute(&mut self, op: &Opcode, pc: usize, res: &mut Vec<Instruction>) -> Option<Value> {
+        if *op == Opcode::PC {
+            return Some(Value::Const(u256::new(pc as u128)));
+        }
+        let (pops, pushes) = stack_effect(op);
+        if pushes != 1 || pops > self.slots.len() {
+            return None;
+        }
+        let local = self.free_local()?;
+        let args = &self.slots[self.slots.len() - pops..];
+        match self
+            .inline
+            .and_then(|runtime| inline_word_op(op, args, local, runtime))
+        {
+            Some(code) => res.extend(code),
+            None => {
+                let opcode = arith_opcode(op)?;
+                res.push(Instruction::I32Const(opcode.try_into().unwrap()));
+                for slot in 0..3 {
+                    match args.iter().rev().nth(slot) {
+                        Some(value) => push_word(*value, res),
+                        None => push_word(Value::Const(u256::ZERO), res),
+                    }
+                }
+                res.push(Instruction::Call(self.funcs.arith));
+                res.push(Instruction::LocalSet(SCRATCH_LOCAL));
+                load_limbs(local, res);
+            }
+        }
+        self.slots.truncate(self.slots.len() - pops);
+        self.max_local = self.max_local.max(local + 4);
+        Some(Value::Local(local))
+    }
+
+    /// Returns the first of four locals no tracked word refers to.
+    fn free_local(&self) -> Option<u32> {
+        (0..STACK_SLOTS)
+            .map(|i| FIRST_WORD_LOCAL + 4 * i)
+            .find(|local| !self.slots.contains(&Value::Local(*local)))
+    }
+
+    /// Discards the top `n` words, popping them from the runtime stack if
+    /// they are not tracked.
+    pub fn discard(&mut self, n: usize, res: &mut Vec<Instruction>) {
+        for _ in 0..n {
+            if self.slots.is_empty() {
+                self.check_peak(res);
+            }
+            if self.slots.pop().is_none() {
+                res.push(Instruction::Call(self.funcs.pop));
+                res.push(Instruction::Drop);
+            }
+        }
+    }
+
+    /// Spills all tracked words to the runtime stack.
+    pub fn flush(&mut self, res: &mut Vec<Instruction>) {
+        self.check_peak(res);
+        for value in self.slots.drain(..) {
+            push_word(value, res);
+            res.push(Instruction::Call(self.funcs.push));
+        }
+    }
+
+    /// Checks for room for the most words tracked at once, unless pushing
+    /// the tracked words checks for it already.
+    fn check_peak(&mut self, res: &mut Vec<Instruction>) {
+        if self.peak > self.slots.len() {
+            self.funcs.check_room(self.peak.try_into().unwrap(), res);
+        }
+        self.peak = 0;
+    }
+
+    /// Copies the runtime stack slot `depth` words below the top into fresh locals.
+    fn load(&mut self, depth: usize, res: &mut Vec<Instruction>) -> Option<Value> {
+        let local = self.free_local()?;
+        self.max_local = self.max_local.max(local + 4);
+
+        res.push(Instruction::I32Const(depth.try_into().unwrap()));
+        res.push(Instruction::Call(self.funcs.slot));
+        res.push(Instruction::LocalSet(SCRATCH_LOCAL));
+        load_limbs(local, res);
+        Some(Value::Local(local))
+    }
+}
+
+/// Pushes the four limbs of a tracked word on the wasm stack.
+fn push_word(value: Value, res: &mut Vec<Instruction>) {
+    match value {
+        Value::Const(v) => res.extend(limbs(v).map(Instruction::I64Const)),
+        Value::Local(l) => res.extend((l..l + 4).map(Instruction::LocalGet)),
+    }
+}
+
+/// Copies the word at the address in the scratch local into four locals.
+fn load_limbs(local: u32, res: &mut Vec<Instruction>) {
+    for limb in 0..4 {
+        res.push(Instruction::LocalGet(SCRATCH_LOCAL));
+        res.push(Instruction::I64Load(MemArg {
+            offset: 8 * u64::from(limb),
+            align: 3,
+            memory_index: 0,
+        }));
+        res.push(Instruction::LocalSet(local + limb));
+    }
+}
+
+/// Splits a word into `i64` limbs, least significant first.
+pub fn limbs(v: u256) -> [i64; 4] {
+    let (hi, lo) = v.into_words();
+    [
+        lo as u64 as i64,
+        (lo >> 64) as u64 as i64,
+        hi as u64 as i64,
+        (hi >> 64) as u64 as i64,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FUNCS: StackFunctions = StackFunctions {
+        push: 1,
+        pop: 4,
+        slot: 2,
+        room: 3,
+        arith: 5,
+    };
+
+    #[test]
+    fn shuffles_are_virtual() {
+        let mut stack = VirtualStack::new(FUNCS, None, true);
+        let mut res = vec![];
+        for op in [
+            Opcode::PUSH1(1),
+            Opcode::PUSH1(2),
+            Opcode::SWAP1,
+            Opcode::DUP2,
+            Opcode::POP,
+        ] {
+            assert!(stack.apply(&op, 0, &mut res));
+        }
+        assert!(res.is_empty());
+        assert_eq!(
+            stack.values(),
+            &[Value::Const(u256::from(2u8)), Value::Const(u256::ONE)]
+        );
+
+        stack.flush(&mut res);
+        let calls: Vec<_> = res
+            .iter()
+            .filter_map(|i| match i {
+                Instruction::Call(f) => Some(*f),
+                _ => None,
+            })
+            .collect();
+        // DUP2 had 3 words tracked at once
+        assert_eq!(calls, vec![FUNCS.room, FUNCS.push, FUNCS.push]);
+    }
+
+    #[test]
+    fn dup_loads_runtime_slot() {
+        let mut stack = VirtualStack::new(FUNCS, None, true);
+        let mut res = vec![];
+        assert!(stack.apply(&Opcode::DUP2, 0, &mut res));
+        assert!(matches!(res[0], Instruction::I32Const(1)));
+        assert!(matches!(res[1], Instruction::Call(2)));
+        assert_eq!(stack.values(), &[Value::Local(FIRST_WORD_LOCAL)]);
+        assert_eq!(stack.word_locals(), 4);
+    }
+
+    #[test]
+    fn runtime_ops_are_rejected() {
+        let mut stack = VirtualStack::new(FUNCS, None, true);
+        let mut res = vec![];
+        assert!(!stack.apply(&Opcode::ADD, 0, &mut res));
+        assert!(!stack.apply(&Opcode::POP, 0, &mut res));
+        assert!(!stack.apply(&Opcode::SWAP1, 0, &mut res));
+    }
+
+    #[test]
+    fn constants_are_folded() {
+        let mut stack = VirtualStack::new(FUNCS, None, true);
+        let mut res = vec![];
+        // PUSH1 0x20 PUSH1 0x40 ADD PUSH1 0x00 ISZERO
+        for op in [
+            Opcode::PUSH1(0x20),
+            Opcode::PUSH1(0x40),
+            Opcode::ADD,
+            Opcode::PUSH1(0x00),
+            Opcode::ISZERO,
+        ] {
+            assert!(stack.apply(&op, 0, &mut res));
+        }
+        assert_eq!(
+            stack.values(),
+            &[Value::Const(u256::from(0x60u8)), Value::Const(u256::ONE)]
+        );
+    }
+
+    #[test]
+    fn full_mask_is_dropped() {
+        let mut stack = VirtualStack::new(FUNCS, None, true);
+        let mut res = vec![];
+        assert!(stack.apply(&Opcode::DUP1, 0, &mut res));
+        let mask = Opcode::PUSHn(32, u256::MAX, vec![0xff; 32]);
+        assert!(stack.apply(&mask, 0, &mut res));
+        assert!(stack.apply(&Opcode::AND, 0, &mut res));
+        assert_eq!(stack.values(), &[Value::Local(FIRST_WORD_LOCAL)]);
+    }
+
+    #[test]
+    fn folding_can_be_disabled() {
+        let mut stack = VirtualStack::new(FUNCS, None, false);
+        let mut res = vec![];
+        assert!(stack.apply(&Opcode::PUSH1(1), 0, &mut res));
+        assert!(stack.apply(&Opcode::PUSH1(1), 0, &mut res));
+        assert!(stack.apply(&Opcode::ADD, 0, &mut res));
+        assert_eq!(stack.values(), &[Value::Local(FIRST_WORD_LOCAL)]);
+        assert!(res.iter().any(|i| matches!(i, Instruction::Call(5))));
+    }
+
+    #[test]
+    fn pure_ops_use_locals() {
+        let mut stack = VirtualStack::new(FUNCS, None, true);
+        let mut res = vec![];
+        // DUP1 PUSH1 0x01 ADD PC
+        assert!(stack.apply(&Opcode::DUP1, 0, &mut res));
+        res.clear();
+        assert!(stack.apply(&Opcode::PUSH1(1), 1, &mut res));
+        assert!(stack.apply(&Opcode::ADD, 3, &mut res));
+        assert!(stack.apply(&Opcode::PC, 4, &mut res));
+        let next = FIRST_WORD_LOCAL + 4;
+        assert_eq!(
+            stack.values(),
+            &[Value::Local(next), Value::Const(u256::from(4u8))]
+        );
+        // ADD, then the top operand, the loaded word and a zero
+        assert!(matches!(res[0], Instruction::I32Const(0x01)));
+        assert!(matches!(res[1], Instruction::I64Const(1)));
+        assert!(matches!(res[5], Instruction::LocalGet(FIRST_WORD_LOCAL)));
+        assert!(matches!(res[13], Instruction::Call(5)));
+        assert!(matches!(res.last(), Some(Instruction::LocalSet(l)) if *l == next + 3));
+
+        // the locals of the loaded word are free again
+        assert!(stack.apply(&Opcode::POP, 5, &mut res));
+        assert!(stack.apply(&Opcode::NOT, 6, &mut res));
+        assert_eq!(stack.values(), &[Value::Local(FIRST_WORD_LOCAL)]);
+        assert_eq!(stack.word_locals(), 8);
+
+        // ops with side effects still need the runtime stack
+        assert!(!stack.apply(&Opcode::SLOAD, 7, &mut res));
+        assert!(!stack.apply(&Opcode::CALLVALUE, 7, &mut res));
+    }
+
+    #[test]
+    fn inlined_ops_use_locals() {
+        let runtime = InlineRuntime {
+            stack: 0,
+            call_value: 1,
+            set_pc: None,
+            fault: 6,
+        };
+        let mut stack = VirtualStack::new(FUNCS, Some(runtime), false);
+        let mut res = vec![];
+        for op in [Opcode::CALLVALUE, Opcode::PUSH1(1), Opcode::EQ] {
+            assert!(stack.apply(&op, 0, &mut res));
+        }
+        assert!(matches!(res[0], Instruction::GlobalGet(1)));
+        assert!(!res.iter().any(|i| matches!(i, Instruction::Call(_))));
+        assert_eq!(stack.values(), &[Value::Local(FIRST_WORD_LOCAL + 4)]);
+    }
+
+    #[test]
+    fn rewrites_use_tracked_words() {
+        let mut stack = VirtualStack::new(FUNCS, None, true);
+        let mut res = vec![];
+        for op in [Opcode::PUSH1(1), Opcode::PUSH1(2), Opcode::PUSH1(0x34)] {
+            assert!(stack.apply(&op, 0, &mut res));
+        }
+        assert_eq!(stack.apply_rewrite(Rewrite::Mask(4)), None);
+        assert_eq!(stack.apply_rewrite(Rewrite::SwapPop(1)), None);
+        assert_eq!(
+            stack.values(),
+            &[Value::Const(u256::ONE), Value::Const(u256::from(4u8))]
+        );
+        assert_eq!(
+            stack.apply_rewrite(Rewrite::SwapPop(2)),
+            Some(Rewrite::SwapPop(2))
+        );
+        assert_eq!(
+            stack.apply_rewrite(Rewrite::PopN(3)),
+            Some(Rewrite::PopN(1))
+        );
+        assert!(stack.values().is_empty());
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn underflowing_checks_remain() {
+        let mut stack = VirtualStack::new(FUNCS, None, true);
+        let mut res = vec![];
+        for op in [Opcode::PUSH1(1), Opcode::PUSH1(2), Opcode::PUSH1(3)] {
+            assert!(stack.apply(&op, 0, &mut res));
+        }
+        assert_eq!(stack.apply_rewrite(Rewrite::Check(2)), None);
+        // DUP5 POP on the 3 tracked words needs 2 more on the runtime stack
+        let rewrite = stack.apply_rewrite(Rewrite::Check(5));
+        assert_eq!(rewrite, Some(Rewrite::Check(2)));
+        FUNCS.check_depth(2, &mut res);
+        // `_evm_stack_slot` faults unless the slot is on the stack
+        assert!(matches!(res[0], Instruction::I32Const(1)));
+        assert!(matches!(res[1], Instruction::Call(2)));
+        assert!(matches!(res[2], Instruction::Drop));
+    }
+
+    #[test]
+    fn overflows_are_checked_on_flush() {
+        let mut stack = VirtualStack::new(FUNCS, None, true);
+        let mut res = vec![];
+        // PUSH1 0x01 PUSH1 0x02 ADD DUP1 POP
+        for op in [
+            Opcode::PUSH1(1),
+            Opcode::PUSH1(2),
+            Opcode::ADD,
+            Opcode::DUP1,
+            Opcode::POP,
+        ] {
+            assert!(stack.apply(&op, 0, &mut res));
+        }
+        assert!(res.is_empty());
+        stack.flush(&mut res);
+        assert!(matches!(res[0], Instruction::I32Const(2)));
+        assert!(matches!(res[1], Instruction::Call(3)));
+        assert!(matches!(res.last(), Some(Instruction::Call(1))));
+
+        // spilling as many words as were tracked checks for room already
+        res.clear();
+        assert!(stack.apply(&Opcode::PUSH1(1), 0, &mut res));
+        stack.flush(&mut res);
+        assert!(!res.iter().any(|i| matches!(i, Instruction::Call(3))));
+
+        // the push of `PUSH1 0x00 POP` is reserved
+        res.clear();
+        stack.reserve(1);
+        assert_eq!(stack.apply_rewrite(Rewrite::Nop), None);
+        stack.discard(1, &mut res);
+        assert!(matches!(res[0], Instruction::I32Const(1)));
+        assert!(matches!(res[1], Instruction::Call(3)));
+        assert!(matches!(res[2], Instruction::Call(4)));
+    }
+
+    #[test]
+    fn limbs_are_little_endian() {
+        let v = u256::from_words(0x3_0000_0000_0000_0002, 0x1_0000_0000_0000_0000);
+        assert_eq!(limbs(v), [0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,583 @@
+// This is free and unencumbered software released into the public domain.
+
+use std::{collections::HashMap, fmt};
+
+use wasmparser::{
+    BinaryReaderError, FunctionBody, Operator, Parser, Payload, TypeRef, ValidPayload, Validator,
+    WasmFeatures,
+};
+
+use crate::analyze::Offs;
+
+/// Host functions a Casper node resolves in the `env` module, as listed by
+/// `execution_engine/src/resolvers/v1_resolver.rs` of casper-node 1.5, i.e.
+/// casper-execution-engine 5.0, which `my-project` tests contracts with.
+/// `casper_print` is only resolved by test builds of the node, and `gas` is
+/// rejected, as it is injected by the node itself.
+const CASPER_HOST_FUNCTIONS: &[&str] = &[
+    "casper_read_value",
+    "casper_load_named_keys",
+    "casper_write",
+    "casper_add",
+    "casper_new_uref",
+    "casper_ret",
+    "casper_get_key",
+    "casper_has_key",
+    "casper_put_key",
+    "casper_remove_key",
+    "casper_revert",
+    "casper_is_valid_uref",
+    "casper_add_associated_key",
+    "casper_remove_associated_key",
+    "casper_update_associated_key",
+    "casper_set_action_threshold",
+    "casper_get_caller",
+    "casper_get_blocktime",
+    "casper_create_purse",
+    "casper_transfer_to_account",
+    "casper_transfer_from_purse_to_account",
+    "casper_transfer_from_purse_to_purse",
+    "casper_get_balance",
+    "casper_get_phase",
+    "casper_get_system_contract",
+    "casper_get_main_purse",
+    "casper_read_host_buffer",
+    "casper_create_contract_package_at_hash",
+    "casper_create_contract_user_group",
+    "casper_add_contract_version",
+    "casper_disable_contract_version",
+    "casper_enable_contract_version",
+    "casper_call_contract",
+    "casper_call_versioned_contract",
+    "casper_get_named_arg_size",
+    "casper_get_named_arg",
+    "casper_remove_contract_user_group",
+    "casper_provision_contract_user_group_uref",
+    "casper_remove_contract_user_group_urefs",
+    "casper_blake2b",
+    "casper_new_dictionary",
+    "casper_dictionary_get",
+    "casper_dictionary_read",
+    "casper_dictionary_put",
+    "casper_load_call_stack",
+    "casper_load_authorization_keys",
+    "casper_random_bytes",
+];
+
+/// Limits a Casper node enforces on deployed modules, on top of only
+/// accepting wasm 1.0 without floating-point operators and with a memory of
+/// their own. The defaults are those of casper-node 1.5: the `[wasm]` and
+/// `[deploys]` sections of `resources/production/chainspec.toml`, and the
+/// constants of the preprocessing in `execution_engine/src/shared/wasm_prep.rs`.
+#[derive(Clone, Debug)]
+pub struct CasperRules {
+    /// `wasm.max_memory` in the chainspec.
+    pub max_memory_pages: u64,
+    /// `DEFAULT_MAX_TABLE_SIZE` in the preprocessing.
+    pub max_table_size: u32,
+    /// `DEFAULT_BR_TABLE_MAX_SIZE` in the preprocessing.
+    pub max_br_table_size: usize,
+    /// `DEFAULT_MAX_GLOBALS` in the preprocessing.
+    pub max_globals: u32,
+    /// `DEFAULT_MAX_PARAMETER_COUNT` in the preprocessing.
+    pub max_params: usize,
+    /// `wasm.max_stack_height` in the chainspec. Locals count towards the
+    /// stack height, so a function with more locals than that traps whenever
+    /// it is called.
+    pub max_locals: u32,
+    /// In bytes, `deploys.max_deploy_size` in the chainspec. A function can't
+    /// be larger than a deploy.
+    pub max_function_size: usize,
+    /// The module functions may be imported from besides `env`, when linking
+    /// against a shared runtime, which also provides the memory.
+    pub runtime_module: Option<String>,
+}
+
+impl Default for CasperRules {
+    fn default() -> Self {
+        Self {
+            max_memory_pages: 64,
+            max_table_size: 4096,
+            max_br_table_size: 256,
+            max_globals: 256,
+            max_params: 256,
+            max_locals: 500,
+            max_function_size: 1024 * 1024,
+            runtime_module: None,
+        }
+    }
+}
+
+/// What a function of the output module was compiled from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// A function synthesized by the compiler, e.g. `_evm_start`.
+    Export(String),
+    /// The wrapper of an ABI method.
+    Method(String),
+    /// EVM code, i.e. `_evm_exec` or a recovered internal function, as
+    /// described by `name`. `ops` maps the index of the first wasm
+    /// instruction of each compiled EVM operator to the offsets of that
+    /// operator and of its block.
+    Code {
+        name: String,
+        ops: Vec<(usize, Offs, Offs)>,
+    },
+}
+
+/// A validation error or a Casper rule broken by the output module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// Where the violation is, if in a function.
+    pub location: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {}", location, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Validates the output module, with the Casper rules if given, reporting
+/// violations in functions against what they were compiled from. Functions
+/// without an origin come from the runtime library.
+pub fn validate(
+    wasm: &[u8],
+    origins: &HashMap<u32, Origin>,
+    rules: Option<&CasperRules>,
+) -> Result<(), Vec<Violation>> {
+    let mut checker = Checker {
+        origins,
+        rules,
+        violations: Vec::new(),
+        imported_functions: 0,
+        defined_functions: 0,
+        memories: 0,
+    };
+    let features = match rules {
+        // only wasm 1.0 is supported, see `wasm_translate::parse`
+        Some(_) => WasmFeatures {
+            mutable_global: false,
+            saturating_float_to_int: false,
+            sign_extension: false,
+            reference_types: false,
+            multi_value: false,
+            bulk_memory: false,
+            simd: false,
+            floats: false,
+            ..Default::default()
+        },
+        None => WasmFeatures::default(),
+    };
+    let mut validator = Validator::new_with_features(features);
+    for payload in Parser::new(0).parse_all(wasm) {
+        let payload = match payload {
+            Ok(payload) => payload,
+            Err(err) => {
+                checker.module_error(err);
+                break;
+            }
+        };
+        if let Err(err) = checker.check(&payload) {
+            checker.module_error(err);
+            break;
+        }
+        match validator.payload(&payload) {
+            Ok(ValidPayload::Func(func, body)) => {
+                let mut func = func.into_validator(Default::default());
+                if let Err(err) = func.validate(&body) {
+                    checker.function_error(func.index(), &body, err.offset(), err.message());
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                // the module structure is broken, so nothing else is reliable
+                checker.module_error(err);
+                break;
+            }
+        }
+    }
+    if rules.is_some() && checker.memories == 0 {
+        checker.module_violation("the module defines no memory".to_string());
+    }
+    match checker.violations.is_empty() {
+        true => Ok(()),
+        false => Err(checker.violations),
+    }
+}
+
+struct Checker<'a> {
+    origins: &'a HashMap<u32, Origin>,
+    rules: Option<&'a CasperRules>,
+    violations: Vec<Violation>,
+    imported_functions: u32,
+    defined_functions: u32,
+    memories: u32,
+}
+
+impl<'a> Checker<'a> {
+    /// Checks the Casper rules on a payload.
+    fn check(&mut self, payload: &Payload) -> Result<(), BinaryReaderError> {
+        match payload {
+            Payload::ImportSection(imports) => {
+                for import in imports.clone() {
+                    let import = import?;
+                    if let TypeRef::Func(_) = import.ty {
+                        self.imported_functions += 1;
+                    }
+                    let rules = match self.rules {
+                        Some(rules) => rules,
+                        None => continue,
+                    };
+                    let runtime_module = rules.runtime_module.as_deref();
+                    let allowed = match import.ty {
+                        TypeRef::Func(_) if Some(import.module) == runtime_module => true,
+                        TypeRef::Func(_) => {
+                            import.module == "env" && CASPER_HOST_FUNCTIONS.contains(&import.name)
+                        }
+                        // the node only imports the memory itself, after preprocessing
+                        TypeRef::Memory(ty) if runtime_module.is_some() => {
+                            self.check_memory(rules, ty.initial, ty.maximum);
+                            self.memories += 1;
+                            import.module == "env" && import.name == "memory"
+                        }
+                        _ => false,
+                    };
+                    if !allowed {
+                        let name = format!("{}.{}", import.module, import.name);
+                        self.module_violation(format!("import `{}` is not allowed", name));
+                    }
+                }
+            }
+            Payload::StartSection { .. } if self.rules.is_some() => {
+                self.module_violation("start section is not allowed".to_string());
+            }
+            Payload::TypeSection(types) => {
+                if let Some(rules) = self.rules {
+                    for ty in types.clone() {
+                        let wasmparser::Type::Func(ty) = ty?;
+                        if ty.params().len() > rules.max_params {
+                            self.module_violation(format!(
+                                "function type of {} parameters exceeds {}",
+                                ty.params().len(),
+                                rules.max_params
+                            ));
+                        }
+                    }
+                }
+            }
+            Payload::MemorySection(memories) => {
+                if let Some(rules) = self.rules {
+                    for memory in memories.clone() {
+                        let memory = memory?;
+                        self.check_memory(rules, memory.initial, memory.maximum);
+                        self.memories += 1;
+                    }
+                }
+            }
+            Payload::GlobalSection(globals) => {
+                if let Some(rules) = self.rules {
+                    if globals.count() > rules.max_globals {
+                        self.module_violation(format!(
+                            "{} globals exceed {}",
+                            globals.count(),
+                            rules.max_globals
+                        ));
+                    }
+                }
+            }
+            Payload::TableSection(tables) => {
+                if let Some(rules) = self.rules {
+                    for table in tables.clone() {
+                        let table = table?;
+                        let size = table.ty.maximum.unwrap_or(table.ty.initial);
+                        if table.ty.initial > rules.max_table_size || size > rules.max_table_size {
+                            self.module_violation(format!(
+                                "table size {} exceeds {} entries",
+                                size, rules.max_table_size
+                            ));
+                        }
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                let index = self.imported_functions + self.defined_functions;
+                self.defined_functions += 1;
+                if let Some(rules) = self.rules {
+                    self.check_function(rules, index, body)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn check_memory(&mut self, rules: &CasperRules, initial: u64, maximum: Option<u64>) {
+        let pages = maximum.unwrap_or(initial).max(initial);
+        if pages > rules.max_memory_pages {
+            self.module_violation(format!(
+                "memory of {} pages exceeds {} pages",
+                pages, rules.max_memory_pages
+            ));
+        }
+    }
+
+    fn check_function(
+        &mut self,
+        rules: &CasperRules,
+        index: u32,
+        body: &FunctionBody,
+    ) -> Result<(), BinaryReaderError> {
+        let size = body.range().len();
+        if size > rules.max_function_size {
+            let message = format!("size of {} bytes exceeds {}", size, rules.max_function_size);
+            self.function_error(index, body, body.range().start, &message);
+        }
+        let mut locals = 0u32;
+        for local in body.get_locals_reader()? {
+            locals = locals.saturating_add(local?.0);
+        }
+        if locals > rules.max_locals {
+            let message = format!("{} locals exceed {}", locals, rules.max_locals);
+            self.function_error(index, body, body.range().start, &message);
+        }
+        let mut operators = body.get_operators_reader()?;
+        while !operators.eof() {
+            let (operator, offset) = operators.read_with_offset()?;
+            if let Operator::BrTable { targets } = operator {
+                let len = targets.len() as usize;
+                if len > rules.max_br_table_size {
+                    let message = format!(
+                        "br_table of {} targets exceeds {}",
+                        len, rules.max_br_table_size
+                    );
+                    self.function_error(index, body, offset, &message);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn module_error(&mut self, err: BinaryReaderError) {
+        let message = format!("{} (at offset 0x{:x})", err.message(), err.offset());
+        self.module_violation(message);
+    }
+
+    fn module_violation(&mut self, message: String) {
+        self.violations.push(Violation {
+            location: None,
+            message,
+        });
+    }
+
+    /// Records a violation at the given module offset within a function.
+    fn function_error(&mut self, index: u32, body: &FunctionBody, offset: usize, message: &str) {
+        let location = match self.origins.get(&index) {
+            None => format!("runtime function {}", index),
+            Some(Origin::Export(name)) => format!("function `{}`", name),
+            Some(Origin::Method(name)) => format!("ABI method `{}`", name),
+            Some(Origin::Code { name, ops }) => {
+                let instr = instruction_index(body, offset);
+                // the operator whose code contains the instruction
+                match ops.iter().rev().find(|(start, ..)| Some(*start) <= instr) {
+                    Some((_, op, block)) => {
+                        format!("EVM block {} (operator at {}) of {}", block, op, name)
+                    }
+                    None => name.clone(),
+                }
+            }
+        };
+        self.violations.push(Violation {
+            location: Some(location),
+            message: message.to_string(),
+        });
+    }
+}
+
+/// Returns the index of the instruction at the given module offset.
+fn instruction_index(body: &FunctionBody, offset: usize) -> Option<usize> {
+    let mut operators = body.get_operators_reader().ok()?;
+    let mut index = None;
+    while !operators.eof() {
+        let (_, start) = operators.read_with_offset().ok()?;
+        if start > offset {
+            break;
+        }
+        index = Some(index.map_or(0, |i| i + 1));
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_encoder::{
+        CodeSection, EntityType, Function, FunctionSection, ImportSection, Instruction,
+        MemorySection, MemoryType, Module, StartSection, TypeSection, ValType,
+    };
+
+    /// Builds a module with a single function of the given body.
+    fn module(body: &[Instruction], locals: &[ValType], casper_breaking: bool) -> Vec<u8> {
+        let mut types = TypeSection::new();
+        types.function([], []);
+        let mut imports = ImportSection::new();
+        imports.import("env", "abort", EntityType::Function(0));
+        let mut functions = FunctionSection::new();
+        functions.function(0);
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType {
+            minimum: 128,
+            maximum: None,
+            memory64: false,
+            shared: false,
+        });
+        let mut func = Function::new_with_locals_types(locals.iter().copied());
+        for instr in body {
+            func.instruction(instr);
+        }
+        func.instruction(&Instruction::End);
+        let mut codes = CodeSection::new();
+        codes.function(&func);
+
+        let mut module = Module::new();
+        module.section(&types);
+        if casper_breaking {
+            module.section(&imports);
+        }
+        module.section(&functions).section(&memories);
+        if casper_breaking {
+            module.section(&StartSection { function_index: 1 });
+        }
+        module.section(&codes);
+        module.finish()
+    }
+
+    #[test]
+    fn test_reports_evm_block() {
+        let wasm = module(
+            &[
+                Instruction::Nop,
+                Instruction::I32Const(0),
+                Instruction::I64Const(0),
+                Instruction::I32Add,
+                Instruction::Drop,
+            ],
+            &[],
+            false,
+        );
+        let origins = HashMap::from([(
+            0,
+            Origin::Code {
+                name: "`_evm_exec`".to_string(),
+                ops: vec![
+                    (0, Offs(0), Offs(0)),
+                    (1, Offs(5), Offs(3)),
+                    (4, Offs(6), Offs(3)),
+                ],
+            },
+        )]);
+        let violations = validate(&wasm, &origins, None).unwrap_err();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(
+            violations[0].location.as_deref(),
+            Some("EVM block 0x3 (operator at 0x5) of `_evm_exec`")
+        );
+
+        let origins = HashMap::from([(0, Origin::Method("transfer".to_string()))]);
+        let violations = validate(&wasm, &origins, None).unwrap_err();
+        assert_eq!(
+            violations[0].location.as_deref(),
+            Some("ABI method `transfer`")
+        );
+    }
+
+    #[test]
+    fn test_casper_rules() {
+        let rules = CasperRules::default();
+        let wasm = module(&[], &[ValType::I64; 2], false);
+        assert_eq!(validate(&wasm, &HashMap::new(), None), Ok(()));
+        let violations = validate(&wasm, &HashMap::new(), Some(&rules)).unwrap_err();
+        assert_eq!(violations.len(), 1); // too many pages
+        assert!(violations[0].message.contains("128 pages"));
+
+        let wasm = module(&[], &[ValType::I64; 2], true);
+        assert_eq!(validate(&wasm, &HashMap::new(), None), Ok(()));
+        let messages: Vec<_> = validate(&wasm, &HashMap::new(), Some(&rules))
+            .unwrap_err()
+            .into_iter()
+            .map(|violation| violation.message)
+            .collect();
+        assert_eq!(messages[0], "import `env.abort` is not allowed");
+        assert!(messages.contains(&"start section is not allowed".to_string()));
+
+        // locals and floats are checked per function
+        let wasm = module(&[], &[ValType::F64; 501], false);
+        let origins = HashMap::from([(0, Origin::Export("_evm_start".to_string()))]);
+        let violations = validate(&wasm, &origins, Some(&rules)).unwrap_err();
+        let located: Vec<_> = violations
+            .iter()
+            .filter(|violation| violation.location.as_deref() == Some("function `_evm_start`"))
+            .collect();
+        assert_eq!(located.len(), 2);
+        assert_eq!(located[0].message, "501 locals exceed 500");
+    }
+
+    #[test]
+    fn test_casper_imports() {
+        // imports a host function and a memory, or defines the memory
+        let module = |host_function: &str, import_memory: bool| {
+            let mut types = TypeSection::new();
+            types.function([ValType::I32], []);
+            let mut imports = ImportSection::new();
+            imports.import("env", host_function, EntityType::Function(0));
+            let memory = MemoryType {
+                minimum: 1,
+                maximum: None,
+                memory64: false,
+                shared: false,
+            };
+            let mut memories = MemorySection::new();
+            let mut module = Module::new();
+            module.section(&types);
+            if import_memory {
+                imports.import("env", "memory", memory);
+            } else {
+                memories.memory(memory);
+            }
+            module.section(&imports).section(&memories);
+            module.finish()
+        };
+        let rules = CasperRules::default();
+        let validate = |wasm: &[u8], rules: &CasperRules| {
+            validate(wasm, &HashMap::new(), Some(rules)).map_err(|violations| {
+                violations
+                    .into_iter()
+                    .map(|v| v.message)
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(validate(&module("casper_revert", false), &rules), Ok(()));
+        assert_eq!(
+            validate(&module("gas", false), &rules),
+            Err(vec!["import `env.gas` is not allowed".to_string()])
+        );
+        assert_eq!(
+            validate(&module("casper_revert", true), &rules),
+            Err(vec![
+                "import `env.memory` is not allowed".to_string(),
+                "the module defines no memory".to_string()
+            ])
+        );
+        // a shared runtime provides the memory
+        let rules = CasperRules {
+            runtime_module: Some("evmlib".to_string()),
+            ..rules
+        };
+        assert_eq!(validate(&module("casper_revert", true), &rules), Ok(()));
+    }
+}
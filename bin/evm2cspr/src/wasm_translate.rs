@@ -20,9 +20,9 @@ pub struct Signature {
 
 #[derive(Debug)]
 pub struct Import {
-    module: String,
-    field: String,
-    ty: EntityType,
+    pub module: String,
+    pub field: String,
+    pub ty: EntityType,
 }
 
 pub type TypeIndex = u32;
@@ -87,6 +87,11 @@ impl<'a> ModuleBuilder<'a> {
         sig_id
     }
 
+    pub fn add_global(&mut self, glob: Glob<'a>) -> u32 {
+        self.globals.push(glob);
+        u32::try_from(self.globals.len()).unwrap() - 1
+    }
+
     pub fn add_export(&mut self, export: Export) -> u32 {
         self.exports.push(export);
         u32::try_from(self.exports.len()).unwrap() - 1
@@ -187,9 +192,9 @@ impl<'a> ModuleBuilder<'a> {
     }
 }
 
-pub fn parse(wasm: &Vec<u8>) -> Result<ModuleBuilder> {
+pub fn parse(wasm: &[u8]) -> Result<ModuleBuilder> {
     let parsed = wasmparser::Parser::new(0)
-        .parse_all(wasm.as_slice())
+        .parse_all(wasm)
         .map(|p| p.unwrap())
         .collect::<Vec<_>>();
 
@@ -730,6 +735,11 @@ pub(crate) mod translator {
     }
 
     pub fn code(body: FunctionBody<'_>) -> Result<Function> {
+        code_with_calls(body, |function_index| function_index)
+    }
+
+    /// Like `code`, mapping the indices of the called and referenced functions.
+    pub fn code_with_calls(body: FunctionBody<'_>, map: impl Fn(u32) -> u32) -> Result<Function> {
         let locals = body
             .get_locals_reader()?
             .into_iter()
@@ -743,8 +753,12 @@ pub(crate) mod translator {
         let mut reader = body.get_operators_reader()?;
         reader.allow_memarg64(true);
         for operator in reader {
-            let operator = operator?;
-            func.instruction(&op(&operator)?);
+            let instr = match op(&operator?)? {
+                Instruction::Call(f) => Instruction::Call(map(f)),
+                Instruction::RefFunc(f) => Instruction::RefFunc(map(f)),
+                instr => instr,
+            };
+            func.instruction(&instr);
         }
         Ok(func)
     }
@@ -13,6 +13,11 @@ use std::borrow::Cow;
 
 pub use ethabi;
 
+/// The version of the interface between compiled contracts and evmlib, which
+/// a shared runtime is installed under. Changes to the exported functions, to
+/// the layout of `Stack` or to the format of the ABI data bump it.
+pub const INTERFACE_VERSION: u32 = 2;
+
 pub fn parse_param_type(input: &str) -> Result<ParamType, ParseError> {
     let (_, typ) = all_consuming(inner_parse_param_type)(input).map_err(|e| match e {
         nom::Err::Error(e) => e,
@@ -22,6 +27,21 @@ pub fn parse_param_type(input: &str) -> Result<ParamType, ParseError> {
     Ok(typ)
 }
 
+/// Parses a comma-separated list of types, such as the parameters of a function. Unlike
+/// splitting on commas, this keeps the components of tuple types together.
+pub fn parse_param_types(input: &str) -> Result<Vec<ParamType>, ParseError> {
+    if input.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let (_, types) = all_consuming(separated_list1(tag(","), inner_parse_param_type))(input)
+        .map_err(|e| match e {
+            nom::Err::Error(e) => e,
+            nom::Err::Failure(e) => e,
+            nom::Err::Incomplete(_) => unreachable!(),
+        })?;
+    Ok(types)
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ParseError<'a> {
     Syntax(nom::error::Error<&'a str>),
@@ -174,7 +194,7 @@ type IResult<'a, T> = Result<(&'a str, T), nom::Err<ParseError<'a>>>;
 
 #[cfg(test)]
 mod tests {
-    use super::{parse_param_type, ParseError};
+    use super::{parse_param_type, parse_param_types, ParseError};
     use ethabi::ParamType;
 
     #[test]
@@ -313,6 +333,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_type_lists() {
+        assert_eq!(parse_param_types("").unwrap(), vec![]);
+        assert_eq!(
+            parse_param_types("(uint256,address),bool[]").unwrap(),
+            vec![
+                ParamType::Tuple(vec![ParamType::Uint(256), ParamType::Address]),
+                ParamType::Array(Box::new(ParamType::Bool)),
+            ]
+        );
+        assert!(parse_param_types("uint256,").is_err());
+    }
+
     #[test]
     fn test_garbage_after_type() {
         assert_eq!(
@@ -1,14 +1,29 @@
 // This is free and unencumbered software released into the public domain.
 
+use abi_types::INTERFACE_VERSION;
+
 use crate::{
-    env::Env,
+    arith,
+    env::{Env, ExitStatus},
     json_utils::{decode::transform_json_call_data, encode::encode_return_data_as_json},
     ops::{ENV, EVM},
-    state::Word,
+    state::{Stack, Word, MAX_STACK_DEPTH, ZERO},
 };
 
+/// Returns `INTERFACE_VERSION`, which `_evm_start` checks and a shared runtime
+/// is installed under.
+#[no_mangle]
+pub unsafe fn _evm_version() -> u32 {
+    INTERFACE_VERSION
+}
+
+/// Exported only with `block-gas`, where the op handlers leave static gas to
+/// the compiled code. The compiler requires it when it charges static gas
+/// per block, and `_evm_start` calls it so that a shared runtime without it
+/// fails to link.
+#[cfg(feature = "block-gas")]
 #[no_mangle]
-pub static mut _abi_buffer: [u8; 0xFFFF] = [1; 0xFFFF]; // FIXME
+pub unsafe fn _evm_block_gas() {}
 
 #[no_mangle]
 pub unsafe fn _evm_init(_table_offset: u32, chain_id: u64, balance: u64) {
@@ -71,24 +86,19 @@ pub unsafe fn _evm_init(_table_offset: u32, chain_id: u64, balance: u64) {
     EVM.self_balance = Word::from(balance);
 }
 
+/// Prepares the call data of an ABI method. The parameter names and types
+/// are strings the compiler emits as data.
 #[no_mangle]
 pub unsafe fn _evm_call(
     selector: u32,
-    param_names_off: usize, // relative to _abi_buffer
+    param_names_ptr: *const u8,
     param_names_len: usize,
-    param_types_off: usize, // relative to _abi_buffer
+    param_types_ptr: *const u8,
     param_types_len: usize,
 ) {
     let raw_call_data = ENV.call_data();
 
-    let param_names_ptr: *mut u8 = _abi_buffer
-        .as_mut_ptr()
-        .offset(param_names_off.try_into().unwrap());
     let param_names = std::slice::from_raw_parts(param_names_ptr, param_names_len);
-
-    let param_types_ptr: *mut u8 = _abi_buffer
-        .as_mut_ptr()
-        .offset(param_types_off.try_into().unwrap());
     let param_types = std::slice::from_raw_parts(param_types_ptr, param_types_len);
 
     let call_data = if param_names.is_empty() {
@@ -112,21 +122,46 @@ pub unsafe fn _evm_call(
     }
 }
 
+/// Prepares empty call data, as for a plain value transfer to `receive`.
+#[no_mangle]
+pub unsafe fn _evm_receive() {
+    #[cfg(all(feature = "cspr", not(test)))]
+    {
+        ENV.call_data = Some(Vec::new());
+    }
+    #[cfg(any(not(feature = "cspr"), test))]
+    {
+        ENV.call_data = Vec::new();
+    }
+}
+
 /// Posts the return value from the execution, translating into JSON using the ABI
 #[no_mangle]
 pub unsafe fn _evm_post_exec(
-    output_types_off: usize, // relative to _abi_buffer
+    output_types_ptr: *const u8, // emitted as data by the compiler
     output_types_len: usize,
+    errors_ptr: *const u8, // likewise, see `encode_return_data_as_json`
+    errors_len: usize,
 ) {
-    // If there is an ABI given then we will try to encode output into JSON
-    if output_types_len > 0 {
-        let output_types_ptr: *mut u8 = _abi_buffer
-            .as_mut_ptr()
-            .offset(output_types_off.try_into().unwrap());
-        let output_types = std::slice::from_raw_parts(output_types_ptr, output_types_len);
-        let json_return_data =
-            encode_return_data_as_json(output_types, ENV.get_return_data(), ENV.get_exit_status())
-                .unwrap();
+    // If there is an ABI given then we will try to encode output into JSON, reverts are decoded
+    // even without, into the message or the error and its arguments
+    let reverted = matches!(ENV.get_exit_status(), Some(ExitStatus::Revert));
+    if output_types_len > 0 || reverted {
+        let output_types = match output_types_len {
+            0 => &[],
+            _ => std::slice::from_raw_parts(output_types_ptr, output_types_len),
+        };
+        let errors = match errors_len {
+            0 => &[],
+            _ => std::slice::from_raw_parts(errors_ptr, errors_len),
+        };
+        let json_return_data = encode_return_data_as_json(
+            output_types,
+            errors,
+            ENV.get_return_data(),
+            ENV.get_exit_status(),
+        )
+        .unwrap();
         ENV.overwrite_return_data(json_return_data);
     }
     ENV.post_exec();
@@ -142,6 +177,109 @@ pub unsafe fn _evm_push_u32(x: u32) {
     EVM.stack.push(x.into())
 }
 
+/// Pushes a word given as four little-endian `u64` limbs without charging
+/// gas. Used by the compiler to spill stack slots kept in wasm locals.
+#[no_mangle]
+pub unsafe fn _evm_push_u256(word_0: u64, word_1: u64, word_2: u64, word_3: u64) {
+    let hi = (word_3 as u128) << 64 | word_2 as u128;
+    let lo = (word_1 as u128) << 64 | word_0 as u128;
+    EVM.stack.push(Word::from_words(hi, lo))
+}
+
+/// Holds the result of `_evm_arith`.
+static mut ARITH_RESULT: Word = ZERO;
+
+/// Evaluates the pure op with the given EVM opcode over operands passed as
+/// four little-endian `u64` limbs each, topmost first, and returns the
+/// address of the result. Lets the compiler keep the operands and result in
+/// wasm locals rather than on the stack; unused operands are ignored.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn _evm_arith(
+    opcode: u32,
+    a_0: u64,
+    a_1: u64,
+    a_2: u64,
+    a_3: u64,
+    b_0: u64,
+    b_1: u64,
+    b_2: u64,
+    b_3: u64,
+    c_0: u64,
+    c_1: u64,
+    c_2: u64,
+    c_3: u64,
+) -> *const Word {
+    let word = |limbs: [u64; 4]| {
+        let hi = (limbs[3] as u128) << 64 | limbs[2] as u128;
+        let lo = (limbs[1] as u128) << 64 | limbs[0] as u128;
+        Word::from_words(hi, lo)
+    };
+    let (a, b, c) = (
+        word([a_0, a_1, a_2, a_3]),
+        word([b_0, b_1, b_2, b_3]),
+        word([c_0, c_1, c_2, c_3]),
+    );
+    match arith::eval(opcode, a, b, c) {
+        Some((gas, result)) => {
+            EVM.burn_static_gas(gas);
+            ARITH_RESULT = result;
+        }
+        None => _evm_fault(),
+    }
+    &ARITH_RESULT
+}
+
+/// Returns the address of the `n`-th stack slot counting from the top, so
+/// that the compiler can load its limbs into wasm locals.
+#[no_mangle]
+pub unsafe fn _evm_stack_slot(n: u32) -> *const Word {
+    let n = n as usize;
+    if n >= EVM.stack.depth {
+        _evm_fault();
+    }
+    &EVM.stack.slots[EVM.stack.depth - 1 - n]
+}
+
+/// Faults unless the stack has room for `n` more words, for the compiler to
+/// check for the overflows of the words it keeps in wasm locals.
+#[no_mangle]
+pub unsafe fn _evm_stack_room(n: u32) {
+    if EVM.stack.depth + n as usize > MAX_STACK_DEPTH {
+        _evm_fault();
+    }
+}
+
+/// Halts execution exceptionally, as on a stack underflow or overflow: like
+/// `INVALID`, all the gas is consumed and execution reverts without data.
+/// The program counter should be set beforehand.
+#[no_mangle]
+pub unsafe fn _evm_fault() -> ! {
+    EVM.burn_gas(EVM.gas_limit);
+    ENV.revert(&[]);
+    ENV.post_exec();
+    unreachable!("fault");
+}
+
+/// Returns the address of the operand stack, whose layout is documented on
+/// `Stack`, for the ops the compiler inlines.
+#[no_mangle]
+pub unsafe fn _evm_stack() -> *mut Stack {
+    &mut EVM.stack
+}
+
+/// Returns `MAX_STACK_DEPTH`, which the compiler checks before inlining ops.
+#[no_mangle]
+pub unsafe fn _evm_stack_slots() -> u32 {
+    MAX_STACK_DEPTH as u32
+}
+
+/// Returns the address of the call value, a word laid out like stack slots.
+#[no_mangle]
+pub unsafe fn _evm_call_value() -> *const Word {
+    &EVM.call_value
+}
+
 #[no_mangle]
 pub unsafe fn _evm_set_pc(pc: u32) {
     #[cfg(feature = "pc")]
@@ -0,0 +1,226 @@
+// This is free and unencumbered software released into the public domain.
+
+//! The pure ops, whose results only depend on their operands. Their handlers
+//! take the operands from the stack, while `_evm_arith` takes them from the
+//! wasm locals the compiler keeps stack words in.
+
+use ethnum::{AsU256, I256};
+use std::ops::{Not, Shl, Shr};
+
+use crate::state::{Word, ONE, ZERO};
+
+/// Evaluates the pure op with the given EVM opcode over its operands,
+/// topmost first, returning its static gas and its result. `EXP` is left
+/// out, as its gas depends on the exponent.
+pub(crate) fn eval(opcode: u32, a: Word, b: Word, c: Word) -> Option<(u64, Word)> {
+    Some(match opcode {
+        0x01 => (3, add(a, b)),
+        0x02 => (5, mul(a, b)),
+        0x03 => (3, sub(a, b)),
+        0x04 => (5, div(a, b)),
+        0x05 => (5, sdiv(a, b)),
+        0x06 => (5, r#mod(a, b)),
+        0x07 => (5, smod(a, b)),
+        0x08 => (8, addmod(a, b, c)),
+        0x09 => (8, mulmod(a, b, c)),
+        0x0b => (5, signextend(a, b)),
+        0x10 => (3, lt(a, b)),
+        0x11 => (3, gt(a, b)),
+        0x12 => (3, slt(a, b)),
+        0x13 => (3, sgt(a, b)),
+        0x14 => (3, eq(a, b)),
+        0x15 => (3, iszero(a)),
+        0x16 => (3, and(a, b)),
+        0x17 => (3, or(a, b)),
+        0x18 => (3, xor(a, b)),
+        0x19 => (3, not(a)),
+        0x1a => (3, byte(a, b)),
+        0x1b => (3, shl(a, b)),
+        0x1c => (3, shr(a, b)),
+        0x1d => (3, sar(a, b)),
+        _ => return None,
+    })
+}
+
+fn bool_word(b: bool) -> Word {
+    if b {
+        ONE
+    } else {
+        ZERO
+    }
+}
+
+pub(crate) fn add(a: Word, b: Word) -> Word {
+    a.wrapping_add(b)
+}
+
+pub(crate) fn mul(a: Word, b: Word) -> Word {
+    a.wrapping_mul(b)
+}
+
+pub(crate) fn sub(a: Word, b: Word) -> Word {
+    a.wrapping_sub(b)
+}
+
+pub(crate) fn div(a: Word, b: Word) -> Word {
+    if b == ZERO {
+        ZERO
+    } else {
+        a / b
+    }
+}
+
+pub(crate) fn sdiv(a: Word, b: Word) -> Word {
+    let (a, b) = (a.as_i256(), b.as_i256());
+    if b == I256::ZERO {
+        ZERO
+    } else {
+        (a / b).as_u256()
+    }
+}
+
+pub(crate) fn r#mod(a: Word, b: Word) -> Word {
+    if b == ZERO {
+        ZERO
+    } else {
+        a % b
+    }
+}
+
+pub(crate) fn smod(a: Word, b: Word) -> Word {
+    let (a, b) = (a.as_i256(), b.as_i256());
+    if b == I256::ZERO {
+        ZERO
+    } else {
+        (a % b).as_u256()
+    }
+}
+
+pub(crate) fn addmod(a: Word, b: Word, n: Word) -> Word {
+    // TODO: need to use 512-bit arithmetic here to prevent overflow before taking the modulus
+    if n == ZERO {
+        ZERO
+    } else {
+        (a + b) % n
+    }
+}
+
+pub(crate) fn mulmod(a: Word, b: Word, n: Word) -> Word {
+    // TODO: need to use 512-bit arithmetic here to prevent overflow before taking the modulus
+    if n == ZERO {
+        ZERO
+    } else {
+        (a * b) % n
+    }
+}
+
+pub(crate) fn signextend(op1: Word, op2: Word) -> Word {
+    if op1 < ethnum::U256::new(32) {
+        // `as_u32` works since op1 < 32
+        let bit_index = (8 * op1.as_u32() + 7) as usize;
+        let word = if bit_index < 128 {
+            op2.low()
+        } else {
+            op2.high()
+        };
+        let bit = word & (1 << (bit_index % 128)) != 0;
+        let mask = (ONE << bit_index) - ONE;
+        if bit {
+            op2 | !mask
+        } else {
+            op2 & mask
+        }
+    } else {
+        op2
+    }
+}
+
+pub(crate) fn lt(a: Word, b: Word) -> Word {
+    bool_word(a < b)
+}
+
+pub(crate) fn gt(a: Word, b: Word) -> Word {
+    bool_word(a > b)
+}
+
+pub(crate) fn slt(a: Word, b: Word) -> Word {
+    bool_word(a.as_i256() < b.as_i256())
+}
+
+pub(crate) fn sgt(a: Word, b: Word) -> Word {
+    bool_word(a.as_i256() > b.as_i256())
+}
+
+pub(crate) fn eq(a: Word, b: Word) -> Word {
+    bool_word(a == b)
+}
+
+pub(crate) fn iszero(x: Word) -> Word {
+    bool_word(x == ZERO)
+}
+
+pub(crate) fn and(a: Word, b: Word) -> Word {
+    a & b
+}
+
+pub(crate) fn or(a: Word, b: Word) -> Word {
+    a | b
+}
+
+pub(crate) fn xor(a: Word, b: Word) -> Word {
+    a ^ b
+}
+
+pub(crate) fn not(x: Word) -> Word {
+    x.not()
+}
+
+pub(crate) fn byte(index: Word, word: Word) -> Word {
+    if index > 31 {
+        ZERO
+    } else {
+        let bytes = word.to_be_bytes();
+        Word::from(bytes[index.as_usize()])
+    }
+}
+
+pub(crate) fn shl(shift: Word, value: Word) -> Word {
+    if value == ZERO || shift > Word::from(255u8) {
+        ZERO
+    } else {
+        value.shl(shift)
+    }
+}
+
+pub(crate) fn shr(shift: Word, value: Word) -> Word {
+    if value == ZERO || shift > Word::from(255u8) {
+        ZERO
+    } else {
+        value.shr(shift)
+    }
+}
+
+pub(crate) fn sar(shift: Word, value: Word) -> Word {
+    let signed_value = value.as_i256();
+    if signed_value == I256::ZERO || shift > Word::from(255u8) {
+        if signed_value.is_positive() {
+            ZERO
+        } else {
+            I256::from(-1).as_u256()
+        }
+    } else {
+        // Cast is safe since we checked if shift is less than 255
+        let shift = shift.as_u32();
+        if signed_value.is_positive() {
+            value.shr(shift).as_u256()
+        } else {
+            signed_value
+                .overflowing_sub(I256::ONE)
+                .0
+                .shr(shift)
+                .overflowing_add(I256::ONE)
+                .0
+                .as_u256()
+        }
+    }
+}
@@ -0,0 +1,427 @@
+//! This module implements the Env trait using the Casper host functions (the `casper_*` imports
+//! resolved by the execution engine, see `execution_engine/src/resolvers/v1_resolver.rs`).
+//!
+//! Values cross the host boundary in Casper's `bytesrepr` encoding: the call data is the `input`
+//! runtime argument (a `Bytes` or `List<U8>` value), storage lives in the `storage` dictionary of
+//! the contract, and the return data is returned as a `List<U8>` value.
+
+use crate::env::{Address, Env, ExitStatus};
+use crate::hash_provider::{HashProvider, Native};
+use crate::state::{Word, WORD_SIZE};
+use std::collections::HashMap;
+
+mod storage;
+
+/// Runtime argument holding the call data.
+const INPUT_ARG: &str = "input";
+/// Named key of the dictionary holding the contract storage.
+const STORAGE_KEY: &str = "storage";
+
+/// `ApiError::ValueNotFound`, returned by `casper_dictionary_get` for a missing item.
+const VALUE_NOT_FOUND: i32 = 6;
+/// `ApiError::User(0)`, the exit code of a reverted call.
+const REVERT_CODE: u32 = 65536;
+/// `ApiError::User(1)`, the exit code of a call out of gas.
+const OUT_OF_GAS_CODE: u32 = 65537;
+
+const UREF_SERIALIZED_LENGTH: usize = 33;
+/// Tag of `Key::URef`.
+const KEY_UREF_TAG: u8 = 2;
+/// Tags of the `CallStackElement` variants.
+const SESSION_TAG: u8 = 0;
+const STORED_SESSION_TAG: u8 = 1;
+const STORED_CONTRACT_TAG: u8 = 2;
+/// `CLType::ByteArray(32)`.
+const WORD_CL_TYPE: [u8; 5] = [15, WORD_SIZE as u8, 0, 0, 0];
+/// `CLType::List(Box::new(CLType::U8))`.
+const BYTES_CL_TYPE: [u8; 2] = [14, 3];
+
+pub struct CsprRuntime {
+    /// Cache for the `input` argument to prevent reading it from the host multiple times.
+    pub call_data: Option<Vec<u8>>,
+    pub storage_cache: Option<HashMap<Word, Word>>,
+    /// Serialized URef of the storage dictionary.
+    pub storage_uref: Option<[u8; UREF_SERIALIZED_LENGTH]>,
+    pub address_cache: Option<Address>,
+    pub origin_cache: Option<Address>,
+    pub caller_cache: Option<Address>,
+    pub exit_status: Option<ExitStatus>,
+    pub return_data: Vec<u8>,
+}
+
+impl Env for CsprRuntime {
+    fn call_data(&mut self) -> &[u8] {
+        if self.call_data.is_none() {
+            let size = Self::input_size();
+            let mut host_result = vec![0u8; size];
+            if size > 0 {
+                let ret = unsafe {
+                    casper_get_named_arg(
+                        INPUT_ARG.as_ptr(),
+                        INPUT_ARG.len(),
+                        host_result.as_mut_ptr(),
+                        size,
+                    )
+                };
+                Self::unwrap_or_revert(ret);
+            }
+            // Skip the u32 length prefix of the byte list.
+            self.call_data = Some(host_result.split_off(size.min(4)));
+        }
+
+        // Unwrap is clearly safe since we just set value to Some(..)
+        self.call_data.as_ref().unwrap()
+    }
+
+    fn call_data_len(&self) -> usize {
+        if let Some(call_data) = self.call_data.as_ref() {
+            return call_data.len();
+        }
+
+        Self::input_size().saturating_sub(4)
+    }
+
+    fn address(&mut self) -> Address {
+        if let Some(address) = self.address_cache {
+            return address;
+        }
+
+        let call_stack = Self::call_stack();
+        let address = Self::hash_to_address(call_stack.last().unwrap());
+
+        self.address_cache = Some(address);
+        address
+    }
+
+    fn origin(&mut self) -> Address {
+        if let Some(address) = self.origin_cache {
+            return address;
+        }
+
+        // The call stack always starts with the account which sent the deploy.
+        let call_stack = Self::call_stack();
+        let address = Self::hash_to_address(call_stack.first().unwrap());
+
+        self.origin_cache = Some(address);
+        address
+    }
+
+    fn caller(&mut self) -> Address {
+        if let Some(address) = self.caller_cache {
+            return address;
+        }
+
+        // A contract called directly by the session code of an account sees the account.
+        let call_stack = Self::call_stack();
+        let caller = call_stack.len().saturating_sub(2);
+        let address = Self::hash_to_address(&call_stack[caller]);
+
+        self.caller_cache = Some(address);
+        address
+    }
+
+    fn block_height(&self) -> u64 {
+        // The host does not expose the block height to contracts.
+        0
+    }
+
+    fn timestamp(&self) -> u64 {
+        // Casper gives the block time in ms, but EVM expects seconds
+        let mut ms = [0u8; 8];
+        unsafe { casper_get_blocktime(ms.as_mut_ptr()) };
+        u64::from_le_bytes(ms) / 1_000
+    }
+
+    fn storage_read(&mut self, key: Word) -> Word {
+        if self.storage_cache.is_none() {
+            self.storage_cache = Some(HashMap::new());
+        }
+
+        if let Some(value) = self.storage_cache.as_ref().unwrap().get(&key) {
+            return *value;
+        }
+
+        let value = self
+            .storage_dictionary(false)
+            .and_then(|uref| {
+                let storage_key = storage::StorageKey::from_word(key);
+                Self::inner_storage_read(&uref, storage_key.as_slice())
+            })
+            .unwrap_or(Word::ZERO);
+        self.storage_cache.as_mut().unwrap().insert(key, value);
+        value
+    }
+
+    fn storage_write(&mut self, key: Word, value: Word) {
+        if self.storage_cache.is_none() {
+            self.storage_cache = Some(HashMap::new());
+        }
+        // Unwrap is safe because we ensure it is Some(..) in the check above
+        let previous_value = self.storage_cache.as_mut().unwrap().insert(key, value);
+        // Storage needs to be updated if we have never seen this key/value pair before,
+        // or if the value is different what from what it used to be.
+        let need_to_update_storage = match previous_value {
+            None => true,
+            Some(x) if x != value => true,
+            _ => false,
+        };
+
+        if need_to_update_storage {
+            // Unwrap is safe because the dictionary is created if missing
+            let uref = self.storage_dictionary(true).unwrap();
+            let storage_key = storage::StorageKey::from_word(key);
+            Self::inner_storage_write(&uref, storage_key.as_slice(), value);
+        }
+    }
+
+    fn log(&mut self, _entry: crate::env::EvmLog) {
+        // The host has no event log, so logs are dropped.
+    }
+
+    fn value_return(&mut self, return_data: &[u8]) {
+        self.exit_status = Some(ExitStatus::Success);
+        self.return_data = return_data.to_vec();
+    }
+
+    fn revert(&mut self, return_data: &[u8]) {
+        self.exit_status = Some(ExitStatus::Revert);
+        self.return_data = return_data.to_vec();
+    }
+
+    fn exit_oog(&mut self) {
+        self.exit_status = Some(ExitStatus::OutOfGas);
+    }
+
+    fn post_exec(&self) {
+        match &self.exit_status {
+            Some(ExitStatus::Success) => {
+                let return_data = &self.return_data;
+                let mut cl_value = Vec::with_capacity(return_data.len() + 10);
+                cl_value.extend_from_slice(&(return_data.len() as u32 + 4).to_le_bytes());
+                cl_value.extend_from_slice(&(return_data.len() as u32).to_le_bytes());
+                cl_value.extend_from_slice(return_data);
+                cl_value.extend_from_slice(&BYTES_CL_TYPE);
+                unsafe { casper_ret(cl_value.as_ptr(), cl_value.len()) }
+            }
+            // A revert only carries an exit code, so the revert data is lost.
+            Some(ExitStatus::Revert) => unsafe { casper_revert(REVERT_CODE) },
+            Some(ExitStatus::OutOfGas) => unsafe { casper_revert(OUT_OF_GAS_CODE) },
+            None => {
+                panic!("Exited without any status being set!")
+            }
+        }
+    }
+
+    fn get_return_data(&self) -> &[u8] {
+        &self.return_data
+    }
+
+    fn get_exit_status(&self) -> &Option<ExitStatus> {
+        &self.exit_status
+    }
+
+    fn overwrite_return_data(&mut self, return_data: Vec<u8>) {
+        self.return_data = return_data;
+    }
+}
+
+impl CsprRuntime {
+    /// Reverts with the host error unless `ret` signals success.
+    fn unwrap_or_revert(ret: i32) {
+        if ret != 0 {
+            unsafe { casper_revert(ret as u32) }
+        }
+    }
+
+    /// Size of the serialized `input` argument, or 0 if it is missing.
+    fn input_size() -> usize {
+        let mut size = 0usize;
+        let ret =
+            unsafe { casper_get_named_arg_size(INPUT_ARG.as_ptr(), INPUT_ARG.len(), &mut size) };
+        if ret != 0 {
+            return 0;
+        }
+        size
+    }
+
+    fn read_host_buffer(size: usize) -> Vec<u8> {
+        let mut buffer = vec![0u8; size];
+        let mut bytes_written = 0usize;
+        let ret = unsafe { casper_read_host_buffer(buffer.as_mut_ptr(), size, &mut bytes_written) };
+        Self::unwrap_or_revert(ret);
+        buffer.truncate(bytes_written);
+        buffer
+    }
+
+    /// The hash identifying each element of the call stack, from the session to the current
+    /// contract: the account hash of session code and the contract hash of a contract.
+    fn call_stack() -> Vec<[u8; 32]> {
+        let mut len = 0usize;
+        let mut size = 0usize;
+        let ret = unsafe { casper_load_call_stack(&mut len, &mut size) };
+        Self::unwrap_or_revert(ret);
+        let bytes = Self::read_host_buffer(size);
+
+        // Skip the u32 length prefix of the element list.
+        let mut offset = 4;
+        let mut call_stack = Vec::with_capacity(len);
+        while offset < bytes.len() {
+            // Session { account_hash }, StoredSession { account_hash, contract_package_hash,
+            // contract_hash }, StoredContract { contract_package_hash, contract_hash }
+            let (hash, size) = match bytes[offset] {
+                SESSION_TAG => (1, 1 + 32),
+                STORED_SESSION_TAG => (1, 1 + 3 * 32),
+                STORED_CONTRACT_TAG => (1 + 32, 1 + 2 * 32),
+                _ => unreachable!(),
+            };
+            let hash = &bytes[offset + hash..offset + hash + 32];
+            call_stack.push(hash.try_into().unwrap());
+            offset += size;
+        }
+        call_stack
+    }
+
+    /// Derives an EVM address from an account or contract hash.
+    fn hash_to_address(hash: &[u8; 32]) -> Address {
+        let hash = Native::keccak256(hash);
+        let mut result = [0u8; 20];
+        result.copy_from_slice(&hash[12..32]);
+        result
+    }
+
+    /// Returns the URef of the storage dictionary, creating it if missing and `create` is set.
+    fn storage_dictionary(&mut self, create: bool) -> Option<[u8; UREF_SERIALIZED_LENGTH]> {
+        if self.storage_uref.is_some() {
+            return self.storage_uref;
+        }
+
+        let mut key = [0u8; 1 + UREF_SERIALIZED_LENGTH];
+        let mut bytes_written = 0usize;
+        let ret = unsafe {
+            casper_get_key(
+                STORAGE_KEY.as_ptr(),
+                STORAGE_KEY.len(),
+                key.as_mut_ptr(),
+                key.len(),
+                &mut bytes_written,
+            )
+        };
+        let uref = if ret == 0 && key[0] == KEY_UREF_TAG {
+            key[1..].try_into().unwrap()
+        } else if create {
+            let mut size = 0usize;
+            let ret = unsafe { casper_new_dictionary(&mut size) };
+            Self::unwrap_or_revert(ret);
+            let uref: [u8; UREF_SERIALIZED_LENGTH] =
+                Self::read_host_buffer(size).try_into().unwrap();
+            key[0] = KEY_UREF_TAG;
+            key[1..].copy_from_slice(&uref);
+            unsafe {
+                casper_put_key(
+                    STORAGE_KEY.as_ptr(),
+                    STORAGE_KEY.len(),
+                    key.as_ptr(),
+                    key.len(),
+                )
+            };
+            uref
+        } else {
+            return None;
+        };
+
+        self.storage_uref = Some(uref);
+        self.storage_uref
+    }
+
+    fn inner_storage_read(uref: &[u8], key: &[u8]) -> Option<Word> {
+        let mut size = 0usize;
+        let ret = unsafe {
+            casper_dictionary_get(
+                uref.as_ptr(),
+                uref.len(),
+                key.as_ptr(),
+                key.len(),
+                &mut size,
+            )
+        };
+        if ret == VALUE_NOT_FOUND {
+            return None;
+        }
+        Self::unwrap_or_revert(ret);
+
+        // Skip the u32 length prefix of the serialized ByteArray(32) value.
+        let cl_value = Self::read_host_buffer(size);
+        let value = cl_value[4..4 + WORD_SIZE].try_into().unwrap();
+        Some(Word::from_be_bytes(value))
+    }
+
+    fn inner_storage_write(uref: &[u8], key: &[u8], value: Word) {
+        let mut cl_value = [0u8; 4 + WORD_SIZE + WORD_CL_TYPE.len()];
+        cl_value[..4].copy_from_slice(&(WORD_SIZE as u32).to_le_bytes());
+        cl_value[4..4 + WORD_SIZE].copy_from_slice(&value.to_be_bytes());
+        cl_value[4 + WORD_SIZE..].copy_from_slice(&WORD_CL_TYPE);
+        let ret = unsafe {
+            casper_dictionary_put(
+                uref.as_ptr(),
+                uref.len(),
+                key.as_ptr(),
+                key.len(),
+                cl_value.as_ptr(),
+                cl_value.len(),
+            )
+        };
+        Self::unwrap_or_revert(ret);
+    }
+}
+
+extern "C" {
+    fn casper_read_host_buffer(
+        dest_ptr: *mut u8,
+        dest_size: usize,
+        bytes_written: *mut usize,
+    ) -> i32;
+
+    fn casper_get_named_arg_size(
+        name_ptr: *const u8,
+        name_size: usize,
+        dest_size: *mut usize,
+    ) -> i32;
+    fn casper_get_named_arg(
+        name_ptr: *const u8,
+        name_size: usize,
+        dest_ptr: *mut u8,
+        dest_size: usize,
+    ) -> i32;
+
+    fn casper_load_call_stack(call_stack_len_ptr: *mut usize, result_size_ptr: *mut usize) -> i32;
+    fn casper_get_blocktime(dest_ptr: *mut u8);
+
+    fn casper_get_key(
+        name_ptr: *const u8,
+        name_size: usize,
+        output_ptr: *mut u8,
+        output_size: usize,
+        bytes_written_ptr: *mut usize,
+    ) -> i32;
+    fn casper_put_key(name_ptr: *const u8, name_size: usize, key_ptr: *const u8, key_size: usize);
+
+    fn casper_new_dictionary(output_size_ptr: *mut usize) -> i32;
+    fn casper_dictionary_get(
+        uref_ptr: *const u8,
+        uref_size: usize,
+        key_bytes_ptr: *const u8,
+        key_bytes_size: usize,
+        output_size: *mut usize,
+    ) -> i32;
+    fn casper_dictionary_put(
+        uref_ptr: *const u8,
+        uref_size: usize,
+        key_bytes_ptr: *const u8,
+        key_bytes_size: usize,
+        value_ptr: *const u8,
+        value_size: usize,
+    ) -> i32;
+
+    fn casper_ret(value_ptr: *const u8, value_size: usize) -> !;
+    fn casper_revert(status: u32) -> !;
+}
@@ -0,0 +1,25 @@
+use crate::state::{Word, WORD_SIZE};
+
+/// Dictionary item keys are strings of at most 64 bytes, which fits a word in hex.
+const KEY_SIZE: usize = 2 * WORD_SIZE;
+
+pub struct StorageKey([u8; KEY_SIZE]);
+
+impl StorageKey {
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn from_word(word: Word) -> Self {
+        let mut bytes = [0u8; KEY_SIZE];
+        // Unwrap is safe because the buffer holds exactly the encoded word
+        hex::encode_to_slice(word.to_be_bytes(), &mut bytes).unwrap();
+        Self(bytes)
+    }
+}
+
+impl AsRef<[u8]> for StorageKey {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
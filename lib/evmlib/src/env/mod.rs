@@ -6,6 +6,7 @@
 
 use crate::state::Word;
 
+#[cfg(any(not(feature = "cspr"), test))]
 pub mod mock;
 
 pub type Address = [u8; 20];
@@ -42,6 +43,7 @@ pub struct EvmLog<'a> {
 }
 
 impl<'a> EvmLog<'a> {
+    #[cfg(any(not(feature = "cspr"), test))]
     pub fn to_json_string(self) -> String {
         let num_topics = self.topics.len();
         let topics_string = if num_topics == 0 {
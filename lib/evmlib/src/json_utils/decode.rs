@@ -1,5 +1,6 @@
 //! A collection of functions for decoding JSON into ethabi::Token values.
 
+use super::value::{self, Value};
 use abi_types::ethabi::{
     self,
     ethereum_types::{H160, U256},
@@ -17,24 +18,29 @@ pub fn transform_json_call_data(
         std::str::from_utf8(param_names).map_err(|_| TransformCallDataError::InvalidUtf8String)?;
     let param_types =
         std::str::from_utf8(param_types).map_err(|_| TransformCallDataError::InvalidUtf8String)?;
+    let param_names: Vec<&str> = match param_names {
+        "" => Vec::new(),
+        param_names => param_names.split(',').collect(),
+    };
+    // the types are not split on commas, which tuple types contain
+    let param_types = abi_types::parse_param_types(param_types)
+        .map_err(|_| TransformCallDataError::InvalidAbiType)?;
     assert_eq!(
-        param_names.split(',').count(),
-        param_types.split(',').count(),
+        param_names.len(),
+        param_types.len(),
         "Expected same number of parameter names and types"
     );
-    let parsed_json: serde_json::Value =
-        serde_json::from_slice(json_call_data).map_err(|_| TransformCallDataError::InvalidJson)?;
+    let parsed_json =
+        value::from_slice(json_call_data).map_err(|_| TransformCallDataError::InvalidJson)?;
     let json_object = parsed_json
         .as_object()
         .ok_or(TransformCallDataError::NotJsonObject)?;
     let mut abi_tokens: Vec<ethabi::Token> = Vec::with_capacity(param_names.len());
-    for (name, typ) in param_names.split(',').zip(param_types.split(',')) {
-        let abi_type =
-            abi_types::parse_param_type(typ).map_err(|_| TransformCallDataError::InvalidAbiType)?;
+    for (name, abi_type) in param_names.iter().zip(param_types.iter()) {
         let param_value = json_object
-            .get(name)
+            .get(*name)
             .ok_or(TransformCallDataError::MissingParameter)?;
-        let abi_value = parse_json_value_to_abi_type(param_value, &abi_type)?;
+        let abi_value = parse_json_value_to_abi_type(param_value, abi_type)?;
         abi_tokens.push(abi_value);
     }
     let args = ethabi::encode(&abi_tokens);
@@ -43,7 +49,7 @@ pub fn transform_json_call_data(
 }
 
 fn parse_json_value_to_abi_type(
-    param_value: &serde_json::Value,
+    param_value: &Value,
     abi_type: &ethabi::ParamType,
 ) -> Result<ethabi::Token, TransformCallDataError> {
     match abi_type {
@@ -129,9 +135,9 @@ fn parse_json_value_to_abi_type(
     }
 }
 
-fn parse_json_value_to_u256(value: &serde_json::Value) -> Result<U256, TransformCallDataError> {
+fn parse_json_value_to_u256(value: &Value) -> Result<U256, TransformCallDataError> {
     match value {
-        serde_json::Value::String(s) => {
+        Value::String(s) => {
             let parsed = if s.starts_with("0x") {
                 ethabi::ethereum_types::U256::from_str_radix(s, 16)
             } else {
@@ -139,7 +145,7 @@ fn parse_json_value_to_u256(value: &serde_json::Value) -> Result<U256, Transform
             };
             parsed.map_err(|_| TransformCallDataError::InvalidAbiValue)
         }
-        serde_json::Value::Number(num) => num
+        Value::Number(_) => value
             .as_u64()
             .map(U256::from)
             .ok_or(TransformCallDataError::InvalidAbiValue),
@@ -147,9 +153,9 @@ fn parse_json_value_to_u256(value: &serde_json::Value) -> Result<U256, Transform
     }
 }
 
-fn parse_json_value_to_i256(value: &serde_json::Value) -> Result<U256, TransformCallDataError> {
+fn parse_json_value_to_i256(value: &Value) -> Result<U256, TransformCallDataError> {
     match value {
-        serde_json::Value::String(s) => {
+        Value::String(s) => {
             let parsed = if s.starts_with("0x") {
                 ethabi::ethereum_types::U256::from_str_radix(s, 16)
                     .map_err(|_| TransformCallDataError::InvalidAbiValue)?
@@ -161,7 +167,7 @@ fn parse_json_value_to_i256(value: &serde_json::Value) -> Result<U256, Transform
             };
             Ok(parsed)
         }
-        serde_json::Value::Number(num) => num
+        Value::Number(_) => value
             .as_i64()
             .map(|i| {
                 let number = ethnum::i256::from(i);
@@ -197,4 +203,17 @@ mod tests {
         let expected_output = hex::decode("3c4308a800000000000000000000000000000000000000000000000000000000000000060000000000000000000000000000000000000000000000000000000000000007").unwrap();
         assert_eq!(output, expected_output);
     }
+
+    #[test]
+    fn test_transform_tuple_call_data() {
+        let output = super::transform_json_call_data(
+            0x3c4308a8,
+            b"p,c",
+            b"(int256,int256),int256",
+            r#"{"p": [6, 7], "c": 8}"#.as_bytes(),
+        )
+        .unwrap();
+        let expected_output = hex::decode("3c4308a8000000000000000000000000000000000000000000000000000000000000000600000000000000000000000000000000000000000000000000000000000000070000000000000000000000000000000000000000000000000000000000000008").unwrap();
+        assert_eq!(output, expected_output);
+    }
 }
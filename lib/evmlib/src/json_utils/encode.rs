@@ -1,30 +1,35 @@
 //! A collection of functions for encoding ethabi::Token values into JSON.
 
+use super::value::Value;
 use crate::env::ExitStatus;
 use abi_types::ethabi;
+use std::collections::BTreeMap;
 
-/// Given a string which lists the ABI types of a function's output, the exit status, and the
-/// return data from the EVM execution; this function attempts to create a json object to encode
-/// this output and returns it serialized into bytes.
+/// The selector of `Error(string)`, which `revert` and `require` use.
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+/// The selector of `Panic(uint256)`, which failing assertions and checks use.
+const PANIC_SELECTOR: [u8; 4] = [0x4e, 0x48, 0x7b, 0x71];
+
+/// Given a string which lists the ABI types of a function's output, the custom errors of the
+/// contract (see `decode_custom_error`), the exit status, and the return data from the EVM
+/// execution; this function attempts to create a json object to encode this output and returns
+/// it serialized into bytes.
 pub fn encode_return_data_as_json(
     output_types: &[u8],
+    errors: &[u8],
     return_data: &[u8],
     exit_status: &Option<ExitStatus>,
 ) -> Result<Vec<u8>, EncodeReturnDataError> {
     let exit_status = exit_status.ok_or(EncodeReturnDataError::NoExitStatus)?;
-    let mut json_result = serde_json::Map::new();
+    let mut json_result = BTreeMap::new();
     match exit_status {
         ExitStatus::Success => {
-            json_result.insert("status".into(), serde_json::Value::String("SUCCESS".into()));
+            json_result.insert("status".into(), Value::String("SUCCESS".into()));
             let output_types = std::str::from_utf8(output_types)
                 .map_err(|_| EncodeReturnDataError::InvalidUtf8String)?;
 
-            let mut abi_types = Vec::new();
-            for output in output_types.split(',') {
-                let abi_type = abi_types::parse_param_type(output)
-                    .map_err(|_| EncodeReturnDataError::InvalidAbiType)?;
-                abi_types.push(abi_type);
-            }
+            let abi_types = abi_types::parse_param_types(output_types)
+                .map_err(|_| EncodeReturnDataError::InvalidAbiType)?;
             let mut return_tokens = ethabi::decode(&abi_types, return_data)
                 .map_err(|_| EncodeReturnDataError::ReturnDataDecodeFailure)?;
             let json_value = if return_tokens.len() == 1 {
@@ -36,45 +41,114 @@ pub fn encode_return_data_as_json(
             json_result.insert("output".into(), json_value);
         }
         ExitStatus::Revert => {
-            json_result.insert("status".into(), serde_json::Value::String("REVERT".into()));
-            // Check for standard Solidity error format
-            if return_data[0..4] == [0x08, 0xc3, 0x79, 0xa0] {
-                let mut return_tokens =
-                    ethabi::decode(&[ethabi::ParamType::String], &return_data[4..])
-                        .map_err(|_| EncodeReturnDataError::ReturnDataDecodeFailure)?;
-                // Unwrap is statically safe because we passed only a single type to decode
-                let error_message = return_tokens.pop().unwrap();
-                let json_value = ethabi_token_to_json_value(error_message);
-                json_result.insert("error".into(), json_value);
-            } else {
-                let json_value =
-                    serde_json::Value::String(format!("0x{}", hex::encode(return_data)));
-                json_result.insert("error".into(), json_value);
-            }
+            json_result.insert("status".into(), Value::String("REVERT".into()));
+            let json_value = decode_revert_data(errors, return_data)
+                .unwrap_or_else(|| Value::String(format!("0x{}", hex::encode(return_data))));
+            json_result.insert("error".into(), json_value);
         }
         ExitStatus::OutOfGas => {
-            json_result.insert(
-                "status".into(),
-                serde_json::Value::String("OUT_OF_GAS".into()),
-            );
+            json_result.insert("status".into(), Value::String("OUT_OF_GAS".into()));
         }
     }
-    let json_data = serde_json::to_vec(&json_result)
-        .map_err(|_| EncodeReturnDataError::JsonSerializationFailure)?;
-    Ok(json_data)
+    Ok(Value::Object(json_result).to_string().into_bytes())
+}
+
+/// Decodes the revert data into the message of `Error(string)`, or into an object naming the
+/// error and its arguments for `Panic(uint256)` and the custom errors. Returns `None` for data
+/// that does not decode, such as empty data.
+fn decode_revert_data(errors: &[u8], return_data: &[u8]) -> Option<Value> {
+    if return_data.len() < 4 {
+        return None;
+    }
+    let (selector, data) = return_data.split_at(4);
+    if selector == ERROR_SELECTOR {
+        let mut tokens = ethabi::decode(&[ethabi::ParamType::String], data).ok()?;
+        // Unwrap is statically safe because we passed only a single type to decode
+        Some(ethabi_token_to_json_value(tokens.pop().unwrap()))
+    } else if selector == PANIC_SELECTOR {
+        let mut tokens = ethabi::decode(&[ethabi::ParamType::Uint(256)], data).ok()?;
+        let code = tokens.pop().unwrap().into_uint().unwrap();
+        let reason = u64::try_from(code).map_or("unknown panic", panic_reason);
+        let mut args = BTreeMap::new();
+        args.insert(
+            "code".into(),
+            ethabi_token_to_json_value(ethabi::Token::Uint(code)),
+        );
+        args.insert("reason".into(), reason.into());
+        Some(error_json("Panic", args))
+    } else {
+        decode_custom_error(errors, selector, data)
+    }
+}
+
+/// Decodes a custom error of the contract. The errors are listed one per line as the selector
+/// in hex, the signature, and the comma-separated parameter names, separated by spaces:
+/// `db42144d InsufficientBalance(address,uint256) account,needed`.
+fn decode_custom_error(errors: &[u8], selector: &[u8], data: &[u8]) -> Option<Value> {
+    let errors = std::str::from_utf8(errors).ok()?;
+    let selector = hex::encode(selector);
+    let mut fields = errors
+        .lines()
+        .map(|line| line.split(' '))
+        .find(|fields| fields.clone().next() == Some(selector.as_str()))?
+        .skip(1);
+    let signature = fields.next()?;
+    let param_names = fields.next().unwrap_or_default();
+
+    let (name, types) = signature.split_at(signature.find('(')?);
+    let types = match types {
+        "()" => vec![],
+        types => match abi_types::parse_param_type(types).ok()? {
+            ethabi::ParamType::Tuple(types) => types,
+            _ => return None,
+        },
+    };
+    let tokens = ethabi::decode(&types, data).ok()?;
+    let mut args = BTreeMap::new();
+    let param_names = param_names.split(',').chain(std::iter::repeat(""));
+    for (i, (param_name, token)) in param_names.zip(tokens).enumerate() {
+        // unnamed parameters are keyed by position
+        let key = match param_name {
+            "" => i.to_string(),
+            param_name => param_name.to_string(),
+        };
+        args.insert(key, ethabi_token_to_json_value(token));
+    }
+    Some(error_json(name, args))
+}
+
+fn error_json(name: &str, args: BTreeMap<String, Value>) -> Value {
+    let mut error = BTreeMap::new();
+    error.insert("name".into(), name.into());
+    error.insert("args".into(), Value::Object(args));
+    Value::Object(error)
+}
+
+/// Describes the panic codes, see
+/// https://docs.soliditylang.org/en/latest/control-structures.html#panic-via-assert-and-error-via-require
+fn panic_reason(code: u64) -> &'static str {
+    match code {
+        0x00 => "generic compiler panic",
+        0x01 => "assertion failed",
+        0x11 => "arithmetic overflow or underflow",
+        0x12 => "division or modulo by zero",
+        0x21 => "invalid enum value",
+        0x22 => "invalid storage byte array encoding",
+        0x31 => "pop on empty array",
+        0x32 => "array index out of bounds",
+        0x41 => "out of memory",
+        0x51 => "call to invalid internal function",
+        _ => "unknown panic",
+    }
 }
 
-fn ethabi_token_to_json_value(token: ethabi::Token) -> serde_json::Value {
+fn ethabi_token_to_json_value(token: ethabi::Token) -> Value {
     match token {
         ethabi::Token::Address(address) => {
-            serde_json::Value::String(format!("0x{}", hex::encode(address.as_bytes())))
-        }
-        ethabi::Token::FixedBytes(bytes) => {
-            serde_json::Value::String(format!("0x{}", hex::encode(bytes)))
-        }
-        ethabi::Token::Bytes(bytes) => {
-            serde_json::Value::String(format!("0x{}", hex::encode(bytes)))
+            Value::String(format!("0x{}", hex::encode(address.as_bytes())))
         }
+        ethabi::Token::FixedBytes(bytes) => Value::String(format!("0x{}", hex::encode(bytes))),
+        ethabi::Token::Bytes(bytes) => Value::String(format!("0x{}", hex::encode(bytes))),
         ethabi::Token::Int(number) => {
             let be_bytes = {
                 let mut buf = [0u8; 32];
@@ -83,27 +157,27 @@ fn ethabi_token_to_json_value(token: ethabi::Token) -> serde_json::Value {
             };
             let signed_number = ethnum::i256::from_be_bytes(be_bytes);
             match i64::try_from(signed_number) {
-                Ok(n) => serde_json::Value::Number(serde_json::value::Number::from(n)),
-                Err(_) => serde_json::Value::String(signed_number.to_string()),
+                Ok(n) => Value::from(n),
+                Err(_) => Value::String(signed_number.to_string()),
             }
         }
         ethabi::Token::Uint(number) => match u64::try_from(number) {
-            Ok(n) => serde_json::Value::Number(serde_json::value::Number::from(n)),
-            Err(_) => serde_json::Value::String(number.to_string()),
+            Ok(n) => Value::from(n),
+            Err(_) => Value::String(number.to_string()),
         },
-        ethabi::Token::Bool(value) => serde_json::Value::Bool(value),
-        ethabi::Token::String(value) => serde_json::Value::String(value),
+        ethabi::Token::Bool(value) => Value::Bool(value),
+        ethabi::Token::String(value) => Value::String(value),
         ethabi::Token::FixedArray(values) => {
             let inner_values = values.into_iter().map(ethabi_token_to_json_value).collect();
-            serde_json::Value::Array(inner_values)
+            Value::Array(inner_values)
         }
         ethabi::Token::Array(values) => {
             let inner_values = values.into_iter().map(ethabi_token_to_json_value).collect();
-            serde_json::Value::Array(inner_values)
+            Value::Array(inner_values)
         }
         ethabi::Token::Tuple(values) => {
             let inner_values = values.into_iter().map(ethabi_token_to_json_value).collect();
-            serde_json::Value::Array(inner_values)
+            Value::Array(inner_values)
         }
     }
 }
@@ -114,15 +188,29 @@ pub enum EncodeReturnDataError {
     InvalidUtf8String,
     InvalidAbiType,
     ReturnDataDecodeFailure,
-    JsonSerializationFailure,
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::env::ExitStatus;
+
+    fn encode_revert(errors: &str, return_data: &str) -> String {
+        let return_data = hex::decode(return_data).unwrap();
+        let output = super::encode_return_data_as_json(
+            b"",
+            errors.as_bytes(),
+            &return_data,
+            &Some(ExitStatus::Revert),
+        )
+        .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
     #[test]
     fn test_encode_return_data_as_json() {
         let output = super::encode_return_data_as_json(
             b"int256",
+            b"",
             &hex::decode("000000000000000000000000000000000000000000000000000000000000002A")
                 .unwrap(),
             &Some(crate::env::ExitStatus::Success),
@@ -131,4 +219,66 @@ mod tests {
         let expected_output = r#"{"output":42,"status":"SUCCESS"}"#.as_bytes();
         assert_eq!(&output, expected_output);
     }
+
+    #[test]
+    fn test_encode_revert_messages() {
+        // Error("no")
+        let message = concat!(
+            "08c379a0",
+            "0000000000000000000000000000000000000000000000000000000000000020",
+            "0000000000000000000000000000000000000000000000000000000000000002",
+            "6e6f000000000000000000000000000000000000000000000000000000000000",
+        );
+        assert_eq!(
+            encode_revert("", message),
+            r#"{"error":"no","status":"REVERT"}"#
+        );
+        // Panic(0x11)
+        let panic = "4e487b710000000000000000000000000000000000000000000000000000000000000011";
+        assert_eq!(
+            encode_revert("", panic),
+            concat!(
+                r#"{"error":{"args":{"code":17,"reason":"arithmetic overflow or underflow"},"#,
+                r#""name":"Panic"},"status":"REVERT"}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_encode_revert_custom_errors() {
+        let errors = "db42144d InsufficientBalance(address,uint256) account,\n01020304 Empty() ";
+        let error = concat!(
+            "db42144d",
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "000000000000000000000000000000000000000000000000000000000000002a",
+        );
+        assert_eq!(
+            encode_revert(errors, error),
+            concat!(
+                r#"{"error":{"args":{"1":42,"account":"0x0000000000000000000000000000000000000001"},"#,
+                r#""name":"InsufficientBalance"},"status":"REVERT"}"#
+            )
+        );
+        assert_eq!(
+            encode_revert(errors, "01020304"),
+            r#"{"error":{"args":{},"name":"Empty"},"status":"REVERT"}"#
+        );
+        // unknown, short, empty and truncated data is given in hex
+        assert_eq!(
+            encode_revert(errors, "0a0b0c0d"),
+            r#"{"error":"0x0a0b0c0d","status":"REVERT"}"#
+        );
+        assert_eq!(
+            encode_revert(errors, "0a0b"),
+            r#"{"error":"0x0a0b","status":"REVERT"}"#
+        );
+        assert_eq!(
+            encode_revert(errors, ""),
+            r#"{"error":"0x","status":"REVERT"}"#
+        );
+        assert_eq!(
+            encode_revert(errors, "db42144d00"),
+            r#"{"error":"0xdb42144d00","status":"REVERT"}"#
+        );
+    }
 }
@@ -0,0 +1,399 @@
+//! A minimal JSON value with its parser and serializer. Numbers keep the text they were written
+//! in, so unlike serde_json nothing goes through `f64`: the Casper node rejects contracts which
+//! contain floating point instructions.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+
+/// Nesting limit of arrays and objects, as in serde_json, to bound the recursion.
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    /// The number as written, which the parser checks against the JSON grammar.
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    /// The members sorted by key, which is also the order they are serialized in.
+    Object(BTreeMap<String, Value>),
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Value>> {
+        match self {
+            Self::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&BTreeMap<String, Value>> {
+        match self {
+            Self::Object(members) => Some(members),
+            _ => None,
+        }
+    }
+
+    /// Returns the number if it is an integer which fits, but not for fractions or exponents.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Number(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Like `as_u64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Number(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Self::Number(n.to_string())
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Self::Number(n.to_string())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Self::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Self::String(s)
+    }
+}
+
+/// Serializes the value compactly, without any whitespace.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Number(n) => f.write_str(n),
+            Self::String(s) => write_string(f, s),
+            Self::Array(values) => {
+                f.write_char('[')?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_char(']')
+            }
+            Self::Object(members) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            '\u{8}' => f.write_str("\\b")?,
+            '\u{c}' => f.write_str("\\f")?,
+            c if c < ' ' => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// Parses a single JSON value, which may be surrounded by whitespace.
+pub fn from_slice(json: &[u8]) -> Result<Value, ParseError> {
+    // Checking the whole input up front lets the parser slice strings at ASCII delimiters.
+    let json = std::str::from_utf8(json).map_err(|_| ParseError)?;
+    let mut parser = Parser { json, pos: 0 };
+    let value = parser.parse_value(0)?;
+    parser.skip_whitespace();
+    match parser.pos == json.len() {
+        true => Ok(value),
+        false => Err(ParseError),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseError;
+
+struct Parser<'a> {
+    json: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.json.as_bytes().get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<u8, ParseError> {
+        let byte = self.peek().ok_or(ParseError)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), ParseError> {
+        match self.next()? == byte {
+            true => Ok(()),
+            false => Err(ParseError),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Value, ParseError> {
+        if depth > MAX_DEPTH {
+            return Err(ParseError);
+        }
+        self.skip_whitespace();
+        match self.peek().ok_or(ParseError)? {
+            b'n' => self.parse_literal("null", Value::Null),
+            b't' => self.parse_literal("true", Value::Bool(true)),
+            b'f' => self.parse_literal("false", Value::Bool(false)),
+            b'"' => self.parse_string().map(Value::String),
+            b'[' => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(values));
+                }
+                loop {
+                    values.push(self.parse_value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.next()? {
+                        b',' => continue,
+                        b']' => return Ok(Value::Array(values)),
+                        _ => return Err(ParseError),
+                    }
+                }
+            }
+            b'{' => {
+                self.pos += 1;
+                let mut members = BTreeMap::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.parse_string()?;
+                    self.skip_whitespace();
+                    self.expect(b':')?;
+                    // a repeated key keeps the last value, as in serde_json
+                    members.insert(key, self.parse_value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.next()? {
+                        b',' => continue,
+                        b'}' => return Ok(Value::Object(members)),
+                        _ => return Err(ParseError),
+                    }
+                }
+            }
+            b'-' | b'0'..=b'9' => self.parse_number(),
+            _ => Err(ParseError),
+        }
+    }
+
+    fn parse_literal(&mut self, literal: &str, value: Value) -> Result<Value, ParseError> {
+        match self.json[self.pos..].starts_with(literal) {
+            true => {
+                self.pos += literal.len();
+                Ok(value)
+            }
+            false => Err(ParseError),
+        }
+    }
+
+    fn skip_digits(&mut self) -> usize {
+        let start = self.pos;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    /// Checks the number against the grammar `-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?`.
+    fn parse_number(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        match self.next()? {
+            b'0' => {}
+            b'1'..=b'9' => {
+                self.skip_digits();
+            }
+            _ => return Err(ParseError),
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if self.skip_digits() == 0 {
+                return Err(ParseError);
+            }
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.pos += 1;
+            }
+            if self.skip_digits() == 0 {
+                return Err(ParseError);
+            }
+        }
+        Ok(Value::Number(self.json[start..self.pos].to_string()))
+    }
+
+    fn parse_string(&mut self) -> Result<String, ParseError> {
+        self.expect(b'"')?;
+        let mut s = String::new();
+        loop {
+            let start = self.pos;
+            while let Some(byte) = self.peek() {
+                if byte == b'"' || byte == b'\\' || byte < b' ' {
+                    break;
+                }
+                self.pos += 1;
+            }
+            s.push_str(&self.json[start..self.pos]);
+            match self.next()? {
+                b'"' => return Ok(s),
+                b'\\' => {}
+                _ => return Err(ParseError),
+            }
+            let c = match self.next()? {
+                b'"' => '"',
+                b'\\' => '\\',
+                b'/' => '/',
+                b'b' => '\u{8}',
+                b'f' => '\u{c}',
+                b'n' => '\n',
+                b'r' => '\r',
+                b't' => '\t',
+                b'u' => {
+                    let high = self.parse_hex4()?;
+                    let code = match high {
+                        0xd800..=0xdbff => {
+                            // a surrogate pair
+                            self.expect(b'\\')?;
+                            self.expect(b'u')?;
+                            let low = self.parse_hex4()?;
+                            if !(0xdc00..=0xdfff).contains(&low) {
+                                return Err(ParseError);
+                            }
+                            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                        }
+                        code => code,
+                    };
+                    char::from_u32(code).ok_or(ParseError)?
+                }
+                _ => return Err(ParseError),
+            };
+            s.push(c);
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, ParseError> {
+        let digits = self.json.get(self.pos..self.pos + 4).ok_or(ParseError)?;
+        if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseError);
+        }
+        self.pos += 4;
+        u32::from_str_radix(digits, 16).map_err(|_| ParseError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_slice, ParseError, Value};
+
+    fn reserialize(json: &str) -> Result<String, ParseError> {
+        from_slice(json.as_bytes()).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn test_parse_and_serialize() {
+        assert_eq!(
+            reserialize(r#" {"b": [1, -2.5e+3, true, null], "a": {}, "b": [] } "#),
+            Ok(r#"{"a":{},"b":[]}"#.to_string())
+        );
+        assert_eq!(
+            reserialize(r#""\"\\\/\b\f\n\r\t\u0001é😀""#),
+            Ok(r#""\"\\/\b\f\n\r\t\u0001é😀""#.to_string())
+        );
+        for invalid in [
+            "",
+            "01",
+            "1.",
+            "-",
+            "1e",
+            "[1,]",
+            r#"{"a"}"#,
+            "\"\t\"",
+            r#""\ud83d""#,
+        ] {
+            assert_eq!(reserialize(invalid), Err(ParseError), "{}", invalid);
+        }
+        assert_eq!(reserialize(&"[".repeat(200)), Err(ParseError));
+    }
+
+    #[test]
+    fn test_integers() {
+        let numbers = from_slice(b"[42, -42, 4.2, 4e1, 18446744073709551616]").unwrap();
+        let numbers = numbers.as_array().unwrap();
+        assert_eq!(numbers[0].as_u64(), Some(42));
+        assert_eq!(numbers[1].as_u64(), None);
+        assert_eq!(numbers[1].as_i64(), Some(-42));
+        assert_eq!(numbers[2].as_i64(), None);
+        assert_eq!(numbers[3].as_u64(), None);
+        assert_eq!(numbers[4].as_u64(), None);
+        assert_eq!(Value::from(u64::MAX).as_u64(), Some(u64::MAX));
+    }
+}
@@ -3,10 +3,12 @@
 #![feature(stmt_expr_attributes)]
 
 mod api;
+mod arith;
+#[cfg(all(feature = "cspr", not(test)))]
+mod cspr_runtime;
 mod env;
 mod hash_provider;
 mod json_utils;
-mod cspr_runtime;
 mod ops;
 mod state;
 
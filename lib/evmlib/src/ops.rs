@@ -1,13 +1,10 @@
 // This is free and unencumbered software released into the public domain.
 
-use ethnum::{AsU256, I256};
-use std::{
-    convert::TryInto,
-    ops::{Not, Shl, Shr},
-};
+use std::convert::TryInto;
 use ux::*;
 
 use crate::{
+    arith,
     env::{Address, Env, EvmLog},
     hash_provider::HashProvider,
     state::{Machine, Memory, Stack, Word, MAX_STACK_DEPTH, ONE, ZERO},
@@ -38,9 +35,10 @@ pub(crate) static mut EVM: Machine = Machine {
 };
 
 #[cfg(all(feature = "cspr", not(test)))]
-pub(crate) static mut ENV: crate::cspr_runtime::csprRuntime = crate::cspr_runtime::csprRuntime {
+pub(crate) static mut ENV: crate::cspr_runtime::CsprRuntime = crate::cspr_runtime::CsprRuntime {
     call_data: None,
     storage_cache: None,
+    storage_uref: None,
     address_cache: None,
     origin_cache: None,
     caller_cache: None,
@@ -62,10 +60,6 @@ pub(crate) static mut ENV: crate::env::mock::MockEnv = crate::env::mock::MockEnv
     exit_status: None,
 };
 
-#[cfg(all(feature = "cspr", not(test)))]
-pub(crate) type Hasher = crate::cspr_runtime::csprRuntime;
-
-#[cfg(any(not(feature = "cspr"), test))]
 pub(crate) type Hasher = crate::hash_provider::Native;
 
 macro_rules! trace {
@@ -89,7 +83,7 @@ macro_rules! trace {
 
 #[no_mangle]
 pub unsafe fn stop() {
-    EVM.burn_gas(0);
+    EVM.burn_static_gas(0);
     EVM.stack.clear();
     ENV.value_return(&[]);
     trace!("STOP");
@@ -97,93 +91,79 @@ pub unsafe fn stop() {
 
 #[no_mangle]
 pub unsafe fn add() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (a, b) = EVM.stack.pop2();
-    EVM.stack.push(a + b);
+    EVM.stack.push(arith::add(a, b));
     trace!("ADD a={} b={}", a, b);
 }
 
 #[no_mangle]
 pub unsafe fn mul() {
-    EVM.burn_gas(5);
+    EVM.burn_static_gas(5);
     let (a, b) = EVM.stack.pop2();
-    EVM.stack.push(a * b);
+    EVM.stack.push(arith::mul(a, b));
     trace!("MUL a={} b={}", a, b);
 }
 
 #[no_mangle]
 pub unsafe fn sub() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (a, b) = EVM.stack.pop2();
-    EVM.stack.push(a - b);
+    EVM.stack.push(arith::sub(a, b));
     trace!("SUB a={} b={}", a, b);
 }
 
 #[no_mangle]
 pub unsafe fn div() {
-    EVM.burn_gas(5);
+    EVM.burn_static_gas(5);
     let (a, b) = EVM.stack.pop2();
-    EVM.stack.push(if b == ZERO { ZERO } else { a / b });
+    EVM.stack.push(arith::div(a, b));
     trace!("DIV a={} b={}", a, b);
 }
 
 #[no_mangle]
 pub unsafe fn sdiv() {
-    EVM.burn_gas(5);
-    let a = EVM.stack.pop().as_i256();
-    let b = EVM.stack.pop().as_i256();
-    EVM.stack.push(if b == I256::ZERO {
-        ZERO
-    } else {
-        (a / b).as_u256()
-    });
-    trace!("SDIV a={} b={}", a, b);
+    EVM.burn_static_gas(5);
+    let (a, b) = EVM.stack.pop2();
+    EVM.stack.push(arith::sdiv(a, b));
+    trace!("SDIV a={} b={}", a.as_i256(), b.as_i256());
 }
 
 #[no_mangle]
 pub unsafe fn r#mod() {
-    EVM.burn_gas(5);
+    EVM.burn_static_gas(5);
     let (a, b) = EVM.stack.pop2();
-    EVM.stack.push(if b == ZERO { ZERO } else { a % b });
+    EVM.stack.push(arith::r#mod(a, b));
     trace!("MOD a={} b={}", a, b);
 }
 
 #[no_mangle]
 pub unsafe fn smod() {
-    EVM.burn_gas(5);
-    let a = EVM.stack.pop().as_i256();
-    let b = EVM.stack.pop().as_i256();
-    EVM.stack.push(if b == I256::ZERO {
-        ZERO
-    } else {
-        (a % b).as_u256()
-    });
-    trace!("SMOD a={} b={}", a, b);
+    EVM.burn_static_gas(5);
+    let (a, b) = EVM.stack.pop2();
+    EVM.stack.push(arith::smod(a, b));
+    trace!("SMOD a={} b={}", a.as_i256(), b.as_i256());
 }
 
 #[no_mangle]
 pub unsafe fn addmod() {
-    EVM.burn_gas(8);
-    // TODO: need to use 512-bit arithmetic here to prevent overflow before taking the modulus
+    EVM.burn_static_gas(8);
     let (a, b, n) = EVM.stack.pop3();
-    let result = if n == ZERO { ZERO } else { (a + b) % n };
-    EVM.stack.push(result);
+    EVM.stack.push(arith::addmod(a, b, n));
     trace!("ADDMOD a={} b={}", a, b);
 }
 
 #[no_mangle]
 pub unsafe fn mulmod() {
-    EVM.burn_gas(8);
-    // TODO: need to use 512-bit arithmetic here to prevent overflow before taking the modulus
+    EVM.burn_static_gas(8);
     let (a, b, n) = EVM.stack.pop3();
-    let result = if n == ZERO { ZERO } else { (a * b) % n };
-    EVM.stack.push(result);
+    EVM.stack.push(arith::mulmod(a, b, n));
     trace!("MULMOD a={} b={}", a, b);
 }
 
 #[no_mangle]
 pub unsafe fn exp() {
-    EVM.burn_gas(10);
+    EVM.burn_static_gas(10);
     let (a, b) = EVM.stack.pop2();
     EVM.stack.push(a.pow(b.try_into().unwrap()));
     trace!("EXP a={} b={}", a, b);
@@ -191,185 +171,127 @@ pub unsafe fn exp() {
 
 #[no_mangle]
 pub unsafe fn signextend() {
-    EVM.burn_gas(5);
+    EVM.burn_static_gas(5);
     let (op1, op2) = EVM.stack.pop2();
-    let result = if op1 < ethnum::U256::new(32) {
-        // `as_u32` works since op1 < 32
-        let bit_index = (8 * op1.as_u32() + 7) as usize;
-        let word = if bit_index < 128 {
-            op2.low()
-        } else {
-            op2.high()
-        };
-        let bit = word & (1 << (bit_index % 128)) != 0;
-        let mask = (ONE << bit_index) - ONE;
-        if bit {
-            op2 | !mask
-        } else {
-            op2 & mask
-        }
-    } else {
-        op2
-    };
-    EVM.stack.push(result);
+    EVM.stack.push(arith::signextend(op1, op2));
     trace!("SIGNEXTEND op1={} op2={}", op1, op2);
 }
 
 #[no_mangle]
 pub unsafe fn lt() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (a, b) = EVM.stack.pop2();
-    EVM.stack.push(if a < b { ONE } else { ZERO });
+    EVM.stack.push(arith::lt(a, b));
     trace!("LT a={} b={}", a, b);
 }
 
 #[no_mangle]
 pub unsafe fn gt() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (a, b) = EVM.stack.pop2();
-    EVM.stack.push(if a > b { ONE } else { ZERO });
+    EVM.stack.push(arith::gt(a, b));
     trace!("GT a={} b={}", a, b);
 }
 
 #[no_mangle]
 pub unsafe fn slt() {
-    EVM.burn_gas(3);
-    let a = EVM.stack.pop().as_i256();
-    let b = EVM.stack.pop().as_i256();
-    EVM.stack.push(if a < b { ONE } else { ZERO });
-    trace!("SLT a={} b={}", a, b);
+    EVM.burn_static_gas(3);
+    let (a, b) = EVM.stack.pop2();
+    EVM.stack.push(arith::slt(a, b));
+    trace!("SLT a={} b={}", a.as_i256(), b.as_i256());
 }
 
 #[no_mangle]
 pub unsafe fn sgt() {
-    EVM.burn_gas(3);
-    let a = EVM.stack.pop().as_i256();
-    let b = EVM.stack.pop().as_i256();
-    EVM.stack.push(if a > b { ONE } else { ZERO });
-    trace!("SGT a={} b={}", a, b);
+    EVM.burn_static_gas(3);
+    let (a, b) = EVM.stack.pop2();
+    EVM.stack.push(arith::sgt(a, b));
+    trace!("SGT a={} b={}", a.as_i256(), b.as_i256());
 }
 
 #[no_mangle]
 pub unsafe fn eq() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (a, b) = EVM.stack.pop2();
-    EVM.stack.push(if a == b { ONE } else { ZERO });
+    EVM.stack.push(arith::eq(a, b));
     trace!("EQ a={} b={}", a, b);
 }
 
 #[no_mangle]
 pub unsafe fn iszero() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let x = EVM.stack.pop();
-    EVM.stack.push(if x == ZERO { ONE } else { ZERO });
+    EVM.stack.push(arith::iszero(x));
     trace!("ISZERO x={}", x);
 }
 
 #[no_mangle]
 pub unsafe fn and() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (a, b) = EVM.stack.pop2();
-    EVM.stack.push(a & b);
+    EVM.stack.push(arith::and(a, b));
     trace!("AND a={} b={}", a, b);
 }
 
 #[no_mangle]
 pub unsafe fn or() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (a, b) = EVM.stack.pop2();
-    EVM.stack.push(a | b);
+    EVM.stack.push(arith::or(a, b));
     trace!("OR a={} b={}", a, b);
 }
 
 #[no_mangle]
 pub unsafe fn xor() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (a, b) = EVM.stack.pop2();
-    EVM.stack.push(a ^ b);
+    EVM.stack.push(arith::xor(a, b));
     trace!("XOR a={} b={}", a, b);
 }
 
 #[no_mangle]
 pub unsafe fn not() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let x = EVM.stack.pop();
-    EVM.stack.push(x.not());
+    EVM.stack.push(arith::not(x));
     trace!("NOT x={}", x);
 }
 
 #[no_mangle]
 pub unsafe fn byte() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (index, word) = EVM.stack.pop2();
-    let result = if index > 31 {
-        ZERO
-    } else {
-        let bytes = word.to_be_bytes();
-        Word::from(bytes[index.as_usize()])
-    };
-    EVM.stack.push(result);
+    EVM.stack.push(arith::byte(index, word));
     trace!("BYTE index={} word={}", index, word);
 }
 
 #[no_mangle]
 pub unsafe fn shl() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (shift, value) = EVM.stack.pop2();
-    let result = if value == ZERO || shift > Word::from(255u8) {
-        ZERO
-    } else {
-        value.shl(shift)
-    };
-    EVM.stack.push(result);
+    EVM.stack.push(arith::shl(shift, value));
     trace!("SHL shift={} value={}", shift, value);
 }
 
 #[no_mangle]
 pub unsafe fn shr() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (shift, value) = EVM.stack.pop2();
-    let result = if value == ZERO || shift > Word::from(255u8) {
-        ZERO
-    } else {
-        value.shr(shift)
-    };
-    EVM.stack.push(result);
+    EVM.stack.push(arith::shr(shift, value));
     trace!("SHR shift={} value={}", shift, value);
 }
 
 #[no_mangle]
 pub unsafe fn sar() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (shift, value) = EVM.stack.pop2();
-    let signed_value = value.as_i256();
-    let result = if signed_value == I256::ZERO || shift > Word::from(255u8) {
-        if signed_value.is_positive() {
-            ZERO
-        } else {
-            I256::from(-1).as_u256()
-        }
-    } else {
-        // Cast is safe since we checked if shift is less than 255
-        let shift = shift.as_u32();
-        if signed_value.is_positive() {
-            value.shr(shift).as_u256()
-        } else {
-            signed_value
-                .overflowing_sub(I256::ONE)
-                .0
-                .shr(shift)
-                .overflowing_add(I256::ONE)
-                .0
-                .as_u256()
-        }
-    };
-    EVM.stack.push(result);
+    EVM.stack.push(arith::sar(shift, value));
     trace!("SAR shift={} value={}", shift, value);
 }
 
 #[no_mangle]
 pub unsafe fn sha3() {
-    EVM.burn_gas(30);
+    EVM.burn_static_gas(30);
     let (offset, size) = EVM.stack.pop2();
     let size = as_usize_or_oog(size);
     let result = if size == 0 {
@@ -388,7 +310,7 @@ pub unsafe fn sha3() {
 
 #[no_mangle]
 pub unsafe fn address() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     let address = ENV.address();
     EVM.stack.push(address_to_u256(&address));
     trace!("ADDRESS");
@@ -396,7 +318,7 @@ pub unsafe fn address() {
 
 #[no_mangle]
 pub unsafe fn balance() {
-    EVM.burn_gas(100);
+    EVM.burn_static_gas(100);
     let address_u256 = EVM.stack.pop();
     let address = u256_to_address(address_u256);
     let result = if address == ENV.address() {
@@ -410,7 +332,7 @@ pub unsafe fn balance() {
 
 #[no_mangle]
 pub unsafe fn origin() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     let address = ENV.origin();
     EVM.stack.push(address_to_u256(&address));
     trace!("ORIGIN");
@@ -418,7 +340,7 @@ pub unsafe fn origin() {
 
 #[no_mangle]
 pub unsafe fn caller() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     let address = ENV.caller();
     EVM.stack.push(address_to_u256(&address));
     trace!("CALLER");
@@ -426,14 +348,14 @@ pub unsafe fn caller() {
 
 #[no_mangle]
 pub unsafe fn callvalue() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     EVM.stack.push(EVM.call_value);
     trace!("CALLVALUE");
 }
 
 #[no_mangle]
 pub unsafe fn calldataload() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     // Note: if the value on the stack is larger than usize::MAX then
     // `as_usize` will return `usize::MAX`, and this is ok because that
     // is the largest possible calldata size.
@@ -456,14 +378,14 @@ pub unsafe fn calldataload() {
 
 #[no_mangle]
 pub unsafe fn calldatasize() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     EVM.stack.push(Word::from(ENV.call_data_len() as u32));
     trace!("CALLDATASIZE");
 }
 
 #[no_mangle]
 pub unsafe fn calldatacopy() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (dest_offset, offset, size) = EVM.stack.pop3();
     data_copy(dest_offset, offset, size, ENV.call_data());
     trace!(
@@ -476,14 +398,14 @@ pub unsafe fn calldatacopy() {
 
 #[no_mangle]
 pub unsafe fn codesize() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     EVM.stack.push(Word::from(EVM.code.len() as u32));
     trace!("CODESIZE");
 }
 
 #[no_mangle]
 pub unsafe fn codecopy() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (dest_offset, offset, size) = EVM.stack.pop3();
     data_copy(dest_offset, offset, size, &EVM.code);
     trace!(
@@ -496,14 +418,14 @@ pub unsafe fn codecopy() {
 
 #[no_mangle]
 pub unsafe fn gasprice() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     EVM.stack.push(Word::from(EVM.gas_price));
     trace!("GASPRICE");
 }
 
 #[no_mangle]
 pub unsafe fn extcodesize() {
-    EVM.burn_gas(100);
+    EVM.burn_static_gas(100);
     let address_u256 = EVM.stack.pop();
     let address = u256_to_address(address_u256);
     // The only code we know about is our own.
@@ -519,7 +441,7 @@ pub unsafe fn extcodesize() {
 
 #[no_mangle]
 pub unsafe fn extcodecopy() {
-    EVM.burn_gas(100);
+    EVM.burn_static_gas(100);
     let (address_u256, dest_offset, offset, size) = EVM.stack.pop4();
     let address = u256_to_address(address_u256);
     // See note in `extcodesize` about why we only act on our own address
@@ -539,7 +461,7 @@ pub unsafe fn extcodecopy() {
 
 #[no_mangle]
 pub unsafe fn returndatasize() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     // Without any implementation of `CALL` there can be no sub-context
     // to have produced return data used in a larger execution.
     // We could consider using cspr's promise API as the previous return data,
@@ -551,7 +473,7 @@ pub unsafe fn returndatasize() {
 
 #[no_mangle]
 pub unsafe fn returndatacopy() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (dest_offset, offset, size) = EVM.stack.pop3();
     // See note in `returndatasize` about why we assume the return data is always empty.
     data_copy(dest_offset, offset, size, &[]);
@@ -565,7 +487,7 @@ pub unsafe fn returndatacopy() {
 
 #[no_mangle]
 pub unsafe fn extcodehash() {
-    EVM.burn_gas(100);
+    EVM.burn_static_gas(100);
     let address_u256 = EVM.stack.pop();
     let address = u256_to_address(address_u256);
     // See note in `extcodesize` about why we only act on our own address
@@ -581,21 +503,21 @@ pub unsafe fn extcodehash() {
 
 #[no_mangle]
 pub unsafe fn blockhash() {
-    EVM.burn_gas(20);
+    EVM.burn_static_gas(20);
     EVM.stack.push(ZERO); // TODO: cspr SDK
     trace!("BLOCKHASH");
 }
 
 #[no_mangle]
 pub unsafe fn coinbase() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     EVM.stack.push(ZERO); // TODO: cspr SDK
     trace!("COINBASE");
 }
 
 #[no_mangle]
 pub unsafe fn timestamp() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     let number = ENV.timestamp();
     EVM.stack.push(Word::from(number));
     trace!("TIMESTAMP");
@@ -603,7 +525,7 @@ pub unsafe fn timestamp() {
 
 #[no_mangle]
 pub unsafe fn number() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     let number = ENV.block_height();
     EVM.stack.push(Word::from(number));
     trace!("NUMBER");
@@ -611,49 +533,49 @@ pub unsafe fn number() {
 
 #[no_mangle]
 pub unsafe fn difficulty() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     EVM.stack.push(ZERO);
     trace!("DIFFICULTY");
 }
 
 #[no_mangle]
 pub unsafe fn gaslimit() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     EVM.stack.push(Word::from(EVM.gas_limit));
     trace!("GASLIMIT");
 }
 
 #[no_mangle]
 pub unsafe fn chainid() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     EVM.stack.push(EVM.chain_id);
     trace!("CHAINID");
 }
 
 #[no_mangle]
 pub unsafe fn selfbalance() {
-    EVM.burn_gas(5);
+    EVM.burn_static_gas(5);
     EVM.stack.push(EVM.self_balance);
     trace!("SELFBALANCE");
 }
 
 #[no_mangle]
 pub unsafe fn basefee() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     EVM.stack.push(ZERO);
     trace!("BASEFEE");
 }
 
 #[no_mangle]
 pub unsafe fn pop() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     let _tos = EVM.stack.pop();
     trace!("POP tos={}", _tos);
 }
 
 #[no_mangle]
 pub unsafe fn mload() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let offset = EVM.stack.pop();
     // TODO: gas cost for memory resize (reads resize the memory too)
     let value = EVM.memory.load_word(offset.try_into().unwrap());
@@ -663,7 +585,7 @@ pub unsafe fn mload() {
 
 #[no_mangle]
 pub unsafe fn mstore() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     // TODO: gas cost for memory resize
     let (offset, value) = EVM.stack.pop2();
     EVM.memory.store_word(offset.try_into().unwrap(), value);
@@ -672,7 +594,7 @@ pub unsafe fn mstore() {
 
 #[no_mangle]
 pub unsafe fn mstore8() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let (offset, value) = (EVM.stack.pop(), EVM.stack.pop() & 0xFF);
     // TODO: gas cost for memory resize
     EVM.memory
@@ -682,7 +604,7 @@ pub unsafe fn mstore8() {
 
 #[no_mangle]
 pub unsafe fn sload() {
-    EVM.burn_gas(100);
+    EVM.burn_static_gas(100);
     // TODO: dynamic hot/cold gas cost
     let key = EVM.stack.pop();
     let value = ENV.storage_read(key);
@@ -692,7 +614,7 @@ pub unsafe fn sload() {
 
 #[no_mangle]
 pub unsafe fn sstore() {
-    EVM.burn_gas(100);
+    EVM.burn_static_gas(100);
     // TODO: dynamic hot/cold gas cost
     let (key, value) = EVM.stack.pop2();
     ENV.storage_write(key, value);
@@ -703,13 +625,13 @@ pub unsafe fn sstore() {
 pub unsafe fn jump() {
     // We only do JUMP gas cost accounting here, the actual branch is
     // synthesized by the compiler.
-    EVM.burn_gas(8);
+    EVM.burn_static_gas(8);
     trace!("JUMP");
 }
 
 #[no_mangle]
 pub unsafe fn pc() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     #[cfg(feature = "pc")]
     EVM.stack.push(Word::from(EVM.program_counter));
     #[cfg(not(feature = "pc"))]
@@ -719,26 +641,27 @@ pub unsafe fn pc() {
 
 #[no_mangle]
 pub unsafe fn msize() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     EVM.stack.push(Word::from(EVM.memory.size() as u64));
     trace!("MSIZE");
 }
 
 #[no_mangle]
 pub unsafe fn gas() {
-    EVM.burn_gas(2);
+    EVM.burn_static_gas(2);
     EVM.stack.push(Word::from(EVM.gas_limit - EVM.gas_used)); // TODO: --fno-gas-accounting
     trace!("GAS");
 }
 
 #[no_mangle]
 pub unsafe fn jumpdest() {
+    EVM.burn_static_gas(1);
     trace!("JUMPDEST");
 }
 
 #[no_mangle]
 pub unsafe fn push1(word: u8) {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     EVM.stack.push(Word::from(word));
     trace!("PUSH1 0x{:02x}", word);
 }
@@ -755,7 +678,7 @@ pub unsafe fn push3(word: u24) {
 
 #[no_mangle]
 pub unsafe fn push4(word: u32) {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     EVM.stack.push(Word::from(word));
     trace!("PUSH4 0x{:04x}", word);
 }
@@ -777,7 +700,7 @@ pub unsafe fn push7(word: u56) {
 
 #[no_mangle]
 pub unsafe fn push8(word: u64) {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     EVM.stack.push(Word::from(word));
     trace!("PUSH8 0x{:08x}", word);
 }
@@ -819,7 +742,7 @@ pub unsafe fn push15(word: /*u120*/ u128) {
 
 #[no_mangle]
 pub unsafe fn push16(word: u128) {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     EVM.stack.push(Word::from_words(0, word));
     trace!("PUSH16 0x{:16x}", word);
 }
@@ -901,7 +824,7 @@ pub unsafe fn push31(word_0: u64, word_1: u64, word_2: u64, word_3: u56) {
 
 #[no_mangle]
 pub unsafe fn push32(word_0: u64, word_1: u64, word_2: u64, word_3: u64) {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     let mut bytes: [u8; 32] = [0; 32];
     bytes[0..8].copy_from_slice(&word_0.to_le_bytes());
     bytes[8..16].copy_from_slice(&word_1.to_le_bytes());
@@ -913,7 +836,7 @@ pub unsafe fn push32(word_0: u64, word_1: u64, word_2: u64, word_3: u64) {
 
 #[no_mangle]
 pub unsafe fn dup1() {
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     EVM.stack.push(EVM.stack.peek());
     trace!("DUP1");
 }
@@ -995,7 +918,7 @@ pub unsafe fn dup16() {
 
 unsafe fn dup(n: u8) {
     assert!((1..=16).contains(&n));
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     EVM.stack.push(EVM.stack.peek_n(n as usize - 1));
     trace!("DUP{}", n);
 }
@@ -1082,14 +1005,58 @@ pub unsafe fn swap16() {
 
 unsafe fn swap(n: u8) {
     assert!((1..=16).contains(&n));
-    EVM.burn_gas(3);
+    EVM.burn_static_gas(3);
     EVM.stack.swap(n.into());
     trace!("SWAP{}", n);
 }
 
+/// Fused `POP` repeated `n` times.
+#[no_mangle]
+pub unsafe fn _evm_pop_n(n: u32) {
+    EVM.burn_static_gas(2 * u64::from(n));
+    for _ in 0..n {
+        EVM.stack.pop();
+    }
+    trace!("POP*{}", n);
+}
+
+/// Fused `SWAPn POP`.
+#[no_mangle]
+pub unsafe fn _evm_swap_pop(n: u32) {
+    assert!((1..=16).contains(&n));
+    EVM.burn_static_gas(3 + 2);
+    EVM.stack.swap(n as usize);
+    let _tos = EVM.stack.pop();
+    trace!("SWAP{} POP tos={}", n, _tos);
+}
+
+/// Fused `ISZERO ISZERO`.
+#[no_mangle]
+pub unsafe fn _evm_bool() {
+    EVM.burn_static_gas(3 + 3);
+    let x = EVM.stack.pop();
+    EVM.stack.push(if x == ZERO { ZERO } else { ONE });
+    trace!("ISZERO ISZERO x={}", x);
+}
+
+/// Fused `PUSH 2^bits-1 AND`.
+#[no_mangle]
+pub unsafe fn _evm_mask(bits: u32) {
+    assert!((1..=256).contains(&bits));
+    EVM.burn_static_gas(3 + 3);
+    let x = EVM.stack.pop();
+    let mask = if bits == 256 {
+        Word::MAX
+    } else {
+        (ONE << bits) - ONE
+    };
+    EVM.stack.push(x & mask);
+    trace!("PUSH AND x={} bits={}", x, bits);
+}
+
 #[no_mangle]
 pub unsafe fn log0() {
-    EVM.burn_gas(375);
+    EVM.burn_static_gas(375);
     let (offset, size) = EVM.stack.pop2();
     let data = EVM.memory.slice(offset.as_usize(), size.as_usize());
     let log = EvmLog {
@@ -1103,7 +1070,7 @@ pub unsafe fn log0() {
 
 #[no_mangle]
 pub unsafe fn log1() {
-    EVM.burn_gas(750);
+    EVM.burn_static_gas(750);
     let (offset, size) = EVM.stack.pop2();
     let topic = EVM.stack.pop();
     let data = EVM.memory.slice(offset.as_usize(), size.as_usize());
@@ -1118,7 +1085,7 @@ pub unsafe fn log1() {
 
 #[no_mangle]
 pub unsafe fn log2() {
-    EVM.burn_gas(1125);
+    EVM.burn_static_gas(1125);
     let (offset, size) = EVM.stack.pop2();
     let (topic1, topic2) = EVM.stack.pop2();
     let data = EVM.memory.slice(offset.as_usize(), size.as_usize());
@@ -1139,7 +1106,7 @@ pub unsafe fn log2() {
 
 #[no_mangle]
 pub unsafe fn log3() {
-    EVM.burn_gas(1500);
+    EVM.burn_static_gas(1500);
     let (offset, size) = EVM.stack.pop2();
     let (topic1, topic2, topic3) = EVM.stack.pop3();
     let data = EVM.memory.slice(offset.as_usize(), size.as_usize());
@@ -1161,7 +1128,7 @@ pub unsafe fn log3() {
 
 #[no_mangle]
 pub unsafe fn log4() {
-    EVM.burn_gas(1875);
+    EVM.burn_static_gas(1875);
     let (offset, size) = EVM.stack.pop2();
     let (topic1, topic2, topic3, topic4) = EVM.stack.pop4();
     let data = EVM.memory.slice(offset.as_usize(), size.as_usize());
@@ -1184,28 +1151,28 @@ pub unsafe fn log4() {
 
 #[no_mangle]
 pub unsafe fn create() {
-    EVM.burn_gas(32000);
+    EVM.burn_static_gas(32000);
     trace!("CREATE");
     todo!("CREATE") // TODO
 }
 
 #[no_mangle]
 pub unsafe fn call() {
-    EVM.burn_gas(100);
+    EVM.burn_static_gas(100);
     trace!("CALL");
     todo!("CALL") // TODO
 }
 
 #[no_mangle]
 pub unsafe fn callcode() {
-    EVM.burn_gas(100);
+    EVM.burn_static_gas(100);
     trace!("CALLCODE");
     todo!("CALLCODE") // TODO
 }
 
 #[no_mangle]
 pub unsafe fn r#return() {
-    EVM.burn_gas(0);
+    EVM.burn_static_gas(0);
     let (offset, size) = EVM.stack.pop2();
     let data = EVM.memory.slice(offset.as_usize(), size.as_usize());
     ENV.value_return(data);
@@ -1216,28 +1183,28 @@ pub unsafe fn r#return() {
 
 #[no_mangle]
 pub unsafe fn delegatecall() {
-    EVM.burn_gas(100);
+    EVM.burn_static_gas(100);
     trace!("DELEGATECALL");
     todo!("DELEGATECALL") // TODO
 }
 
 #[no_mangle]
 pub unsafe fn create2() {
-    EVM.burn_gas(32000);
+    EVM.burn_static_gas(32000);
     trace!("CREATE2");
     todo!("CREATE2") // TODO
 }
 
 #[no_mangle]
 pub unsafe fn staticcall() {
-    EVM.burn_gas(100);
+    EVM.burn_static_gas(100);
     trace!("STATICCALL");
     todo!("STATICCALL") // TODO
 }
 
 #[no_mangle]
 pub unsafe fn revert() {
-    EVM.burn_gas(0);
+    EVM.burn_static_gas(0);
     let (offset, size) = EVM.stack.pop2();
     let data = EVM.memory.slice(offset.as_usize(), size.as_usize());
     ENV.revert(data);
@@ -1255,7 +1222,7 @@ pub unsafe fn invalid() {
 
 #[no_mangle]
 pub unsafe fn selfdestruct() {
-    EVM.burn_gas(5000);
+    EVM.burn_static_gas(5000);
     trace!("SELFDESTRUCT");
     todo!("SELFDESTRUCT") // TODO: state reset
 }
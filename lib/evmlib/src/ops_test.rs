@@ -952,6 +952,149 @@ mod tests {
     #[test]
     fn test_swap16() {}
 
+    #[test]
+    fn test_pop_n() {
+        unsafe {
+            EVM.reset();
+            push1(1);
+            push1(2);
+            push1(3);
+            _evm_pop_n(2);
+            assert_eq!(EVM.stack.depth, 1);
+            assert_eq!(EVM.stack.peek(), 1);
+        }
+    }
+
+    #[test]
+    fn test_swap_pop() {
+        unsafe {
+            EVM.reset();
+            push1(1);
+            push1(2);
+            push1(3);
+            _evm_swap_pop(2);
+            assert_eq!(EVM.stack.depth, 2);
+            assert_eq!(EVM.stack.slots[0], 3);
+            assert_eq!(EVM.stack.slots[1], 2);
+        }
+    }
+
+    #[test]
+    fn test_bool() {
+        unsafe {
+            EVM.reset();
+            push1(42);
+            _evm_bool();
+            assert_eq!(EVM.stack.peek(), 1);
+            push1(0);
+            _evm_bool();
+            assert_eq!(EVM.stack.peek(), 0);
+        }
+    }
+
+    #[test]
+    fn test_mask() {
+        unsafe {
+            EVM.reset();
+            push2(0x1234);
+            _evm_mask(8);
+            assert_eq!(EVM.stack.peek(), 0x34);
+            push1(0xff);
+            _evm_mask(256);
+            assert_eq!(EVM.stack.peek(), 0xff);
+        }
+    }
+
+    #[test]
+    fn test_stack_layout() {
+        unsafe {
+            EVM.reset();
+            push1(0x2a);
+            crate::api::_evm_push_u256(u64::MAX - 1, 0, 0, u64::MAX);
+            let base = crate::api::_evm_stack() as *const u8;
+            let depth = base.add(MAX_STACK_DEPTH * WORD_SIZE) as *const usize;
+            assert_eq!(*depth, 2);
+            let limb = |slot: usize, limb: usize| {
+                let offset = slot * WORD_SIZE + limb * 8;
+                u64::from_le_bytes(*(base.add(offset) as *const [u8; 8]))
+            };
+            assert_eq!([limb(0, 0), limb(0, 1), limb(0, 3)], [0x2a, 0, 0]);
+            assert_eq!([limb(1, 0), limb(1, 3)], [u64::MAX - 1, u64::MAX]);
+        }
+    }
+
+    #[test]
+    fn test_arith() {
+        unsafe {
+            EVM.reset();
+            // 1 - 3 wraps around like SUB, the stack is left alone
+            let result = crate::api::_evm_arith(0x03, 1, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0);
+            assert_eq!(*result, Word::MAX - 1);
+            assert_eq!(EVM.stack.depth, 0);
+            // ADDMOD takes the third operand, SAR the limbs of a negative value
+            let result = crate::api::_evm_arith(0x08, 5, 0, 0, 0, 4, 0, 0, 0, 7, 0, 0, 0);
+            assert_eq!(*result, 2);
+            let result = crate::api::_evm_arith(0x1d, 1, 0, 0, 0, !1, !0, !0, !0, 0, 0, 0, 0);
+            assert_eq!(*result, Word::MAX);
+        }
+    }
+
+    #[test]
+    fn test_stack_slot_underflow() {
+        unsafe {
+            EVM.reset();
+            ENV.reset();
+            push1(1);
+            assert_eq!(*crate::api::_evm_stack_slot(0), 1);
+            let fault = std::panic::catch_unwind(|| crate::api::_evm_stack_slot(1));
+            assert!(fault.is_err());
+            assert_eq!(ENV.exit_status, Some(ExitStatus::Revert));
+        }
+    }
+
+    #[test]
+    fn test_stack_room_overflow() {
+        unsafe {
+            EVM.reset();
+            ENV.reset();
+            push1(1);
+            crate::api::_evm_stack_room(MAX_STACK_DEPTH as u32 - 1);
+            let fault =
+                std::panic::catch_unwind(|| crate::api::_evm_stack_room(MAX_STACK_DEPTH as u32));
+            assert!(fault.is_err());
+            assert_eq!(ENV.exit_status, Some(ExitStatus::Revert));
+        }
+    }
+
+    #[test]
+    fn test_post_exec_without_outputs() {
+        unsafe {
+            // Panic(0x12), a division by zero
+            let panic = hex::decode(concat!(
+                "4e487b71",
+                "0000000000000000000000000000000000000000000000000000000000000012",
+            ))
+            .unwrap();
+            ENV.reset();
+            ENV.revert(&panic);
+            crate::api::_evm_post_exec(std::ptr::null(), 0, std::ptr::null(), 0);
+            assert_eq!(
+                std::str::from_utf8(&ENV.return_data).unwrap(),
+                concat!(
+                    r#"{"error":{"args":{"code":18,"reason":"division or modulo by zero"},"#,
+                    r#""name":"Panic"},"status":"REVERT"}"#
+                )
+            );
+
+            // successful executions keep their return data
+            ENV.reset();
+            ENV.return_data = vec![0x2a];
+            ENV.exit_status = Some(ExitStatus::Success);
+            crate::api::_evm_post_exec(std::ptr::null(), 0, std::ptr::null(), 0);
+            assert_eq!(ENV.return_data, [0x2a]);
+        }
+    }
+
     #[test]
     fn test_log0() {
         let test_data = b"hello_world_0";
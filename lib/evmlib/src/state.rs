@@ -11,9 +11,19 @@ pub(crate) const ONE: Word = u256::ONE;
 pub(crate) const MAX_STACK_DEPTH: usize = 32;
 pub(crate) const WORD_SIZE: usize = mem::size_of::<Word>();
 
-pub(crate) struct Stack {
-    pub(crate) depth: usize,
+/// The operand stack. Its layout is an ABI between the compiler and the
+/// runtime, as the compiler inlines simple ops as wasm code accessing it:
+///
+/// - `slots` is at offset 0, bottom of the stack first, each slot a word of
+///   four little-endian `u64` limbs, least significant limb first;
+/// - `depth` is at offset `MAX_STACK_DEPTH * WORD_SIZE`, a `u32` on wasm32.
+///
+/// Slots at or above `depth` have unspecified contents. `_evm_stack` returns
+/// the address of the stack.
+#[repr(C)]
+pub struct Stack {
     pub(crate) slots: [Word; MAX_STACK_DEPTH],
+    pub(crate) depth: usize,
 }
 
 pub(crate) struct Memory {
@@ -214,4 +224,14 @@ impl Machine {
             self.gas_used += gas_used;
         }
     }
+
+    /// Charges the operand-independent cost of an op. With `block-gas`, the
+    /// compiler charges these costs once per basic block instead.
+    #[inline]
+    pub fn burn_static_gas(&mut self, gas_used: u64) {
+        #[cfg(not(feature = "block-gas"))]
+        self.burn_gas(gas_used);
+        #[cfg(feature = "block-gas")]
+        let _ = gas_used;
+    }
 }
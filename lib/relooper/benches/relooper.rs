@@ -0,0 +1,166 @@
+//! Benchmarks of the relooper on contract-sized CFGs: `cargo bench -p relooper`.
+//!
+//! Besides CFGs generated in the shape solc compiles contracts into, every CFG
+//! committed under `test_data` is benchmarked. `evm2cspr --debug-folder DIR`
+//! dumps the CFG of each function it compiles in that format, as `stripped.cfg`
+//! and `func_*.stripped.cfg`, which can be copied there as they are.
+
+#![feature(test)]
+
+extern crate test;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use relooper::graph::cfg::{Cfg, CfgEdge};
+use relooper::graph::dense::DenseCfg;
+use relooper::graph::enrichments::EnrichedCfg;
+use relooper::graph::supergraph::reduce;
+use test::{black_box, Bencher};
+
+/// Generates the CFG of a contract with `functions` external functions of
+/// about `blocks` blocks each: a binary search over the selectors, and
+/// bodies of `require`s, `if`s and loops.
+fn solidity_like(functions: usize, blocks: usize) -> Cfg<usize> {
+    let mut cfg = Cfg::new(0);
+    let mut next = 1;
+    let mut node = || {
+        next += 1;
+        next - 1
+    };
+    let revert = node();
+    cfg.add_node(revert);
+
+    // dispatcher, the entry checks the call data size first
+    let dispatch = node();
+    cfg.add_edge(0, CfgEdge::Cond(dispatch, revert));
+    let mut searches = vec![(dispatch, 0..functions)];
+    while let Some((at, selectors)) = searches.pop() {
+        if selectors.len() <= 4 {
+            let mut at = at;
+            for f in selectors {
+                let (body, rest) = (node(), node());
+                cfg.add_edge(at, CfgEdge::Cond(body, rest));
+                function_body(&mut cfg, &mut node, body, revert, blocks + f % 7);
+                at = rest;
+            }
+            cfg.add_edge(at, CfgEdge::Uncond(revert));
+        } else {
+            let (low, high) = (node(), node());
+            cfg.add_edge(at, CfgEdge::Cond(high, low));
+            let mid = (selectors.start + selectors.end) / 2;
+            searches.push((low, selectors.start..mid));
+            searches.push((high, mid..selectors.end));
+        }
+    }
+    cfg
+}
+
+fn function_body(
+    cfg: &mut Cfg<usize>,
+    node: &mut impl FnMut() -> usize,
+    mut at: usize,
+    revert: usize,
+    blocks: usize,
+) {
+    let mut added = 0;
+    while added < blocks {
+        let next = node();
+        match added % 3 {
+            // require
+            0 => {
+                cfg.add_edge(at, CfgEdge::Cond(next, revert));
+                added += 1;
+            }
+            // if-else
+            1 => {
+                let (then, otherwise) = (node(), node());
+                cfg.add_edge(at, CfgEdge::Cond(then, otherwise));
+                cfg.add_edge(then, CfgEdge::Uncond(next));
+                cfg.add_edge(otherwise, CfgEdge::Uncond(next));
+                added += 3;
+            }
+            // loop with an early exit
+            _ => {
+                let (head, body, latch) = (node(), node(), node());
+                cfg.add_edge(at, CfgEdge::Uncond(head));
+                cfg.add_edge(head, CfgEdge::Cond(body, next));
+                cfg.add_edge(body, CfgEdge::Cond(next, latch));
+                cfg.add_edge(latch, CfgEdge::Uncond(head));
+                added += 4;
+            }
+        }
+        at = next;
+    }
+    cfg.add_node(at); // return
+}
+
+fn fixture(text: &str) -> Cfg<usize> {
+    let cfg: Cfg<String> = text.parse().unwrap();
+    let mut labels: Vec<&String> = cfg.nodes().into_iter().collect();
+    labels.sort();
+    let ids: HashMap<&String, usize> = labels.into_iter().zip(0..).collect();
+    cfg.map_label(|l| ids[l])
+}
+
+/// Reads every CFG in `test_data`, the fixtures and any committed dumps.
+fn test_data() -> Vec<Cfg<usize>> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test_data");
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| fixture(&std::fs::read_to_string(path).unwrap()))
+        .collect()
+}
+
+fn reloop(cfg: &Cfg<usize>) {
+    let reduced = reduce(cfg);
+    let enriched = EnrichedCfg::new(reduced);
+    black_box(enriched.reloop());
+}
+
+#[bench]
+fn reloop_solc_generated(b: &mut Bencher) {
+    let cfg = fixture(include_str!("../test_data/solc-generated.txt"));
+    b.iter(|| reloop(&cfg));
+}
+
+#[bench]
+fn reloop_1k_blocks(b: &mut Bencher) {
+    let cfg = solidity_like(20, 50);
+    b.iter(|| reloop(&cfg));
+}
+
+#[bench]
+fn reloop_10k_blocks(b: &mut Bencher) {
+    let cfg = solidity_like(200, 50);
+    b.iter(|| reloop(&cfg));
+}
+
+#[bench]
+fn reloop_30k_blocks(b: &mut Bencher) {
+    let cfg = solidity_like(500, 60);
+    b.iter(|| reloop(&cfg));
+}
+
+#[bench]
+fn reduce_30k_blocks(b: &mut Bencher) {
+    let cfg = solidity_like(500, 60);
+    b.iter(|| black_box(reduce(&cfg)));
+}
+
+#[bench]
+fn dominators_30k_blocks(b: &mut Bencher) {
+    let cfg = solidity_like(500, 60);
+    b.iter(|| black_box(DenseCfg::new(&cfg, 0).immediate_dominators()));
+}
+
+#[bench]
+fn reloop_test_data(b: &mut Bencher) {
+    let cfgs = test_data();
+    b.iter(|| cfgs.iter().for_each(reloop));
+}
@@ -0,0 +1,300 @@
+use crate::graph::cfg::{Cfg, CfgLabel};
+use crate::graph::dense::DenseCfg;
+use crate::graph::enrichments::{DomTree, EnrichedCfg};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+
+/// Analyses of the nodes reachable from the entry, keyed by label so that they can be mapped
+/// back to the blocks the CFG was built from. The ones of an `EnrichedCfg` are those of its `cfg`.
+impl<TLabel: CfgLabel> Cfg<TLabel> {
+    pub fn dominators(&self) -> DomTree<TLabel> {
+        DomTree::from(
+            EnrichedCfg::domination_tree(self, self.entry)
+                .into_iter()
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// The nodes where the paths from each node join paths that don't go through it,
+    /// where SSA construction places its phis.
+    pub fn dominance_frontiers(&self) -> HashMap<TLabel, BTreeSet<TLabel>> {
+        let dense = DenseCfg::new(self, self.entry);
+        let idom = dense.immediate_dominators();
+        dense
+            .dominance_frontiers(&idom)
+            .into_iter()
+            .enumerate()
+            .map(|(node, frontier)| {
+                let frontier = frontier.into_iter().map(|n| dense.label(n)).collect();
+                (dense.label(node), frontier)
+            })
+            .collect()
+    }
+
+    /// The immediate post-dominator of each node, `None` if only the exit of the CFG
+    /// post-dominates it. Nodes that never reach a terminal one are left out.
+    pub fn post_dominators(&self) -> HashMap<TLabel, Option<TLabel>> {
+        let dense = DenseCfg::new(self, self.entry);
+        dense
+            .immediate_post_dominators()
+            .into_iter()
+            .map(|(node, ipdom)| (dense.label(node), ipdom.map(|n| dense.label(n))))
+            .collect()
+    }
+
+    /// See `DenseCfg::loop_forest`.
+    pub fn loop_forest(&self) -> LoopForest<TLabel> {
+        let dense = DenseCfg::new(self, self.entry);
+        let mut loops: Vec<Loop<TLabel>> = dense
+            .loop_forest()
+            .into_iter()
+            .map(|l| {
+                let labels = |nodes: Vec<usize>| -> Vec<TLabel> {
+                    let mut labels: Vec<TLabel> =
+                        nodes.into_iter().map(|n| dense.label(n)).collect();
+                    labels.sort();
+                    labels
+                };
+                let exits = l
+                    .body
+                    .iter()
+                    .flat_map(|&from| dense.successors(from).iter().map(move |&to| (from, to)))
+                    .filter(|(_, to)| l.body.binary_search(to).is_err())
+                    .map(|(from, to)| (dense.label(from), dense.label(to)))
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .collect();
+                Loop {
+                    headers: labels(l.headers),
+                    body: labels(l.body),
+                    exits,
+                    parent: l.parent,
+                    children: Vec::new(),
+                }
+            })
+            .collect();
+        for child in 0..loops.len() {
+            if let Some(parent) = loops[child].parent {
+                loops[parent].children.push(child);
+            }
+        }
+        LoopForest::from(loops)
+    }
+}
+
+/// A loop of a `LoopForest`, its labels sorted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Loop<TLabel> {
+    /// the nodes entered from outside of the loop, a single one for natural loops
+    pub headers: Vec<TLabel>,
+    /// the nodes of the loop, headers and nested loops included
+    pub body: Vec<TLabel>,
+    /// the edges leaving the loop
+    pub exits: Vec<(TLabel, TLabel)>,
+    /// the index of the innermost loop around this one
+    pub parent: Option<usize>,
+    /// the indices of the loops directly nested in this one
+    pub children: Vec<usize>,
+}
+
+impl<TLabel: Copy> Loop<TLabel> {
+    /// The header of a natural loop, `None` for irreducible ones.
+    pub fn header(&self) -> Option<TLabel> {
+        match self.headers[..] {
+            [header] => Some(header),
+            _ => None,
+        }
+    }
+}
+
+/// The loops of a CFG, outer loops before the ones nested in them.
+#[derive(Clone, Debug, Serialize)]
+pub struct LoopForest<TLabel: CfgLabel> {
+    pub loops: Vec<Loop<TLabel>>,
+    #[serde(skip)]
+    innermost: HashMap<TLabel, usize>,
+}
+
+impl<TLabel: CfgLabel> From<Vec<Loop<TLabel>>> for LoopForest<TLabel> {
+    fn from(loops: Vec<Loop<TLabel>>) -> Self {
+        let mut innermost = HashMap::new();
+        for (i, l) in loops.iter().enumerate() {
+            for &node in &l.body {
+                innermost.insert(node, i);
+            }
+        }
+        Self { loops, innermost }
+    }
+}
+
+impl<TLabel: CfgLabel> LoopForest<TLabel> {
+    /// The loops not nested in any other.
+    pub fn roots(&self) -> impl Iterator<Item = &Loop<TLabel>> {
+        self.loops.iter().filter(|l| l.parent.is_none())
+    }
+
+    /// The index of the innermost loop containing a node.
+    pub fn innermost(&self, label: TLabel) -> Option<usize> {
+        self.innermost.get(&label).copied()
+    }
+
+    /// The number of loops containing a node.
+    pub fn depth(&self, label: TLabel) -> usize {
+        let mut depth = 0;
+        let mut current = self.innermost(label);
+        while let Some(i) = current {
+            depth += 1;
+            current = self.loops[i].parent;
+        }
+        depth
+    }
+
+    /// Relabels the loops, for example with the blocks the nodes were split from. Nodes mapped
+    /// to the same label are merged, and belong to the last loop of the ones containing them.
+    pub fn map_label<U: CfgLabel>(&self, mapping: impl Fn(TLabel) -> U) -> LoopForest<U> {
+        let relabel = |labels: &[TLabel]| -> Vec<U> {
+            let labels: BTreeSet<U> = labels.iter().map(|&l| mapping(l)).collect();
+            labels.into_iter().collect()
+        };
+        let loops: Vec<Loop<U>> = self
+            .loops
+            .iter()
+            .map(|l| {
+                let exits: BTreeSet<(U, U)> = l
+                    .exits
+                    .iter()
+                    .map(|&(f, t)| (mapping(f), mapping(t)))
+                    .collect();
+                Loop {
+                    headers: relabel(&l.headers),
+                    body: relabel(&l.body),
+                    exits: exits.into_iter().collect(),
+                    parent: l.parent,
+                    children: l.children.clone(),
+                }
+            })
+            .collect();
+        LoopForest::from(loops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graph::analysis::Loop;
+    use crate::graph::cfg::Cfg;
+    use crate::graph::cfg::CfgEdge::{Cond, Switch, Uncond};
+    use crate::graph::supergraph::reduce;
+    use std::collections::{BTreeSet, HashMap};
+
+    /// 0 -> 1 -> 2 <-> 3, 2 -> 4 and 4 -> 1, 2 or 5
+    fn nested_loops() -> Cfg<usize> {
+        Cfg::from_edges(
+            0,
+            vec![
+                (0, Uncond(1)),
+                (1, Uncond(2)),
+                (2, Cond(3, 4)),
+                (3, Uncond(2)),
+                (4, Switch(vec![(0, 1), (1, 2), (2, 5)])),
+            ]
+            .into_iter()
+            .collect(),
+        )
+    }
+
+    #[test]
+    fn dominators() {
+        let domination = nested_loops().dominators();
+        assert_eq!(domination.immediate_dominator(0), None);
+        assert_eq!(domination.immediate_dominator(3), Some(2));
+        assert_eq!(domination.immediate_dominator(7), None);
+        assert!(domination.dominates(1, 5));
+        assert!(domination.dominates(3, 3));
+        assert!(!domination.dominates(3, 4));
+    }
+
+    #[test]
+    fn dominance_frontiers() {
+        let frontiers = nested_loops().dominance_frontiers();
+        let frontier = |n| frontiers[&n].iter().copied().collect::<Vec<_>>();
+        assert!(frontier(0).is_empty());
+        assert_eq!(frontier(1), vec![1]);
+        assert_eq!(frontier(2), vec![1, 2]);
+        assert_eq!(frontier(3), vec![2]);
+        assert_eq!(frontier(4), vec![1, 2]);
+        assert!(frontier(5).is_empty());
+    }
+
+    #[test]
+    fn post_dominators() {
+        let mut cfg = nested_loops();
+        // 6 loops forever
+        *cfg.edge_mut(&0) = Cond(1, 6);
+        cfg.add_edge(6, Uncond(6));
+        let ipdom = cfg.post_dominators();
+        let expected: HashMap<usize, Option<usize>> = vec![
+            (0, Some(1)),
+            (1, Some(2)),
+            (2, Some(4)),
+            (3, Some(2)),
+            (4, Some(5)),
+            (5, None),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(ipdom, expected);
+    }
+
+    #[test]
+    fn loop_forest() {
+        let forest = nested_loops().loop_forest();
+        assert_eq!(
+            forest.loops,
+            vec![
+                Loop {
+                    headers: vec![1],
+                    body: vec![1, 2, 3, 4],
+                    exits: vec![(4, 5)],
+                    parent: None,
+                    children: vec![1],
+                },
+                // 2 heads both 2 <-> 3 and 2 -> 4 -> 2
+                Loop {
+                    headers: vec![2],
+                    body: vec![2, 3, 4],
+                    exits: vec![(4, 1), (4, 5)],
+                    parent: Some(0),
+                    children: vec![],
+                },
+            ]
+        );
+        assert_eq!(forest.roots().count(), 1);
+        assert_eq!(forest.innermost(3), Some(1));
+        assert_eq!(forest.innermost(1), Some(0));
+        assert_eq!(forest.depth(3), 2);
+        assert_eq!(forest.depth(5), 0);
+    }
+
+    #[test]
+    fn irreducible_loops() {
+        // 1 <-> 2 entered at both
+        let cfg = Cfg::from_edges(
+            0,
+            vec![(0, Cond(1, 2)), (1, Cond(2, 3)), (2, Uncond(1))]
+                .into_iter()
+                .collect(),
+        );
+        let forest = cfg.loop_forest();
+        assert_eq!(forest.loops.len(), 1);
+        assert_eq!(forest.loops[0].headers, vec![1, 2]);
+        assert_eq!(forest.loops[0].header(), None);
+
+        // splitting makes it natural, and the split nodes map back to the same loop
+        let reduced = reduce(&cfg);
+        let forest = reduced.loop_forest();
+        assert!(forest.loops.iter().all(|l| l.header().is_some()));
+        let origins = forest.map_label(|l| l.origin);
+        let bodies: BTreeSet<Vec<usize>> = origins.loops.iter().map(|l| l.body.clone()).collect();
+        assert_eq!(bodies, vec![vec![1, 2]].into_iter().collect());
+    }
+}
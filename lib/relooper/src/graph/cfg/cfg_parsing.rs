@@ -1,33 +1,92 @@
-use crate::graph::cfg::CfgEdge::{Cond, Uncond};
+use crate::graph::cfg::CfgEdge::{Cond, Switch, Terminal, Uncond};
 use crate::graph::cfg::{Cfg, CfgEdge};
 use anyhow::{ensure, format_err};
+use std::fmt::{Display, Formatter};
 use std::hash::Hash;
 use std::str::FromStr;
 
+/// An edge is written as its targets separated by spaces: one for `Uncond`, two for `Cond`,
+/// or any number of `key:target` cases for `Switch`.
 impl<E: std::error::Error + Send + Sync + 'static, TLabel: FromStr<Err = E>> FromStr
     for CfgEdge<TLabel>
 {
     type Err = anyhow::Error;
 
     fn from_str(value: &str) -> Result<Self, Self::Err> {
-        let (first_str, maybe_second) = {
-            let mut split_i = value.split(' ');
-            (split_i.next().unwrap(), split_i.next())
-        };
-
-        match maybe_second {
-            None => TLabel::from_str(first_str)
-                .map(Uncond)
-                .map_err(|e| anyhow::Error::new(e)),
-            Some(uncond_str) => {
-                let cond = TLabel::from_str(first_str).map_err(|e| anyhow::Error::new(e))?;
-                let uncond = TLabel::from_str(uncond_str).map_err(|e| anyhow::Error::new(e))?;
-                Ok(Cond(cond, uncond))
+        let targets: Vec<&str> = value.split_whitespace().collect();
+        let label = |s: &str| TLabel::from_str(s).map_err(|e| anyhow::Error::new(e));
+
+        if targets.iter().any(|t| t.contains(':')) {
+            let cases = targets
+                .iter()
+                .map(|case| {
+                    let (key, target) = case
+                        .split_once(':')
+                        .ok_or_else(|| format_err!("switch case without key: {}", case))?;
+                    Ok((key.parse()?, label(target)?))
+                })
+                .collect::<Result<_, Self::Err>>()?;
+            return Ok(Switch(cases));
+        }
+        match targets[..] {
+            [uncond] => Ok(Uncond(label(uncond)?)),
+            [cond, uncond] => Ok(Cond(label(cond)?, label(uncond)?)),
+            _ => Err(format_err!("invalid edge format: {}", value)),
+        }
+    }
+}
+
+impl<TLabel: Display> Display for CfgEdge<TLabel> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Uncond(u) => write!(f, "{}", u),
+            Cond(cond, uncond) => write!(f, "{} {}", cond, uncond),
+            Switch(v) => {
+                let cases: Vec<String> = v.iter().map(|(k, t)| format!("{}:{}", k, t)).collect();
+                write!(f, "{}", cases.join(" "))
             }
+            Terminal => Ok(()),
         }
     }
 }
 
+/// The format `test_data` is in: the entry on the first line, then a line for each node
+/// with outgoing edges, the node followed by its edge. Nodes without edges are terminal.
+impl<TLabel: Display> Display for Cfg<TLabel> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut lines: Vec<String> = self
+            .edges()
+            .iter()
+            .filter(|(_, edge)| !matches!(edge, Terminal))
+            .map(|(from, edge)| format!("{} {}", from, edge))
+            .collect();
+        lines.sort();
+        writeln!(f, "{}", self.entry)?;
+        for line in lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl<E, TLabel> FromStr for Cfg<TLabel>
+where
+    E: std::error::Error + Send + Sync + 'static,
+    TLabel: FromStr<Err = E> + Eq + Hash + Clone,
+{
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let lines: Vec<String> = text
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(String::from)
+            .collect();
+        Cfg::try_from(&lines)
+    }
+}
+
 impl<E, TLabel> TryFrom<&Vec<String>> for Cfg<TLabel>
 where
     E: std::error::Error + Send + Sync + 'static,
@@ -37,8 +96,8 @@ where
 
     fn try_from(strings: &Vec<String>) -> Result<Self, Self::Error> {
         ensure!(
-            strings.len() >= 2,
-            "well-formed cfg should contain entry line and at least one edge"
+            !strings.is_empty(),
+            "well-formed cfg should contain entry line"
         );
 
         let entry_str = strings.first().unwrap();
@@ -57,3 +116,66 @@ where
         Ok(cfg)
     }
 }
+
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use crate::graph::cfg::Cfg;
+    use std::collections::HashMap;
+
+    pub(crate) const FIXTURES: [&str; 7] = [
+        include_str!("../../../test_data/1.txt"),
+        include_str!("../../../test_data/2.txt"),
+        include_str!("../../../test_data/compiled-from-sol.txt"),
+        include_str!("../../../test_data/dyn-edges.txt"),
+        include_str!("../../../test_data/solc-generated.txt"),
+        include_str!("../../../test_data/unreducible-moderate.txt"),
+        include_str!("../../../test_data/unreducible-simple.txt"),
+    ];
+
+    /// Parses a CFG from `test_data`, numbering its labels in order.
+    pub(crate) fn fixture(text: &str) -> Cfg<usize> {
+        let cfg: Cfg<String> = text.parse().unwrap();
+        let mut labels: Vec<&String> = cfg.nodes().into_iter().collect();
+        labels.sort();
+        let ids: HashMap<&String, usize> = labels.into_iter().zip(0..).collect();
+        cfg.map_label(|l| ids[l])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::{fixture, FIXTURES};
+    use crate::graph::cfg::Cfg;
+    use crate::graph::cfg::CfgEdge::{Cond, Switch, Uncond};
+
+    #[test]
+    fn round_trip() {
+        let cfg = Cfg::from_edges(
+            0,
+            vec![
+                (0, Cond(1, 2)),
+                (1, Switch(vec![(0, 3), (4, 2)])),
+                (2, Uncond(3)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let text = cfg.to_string();
+        assert_eq!(text, "0\n0 1 2\n1 0:3 4:2\n2 3\n");
+        let parsed: Cfg<usize> = text.parse().unwrap();
+        assert_eq!(parsed.edges(), cfg.edges());
+
+        for text in FIXTURES {
+            let cfg = fixture(text);
+            let parsed: Cfg<usize> = cfg.to_string().parse().unwrap();
+            assert_eq!(parsed.edges(), cfg.edges());
+        }
+    }
+
+    #[test]
+    fn invalid_edges() {
+        assert!("0\n0 1 2 3".parse::<Cfg<usize>>().is_err());
+        assert!("0\n0 1:2 3".parse::<Cfg<usize>>().is_err());
+        assert!("0\n0 x".parse::<Cfg<usize>>().is_err());
+    }
+}
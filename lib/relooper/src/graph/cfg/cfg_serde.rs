@@ -0,0 +1,96 @@
+use crate::graph::cfg::{Cfg, CfgEdge, CfgLabel};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashSet;
+
+/// A CFG is serialized as its entry and the edge of every node, terminal ones included,
+/// sorted by label so that the output of equal CFGs is the same:
+/// `{"entry": 0, "edges": [[0, {"Cond": [1, 2]}], [1, {"Uncond": 2}], [2, "Terminal"]]}`.
+#[derive(Serialize)]
+struct CfgRef<'a, TLabel> {
+    entry: &'a TLabel,
+    edges: Vec<(&'a TLabel, &'a CfgEdge<TLabel>)>,
+}
+
+#[derive(Deserialize)]
+struct CfgRepr<TLabel> {
+    entry: TLabel,
+    edges: Vec<(TLabel, CfgEdge<TLabel>)>,
+}
+
+impl<TLabel: CfgLabel + Serialize> Serialize for Cfg<TLabel> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut edges: Vec<(&TLabel, &CfgEdge<TLabel>)> = self.out_edges.iter().collect();
+        edges.sort_by_key(|(from, _)| *from);
+        CfgRef {
+            entry: &self.entry,
+            edges,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Targets missing from the edges are terminal, as with `Cfg::add_edge`.
+impl<'de, TLabel: CfgLabel + Deserialize<'de>> Deserialize<'de> for Cfg<TLabel> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = CfgRepr::deserialize(deserializer)?;
+        let mut cfg = Cfg::new(repr.entry);
+        let mut seen: HashSet<TLabel> = HashSet::new();
+        for (from, edge) in repr.edges {
+            if !seen.insert(from) {
+                return Err(D::Error::custom(format!("duplicate edge of {:?}", from)));
+            }
+            cfg.add_edge(from, edge);
+        }
+        Ok(cfg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graph::cfg::cfg_parsing::fixtures::{fixture, FIXTURES};
+    use crate::graph::cfg::Cfg;
+    use crate::graph::cfg::CfgEdge::{Cond, Switch, Uncond};
+
+    #[test]
+    fn json_format() {
+        let cfg = Cfg::from_edges(
+            0,
+            vec![(0, Cond(1, 2)), (1, Switch(vec![(0, 2), (3, 0)]))]
+                .into_iter()
+                .collect(),
+        );
+        let json = serde_json::to_string(&cfg).unwrap();
+        assert_eq!(
+            json,
+            r#"{"entry":0,"edges":[[0,{"Cond":[1,2]}],[1,{"Switch":[[0,2],[3,0]]}],[2,"Terminal"]]}"#
+        );
+        let parsed: Cfg<usize> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.entry, 0);
+        assert_eq!(parsed.edges(), cfg.edges());
+    }
+
+    #[test]
+    fn round_trip() {
+        for text in FIXTURES {
+            let cfg = fixture(text);
+            let json = serde_json::to_string(&cfg).unwrap();
+            let parsed: Cfg<usize> = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed.entry, cfg.entry);
+            assert_eq!(parsed.edges(), cfg.edges());
+            assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn missing_and_duplicate_edges() {
+        let parsed: Cfg<usize> =
+            serde_json::from_str(r#"{"entry":0,"edges":[[0,{"Uncond":1}]]}"#).unwrap();
+        assert_eq!(parsed.edge(&0), &Uncond(1));
+        assert!(parsed.edge(&1).iter().next().is_none());
+
+        let duplicate = r#"{"entry":0,"edges":[[0,{"Uncond":1}],[0,"Terminal"]]}"#;
+        let err = serde_json::from_str::<Cfg<usize>>(duplicate).unwrap_err();
+        assert!(err.to_string().contains("duplicate edge of 0"), "{}", err);
+    }
+}
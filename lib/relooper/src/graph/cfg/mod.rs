@@ -1,16 +1,18 @@
 use crate::graph::cfg::CfgEdge::{Cond, Switch, Terminal, Uncond};
 use crate::traversal::graph::bfs::Bfs;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::hash::Hash;
 
-mod cfg_parsing;
+pub(crate) mod cfg_parsing;
+mod cfg_serde;
 
 pub trait CfgLabel: Copy + Hash + Eq + Ord + Debug {}
 
 impl<T: Copy + Hash + Eq + Ord + Debug> CfgLabel for T {}
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum CfgEdge<TLabel> {
     Uncond(TLabel),
     Cond(TLabel, TLabel),
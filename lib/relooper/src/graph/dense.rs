@@ -0,0 +1,520 @@
+use crate::graph::cfg::{Cfg, CfgLabel};
+use std::collections::{HashMap, HashSet};
+
+/// CFG over dense node indices, for analyses that would otherwise keep maps and sets of labels.
+/// Only nodes reachable from the entry are kept, numbered in reverse postorder,
+/// so the entry is `0` and every edge to a lower index is a back edge of the DFS.
+pub struct DenseCfg<TLabel: CfgLabel> {
+    labels: Vec<TLabel>,
+    index: HashMap<TLabel, usize>,
+    succs: Vec<Vec<usize>>,
+    preds: Vec<Vec<usize>>,
+}
+
+impl<TLabel: CfgLabel> DenseCfg<TLabel> {
+    pub fn new(cfg: &Cfg<TLabel>, entry: TLabel) -> Self {
+        // iterative dfs, children are visited in edge order
+        let mut postorder: Vec<TLabel> = Vec::new();
+        let mut visited: HashSet<TLabel> = HashSet::from([entry]);
+        let mut stack = vec![(entry, cfg.edge(&entry).iter())];
+        while let Some((_, children)) = stack.last_mut() {
+            match children.find(|c| !visited.contains(c)) {
+                Some(&child) => {
+                    visited.insert(child);
+                    stack.push((child, cfg.edge(&child).iter()));
+                }
+                None => {
+                    let (label, _) = stack.pop().unwrap();
+                    postorder.push(label);
+                }
+            }
+        }
+
+        let labels: Vec<TLabel> = postorder.into_iter().rev().collect();
+        let index: HashMap<TLabel, usize> =
+            labels.iter().enumerate().map(|(i, &l)| (l, i)).collect();
+        let mut succs: Vec<Vec<usize>> = vec![Vec::new(); labels.len()];
+        let mut preds: Vec<Vec<usize>> = vec![Vec::new(); labels.len()];
+        for (from, label) in labels.iter().enumerate() {
+            for to in cfg.edge(label).iter() {
+                let to = index[to];
+                if !succs[from].contains(&to) {
+                    succs[from].push(to);
+                    preds[to].push(from);
+                }
+            }
+        }
+
+        Self {
+            labels,
+            index,
+            succs,
+            preds,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// labels in reverse postorder
+    pub fn labels(&self) -> &[TLabel] {
+        &self.labels
+    }
+
+    pub fn label(&self, node: usize) -> TLabel {
+        self.labels[node]
+    }
+
+    pub fn index(&self, label: &TLabel) -> Option<usize> {
+        self.index.get(label).copied()
+    }
+
+    pub fn successors(&self, node: usize) -> &[usize] {
+        &self.succs[node]
+    }
+
+    pub fn predecessors(&self, node: usize) -> &[usize] {
+        &self.preds[node]
+    }
+
+    /// Immediate dominator of each node, the entry being its own dominator.
+    ///
+    /// Cooper, Harvey & Kennedy, "A Simple, Fast Dominance Algorithm": iterates
+    /// `idom(n) = intersect(idom(p) for p in preds(n))` in reverse postorder until nothing changes,
+    /// intersecting by walking up the partial dominator tree from both sides.
+    /// Converges in a couple of passes on the CFGs of structured code.
+    pub fn immediate_dominators(&self) -> Vec<usize> {
+        let order: Vec<usize> = (0..self.len()).collect();
+        dominators(&order, &self.preds)
+            .into_iter()
+            .map(|idom| idom.expect("all nodes are reachable"))
+            .collect()
+    }
+
+    /// Dominance frontier of each node: the nodes it doesn't strictly dominate with a predecessor
+    /// it dominates, where the paths from it join others. From the same paper.
+    pub fn dominance_frontiers(&self, idom: &[usize]) -> Vec<Vec<usize>> {
+        let mut frontiers: Vec<Vec<usize>> = vec![Vec::new(); self.len()];
+        for node in 0..self.len() {
+            // the entry is also entered from outside
+            if self.preds[node].len() + usize::from(node == 0) < 2 {
+                continue;
+            }
+            for &pred in &self.preds[node] {
+                // up to the immediate dominator, or past the entry if it's the one joined
+                let mut runner = pred;
+                while (node == 0 || runner != idom[node]) && !frontiers[runner].contains(&node) {
+                    frontiers[runner].push(node);
+                    if runner == 0 {
+                        break;
+                    }
+                    runner = idom[runner];
+                }
+            }
+        }
+        frontiers
+    }
+
+    /// Immediate post-dominator of each node, `None` for the nodes only the exit post-dominates,
+    /// the exit being a virtual node succeeding all terminal ones. Nodes that can't reach it,
+    /// in loops without exits, have no post-dominators and are left out.
+    pub fn immediate_post_dominators(&self) -> HashMap<usize, Option<usize>> {
+        // the reverse graph, the exit being `len`
+        let exit = self.len();
+        let mut succs: Vec<Vec<usize>> = self.preds.clone();
+        succs.push((0..exit).filter(|&n| self.succs[n].is_empty()).collect());
+        let mut preds: Vec<Vec<usize>> = self.succs.clone();
+        preds.push(Vec::new());
+        for &terminal in &succs[exit] {
+            preds[terminal].push(exit);
+        }
+
+        let order = reverse_postorder(exit, &succs);
+        dominators(&order, &preds)
+            .into_iter()
+            .enumerate()
+            .take(exit)
+            .filter_map(|(node, ipdom)| {
+                ipdom.map(|ipdom| (node, Some(ipdom).filter(|&p| p != exit)))
+            })
+            .collect()
+    }
+
+    /// The loop nesting forest, outer loops first: the strongly connected components of
+    /// more than a node, or of a node branching to itself, are loops, entered at their headers.
+    /// The loops nested in one are the components of its body without the edges to its headers.
+    /// Loops of reducible CFGs are natural loops, of a single header dominating their bodies.
+    pub fn loop_forest(&self) -> Vec<DenseLoop> {
+        let mut loops: Vec<DenseLoop> = Vec::new();
+        let mut scc = Scc::new(self.len());
+        let mut in_body = vec![false; self.len()];
+        let mut is_header = vec![false; self.len()];
+        // bodies to look for loops in, with the loop they are the body of
+        let mut work: Vec<(Vec<usize>, Option<usize>)> = vec![((0..self.len()).collect(), None)];
+        while let Some((nodes, parent)) = work.pop() {
+            for &n in &nodes {
+                in_body[n] = true;
+            }
+            if let Some(parent) = parent {
+                for &h in &loops[parent].headers {
+                    is_header[h] = true;
+                }
+            }
+            let (within, headers) = (&in_body, &is_header);
+            let components = scc.components(&nodes, |n| {
+                self.succs[n]
+                    .iter()
+                    .copied()
+                    .filter(move |&s| within[s] && !headers[s])
+            });
+            for &n in &nodes {
+                in_body[n] = false;
+                is_header[n] = false;
+            }
+
+            let mut nested = Vec::new();
+            for mut body in components {
+                if body.len() == 1 && !self.succs[body[0]].contains(&body[0]) {
+                    continue;
+                }
+                body.sort_unstable();
+                let headers: Vec<usize> = body
+                    .iter()
+                    .copied()
+                    .filter(|&n| {
+                        n == 0 || self.preds[n].iter().any(|p| body.binary_search(p).is_err())
+                    })
+                    .collect();
+                loops.push(DenseLoop {
+                    headers,
+                    body: body.clone(),
+                    parent,
+                });
+                nested.push((body, Some(loops.len() - 1)));
+            }
+            // outer loops before the ones they nest, siblings in order
+            work.extend(nested.into_iter().rev());
+        }
+        loops
+    }
+}
+
+/// A loop of a `DenseCfg::loop_forest`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DenseLoop {
+    /// the nodes of the body entered from outside of it, sorted
+    pub headers: Vec<usize>,
+    /// the nodes of the loop, nested loops included, sorted
+    pub body: Vec<usize>,
+    /// the index of the innermost loop around it
+    pub parent: Option<usize>,
+}
+
+/// Reverse postorder of the nodes reachable from `entry`.
+fn reverse_postorder(entry: usize, succs: &[Vec<usize>]) -> Vec<usize> {
+    let mut postorder = Vec::new();
+    let mut visited = vec![false; succs.len()];
+    visited[entry] = true;
+    let mut stack = vec![(entry, succs[entry].iter())];
+    while let Some((_, children)) = stack.last_mut() {
+        match children.find(|&&c| !visited[c]) {
+            Some(&child) => {
+                visited[child] = true;
+                stack.push((child, succs[child].iter()));
+            }
+            None => {
+                let (node, _) = stack.pop().unwrap();
+                postorder.push(node);
+            }
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+/// Cooper-Harvey-Kennedy over the nodes in reverse postorder `order`, the first being the entry.
+/// The nodes left out are unreachable and have no dominators.
+fn dominators(order: &[usize], preds: &[Vec<usize>]) -> Vec<Option<usize>> {
+    const UNDEFINED: usize = usize::MAX;
+    let mut position = vec![UNDEFINED; preds.len()];
+    for (i, &node) in order.iter().enumerate() {
+        position[node] = i;
+    }
+    // dominators by position in `order`
+    let mut idom = vec![UNDEFINED; order.len()];
+    if order.is_empty() {
+        return vec![None; preds.len()];
+    }
+    idom[0] = 0;
+
+    let intersect = |idom: &[usize], mut a: usize, mut b: usize| {
+        while a != b {
+            while a > b {
+                a = idom[a];
+            }
+            while b > a {
+                b = idom[b];
+            }
+        }
+        a
+    };
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, &node) in order.iter().enumerate().skip(1) {
+            let mut processed = preds[node]
+                .iter()
+                .map(|&p| position[p])
+                .filter(|&p| p != UNDEFINED && idom[p] != UNDEFINED);
+            let first = processed
+                .next()
+                .expect("reachable nodes have a processed predecessor");
+            let new_idom = processed.fold(first, |acc, p| intersect(&idom, p, acc));
+            if idom[i] != new_idom {
+                idom[i] = new_idom;
+                changed = true;
+            }
+        }
+    }
+
+    let mut by_node = vec![None; preds.len()];
+    for (i, &node) in order.iter().enumerate() {
+        by_node[node] = Some(order[idom[i]]);
+    }
+    by_node
+}
+
+/// Tarjan's strongly connected components, iteratively, of subgraphs of a graph of `len` nodes.
+struct Scc {
+    index: Vec<usize>,
+    lowlink: Vec<usize>,
+    on_stack: Vec<bool>,
+}
+
+impl Scc {
+    const UNVISITED: usize = usize::MAX;
+
+    fn new(len: usize) -> Self {
+        Self {
+            index: vec![Self::UNVISITED; len],
+            lowlink: vec![0; len],
+            on_stack: vec![false; len],
+        }
+    }
+
+    /// The components of the subgraph of `nodes` with the `edges` from each of them, which
+    /// must stay within `nodes`.
+    fn components<I: Iterator<Item = usize>>(
+        &mut self,
+        nodes: &[usize],
+        edges: impl Fn(usize) -> I,
+    ) -> Vec<Vec<usize>> {
+        let mut components = Vec::new();
+        let mut next_index = 0;
+        let mut stack: Vec<usize> = Vec::new();
+        for &root in nodes {
+            if self.index[root] != Self::UNVISITED {
+                continue;
+            }
+            let mut calls = vec![(root, edges(root))];
+            self.visit(root, &mut next_index, &mut stack);
+            while let Some((node, children)) = calls.last_mut() {
+                let node = *node;
+                match children.next() {
+                    Some(child) if self.index[child] == Self::UNVISITED => {
+                        self.visit(child, &mut next_index, &mut stack);
+                        calls.push((child, edges(child)));
+                    }
+                    Some(child) => {
+                        if self.on_stack[child] {
+                            self.lowlink[node] = self.lowlink[node].min(self.index[child]);
+                        }
+                    }
+                    None => {
+                        calls.pop();
+                        if let Some((parent, _)) = calls.last() {
+                            self.lowlink[*parent] = self.lowlink[*parent].min(self.lowlink[node]);
+                        }
+                        if self.lowlink[node] == self.index[node] {
+                            let mut component = Vec::new();
+                            loop {
+                                let n = stack.pop().unwrap();
+                                self.on_stack[n] = false;
+                                component.push(n);
+                                if n == node {
+                                    break;
+                                }
+                            }
+                            components.push(component);
+                        }
+                    }
+                }
+            }
+        }
+        for &n in nodes {
+            self.index[n] = Self::UNVISITED;
+        }
+        // Tarjan finds components in reverse topological order
+        components.reverse();
+        components
+    }
+
+    fn visit(&mut self, node: usize, next_index: &mut usize, stack: &mut Vec<usize>) {
+        self.index[node] = *next_index;
+        self.lowlink[node] = *next_index;
+        *next_index += 1;
+        stack.push(node);
+        self.on_stack[node] = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::graph::cfg::cfg_parsing::fixtures::{fixture, FIXTURES};
+    use crate::graph::cfg::Cfg;
+    use crate::graph::cfg::CfgEdge::{Cond, Switch, Uncond};
+    use crate::graph::dense::DenseCfg;
+    use std::collections::HashSet;
+
+    #[test]
+    fn reverse_postorder() {
+        let cfg = Cfg::from_edges(
+            0,
+            vec![
+                (0, Cond(1, 2)),
+                (1, Uncond(3)),
+                (2, Uncond(3)),
+                (3, Cond(0, 4)),
+                (5, Uncond(4)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let dense = DenseCfg::new(&cfg, 0);
+
+        assert_eq!(dense.labels(), &[0, 2, 1, 3, 4]);
+        assert_eq!(dense.index(&5), None);
+        let three = dense.index(&3).unwrap();
+        assert_eq!(dense.successors(three), &[0, 4]);
+        assert_eq!(dense.predecessors(0), &[three]);
+    }
+
+    #[test]
+    fn immediate_dominators() {
+        // the irreducible loop 2 <-> 3 is dominated by 1 only
+        let cfg = Cfg::from_edges(
+            0,
+            vec![
+                (0, Uncond(1)),
+                (1, Cond(2, 3)),
+                (2, Cond(3, 4)),
+                (3, Uncond(2)),
+                (4, Switch(vec![(0, 5), (1, 6)])),
+                (5, Uncond(7)),
+                (6, Uncond(7)),
+                (7, Uncond(1)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let dense = DenseCfg::new(&cfg, 0);
+        let idom = dense.immediate_dominators();
+
+        let idom_of = |l| dense.label(idom[dense.index(&l).unwrap()]);
+        let expected = [
+            (0, 0),
+            (1, 0),
+            (2, 1),
+            (3, 1),
+            (4, 2),
+            (5, 4),
+            (6, 4),
+            (7, 4),
+        ];
+        for (label, dominator) in expected {
+            assert_eq!(idom_of(label), dominator, "idom of {}", label);
+        }
+    }
+
+    /// nodes reachable from the entry without passing through `removed`
+    fn reachable_without(dense: &DenseCfg<usize>, removed: usize) -> HashSet<usize> {
+        let mut reached = HashSet::new();
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            if node != removed && reached.insert(node) {
+                stack.extend(dense.successors(node));
+            }
+        }
+        reached
+    }
+
+    #[test]
+    fn dominators_of_fixtures() {
+        for text in FIXTURES {
+            let cfg = fixture(text);
+            let dense = DenseCfg::new(&cfg, cfg.entry);
+            let idom = dense.immediate_dominators();
+
+            // the strict dominators of a node are the ones it can't be reached without
+            let dominators: Vec<HashSet<usize>> = (0..dense.len())
+                .map(|node| {
+                    (0..dense.len())
+                        .filter(|&d| d != node && !reachable_without(&dense, d).contains(&node))
+                        .collect()
+                })
+                .collect();
+            for node in 1..dense.len() {
+                // the immediate one is dominated by all the others
+                assert!(dominators[node].contains(&idom[node]));
+                let mut expected = dominators[idom[node]].clone();
+                expected.insert(idom[node]);
+                assert_eq!(dominators[node], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn frontiers_and_loops_of_fixtures() {
+        for text in FIXTURES {
+            let cfg = fixture(text);
+            let dense = DenseCfg::new(&cfg, cfg.entry);
+            let idom = dense.immediate_dominators();
+            let frontiers = dense.dominance_frontiers(&idom);
+            let dominates =
+                |d: usize, node: usize| d == node || !reachable_without(&dense, d).contains(&node);
+
+            // a node's frontier is where it stops strictly dominating, a predecessor away
+            for (node, frontier) in frontiers.iter().enumerate() {
+                let mut expected: Vec<usize> = (0..dense.len())
+                    .filter(|&y| {
+                        dense.predecessors(y).iter().any(|&p| dominates(node, p))
+                            && (y == node || !dominates(node, y))
+                    })
+                    .collect();
+                let mut frontier = frontier.clone();
+                expected.sort_unstable();
+                frontier.sort_unstable();
+                assert_eq!(frontier, expected, "frontier of {}", dense.label(node));
+            }
+
+            // loops are entered at their headers only, and the loops of a node are nested
+            let loops = dense.loop_forest();
+            for l in &loops {
+                for &node in &l.body {
+                    for &p in dense.predecessors(node) {
+                        assert!(l.body.contains(&p) || l.headers.contains(&node));
+                    }
+                }
+                if let Some(parent) = l.parent {
+                    assert!(l.body.iter().all(|n| loops[parent].body.contains(n)));
+                }
+            }
+        }
+    }
+}
@@ -181,6 +181,22 @@ impl<TLabel: CfgLabel + Display> ReSeq<TLabel> {
 
                         (current_id + 1, None)
                     }
+                    ReBlock::BrIf(jmp) | ReBlock::BrUnless(jmp) => {
+                        let name = match block {
+                            ReBlock::BrIf(_) => "BrIf",
+                            _ => "BrUnless",
+                        };
+                        res.push(format!("r{current_id}[label=\"{name} {current_id}\"];"));
+
+                        let branch_to = back_branches
+                            .get(back_branches.len() - 1 - (*jmp as usize))
+                            .expect("unexpected branch");
+                        res.push(format!(
+                            "r{current_id} -> r{branch_to}[constraint=false,color=\"blue\",style=\"dashed\"]"
+                        ));
+
+                        (current_id + 1, Some(current_id))
+                    }
                     ReBlock::Return => {
                         res.push(format!("r{current_id}[label=\"Return {current_id}\"];"));
 
@@ -1,7 +1,7 @@
 use crate::graph::cfg::{Cfg, CfgEdge, CfgLabel};
-use crate::traversal::graph::bfs::Bfs;
-use crate::traversal::graph::dfs::{Dfs, DfsPost, DfsPostReverseInstantiator};
-use std::collections::{HashMap, HashSet, VecDeque};
+use crate::graph::dense::DenseCfg;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
 use std::vec::Vec;
 
 pub struct EnrichedCfg<TLabel: CfgLabel> {
@@ -15,40 +15,38 @@ pub struct EnrichedCfg<TLabel: CfgLabel> {
 
 impl<TLabel: CfgLabel> EnrichedCfg<TLabel> {
     pub fn new(cfg: Cfg<TLabel>) -> Self {
-        let node_ordering = NodeOrdering::new(&cfg, cfg.entry);
+        let dense = DenseCfg::new(&cfg, cfg.entry);
+        let node_ordering = NodeOrdering::from(&dense);
 
         let mut merge_nodes: HashSet<TLabel> = HashSet::new();
         let mut loop_nodes: HashSet<TLabel> = HashSet::new();
         let mut if_nodes: HashSet<TLabel> = HashSet::new();
 
-        let in_edges = cfg.in_edges();
-
-        for &n in cfg.nodes() {
-            let in_edges_count = in_edges.get(&n).map_or(0, |v| {
-                v.iter()
-                    .filter(|&&from| node_ordering.is_forward(from, n))
-                    .count()
-            });
-            if in_edges_count > 1 {
+        // nodes are numbered in reverse postorder, so backward edges go to lower indices,
+        // or to the node itself for self-loops
+        for node in 0..dense.len() {
+            let n = dense.label(node);
+            let forward_in_edges = dense
+                .predecessors(node)
+                .iter()
+                .filter(|&&from| from < node)
+                .count();
+            if forward_in_edges > 1 {
                 merge_nodes.insert(n);
             }
 
-            let reachable: HashSet<_> =
-                Bfs::start_from_except(n, |&l| cfg.children(&l).into_iter().copied()).collect();
-            for &c in cfg.children(&n).into_iter() {
-                if node_ordering.is_backward(n, c) && reachable.contains(&c) {
-                    loop_nodes.insert(c);
+            for &c in dense.successors(node) {
+                if c <= node {
+                    loop_nodes.insert(dense.label(c));
                 }
             }
 
-            if let CfgEdge::Cond(_, _) = cfg.edges().get(&n).unwrap() {
+            if let CfgEdge::Cond(_, _) = cfg.edge(&n) {
                 if_nodes.insert(n);
             }
         }
 
-        let domination_map = Self::domination_tree(&cfg, &node_ordering, cfg.entry);
-        let domination_vec = Vec::from_iter(domination_map);
-        let domination = DomTree::from(domination_vec);
+        let domination = DomTree::from(Self::dense_domination(&dense));
 
         Self {
             cfg,
@@ -60,60 +58,20 @@ impl<TLabel: CfgLabel> EnrichedCfg<TLabel> {
         }
     }
 
-    pub fn domination_tree(
-        cfg: &Cfg<TLabel>,
-        node_ordering: &NodeOrdering<TLabel>,
-        begin: TLabel,
-    ) -> HashMap<TLabel, TLabel> /* map points from node id to id of its dominator */ {
-        let mut result = HashMap::<TLabel, TLabel>::new();
-        let mut bfs = VecDeque::<TLabel>::new();
-        let mut visited = HashSet::<TLabel>::new();
-        for &n in node_ordering.sequence() {
-            result.insert(n, begin);
-        }
-        bfs.push_back(begin); // should be next. upd: i dont think so
-        visited.insert(begin);
-        loop {
-            if bfs.is_empty() {
-                break;
-            }
-            let &cur_id = bfs.front().unwrap();
-            visited.insert(cur_id);
-            bfs.pop_front().unwrap();
-            Self::update_dominators(cfg, node_ordering, cur_id, begin, &mut result);
-            for &id in cfg.children(&cur_id) {
-                if !visited.contains(&id) {
-                    bfs.push_back(id);
-                }
-            }
-        }
-        result
+    pub fn domination_tree(cfg: &Cfg<TLabel>, begin: TLabel) -> HashMap<TLabel, TLabel> /* map points from node id to id of its dominator */
+    {
+        Self::dense_domination(&DenseCfg::new(cfg, begin))
+            .into_iter()
+            .collect()
     }
 
-    fn update_dominators(
-        cfg: &Cfg<TLabel>,
-        node_ordering: &NodeOrdering<TLabel>,
-        cur_id: TLabel,
-        origin: TLabel,
-        result: &mut HashMap<TLabel, TLabel>,
-    ) {
-        let mut reachable_set = HashSet::<TLabel>::default();
-        for &node in node_ordering.sequence() {
-            reachable_set.insert(node);
-        }
-
-        let reached = Dfs::start_from(origin, |&n| {
-            let mut ch = cfg.children(&n);
-            ch.remove(&cur_id);
-            ch.into_iter().copied()
-        });
-        for id in reached {
-            reachable_set.remove(&id);
-        }
-        reachable_set.remove(&cur_id);
-        for id in reachable_set {
-            result.insert(id, cur_id);
-        }
+    fn dense_domination(dense: &DenseCfg<TLabel>) -> Vec<(TLabel, TLabel)> {
+        dense
+            .immediate_dominators()
+            .into_iter()
+            .enumerate()
+            .map(|(node, idom)| (dense.label(node), dense.label(idom)))
+            .collect()
     }
 }
 
@@ -126,13 +84,8 @@ impl<TLabel: CfgLabel> EnrichedCfg<TLabel> {
 /// Domination tree is a graph with nodes of CFG, but edges only from dominator to dominated nodes.
 /// Domination tree uniquely specified by given CFG
 ///
-/// We build domination tree next way:
-/// 1) make an array of results (hash_map (dominated -> dominator)) and initialize it with entry node as dominator for every node.
-/// 2) Than we iterate in nodes in reverse postorder(?) and make next operation for each node:
-///   2.1) remove this node and all its edges from graph, go throw graph with dfs, and find all nodes unreachable without this nodes
-///   2.2) update immediate dominator for all unreachable nodes
-///
-/// Thanks to reverse postorder we will find immediate dominator for all nodes.
+/// We build domination tree with the Cooper–Harvey–Kennedy algorithm over a `DenseCfg`,
+/// see `DenseCfg::immediate_dominators`. The entry node is recorded as dominated by itself.
 ///
 pub struct DomTree<TLabel: CfgLabel> {
     dominates: HashMap<TLabel, HashSet<TLabel>>,
@@ -155,8 +108,43 @@ impl<TLabel: CfgLabel> From<Vec<(TLabel, TLabel)>> for DomTree<TLabel> {
     }
 }
 
+/// Serialized as the `[node, immediate dominator]` pairs, sorted by node.
+impl<TLabel: CfgLabel + Serialize> Serialize for DomTree<TLabel> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut edges: Vec<(&TLabel, &TLabel)> = self.dominated.iter().collect();
+        edges.sort();
+        edges.serialize(serializer)
+    }
+}
+
+impl<'de, TLabel: CfgLabel + Deserialize<'de>> Deserialize<'de> for DomTree<TLabel> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<(TLabel, TLabel)>::deserialize(deserializer).map(DomTree::from)
+    }
+}
+
 impl<TLabel: CfgLabel> DomTree<TLabel> {
-    pub(crate) fn immediately_dominated_by(&self, label: TLabel) -> HashSet<TLabel> {
+    /// The immediate dominator of a node, `None` for the entry and unreachable nodes.
+    pub fn immediate_dominator(&self, label: TLabel) -> Option<TLabel> {
+        self.dominated
+            .get(&label)
+            .copied()
+            .filter(|&dominator| dominator != label)
+    }
+
+    /// Whether every path from the entry to `node` visits `dominator`, nodes dominating themselves.
+    pub fn dominates(&self, dominator: TLabel, node: TLabel) -> bool {
+        let mut current = Some(node).filter(|n| self.dominated.contains_key(n));
+        while let Some(n) = current {
+            if n == dominator {
+                return true;
+            }
+            current = self.immediate_dominator(n);
+        }
+        false
+    }
+
+    pub fn immediately_dominated_by(&self, label: TLabel) -> HashSet<TLabel> {
         self.dominates
             .get(&label)
             .unwrap_or(&HashSet::new())
@@ -171,17 +159,14 @@ pub struct NodeOrdering<TLabel: CfgLabel> {
 
 impl<TLabel: CfgLabel> NodeOrdering<TLabel> {
     pub fn new(cfg: &Cfg<TLabel>, entry: TLabel) -> Self {
-        let vec =
-            DfsPost::<_, _, HashSet<_>>::reverse(entry, |x| cfg.children(x).into_iter().copied());
-        let idx: HashMap<TLabel, usize> = vec.iter().enumerate().map(|(i, &n)| (n, i)).collect();
-        Self { vec, idx }
+        Self::from(&DenseCfg::new(cfg, entry))
     }
 
     pub fn is_backward(&self, from: TLabel, to: TLabel) -> bool {
         self.idx
             .get(&from)
             .zip(self.idx.get(&to))
-            .map(|(&f, &t)| f > t)
+            .map(|(&f, &t)| f >= t)
             .unwrap()
     }
 
@@ -193,3 +178,11 @@ impl<TLabel: CfgLabel> NodeOrdering<TLabel> {
         &self.vec
     }
 }
+
+impl<TLabel: CfgLabel> From<&DenseCfg<TLabel>> for NodeOrdering<TLabel> {
+    fn from(dense: &DenseCfg<TLabel>) -> Self {
+        let vec = dense.labels().to_vec();
+        let idx: HashMap<TLabel, usize> = vec.iter().enumerate().map(|(i, &n)| (n, i)).collect();
+        Self { vec, idx }
+    }
+}
@@ -0,0 +1,529 @@
+use crate::graph::cfg::{Cfg, CfgEdge, CfgLabel};
+use crate::graph::relooper::{ReBlock, ReSeq};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display, Formatter};
+
+/// Where the relooped tree and the CFG part ways.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Divergence<TLabel> {
+    /// After running `path`, the tree runs `found` where the CFG goes on to `expected`,
+    /// `None` standing for returning.
+    Mismatch {
+        path: Vec<TLabel>,
+        expected: Option<TLabel>,
+        found: Option<TLabel>,
+    },
+    /// After running `path`, the tree can't go on.
+    Stuck { path: Vec<TLabel>, reason: String },
+}
+
+impl<TLabel: Debug> Display for Divergence<TLabel> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let node = |label: &Option<TLabel>| match label {
+            Some(label) => format!("{:?}", label),
+            None => "return".to_string(),
+        };
+        match self {
+            Self::Mismatch {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "after {:?} the relooped tree runs {} where the CFG goes to {}",
+                path,
+                node(found),
+                node(expected)
+            ),
+            Self::Stuck { path, reason } => {
+                write!(f, "after {:?} the relooped tree is stuck: {}", path, reason)
+            }
+        }
+    }
+}
+
+impl<TLabel: Debug> std::error::Error for Divergence<TLabel> {}
+
+/// The relooped tree flattened into structured control flow instructions, as wasm has them.
+/// `Block` and `If` hold the index of their `End`, `If` that of its `Else` too.
+enum Op<'a, TLabel> {
+    Actions(TLabel),
+    Block(usize),
+    Loop,
+    If(usize, usize),
+    Else(usize),
+    End,
+    Br(u32),
+    /// `BrIf` and `BrUnless`, branching if the condition is the given one
+    BrIf(u32, bool),
+    TableJump(&'a BTreeMap<usize, u32>),
+    Return,
+}
+
+/// How the CFG left the last node run, for the tree to branch on.
+#[derive(Clone, Copy)]
+enum Decision {
+    Cond(bool),
+    Case(usize),
+}
+
+/// Checks that a relooped tree runs the same paths as the CFG it was relooped from.
+///
+/// The tree is interpreted in lockstep with a walk of the CFG: the oracle decides which way
+/// conditional and switch nodes go, and each `Actions` of the tree must run the node the
+/// CFG goes to, the tree branching on the same decision. As decisions determine the paths
+/// of both, the tree reproduces every path of the CFG and no other if they agree for all
+/// decisions. Tree labels are mapped to the labels of the CFG by `origin`, e.g. to check
+/// trees relooped from a `reduce`d CFG against the original one.
+pub struct PathChecker<'a, TLabel: CfgLabel> {
+    cfg: &'a Cfg<TLabel>,
+    ops: Vec<Op<'a, TLabel>>,
+}
+
+impl<'a, TLabel: CfgLabel> PathChecker<'a, TLabel> {
+    pub fn new<RLabel: CfgLabel>(
+        cfg: &'a Cfg<TLabel>,
+        relooped: &'a ReSeq<RLabel>,
+        origin: impl Fn(RLabel) -> TLabel,
+    ) -> Self {
+        let mut ops = Vec::new();
+        flatten(relooped, &origin, &mut ops);
+        Self { cfg, ops }
+    }
+
+    /// Runs the path decided by `decide`, called with each conditional or switch node and its
+    /// number of successors to pick the index of the one to go to. Stops at the end of the
+    /// path or after running `max_nodes` nodes, returning the nodes run.
+    pub fn check_path(
+        &self,
+        mut decide: impl FnMut(TLabel, usize) -> usize,
+        max_nodes: usize,
+    ) -> Result<Vec<TLabel>, Divergence<TLabel>> {
+        let mut path: Vec<TLabel> = Vec::new();
+        let mut expected = Some(self.cfg.entry);
+        let mut decision: Option<Decision> = None;
+        // the constructs the code is in
+        let mut stack: Vec<usize> = Vec::new();
+        let mut pc = 0;
+        // the tree loops forever without running a node once it revisits a state
+        let mut idle = 0;
+
+        let stuck = |path: &Vec<TLabel>, reason: String| {
+            Err(Divergence::Stuck {
+                path: path.clone(),
+                reason,
+            })
+        };
+        loop {
+            idle += 1;
+            if idle > 2 * self.ops.len() + 2 {
+                return stuck(&path, "loops forever without running a node".to_string());
+            }
+
+            let br = match self.ops.get(pc).unwrap_or(&Op::Return) {
+                &Op::Actions(label) => {
+                    if path.len() == max_nodes {
+                        return Ok(path);
+                    }
+                    if decision.is_some() {
+                        let last = path.last().unwrap();
+                        return stuck(&path, format!("doesn't branch on the edge of {:?}", last));
+                    }
+                    if expected != Some(label) {
+                        return Err(Divergence::Mismatch {
+                            path,
+                            expected,
+                            found: Some(label),
+                        });
+                    }
+                    path.push(label);
+                    idle = 0;
+                    (expected, decision) = match self.cfg.edge(&label) {
+                        CfgEdge::Uncond(to) => (Some(*to), None),
+                        CfgEdge::Cond(cond, fallthrough) => match decide(label, 2) {
+                            0 => (Some(*cond), Some(Decision::Cond(true))),
+                            _ => (Some(*fallthrough), Some(Decision::Cond(false))),
+                        },
+                        CfgEdge::Switch(cases) => {
+                            let (key, to) = cases[decide(label, cases.len())];
+                            (Some(to), Some(Decision::Case(key)))
+                        }
+                        CfgEdge::Terminal => (None, None),
+                    };
+                    None
+                }
+                Op::Block(_) | Op::Loop => {
+                    stack.push(pc);
+                    None
+                }
+                &Op::If(else_pc, _) => match decision.take() {
+                    Some(Decision::Cond(taken)) => {
+                        stack.push(pc);
+                        if !taken {
+                            pc = else_pc;
+                        }
+                        None
+                    }
+                    _ => return stuck(&path, "`If` without a conditional edge".to_string()),
+                },
+                &Op::Else(end) => {
+                    pc = end - 1;
+                    None
+                }
+                Op::End => {
+                    stack.pop();
+                    None
+                }
+                &Op::Br(depth) => Some(depth),
+                &Op::BrIf(depth, when) => match decision.take() {
+                    Some(Decision::Cond(holds)) => (holds == when).then_some(depth),
+                    _ => return stuck(&path, "`BrIf` without a conditional edge".to_string()),
+                },
+                Op::TableJump(table) => match decision.take() {
+                    Some(Decision::Case(key)) => match table.get(&key) {
+                        Some(&depth) => Some(depth),
+                        None => return stuck(&path, format!("no jump for case {}", key)),
+                    },
+                    _ => return stuck(&path, "`TableJump` without a switch edge".to_string()),
+                },
+                Op::Return => {
+                    if expected.is_some() || decision.is_some() {
+                        return Err(Divergence::Mismatch {
+                            path,
+                            expected,
+                            found: None,
+                        });
+                    }
+                    return Ok(path);
+                }
+            };
+
+            pc = match br {
+                None => pc + 1,
+                Some(depth) => {
+                    let depth = depth as usize;
+                    if depth >= stack.len() {
+                        return stuck(
+                            &path,
+                            format!("`Br({})` out of {} blocks", depth, stack.len()),
+                        );
+                    }
+                    let target = stack[stack.len() - 1 - depth];
+                    match self.ops[target] {
+                        // continue the loop
+                        Op::Loop => {
+                            stack.truncate(stack.len() - depth);
+                            target + 1
+                        }
+                        Op::Block(end) | Op::If(_, end) => {
+                            stack.truncate(stack.len() - 1 - depth);
+                            end + 1
+                        }
+                        _ => unreachable!("only constructs are on the stack"),
+                    }
+                }
+            };
+        }
+    }
+
+    /// Checks every path of at most `max_nodes` nodes, returning how many there are.
+    /// There are exponentially many in the number of branches, so only small CFGs or
+    /// bounds are practical.
+    pub fn check_all_paths(&self, max_nodes: usize) -> Result<usize, Divergence<TLabel>> {
+        // decisions of the last path checked, as (chosen successor, successors)
+        let mut decisions: Vec<(usize, usize)> = Vec::new();
+        let mut paths = 0;
+        loop {
+            let mut taken = Vec::new();
+            self.check_path(
+                |_, successors| {
+                    let choice = decisions.get(taken.len()).map_or(0, |&(choice, _)| choice);
+                    taken.push((choice, successors));
+                    choice
+                },
+                max_nodes,
+            )?;
+            paths += 1;
+
+            // the next path takes the next choice of the last decision with choices left
+            decisions = taken;
+            while let Some((choice, successors)) = decisions.pop() {
+                if choice + 1 < successors {
+                    decisions.push((choice + 1, successors));
+                    break;
+                }
+            }
+            if decisions.is_empty() {
+                return Ok(paths);
+            }
+        }
+    }
+}
+
+fn flatten<'a, RLabel: CfgLabel, TLabel>(
+    seq: &'a ReSeq<RLabel>,
+    origin: &impl Fn(RLabel) -> TLabel,
+    ops: &mut Vec<Op<'a, TLabel>>,
+) {
+    for block in &seq.0 {
+        match block {
+            ReBlock::Block(inner) | ReBlock::Loop(inner) => {
+                let start = ops.len();
+                ops.push(Op::End); // placeholder
+                flatten(inner, origin, ops);
+                let end = ops.len();
+                ops.push(Op::End);
+                ops[start] = match block {
+                    ReBlock::Block(_) => Op::Block(end),
+                    _ => Op::Loop,
+                };
+            }
+            ReBlock::If(then, otherwise) => {
+                let start = ops.len();
+                ops.push(Op::End); // placeholder
+                flatten(then, origin, ops);
+                let else_pc = ops.len();
+                ops.push(Op::End); // placeholder
+                flatten(otherwise, origin, ops);
+                let end = ops.len();
+                ops.push(Op::End);
+                ops[start] = Op::If(else_pc, end);
+                ops[else_pc] = Op::Else(end);
+            }
+            &ReBlock::Actions(label) => ops.push(Op::Actions(origin(label))),
+            &ReBlock::Br(depth) => ops.push(Op::Br(depth)),
+            &ReBlock::BrIf(depth) => ops.push(Op::BrIf(depth, true)),
+            &ReBlock::BrUnless(depth) => ops.push(Op::BrIf(depth, false)),
+            ReBlock::TableJump(table) => ops.push(Op::TableJump(table)),
+            ReBlock::Return => ops.push(Op::Return),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::graph::cfg::cfg_parsing::fixtures::{fixture, FIXTURES};
+    use crate::graph::cfg::CfgEdge::{Cond, Switch, Terminal, Uncond};
+    use crate::graph::cfg::{Cfg, CfgEdge};
+    use crate::graph::dense::DenseCfg;
+    use crate::graph::enrichments::EnrichedCfg;
+    use crate::graph::equivalence::{Divergence, PathChecker};
+    use crate::graph::relooper::ReBlock::{Actions, Br, If, Loop, Return, TableJump};
+    use crate::graph::relooper::ReSeq;
+    use crate::graph::supergraph::{reduce, reduce_within, ReduceError, SLabel};
+
+    /// xorshift64*, so that the tests don't need a crate for random numbers
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn edge(targets: &[usize], rng: &mut Rng) -> CfgEdge<usize> {
+        match targets {
+            [] => Terminal,
+            [to] => Uncond(*to),
+            [cond, fallthrough] => Cond(*cond, *fallthrough),
+            cases => Switch(
+                cases
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &to)| (3 * i + rng.below(3), to))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn from_targets(targets: &[Vec<usize>], rng: &mut Rng) -> Cfg<usize> {
+        let edges = targets
+            .iter()
+            .enumerate()
+            .map(|(from, to)| (from, edge(to, rng)))
+            .collect();
+        Cfg::from_edges(0, edges)
+    }
+
+    /// A random CFG of forward edges, every node being reachable through one from a lower node,
+    /// and back edges. These go to dominators if `reducible`, anywhere behind otherwise.
+    fn random_cfg(rng: &mut Rng, nodes: usize, reducible: bool) -> Cfg<usize> {
+        let mut targets: Vec<Vec<usize>> = vec![Vec::new(); nodes];
+        for to in 1..nodes {
+            targets[rng.below(to)].push(to);
+        }
+        for (from, successors) in targets.iter_mut().enumerate().take(nodes - 1) {
+            for _ in 0..rng.below(3) {
+                let to = from + 1 + rng.below(nodes - from - 1);
+                if !successors.contains(&to) {
+                    successors.push(to);
+                }
+            }
+        }
+
+        let forward = from_targets(&targets, rng);
+        let dense = DenseCfg::new(&forward, 0);
+        let idom = dense.immediate_dominators();
+        for (from, successors) in targets.iter_mut().enumerate() {
+            if rng.below(3) != 0 {
+                continue;
+            }
+            let to = if reducible {
+                let mut to = dense.index(&from).unwrap();
+                while to != 0 && rng.below(2) == 0 {
+                    to = idom[to];
+                }
+                dense.label(to)
+            } else {
+                rng.below(from + 1)
+            };
+            if !successors.contains(&to) {
+                successors.push(to);
+            }
+        }
+        from_targets(&targets, rng)
+    }
+
+    /// Relooped with node splitting bounded, as random irreducible CFGs may
+    /// take exponentially many splits.
+    fn relooped(cfg: &Cfg<usize>) -> Result<ReSeq<SLabel<usize>>, ReduceError<usize>> {
+        Ok(EnrichedCfg::new(reduce_within(cfg, 4 * cfg.nodes().len())?).reloop())
+    }
+
+    fn check_paths(cfg: &Cfg<usize>, relooped: &ReSeq<SLabel<usize>>, rng: &mut Rng) {
+        let checker = PathChecker::new(cfg, relooped, |l| l.origin);
+        let report = |divergence: Divergence<usize>| -> ! {
+            panic!("{}\nin the relooped\n{}", divergence, cfg)
+        };
+        for _ in 0..20 {
+            let max_nodes = 4 * cfg.nodes().len();
+            if let Err(divergence) = checker.check_path(|_, n| rng.below(n), max_nodes) {
+                report(divergence);
+            }
+        }
+        if let Err(divergence) = checker.check_all_paths(6) {
+            report(divergence);
+        }
+    }
+
+    #[test]
+    fn all_paths() {
+        let cfg = Cfg::from_edges(
+            0,
+            vec![(0, Cond(1, 2)), (1, Uncond(0))].into_iter().collect(),
+        );
+        let relooped = relooped(&cfg).unwrap();
+        let checker = PathChecker::new(&cfg, &relooped, |l| l.origin);
+
+        // 0 2, 0 1 0 2 and 0 1 0 1
+        assert_eq!(checker.check_all_paths(4), Ok(3));
+        let loops = |_, _| 0;
+        assert_eq!(checker.check_path(loops, 5), Ok(vec![0, 1, 0, 1, 0]));
+    }
+
+    #[test]
+    fn divergences() {
+        let cfg = Cfg::from_edges(
+            0,
+            vec![(0, Cond(1, 2)), (1, Switch(vec![(4, 0), (7, 2)]))]
+                .into_iter()
+                .collect(),
+        );
+        let check = |blocks| {
+            let relooped = ReSeq(vec![Loop(ReSeq(blocks))]);
+            PathChecker::new(&cfg, &relooped, |l| l).check_all_paths(5)
+        };
+        let good = || {
+            vec![
+                Actions(0),
+                If(
+                    ReSeq(vec![Actions(1), TableJump([(4, 1), (7, 0)].into())]),
+                    ReSeq(vec![]),
+                ),
+                Actions(2),
+                Return,
+            ]
+        };
+        assert!(check(good()).is_ok());
+
+        let mut swapped = good();
+        swapped[1] = If(ReSeq(vec![]), ReSeq(vec![Actions(1)]));
+        assert_eq!(
+            check(swapped),
+            Err(Divergence::Mismatch {
+                path: vec![0],
+                expected: Some(1),
+                found: Some(2),
+            })
+        );
+
+        let mut missing_case = good();
+        missing_case[1] = If(
+            ReSeq(vec![Actions(1), TableJump([(4, 1)].into())]),
+            ReSeq(vec![]),
+        );
+        assert!(matches!(
+            check(missing_case),
+            Err(Divergence::Stuck { reason, .. }) if reason == "no jump for case 7"
+        ));
+
+        let mut far_br = good();
+        far_br[1] = If(ReSeq(vec![Actions(1), Br(3)]), ReSeq(vec![]));
+        assert!(matches!(check(far_br), Err(Divergence::Stuck { .. })));
+
+        let mut no_return = good();
+        no_return[2] = Br(0);
+        assert!(matches!(
+            check(no_return),
+            Err(Divergence::Mismatch {
+                expected: Some(2),
+                found: Some(0),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn fixtures() {
+        let mut rng = Rng(0x5eed);
+        for text in FIXTURES {
+            let cfg = fixture(text);
+            check_paths(&cfg, &relooped(&cfg).unwrap(), &mut rng);
+        }
+    }
+
+    #[test]
+    fn random_reducible_cfgs() {
+        let mut rng = Rng(0x0dd_5eed);
+        for _ in 0..500 {
+            let nodes = 2 + rng.below(14);
+            let cfg = random_cfg(&mut rng, nodes, true);
+            // reducible CFGs aren't split
+            let reduced = reduce(&cfg);
+            assert_eq!(reduced.nodes().len(), nodes, "split the reducible\n{}", cfg);
+            check_paths(&cfg, &EnrichedCfg::new(reduced).reloop(), &mut rng);
+        }
+    }
+
+    #[test]
+    fn random_irreducible_cfgs() {
+        let mut rng = Rng(0xbad_5eed);
+        let mut checked = 0;
+        for _ in 0..500 {
+            let nodes = 2 + rng.below(14);
+            let cfg = random_cfg(&mut rng, nodes, false);
+            if let Ok(relooped) = relooped(&cfg) {
+                check_paths(&cfg, &relooped, &mut rng);
+                checked += 1;
+            }
+        }
+        assert!(checked > 400, "only {} CFGs reduced", checked);
+    }
+}
@@ -1,5 +1,8 @@
+pub mod analysis;
 pub mod cfg;
+pub mod dense;
 pub mod dot_debug;
 pub mod enrichments;
+pub mod equivalence;
 pub mod relooper;
 pub mod supergraph;
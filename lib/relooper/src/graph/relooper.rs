@@ -1,17 +1,21 @@
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Display;
 
+use serde::{Deserialize, Serialize};
+
 use crate::graph::cfg::{CfgEdge::*, CfgLabel};
 use crate::graph::enrichments::EnrichedCfg;
 use crate::graph::relooper::ReBlock::*;
 
-#[derive(Debug)]
+/// Relooped trees nest as deep as the CFG's merge nodes and loops, so deserializing those of
+/// contracts usually needs `serde_json::Deserializer::disable_recursion_limit`.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ReSeq<TLabel: CfgLabel>(pub Vec<ReBlock<TLabel>>);
 
 /// describes relooped graph structure
 /// consists of three "container" variants and several "actions" variants
 /// containers define tree structure, actions denotes runtime control flow behaviour
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ReBlock<TLabel: CfgLabel> {
     Block(ReSeq<TLabel>),
     Loop(ReSeq<TLabel>),
@@ -19,6 +23,10 @@ pub enum ReBlock<TLabel: CfgLabel> {
 
     Actions(TLabel),
     Br(u32),
+    /// `Br` taken if the condition of the last node's `Cond` edge holds, to its first target
+    BrIf(u32),
+    /// `Br` taken if the condition doesn't hold
+    BrUnless(u32),
     TableJump(BTreeMap<usize, u32>),
     Return,
 }
@@ -35,6 +43,118 @@ impl<TLabel: CfgLabel> ReSeq<TLabel> {
     pub(crate) fn single(block: ReBlock<TLabel>) -> ReSeq<TLabel> {
         ReSeq(vec![block])
     }
+
+    /// Whether control may reach the end of the sequence, conservatively.
+    pub fn falls_through(&self) -> bool {
+        match self.0.last() {
+            Some(Br(_) | TableJump(_) | Return) => false,
+            Some(If(then, otherwise)) => then.falls_through() || otherwise.falls_through(),
+            Some(Loop(inner)) => inner.falls_through(),
+            _ => true,
+        }
+    }
+
+    /// Whether there are branches to the construct `depth` levels out of the sequence.
+    fn branches_to(&self, depth: u32) -> bool {
+        self.0.iter().any(|block| match block {
+            Block(inner) | Loop(inner) => inner.branches_to(depth + 1),
+            If(then, otherwise) => then.branches_to(depth + 1) || otherwise.branches_to(depth + 1),
+            Br(d) | BrIf(d) | BrUnless(d) => *d == depth,
+            TableJump(table) => table.values().any(|d| *d == depth),
+            Actions(_) | Return => false,
+        })
+    }
+
+    /// Adjusts the branches out of the sequence to it being moved out of the construct around
+    /// it, which mustn't be branched to from the sequence.
+    fn unnest(&mut self, inner: u32) {
+        for block in &mut self.0 {
+            match block {
+                Block(seq) | Loop(seq) => seq.unnest(inner + 1),
+                If(then, otherwise) => {
+                    then.unnest(inner + 1);
+                    otherwise.unnest(inner + 1);
+                }
+                Br(depth) | BrIf(depth) | BrUnless(depth) if *depth > inner => *depth -= 1,
+                TableJump(table) => table
+                    .values_mut()
+                    .filter(|depth| **depth > inner)
+                    .for_each(|depth| *depth -= 1),
+                _ => {}
+            }
+        }
+    }
+
+    /// Lays down a two-way branch as a conditional branch followed by the other arm when an arm
+    /// only branches, or as an `If` of one arm followed by the other one when that arm never
+    /// falls through, not to nest the other one.
+    fn branch_on(then: ReSeq<TLabel>, mut otherwise: ReSeq<TLabel>) -> ReSeq<TLabel> {
+        let mut then = then;
+        match (&then.0[..], &otherwise.0[..]) {
+            (&[Br(depth)], _) => {
+                otherwise.unnest(0);
+                BrIf(depth - 1).cons(otherwise)
+            }
+            (_, &[Br(depth)]) => {
+                then.unnest(0);
+                BrUnless(depth - 1).cons(then)
+            }
+            _ if !then.falls_through() && !otherwise.0.is_empty() => {
+                otherwise.unnest(0);
+                If(then, ReSeq(vec![])).cons(otherwise)
+            }
+            _ if !otherwise.falls_through() && !then.0.is_empty() => {
+                then.unnest(0);
+                If(ReSeq(vec![]), otherwise).cons(then)
+            }
+            _ => ReSeq::single(If(then, otherwise)),
+        }
+    }
+
+    /// Removes the branches control would fall through to anyway, flattens the constructs that
+    /// aren't branched to and turns `If`s into conditional branches where it can, see `branch_on`.
+    /// Branching to any of the `exits` depths is the same as falling off the end of the sequence.
+    fn simplify(self, exits: &[u32]) -> ReSeq<TLabel> {
+        let len = self.0.len();
+        let mut res = Vec::with_capacity(len);
+        for (i, block) in self.0.into_iter().enumerate() {
+            let last = i + 1 == len;
+            // the exits of constructs at the end of the sequence are those of the sequence
+            let outer: Vec<u32> = match last {
+                true => exits.iter().map(|depth| depth + 1).collect(),
+                false => vec![],
+            };
+            match block {
+                Block(inner) => {
+                    let mut body_exits = vec![0];
+                    body_exits.extend(&outer);
+                    let mut inner = inner.simplify(&body_exits);
+                    if inner.branches_to(0) {
+                        res.push(Block(inner));
+                    } else {
+                        inner.unnest(0);
+                        res.extend(inner.0);
+                    }
+                }
+                Loop(inner) => {
+                    let mut inner = inner.simplify(&outer);
+                    if inner.branches_to(0) {
+                        res.push(Loop(inner));
+                    } else {
+                        inner.unnest(0);
+                        res.extend(inner.0);
+                    }
+                }
+                If(then, otherwise) => {
+                    let branch = Self::branch_on(then.simplify(&outer), otherwise.simplify(&outer));
+                    res.extend(branch.0);
+                }
+                Br(depth) if last && exits.contains(&depth) => {}
+                block => res.push(block),
+            }
+        }
+        ReSeq(res)
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -187,6 +307,131 @@ impl<TLabel: CfgLabel + Display> EnrichedCfg<TLabel> {
     }
 
     pub fn reloop(&self) -> ReSeq<TLabel> {
-        self.do_tree(self.cfg.entry, &Vec::new())
+        self.do_tree(self.cfg.entry, &Vec::new()).simplify(&[])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::graph::cfg::cfg_parsing::fixtures::{fixture, FIXTURES};
+    use crate::graph::cfg::Cfg;
+    use crate::graph::cfg::CfgEdge::{Cond, Switch, Uncond};
+    use crate::graph::enrichments::{DomTree, EnrichedCfg};
+    use crate::graph::equivalence::PathChecker;
+    use crate::graph::relooper::ReBlock::{Actions, Br, BrIf, If, Loop, Return};
+    use crate::graph::relooper::ReSeq;
+    use crate::graph::supergraph::{reduce, SLabel};
+    use serde::Deserialize;
+
+    fn from_deep_json(json: &str) -> ReSeq<SLabel<usize>> {
+        let mut deserializer = serde_json::Deserializer::from_str(json);
+        deserializer.disable_recursion_limit();
+        ReSeq::deserialize(&mut deserializer).unwrap()
+    }
+
+    #[test]
+    fn json_format() {
+        let cfg = Cfg::from_edges(
+            0,
+            vec![(0, Cond(1, 2)), (1, Uncond(0))].into_iter().collect(),
+        );
+        let enriched = EnrichedCfg::new(cfg);
+        assert_eq!(
+            serde_json::to_string(&enriched.domination).unwrap(),
+            "[[0,0],[1,0],[2,0]]"
+        );
+        assert_eq!(
+            serde_json::to_string(&enriched.reloop()).unwrap(),
+            r#"[{"Loop":[{"Actions":0},{"If":[[{"Actions":1},{"Br":1}],[]]},{"Actions":2},"Return"]}]"#
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        for text in FIXTURES {
+            let reduced = reduce(&fixture(text));
+            let json = serde_json::to_string(&reduced).unwrap();
+            let parsed: Cfg<SLabel<usize>> = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed.edges(), reduced.edges());
+
+            let enriched = EnrichedCfg::new(parsed);
+            let json = serde_json::to_string(&enriched.domination).unwrap();
+            let parsed: DomTree<SLabel<usize>> = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed.dominated, enriched.domination.dominated);
+
+            let relooped = enriched.reloop();
+            let json = serde_json::to_string(&relooped).unwrap();
+            assert_eq!(from_deep_json(&json), relooped);
+        }
+    }
+
+    #[test]
+    fn simplified_shapes() {
+        let relooped = |text: &str| {
+            let cfg: Cfg<usize> = text.parse().unwrap();
+            let relooped = EnrichedCfg::new(cfg.clone()).reloop();
+            let checker = PathChecker::new(&cfg, &relooped, |l| l);
+            assert!(checker.check_all_paths(8).is_ok());
+            relooped
+        };
+
+        // the branches to the merge node are fallen through, so its block goes
+        assert_eq!(
+            relooped("0\n0 1 2\n1 3\n2 3"),
+            ReSeq(vec![
+                Actions(0),
+                If(ReSeq(vec![Actions(1)]), ReSeq(vec![Actions(2)])),
+                Actions(3),
+                Return,
+            ])
+        );
+        // an `If` of an arm falling through to the merge node has no else arm
+        assert_eq!(
+            relooped("0\n0 1 2\n1 2"),
+            ReSeq(vec![
+                Actions(0),
+                If(ReSeq(vec![Actions(1)]), ReSeq(vec![])),
+                Actions(2),
+                Return,
+            ])
+        );
+        // an arm only branching is a conditional branch
+        assert_eq!(
+            relooped("0\n0 1\n1 1 2"),
+            ReSeq(vec![
+                Actions(0),
+                Loop(ReSeq(vec![Actions(1), BrIf(0), Actions(2), Return])),
+            ])
+        );
+        // the exit of the loop isn't nested in the `If` continuing it
+        assert_eq!(
+            relooped("0\n0 1\n1 2 3\n2 1"),
+            ReSeq(vec![
+                Actions(0),
+                Loop(ReSeq(vec![
+                    Actions(1),
+                    If(ReSeq(vec![Actions(2), Br(1)]), ReSeq(vec![])),
+                    Actions(3),
+                    Return,
+                ])),
+            ])
+        );
+    }
+
+    #[test]
+    fn self_loops() {
+        let cfg = Cfg::from_edges(
+            0,
+            vec![
+                (0, Cond(0, 1)),
+                (1, Switch(vec![(0, 1), (1, 2)])),
+                (2, Uncond(2)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let relooped = EnrichedCfg::new(cfg.clone()).reloop();
+        let checker = PathChecker::new(&cfg, &relooped, |l| l);
+        assert!(checker.check_all_paths(6).is_ok());
     }
 }
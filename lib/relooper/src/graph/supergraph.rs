@@ -1,12 +1,45 @@
-use crate::graph::cfg::{Cfg, CfgLabel};
+use crate::graph::cfg::{Cfg, CfgEdge, CfgLabel};
+use crate::graph::dense::DenseCfg;
 use crate::graph::supergraph::NodeAction::{MergeInto, SplitFor};
-use crate::traversal::graph::dfs::{DfsPost, DfsPostReverseInstantiator};
-use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::{Debug, Display, Formatter};
 
 type SVersion = usize;
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+/// Irreducible control flow that couldn't be made reducible.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReduceError<TLabel> {
+    /// Splitting the regions entered at `entries` would grow the CFG to
+    /// `nodes` nodes, over the `limit`.
+    SplitBudget {
+        entries: Vec<TLabel>,
+        nodes: usize,
+        limit: usize,
+    },
+}
+
+impl<TLabel: Debug> Display for ReduceError<TLabel> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SplitBudget {
+                entries,
+                nodes,
+                limit,
+            } => write!(
+                f,
+                "irreducible control flow entered at {:?}: node splitting would grow the CFG \
+                 to {} nodes, over the limit of {}",
+                entries, nodes, limit
+            ),
+        }
+    }
+}
+
+impl<TLabel: Debug> std::error::Error for ReduceError<TLabel> {}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Serialize, Deserialize)]
 pub struct SLabel<TLabel: CfgLabel> {
     pub origin: TLabel,
     version: SVersion,
@@ -37,237 +70,249 @@ impl<TLabel: CfgLabel> SLabel<TLabel> {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Ord, PartialOrd, Debug)]
-struct SNode<TLabel: CfgLabel> {
-    head: SLabel<TLabel>,
-    contained: BTreeSet<SLabel<TLabel>>,
-}
-
-impl<TLabel: CfgLabel> From<SLabel<TLabel>> for SNode<TLabel> {
-    fn from(label: SLabel<TLabel>) -> Self {
-        Self {
-            head: label,
-            contained: BTreeSet::from([label]),
-        }
-    }
-}
-
-impl<TLabel: CfgLabel> SNode<TLabel> {
-    fn new(head: SLabel<TLabel>, contained: BTreeSet<SLabel<TLabel>>) -> Self {
-        Self { head, contained }
-    }
-}
-
-type SNodeLabel<TLabel> = SLabel<TLabel>;
-
+/// The CFG being reduced, over dense node indices as in `DenseCfg`, along with the supernodes
+/// its nodes are merged into. A supernode is told apart by the node at its head, the entry being
+/// `0`. Splitting appends the copies it makes to the nodes.
 pub struct SuperGraph<TLabel: CfgLabel> {
-    cfg: Cfg<SLabel<TLabel>>,
-    versions: BTreeMap<TLabel, usize>,
-    nodes: BTreeMap<SNodeLabel<TLabel>, SNode<TLabel>>,
-    label_location: BTreeMap<SLabel<TLabel>, SNodeLabel<TLabel>>,
+    labels: Vec<SLabel<TLabel>>,
+    /// the original node each node is a copy of, or is, for numbering the copies
+    origins: Vec<usize>,
+    versions: Vec<SVersion>,
+    edges: Vec<CfgEdge<usize>>,
+    /// the in-edges of each node, kept up to date as splitting redirects edges
+    preds: Vec<Vec<usize>>,
+    /// the head of the supernode each node is in
+    location: Vec<usize>,
+    /// the nodes of each supernode by its head, empty for the other nodes
+    contained: Vec<Vec<usize>>,
 }
 
-type SplitInto<TLabel> = Vec<SLabel<TLabel>>;
-
 #[derive(Debug)]
-enum NodeAction<TLabel: CfgLabel> {
-    MergeInto(SLabel<TLabel>),
-    SplitFor(SplitInto<TLabel>),
+enum NodeAction {
+    MergeInto(usize),
+    SplitFor(Vec<usize>),
 }
 
 impl<TLabel: CfgLabel> SuperGraph<TLabel> {
+    /// Makes a supernode of each node reachable from the entry.
     pub(crate) fn new(cfg: &Cfg<TLabel>) -> Self {
-        let new_cfg = cfg.map_label(|&l| SLabel::from(l));
-
-        let nodes: BTreeMap<SLabel<TLabel>, SNode<TLabel>> = new_cfg
-            .nodes()
+        let dense = DenseCfg::new(cfg, cfg.entry);
+        let len = dense.len();
+        let edges = dense
+            .labels()
             .iter()
-            .copied()
-            .map(|&l| (l, SNode::from(l)))
+            .map(|label| cfg.edge(label).map(|to| dense.index(to).unwrap()))
             .collect();
-
-        let label_location: BTreeMap<SLabel<TLabel>, SLabel<TLabel>> =
-            nodes.iter().map(|(&l, _n)| (l, l)).collect();
-
-        let versions: BTreeMap<TLabel, usize> =
-            label_location.iter().map(|(&l, _)| (l.origin, 0)).collect();
-
         Self {
-            cfg: new_cfg,
-            versions,
-            nodes,
-            label_location,
+            labels: dense.labels().iter().map(|&l| SLabel::from(l)).collect(),
+            origins: (0..len).collect(),
+            versions: vec![0; len],
+            edges,
+            preds: (0..len).map(|n| dense.predecessors(n).to_vec()).collect(),
+            location: (0..len).collect(),
+            contained: (0..len).map(|n| vec![n]).collect(),
         }
     }
 
-    /// We are using that order for traversing supernodes graph for choosing between merge/split actions
-    fn snode_order(&self) -> Vec<SLabel<TLabel>> {
-        let start = self.nodes.get(&self.cfg.entry).unwrap().clone();
-        DfsPost::<_, _, HashSet<_>>::reverse(start.head, |slabel| {
-            let snode = self.nodes.get(slabel).unwrap();
-            snode.contained.iter().flat_map(|l| {
-                self.cfg
-                    .children(l)
-                    .into_iter()
-                    .map(|to| *self.label_location.get(to).unwrap())
-            })
-        })
+    fn heads(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.labels.len()).filter(|&n| self.location[n] == n)
     }
 
-    /// finding out applicable action for given supernode
-    fn node_action(
-        &self,
-        node: &SNode<TLabel>,
-        cfg_in_edges: &HashMap<SLabel<TLabel>, HashSet<SLabel<TLabel>>>,
-    ) -> Option<NodeAction<TLabel>> {
-        let mut incoming: BTreeSet<&SNode<TLabel>> = cfg_in_edges
-            .get(&node.head)
-            .into_iter()
-            .flatten()
-            .map(|l| self.label_location.get(l).unwrap())
-            .map(|snode_label| self.nodes.get(snode_label).unwrap())
+    /// the supernodes entering the one at `head`, ordered by label
+    fn incoming(&self, head: usize) -> Vec<usize> {
+        let mut incoming: Vec<usize> = self.preds[head]
+            .iter()
+            .map(|&pred| self.location[pred])
+            // internal edges ending in the head don't enter it
+            .filter(|&snode| snode != head)
             .collect();
-        // if given node have internal edges ending in its head, it will be seen as incoming supernode, which isn't useful
-        incoming.remove(node);
+        incoming.sort_by_key(|&snode| self.labels[snode]);
+        incoming.dedup();
+        incoming
+    }
 
+    /// finding out applicable action for given supernode
+    fn node_action(&self, head: usize) -> Option<NodeAction> {
+        // the entry can't be merged into its predecessors nor split, it is where the others end up merged into
+        if head == 0 {
+            return None;
+        }
+        let mut incoming = self.incoming(head);
         match incoming.len() {
             0 => None,
-            1 => Some(MergeInto(incoming.first().unwrap().head)),
-            _ => Some(SplitFor(incoming.into_iter().map(|s| s.head).collect())),
+            1 => Some(MergeInto(incoming.pop().unwrap())),
+            _ => Some(SplitFor(incoming)),
         }
     }
 
-    /// merging `from_label` into `to_label`, entirely removing `from` supernode from the supergraph
-    fn merge(&mut self, from_label: SLabel<TLabel>, to_label: SLabel<TLabel>) {
-        let from = self.nodes.remove(&from_label).unwrap();
-        let to = self.nodes.get_mut(&to_label).unwrap();
-        to.contained.extend(from.contained.iter());
-        for inner in from.contained {
-            assert_eq!(self.label_location.insert(inner, to.head), Some(from.head));
+    /// merging the supernode at `from` into the one at `to`, entirely removing `from`
+    fn merge(&mut self, from: usize, to: usize) {
+        let inner = std::mem::take(&mut self.contained[from]);
+        for &node in &inner {
+            self.location[node] = to;
         }
+        self.contained[to].extend(inner);
     }
 
-    /// splitting given supernode for each of `split` nodes
-    /// duplicates every node residing that supernode
-    fn split(&mut self, node_label: SLabel<TLabel>, split: &SplitInto<TLabel>) {
-        let split_snode = self.nodes.get(&node_label).unwrap().to_owned();
-
-        let outgoing_edges: HashMap<_, _> = split_snode
-            .contained
-            .iter()
-            .copied()
-            .map(|inner| (inner, self.cfg.edge(&inner).clone()))
-            .collect();
-
-        //duplicate every label in that supernode (for each split except the first one, bc original version can be reused)
-        for split_for_l in &split[1..] {
-            let split_for = self.nodes.get(split_for_l).unwrap();
-            let mut versions_mapping: HashMap<SLabel<TLabel>, SLabel<TLabel>> = Default::default();
-            for &inner in split_snode.contained.iter() {
-                self.versions.entry(inner.origin).and_modify(|version| {
-                    *version += 1;
-                    let a = *version;
-                    let new_ver = SLabel::new(inner.origin, a);
-                    versions_mapping.insert(inner, new_ver);
-                });
+    /// merges the supernodes at `pending`, and the ones merging leaves with a single incoming
+    /// supernode, until there are none left to merge
+    fn merge_all(&mut self, mut pending: Vec<usize>) {
+        while let Some(head) = pending.pop() {
+            if self.location[head] != head {
+                continue; // merged already
             }
-
-            // copy internal & outgoing edges
-            for (o_from, edge) in &outgoing_edges {
-                let curr_from = versions_mapping[o_from];
-                // in case of internal edge, we should redirect that edge to new copy of internal node
-                let maybe_redirected_edge = edge.map(|l| *versions_mapping.get(l).unwrap_or(l));
-                self.cfg.add_edge(curr_from, maybe_redirected_edge);
-            }
-
-            for &f in &split_for.contained {
-                let e = self.cfg.edge_mut(&f);
-                if e.iter().any(|&to| to == split_snode.head) {
-                    e.apply(|to| *versions_mapping.get(to).unwrap_or(to))
+            if let Some(MergeInto(to)) = self.node_action(head) {
+                // the supernodes entered from this one are entered from `to` instead, which
+                // `to` itself may have been entered from besides
+                for &node in &self.contained[head] {
+                    pending.extend(self.edges[node].iter().map(|&to| self.location[to]));
                 }
+                self.merge(head, to);
             }
+        }
+    }
 
-            // populate supernode graph with new node's new version (for each split)
-            let splitted_head = versions_mapping.get(&split_snode.head).unwrap().to_owned();
-            let contained: Vec<_> = versions_mapping.values().copied().collect();
-            self.nodes.insert(
-                splitted_head,
-                SNode::new(
-                    splitted_head,
-                    BTreeSet::from_iter(contained.iter().copied()),
-                ),
-            );
-            let node_ref = self.nodes.get(&splitted_head).unwrap();
-            for c in contained {
-                self.label_location.insert(c, node_ref.head);
-            }
+    fn add_pred(&mut self, node: usize, pred: usize) {
+        if !self.preds[node].contains(&pred) {
+            self.preds[node].push(pred);
         }
     }
 
-    /// iterates over supergraph (using `snode_order` on each iteration) until there is only one node left
-    /// on each iteration we either:
-    /// * remove one supernode (by merging it into another one)
-    /// * or duplicate one (by splitting, one dup for each "parent")
-    /// in the end, there is only one supernode, which contains all the nodes and whose head is "entry" node
-    fn reduce(&mut self) {
-        let mut in_edges: Option<HashMap<SLabel<TLabel>, HashSet<SLabel<TLabel>>>> = None;
-        'outer: loop {
-            let order: Vec<SLabel<TLabel>> = self.snode_order();
-
-            let mut splits: HashMap<SLabel<TLabel>, SplitInto<TLabel>> = Default::default();
-
-            for snode_label in order {
-                let n = self.nodes.get(&snode_label).unwrap();
-                let in_edges = in_edges.get_or_insert_with(|| self.cfg.in_edges());
-                match self.node_action(n, in_edges) {
-                    None => {}
-                    Some(SplitFor(split)) => {
-                        splits.insert(n.head, split);
-                    }
-                    Some(MergeInto(to)) => {
-                        self.merge(n.head, to);
-                        continue 'outer;
-                    }
+    /// splitting the supernode at `head` for each of the supernodes at `split`
+    /// duplicates every node residing in that supernode, returns the heads of the copies
+    fn split(&mut self, head: usize, split: &[usize]) -> Vec<usize> {
+        let inner = self.contained[head].clone();
+        let mut heads = Vec::with_capacity(split.len() - 1);
+
+        // duplicate every node in that supernode (for each split except the first one, bc original version can be reused)
+        for &split_for in &split[1..] {
+            let first = self.labels.len();
+            let copies: HashMap<usize, usize> = inner.iter().copied().zip(first..).collect();
+            for &node in &inner {
+                let origin = self.origins[node];
+                self.versions[origin] += 1;
+                self.labels
+                    .push(SLabel::new(self.labels[node].origin, self.versions[origin]));
+                self.origins.push(origin);
+                // in case of internal edge, it is redirected to the new copy of the internal node
+                let edge = self.edges[node].map(|to| *copies.get(to).unwrap_or(to));
+                self.edges.push(edge);
+                self.preds.push(Vec::new());
+                self.location.push(copies[&head]);
+                self.contained.push(Vec::new());
+            }
+            for copy in first..self.labels.len() {
+                let targets: Vec<usize> = self.edges[copy].iter().copied().collect();
+                for to in targets {
+                    self.add_pred(to, copy);
                 }
             }
+            self.contained[copies[&head]] = (first..self.labels.len()).collect();
 
-            let mut split_len: BTreeMap<usize, Vec<&SLabel<TLabel>>> = BTreeMap::new();
-
-            for (split_node, split_for) in splits.iter() {
-                split_len
-                    .entry(split_for.len())
-                    .or_default()
-                    .push(split_node);
+            // the supernode the copy is for enters it instead of the original
+            for from in self.contained[split_for].clone() {
+                if !self.edges[from].iter().any(|&to| to == head) {
+                    continue;
+                }
+                for to in self.edges[from].iter().copied().collect::<Vec<usize>>() {
+                    if let Some(&copy) = copies.get(&to) {
+                        self.preds[to].retain(|&pred| pred != from);
+                        self.add_pred(copy, from);
+                    }
+                }
+                self.edges[from].apply(|to| *copies.get(to).unwrap_or(to));
             }
+            heads.push(copies[&head]);
+        }
+        heads
+    }
 
-            if let Some((_, biggest_splits)) = split_len.last_key_value() {
-                // TODO select by internal node count?
-                let split_node = biggest_splits.first().unwrap();
-                let split_for = splits.get(split_node).unwrap();
-
-                self.split(**split_node, split_for);
-                in_edges = None;
-
-                continue;
-            } else {
-                break;
+    /// merges supernodes until there is only one node left, splitting one whenever none can be merged:
+    /// * a supernode entered from a single one is merged into it
+    /// * one entered from several is duplicated, one dup for each "parent", which can then be merged
+    ///
+    /// in the end, there is only one supernode, which contains all the nodes and whose head is "entry" node
+    ///
+    /// fails if a split would grow the cfg over `limit` nodes
+    fn reduce(&mut self, limit: usize) -> Result<(), ReduceError<TLabel>> {
+        // in reverse postorder, as `pending` is popped from its end
+        self.merge_all((0..self.labels.len()).rev().collect());
+        loop {
+            let splits: Vec<(usize, Vec<usize>)> = self
+                .heads()
+                .filter_map(|head| match self.node_action(head) {
+                    Some(SplitFor(split)) => Some((head, split)),
+                    _ => None,
+                })
+                .collect();
+
+            // the supernode entered from the most others, the first by label among them
+            // TODO select by internal node count?
+            let (split_node, split_for) = match splits
+                .iter()
+                .max_by_key(|(head, split)| (split.len(), Reverse(self.labels[*head])))
+            {
+                Some(split) => split,
+                None => break,
+            };
+
+            let copies = self.contained[*split_node].len() * (split_for.len() - 1);
+            let nodes = self.labels.len() + copies;
+            if nodes > limit {
+                let entries: BTreeSet<TLabel> = splits
+                    .iter()
+                    .map(|(head, _)| self.labels[*head].origin)
+                    .collect();
+                return Err(ReduceError::SplitBudget {
+                    entries: entries.into_iter().collect(),
+                    nodes,
+                    limit,
+                });
             }
+            let mut pending = self.split(*split_node, split_for);
+            pending.push(*split_node);
+            self.merge_all(pending);
         }
-        assert_eq!(self.nodes.len(), 1);
+        assert_eq!(self.heads().count(), 1);
+        Ok(())
+    }
+
+    fn into_cfg(self) -> Cfg<SLabel<TLabel>> {
+        let mut cfg = Cfg::new(self.labels[0]);
+        for (node, edge) in self.edges.iter().enumerate() {
+            cfg.add_edge(self.labels[node], edge.map(|&to| self.labels[to]));
+        }
+        cfg
     }
 }
 
+/// Makes the part of `cfg` reachable from its entry reducible, splitting the
+/// nodes of irreducible regions so that every loop has a single entry.
+/// Splitting is exponential in the worst case, see `reduce_within` to bound it.
 pub fn reduce<TLabel: CfgLabel>(cfg: &Cfg<TLabel>) -> Cfg<SLabel<TLabel>> {
+    match reduce_within(cfg, usize::MAX) {
+        Ok(reduced) => reduced,
+        Err(err) => unreachable!("{}", err),
+    }
+}
+
+/// Like `reduce`, with splitting limited to a CFG of `limit` nodes.
+pub fn reduce_within<TLabel: CfgLabel>(
+    cfg: &Cfg<TLabel>,
+    limit: usize,
+) -> Result<Cfg<SLabel<TLabel>>, ReduceError<TLabel>> {
     let mut super_graph = SuperGraph::new(cfg);
-    super_graph.reduce();
-    super_graph.cfg
+    super_graph.reduce(limit)?;
+    Ok(super_graph.into_cfg())
 }
 
 #[cfg(test)]
 mod test {
+    use crate::graph::cfg::cfg_parsing::fixtures::{fixture, FIXTURES};
     use crate::graph::cfg::CfgEdge::{Cond, Uncond};
     use crate::graph::cfg::{Cfg, CfgLabel};
-    use crate::graph::supergraph::{reduce, SLabel};
+    use crate::graph::enrichments::EnrichedCfg;
+    use crate::graph::supergraph::{reduce, reduce_within, ReduceError, SLabel};
     use std::collections::{HashMap, HashSet};
 
     fn test_reduce<TLabel: CfgLabel>(
@@ -338,4 +383,48 @@ mod test {
 
         assert!(test_reduce(cfg, reduced));
     }
+
+    #[test]
+    fn fixtures() {
+        for text in FIXTURES {
+            let mut cfg = fixture(text);
+            let reduced = reduce(&cfg);
+            let enriched = EnrichedCfg::new(reduced.clone());
+            enriched.reloop();
+
+            cfg.strip_unreachable();
+            assert!(test_reduce(cfg, reduced));
+        }
+    }
+
+    #[test]
+    fn entry_in_loop() {
+        let cfg = Cfg::from_edges(
+            0,
+            vec![(0, Uncond(1)), (1, Cond(0, 2))].into_iter().collect(),
+        );
+        let reduced = reduce(&cfg);
+
+        assert_eq!(reduced.nodes().len(), 3);
+        assert!(test_reduce(cfg, reduced));
+    }
+
+    #[test]
+    fn split_budget() {
+        let cfg = fixture(include_str!("../../test_data/unreducible-moderate.txt"));
+        let nodes = cfg.nodes().len();
+        assert!(reduce_within(&cfg, 2 * nodes).is_ok());
+        match reduce_within(&cfg, nodes) {
+            Err(ReduceError::SplitBudget { entries, limit, .. }) => {
+                assert_eq!(limit, nodes);
+                assert!(entries.len() > 1, "{:?}", entries);
+            }
+            Ok(_) => panic!("irreducible cfg reduced without splitting"),
+        }
+
+        // reducible graphs need no splitting
+        let cfg = fixture(include_str!("../../test_data/2.txt"));
+        let nodes = cfg.nodes().len();
+        assert_eq!(reduce_within(&cfg, nodes).unwrap().nodes().len(), nodes);
+    }
 }